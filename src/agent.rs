@@ -0,0 +1,282 @@
+//! Agent keeping the passphrases and PINs which unlock identities for a while, so that filters
+//! run by git for one file after another don't ask for them again each time.
+//!
+//! The agent listens on a unix socket in `$XDG_RUNTIME_DIR`, which only the user can access.
+//! Each connection carries a single request line: `get <description>`, `put <description>
+//! <passphrase>`, `forget <description>` or `stop`, with the values base64 encoded. `get` is
+//! answered with `ok <passphrase>` or `none`, the others with `ok`. Entries expire after the
+//! time to live the agent was started with.
+//!
+//! Clients only `put` passphrases which unlocked their identity, and `forget` passphrases the
+//! agent handed out which didn't, e.g. after the identity file was encrypted again.
+
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use age::secrecy::{ExposeSecret, SecretString};
+use anyhow::{bail, Result};
+use base64::{prelude::BASE64_STANDARD, Engine};
+
+/// How long clients wait for the agent, it answers from memory
+#[cfg(unix)]
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Path of the agent's socket, `None` without `XDG_RUNTIME_DIR`
+pub(crate) fn socket_path() -> Option<PathBuf> {
+    let dir = std::env::var_os("XDG_RUNTIME_DIR").filter(|d| !d.is_empty())?;
+    Some(PathBuf::from(dir).join("git-agecrypt").join("agent.sock"))
+}
+
+/// The passphrase the agent holds for `description`, `None` if no agent is running or it
+/// doesn't know the passphrase
+pub(crate) fn lookup(description: &str) -> Option<SecretString> {
+    let response = request(&format!("get {}", BASE64_STANDARD.encode(description))).ok()?;
+    let passphrase = response.strip_prefix("ok ")?;
+    let passphrase = BASE64_STANDARD.decode(passphrase).ok()?;
+    String::from_utf8(passphrase).ok().map(SecretString::new)
+}
+
+/// Hands the passphrase entered for `description` to the agent, if one is running, once it
+/// unlocked the identity
+pub(crate) fn store(description: &str, passphrase: &SecretString) {
+    let line = format!(
+        "put {} {}",
+        BASE64_STANDARD.encode(description),
+        BASE64_STANDARD.encode(passphrase.expose_secret())
+    );
+    if let Err(err) = request(&line) {
+        log::debug!("Passphrase not stored in agent; error={:?}", err);
+    }
+}
+
+/// Makes the agent drop the passphrase for `description`, as it didn't unlock the identity
+pub(crate) fn forget(description: &str) {
+    if let Err(err) = request(&format!("forget {}", BASE64_STANDARD.encode(description))) {
+        log::debug!("Passphrase not removed from agent; error={:?}", err);
+    }
+}
+
+/// Asks the agent to exit, returns whether one was running
+pub(crate) fn stop() -> Result<bool> {
+    match request("stop") {
+        Ok(_) => Ok(true),
+        Err(err) if is_not_running(&err) => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Whether an agent answers on the socket
+pub(crate) fn is_running() -> bool {
+    request("get").is_ok()
+}
+
+fn is_not_running(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<std::io::Error>().map(|e| e.kind()),
+        Some(std::io::ErrorKind::NotFound | std::io::ErrorKind::ConnectionRefused)
+    )
+}
+
+#[cfg(unix)]
+fn request(line: &str) -> Result<String> {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        os::unix::net::UnixStream,
+    };
+
+    let Some(path) = socket_path() else {
+        bail!("XDG_RUNTIME_DIR isn't set");
+    };
+    let mut stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.write_all(format!("{}\n", line).as_bytes())?;
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response)?;
+    let response = response.trim_end();
+    if response.is_empty() {
+        bail!("The agent closed the connection without answering");
+    }
+    Ok(response.into())
+}
+
+#[cfg(not(unix))]
+fn request(_line: &str) -> Result<String> {
+    bail!("The agent is only available on unix systems")
+}
+
+/// Passphrases held by the agent
+struct Entries {
+    ttl: Duration,
+    entries: HashMap<String, (SecretString, Instant)>,
+}
+
+impl Entries {
+    fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Handles a request line, returning the response and whether the agent should exit
+    fn handle(&mut self, line: &str) -> (String, bool) {
+        let now = Instant::now();
+        let ttl = self.ttl;
+        self.entries.retain(|_, (_, stored)| now - *stored < ttl);
+        let decode = |value: &str| {
+            BASE64_STANDARD
+                .decode(value)
+                .ok()
+                .and_then(|v| String::from_utf8(v).ok())
+        };
+        let mut words = line.split(' ');
+        match (words.next(), words.next(), words.next()) {
+            (Some("get"), Some(description), None) => {
+                match decode(description).and_then(|d| self.entries.get(&d)) {
+                    Some((passphrase, _)) => (
+                        format!("ok {}", BASE64_STANDARD.encode(passphrase.expose_secret())),
+                        false,
+                    ),
+                    None => ("none".into(), false),
+                }
+            }
+            // Used to check that the agent is running
+            (Some("get"), None, None) => ("none".into(), false),
+            (Some("put"), Some(description), Some(passphrase)) => {
+                match (decode(description), decode(passphrase)) {
+                    (Some(description), Some(passphrase)) => {
+                        self.entries
+                            .insert(description, (SecretString::new(passphrase), now));
+                        ("ok".into(), false)
+                    }
+                    _ => ("error invalid encoding".into(), false),
+                }
+            }
+            (Some("forget"), Some(description), None) => match decode(description) {
+                Some(description) => {
+                    self.entries.remove(&description);
+                    ("ok".into(), false)
+                }
+                None => ("error invalid encoding".into(), false),
+            },
+            (Some("stop"), None, None) => ("ok".into(), true),
+            _ => ("error unknown request".into(), false),
+        }
+    }
+}
+
+/// Runs the agent on the socket, until it is asked to stop
+#[cfg(unix)]
+pub(crate) fn serve(ttl: Duration) -> Result<()> {
+    use anyhow::Context;
+    use std::{
+        fs,
+        io::{BufRead, BufReader, Write},
+        os::unix::{
+            fs::{DirBuilderExt, PermissionsExt},
+            net::UnixListener,
+        },
+    };
+
+    let Some(path) = socket_path() else {
+        bail!("XDG_RUNTIME_DIR isn't set, the agent needs it for its socket");
+    };
+    if is_running() {
+        bail!("An agent is already running on {:?}", path);
+    }
+    let dir = path.parent().expect("socket is in a directory");
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+        .with_context(|| format!("Couldn't create {:?}", dir))?;
+    fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?;
+    // Left behind by an agent which didn't exit cleanly
+    let _ = fs::remove_file(&path);
+    let listener =
+        UnixListener::bind(&path).with_context(|| format!("Couldn't listen on {:?}", path))?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+    log::info!("Agent listening; socket={:?}, ttl={:?}", path, ttl);
+
+    let mut entries = Entries::new(ttl);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                log::warn!("Couldn't accept connection; error={}", err);
+                continue;
+            }
+        };
+        let _ = stream.set_read_timeout(Some(CLIENT_TIMEOUT));
+        let mut line = String::new();
+        if let Err(err) = BufReader::new(&stream).read_line(&mut line) {
+            log::debug!("Couldn't read request; error={}", err);
+            continue;
+        }
+        let (response, exit) = entries.handle(line.trim_end());
+        let _ = (&stream).write_all(format!("{}\n", response).as_bytes());
+        if exit {
+            break;
+        }
+    }
+    let _ = fs::remove_file(&path);
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn serve(_ttl: Duration) -> Result<()> {
+    bail!("The agent is only available on unix systems")
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    fn put(entries: &mut Entries, description: &str, passphrase: &str) -> String {
+        entries
+            .handle(&format!(
+                "put {} {}",
+                BASE64_STANDARD.encode(description),
+                BASE64_STANDARD.encode(passphrase)
+            ))
+            .0
+    }
+
+    fn get(entries: &mut Entries, description: &str) -> String {
+        entries
+            .handle(&format!("get {}", BASE64_STANDARD.encode(description)))
+            .0
+    }
+
+    #[rstest]
+    fn test_entries() {
+        let mut entries = Entries::new(Duration::from_secs(60));
+        assert_eq!(get(&mut entries, "key.txt"), "none");
+        assert_eq!(put(&mut entries, "key.txt", "hunter2"), "ok");
+        assert_eq!(
+            get(&mut entries, "key.txt"),
+            format!("ok {}", BASE64_STANDARD.encode("hunter2"))
+        );
+        assert_eq!(get(&mut entries, "other.txt"), "none");
+        let forget = format!("forget {}", BASE64_STANDARD.encode("key.txt"));
+        assert_eq!(entries.handle(&forget), ("ok".into(), false));
+        assert_eq!(get(&mut entries, "key.txt"), "none");
+        assert_eq!(entries.handle("get"), ("none".into(), false));
+        assert_eq!(entries.handle("put !!! ???").0, "error invalid encoding");
+        assert_eq!(entries.handle("list").0, "error unknown request");
+        assert_eq!(entries.handle("stop"), ("ok".into(), true));
+    }
+
+    #[rstest]
+    fn test_entries_expire() {
+        let mut entries = Entries::new(Duration::ZERO);
+        assert_eq!(put(&mut entries, "key.txt", "hunter2"), "ok");
+        assert_eq!(get(&mut entries, "key.txt"), "none");
+    }
+}
@@ -0,0 +1,114 @@
+use std::path::{Path, PathBuf};
+
+use crate::{
+    cli::internal::CommandContext,
+    ctx::{Context as _, ContextWrapper},
+    git::{LibGit2Repository, Repository},
+    Result, RuleSet,
+};
+
+/// A repository set up for git-agecrypt, encrypting and decrypting files like the git filters
+pub struct Context {
+    cmd: CommandContext<ContextWrapper<LibGit2Repository>>,
+}
+
+impl Context {
+    /// Opens the repository containing `dir`.
+    ///
+    /// The rules are read from `config` if given, otherwise from `git-agecrypt.toml` or
+    /// `git-agecrypt.yaml` in the repository root.
+    pub fn open(dir: impl AsRef<Path>, config: Option<PathBuf>) -> Result<Self> {
+        let repo = LibGit2Repository::from_dir(dir.as_ref().to_path_buf())?;
+        Ok(Self {
+            cmd: CommandContext {
+                ctx: ContextWrapper::new(repo, config),
+            },
+        })
+    }
+
+    /// The root of the repository's working copy
+    pub fn workdir(&self) -> &Path {
+        self.cmd.ctx.repo().workdir()
+    }
+
+    /// The rules of the repository
+    pub fn rules(&self) -> Result<RuleSet> {
+        Ok(self.cmd.ctx.config()?)
+    }
+
+    /// Encrypts the plaintext of `path` (relative to the repository root) for committing,
+    /// as the `clean` filter does.
+    ///
+    /// Unchanged files keep their previous ciphertext.
+    pub fn clean(&self, path: impl AsRef<Path>, plaintext: Vec<u8>) -> Result<Vec<u8>> {
+        Ok(self
+            .cmd
+            .clean_contents(path.as_ref(), plaintext.into(), false)?)
+    }
+
+    /// Decrypts the ciphertext of `path` (relative to the repository root) for the working
+    /// copy, as the `smudge` filter does.
+    ///
+    /// Files which are left encrypted, e.g. in a locked repository, are returned as they are.
+    pub fn smudge(&self, path: impl AsRef<Path>, ciphertext: Vec<u8>) -> Result<Vec<u8>> {
+        Ok(self
+            .cmd
+            .smudge_contents(path.as_ref(), ciphertext, false)?
+            .to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::age::secrecy::ExposeSecret;
+    use assert_fs::prelude::*;
+    use assert_fs::TempDir;
+    use duct::cmd;
+    use rstest::rstest;
+
+    use super::*;
+    use crate::{config::AgeIdentity, Error};
+
+    #[rstest]
+    #[case::committed(true)]
+    #[case::unborn(false)]
+    fn test_clean_smudge(#[case] committed: bool) -> anyhow::Result<()> {
+        let dir = TempDir::new()?;
+        cmd!("git", "init").dir(dir.path()).run()?;
+        if committed {
+            cmd!("git", "commit", "--allow-empty", "-m", "init")
+                .env("GIT_AUTHOR_NAME", "test")
+                .env("GIT_AUTHOR_EMAIL", "test@example.com")
+                .env("GIT_COMMITTER_NAME", "test")
+                .env("GIT_COMMITTER_EMAIL", "test@example.com")
+                .dir(dir.path())
+                .run()?;
+        }
+        let identity = ::age::x25519::Identity::generate();
+        let key = dir.child("key.txt");
+        key.write_str(&format!("{}\n", identity.to_string().expose_secret()))?;
+        dir.child("git-agecrypt.toml").write_str(&format!(
+            "[config]\n\"secret.txt\" = [\"{}\"]\n",
+            identity.to_public()
+        ))?;
+
+        let ctx = Context::open(dir.path(), None)?;
+        ctx.cmd
+            .ctx
+            .age_identities()
+            .add(AgeIdentity::try_from(key.path().to_path_buf())?)?;
+        assert!(ctx
+            .rules()?
+            .get_rule(&ctx.workdir().join("secret.txt"))
+            .is_ok());
+
+        let ciphertext = ctx.clean("secret.txt", b"secret".to_vec())?;
+        assert_ne!(ciphertext, b"secret");
+        assert_eq!(ctx.smudge("secret.txt", ciphertext)?, b"secret");
+        assert!(matches!(
+            ctx.clean("other.txt", b"other".to_vec()),
+            Err(Error::Config(_))
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,173 @@
+//! Maintains the `.gitattributes` entries assigning the git-agecrypt filters to files
+
+use std::{fs, io, path::Path};
+
+use anyhow::{Context, Result};
+
+const BEGIN_MARKER: &str = "# BEGIN git-agecrypt managed block, changes are overwritten";
+const END_MARKER: &str = "# END git-agecrypt managed block";
+/// `-text` keeps git's end-of-line conversion away from the ciphertext, e.g. with
+/// `core.autocrlf` on Windows
+const ATTRIBUTES: &str = "filter=git-agecrypt diff=git-agecrypt merge=git-agecrypt -text";
+/// Resets the attributes of files matched by a pattern which must stay plaintext
+const UNSET_ATTRIBUTES: &str = "!filter !diff !merge !text";
+
+/// Replaces the managed block of `.gitattributes` with entries for `patterns`, followed by
+/// entries resetting the attributes of the files matching `exclusions`.
+///
+/// The block is removed if there are no patterns, as is the file if nothing else is left in
+/// it. Returns whether the file was changed.
+pub(crate) fn sync(path: &Path, patterns: &[String], exclusions: &[String]) -> Result<bool> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err).with_context(|| format!("Couldn't read {:?}", path)),
+    };
+    let updated = replace_block(&contents, patterns, exclusions);
+    if updated == contents {
+        return Ok(false);
+    }
+    if updated.is_empty() {
+        fs::remove_file(path).with_context(|| format!("Couldn't remove {:?}", path))?;
+    } else {
+        fs::write(path, updated).with_context(|| format!("Couldn't write {:?}", path))?;
+    }
+    Ok(true)
+}
+
+/// Removes the `filter`, `diff` and `merge` attributes naming one of the drivers of another
+/// tool, e.g. `filter=git-crypt`, dropping lines which assign no other attributes.
+///
+/// Returns whether the file was changed.
+pub(crate) fn remove_drivers(path: &Path, drivers: &[String]) -> Result<bool> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err).with_context(|| format!("Couldn't read {:?}", path)),
+    };
+    let updated = strip_drivers(&contents, drivers);
+    if updated == contents {
+        return Ok(false);
+    }
+    fs::write(path, updated).with_context(|| format!("Couldn't write {:?}", path))?;
+    Ok(true)
+}
+
+fn strip_drivers(contents: &str, drivers: &[String]) -> String {
+    let legacy = |attr: &str| {
+        ["filter=", "diff=", "merge="]
+            .iter()
+            .filter_map(|prefix| attr.strip_prefix(prefix))
+            .any(|driver| drivers.iter().any(|d| d == driver))
+    };
+    let mut rv = String::new();
+    for line in contents.lines() {
+        let mut fields = line.split_whitespace();
+        let pattern = fields.next();
+        let attrs: Vec<&str> = fields.collect();
+        if pattern.is_none_or(|p| p.starts_with('#')) || !attrs.iter().any(|a| legacy(a)) {
+            rv.push_str(line);
+            rv.push('\n');
+            continue;
+        }
+        let kept: Vec<&str> = attrs.into_iter().filter(|a| !legacy(a)).collect();
+        if !kept.is_empty() {
+            rv.push_str(&format!("{} {}\n", pattern.unwrap(), kept.join(" ")));
+        }
+    }
+    rv
+}
+
+fn replace_block(contents: &str, patterns: &[String], exclusions: &[String]) -> String {
+    let mut rv = String::new();
+    let mut lines = contents.lines();
+    let mut replaced = false;
+    while let Some(line) = lines.next() {
+        if line == BEGIN_MARKER {
+            for line in lines.by_ref() {
+                if line == END_MARKER {
+                    break;
+                }
+            }
+            if !replaced {
+                rv.push_str(&block(patterns, exclusions));
+                replaced = true;
+            }
+        } else {
+            rv.push_str(line);
+            rv.push('\n');
+        }
+    }
+    if !replaced {
+        rv.push_str(&block(patterns, exclusions));
+    }
+    rv
+}
+
+fn block(patterns: &[String], exclusions: &[String]) -> String {
+    if patterns.is_empty() {
+        return String::new();
+    }
+    let mut rv = format!("{}\n", BEGIN_MARKER);
+    for pattern in patterns {
+        rv.push_str(&format!("{} {}\n", pattern, ATTRIBUTES));
+    }
+    for pattern in exclusions {
+        rv.push_str(&format!("{} {}\n", pattern, UNSET_ATTRIBUTES));
+    }
+    rv.push_str(END_MARKER);
+    rv.push('\n');
+    rv
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    fn test_replace_block() {
+        let patterns = ["/a".to_string(), "/b/**".to_string()];
+        let added = replace_block("*.png binary\n", &patterns, &[]);
+        assert_eq!(
+            added,
+            format!(
+                "*.png binary\n{}\n/a {}\n/b/** {}\n{}\n",
+                BEGIN_MARKER, ATTRIBUTES, ATTRIBUTES, END_MARKER
+            )
+        );
+        assert_eq!(replace_block(&added, &patterns, &[]), added);
+
+        let changed = replace_block(&format!("{}* text\n", added), &patterns[..1], &[]);
+        assert_eq!(
+            changed,
+            format!(
+                "*.png binary\n{}\n/a {}\n{}\n* text\n",
+                BEGIN_MARKER, ATTRIBUTES, END_MARKER
+            )
+        );
+        assert_eq!(replace_block(&changed, &[], &[]), "*.png binary\n* text\n");
+        assert_eq!(replace_block("", &[], &[]), "");
+
+        let exclusions = ["/b/**/*.pub".to_string()];
+        assert_eq!(
+            replace_block("", &patterns[1..], &exclusions),
+            format!(
+                "{}\n/b/** {}\n/b/**/*.pub {}\n{}\n",
+                BEGIN_MARKER, ATTRIBUTES, UNSET_ATTRIBUTES, END_MARKER
+            )
+        );
+    }
+
+    #[rstest]
+    fn test_strip_drivers() {
+        let drivers = ["git-crypt".to_string(), "git-crypt-ci".to_string()];
+        let contents = "# filter=git-crypt\nsecret filter=git-crypt diff=git-crypt\n\
+            ci/* filter=git-crypt-ci -text\n*.png binary\nother filter=git-crypt-other\n";
+        assert_eq!(
+            strip_drivers(contents, &drivers),
+            "# filter=git-crypt\nci/* -text\n*.png binary\nother filter=git-crypt-other\n"
+        );
+    }
+}
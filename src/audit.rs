@@ -0,0 +1,40 @@
+use std::{fs::OpenOptions, io::Write, path::Path, time::SystemTime};
+
+use anyhow::Result;
+
+/// Outcome of a decryption recorded in the audit log
+pub(crate) enum Outcome<'a> {
+    Success { identity: &'a str },
+    Failure,
+}
+
+/// Appends a record of a decryption to the audit log.
+///
+/// Only metadata is written, never plaintext or key material. Logging is best-effort:
+/// failures are reported but never prevent decryption.
+pub(crate) fn record(log: &Path, operation: &str, file: &Path, outcome: Outcome) {
+    if let Err(err) = try_record(log, operation, file, outcome) {
+        log::warn!("Couldn't write audit log; path={:?}, error={:?}", log, err);
+    }
+}
+
+fn try_record(log: &Path, operation: &str, file: &Path, outcome: Outcome) -> Result<()> {
+    let (result, identity) = match outcome {
+        Outcome::Success { identity } => ("success", identity),
+        Outcome::Failure => ("failure", ""),
+    };
+    let line = format!(
+        "{} operation={} file={:?} identity={:?} result={}\n",
+        humantime::format_rfc3339_seconds(SystemTime::now()),
+        operation,
+        file.to_string_lossy(),
+        identity,
+        result
+    );
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log)?
+        .write_all(line.as_bytes())?;
+    Ok(())
+}
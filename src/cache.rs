@@ -0,0 +1,135 @@
+//! Size bounded cache of decrypted blobs, so `textconv` doesn't decrypt the same version of a
+//! file again for every `git log -p` or `git diff`.
+//!
+//! Entries are files named after the blob id of the ciphertext. Their modification time is
+//! updated when they are used, and the least recently used entries are removed once the cache
+//! grows over its size limit.
+
+use std::{
+    fs::{self, File},
+    path::PathBuf,
+    process,
+    time::SystemTime,
+};
+
+use anyhow::Result;
+
+pub(crate) struct BlobCache {
+    dir: PathBuf,
+    max_size: u64,
+}
+
+impl BlobCache {
+    /// A cache in `dir` holding at most `max_size` bytes, disabled if `max_size` is 0
+    pub fn new(dir: PathBuf, max_size: u64) -> Self {
+        Self { dir, max_size }
+    }
+
+    /// Whether contents of `len` bytes can be cached
+    pub fn fits(&self, len: u64) -> bool {
+        self.max_size != 0 && len <= self.max_size
+    }
+
+    /// The cached contents of the blob `id`, `None` if they aren't cached
+    pub fn get(&self, id: &str) -> Option<Vec<u8>> {
+        if self.max_size == 0 {
+            return None;
+        }
+        let path = self.dir.join(id);
+        let contents = fs::read(&path).ok()?;
+        if let Err(err) = File::options()
+            .write(true)
+            .open(&path)
+            .and_then(|f| f.set_modified(SystemTime::now()))
+        {
+            log::debug!(
+                "Couldn't mark cache entry as used; path={:?}, error={}",
+                path,
+                err
+            );
+        }
+        Some(contents)
+    }
+
+    /// Stores the contents of the blob `id`, evicting the least recently used entries if the
+    /// cache gets too large
+    pub fn put(&self, id: &str, contents: &[u8]) -> Result<()> {
+        if !self.fits(contents.len() as u64) {
+            return Ok(());
+        }
+        fs::create_dir_all(&self.dir)?;
+        // Several textconv processes may run at the same time, readers must never see a
+        // partially written entry
+        let tmp = self.dir.join(format!(".{}.{}", id, process::id()));
+        fs::write(&tmp, contents)?;
+        fs::rename(&tmp, self.dir.join(id))?;
+        self.evict()
+    }
+
+    fn evict(&self) -> Result<()> {
+        let mut entries = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if metadata.is_file() && !entry.file_name().to_string_lossy().starts_with('.') {
+                entries.push((metadata.modified()?, metadata.len(), entry.path()));
+            }
+        }
+        let mut size: u64 = entries.iter().map(|(_, len, _)| len).sum();
+        entries.sort();
+        for (_, len, path) in entries {
+            if size <= self.max_size {
+                break;
+            }
+            log::debug!("Evicting cache entry; path={:?}", path);
+            match fs::remove_file(&path) {
+                Ok(()) => size -= len,
+                Err(err) => {
+                    log::debug!("Couldn't evict cache entry; path={:?}, error={}", path, err)
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use assert_fs::TempDir;
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    fn test_cache() -> Result<()> {
+        let dir = TempDir::new()?;
+        let cache = BlobCache::new(dir.path().join("cache"), 10);
+        assert_eq!(cache.get("a"), None);
+
+        cache.put("a", b"aaaa")?;
+        cache.put("b", b"bbbb")?;
+        assert_eq!(cache.get("a").as_deref(), Some(&b"aaaa"[..]));
+
+        // `b` is the least recently used entry now
+        let past = SystemTime::now() - Duration::from_secs(60);
+        File::options()
+            .write(true)
+            .open(dir.path().join("cache/b"))?
+            .set_modified(past)?;
+        cache.put("c", b"cccc")?;
+        assert_eq!(cache.get("b"), None);
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("c").is_some());
+
+        cache.put("d", b"too large to cache")?;
+        assert_eq!(cache.get("d"), None);
+
+        let disabled = BlobCache::new(dir.path().join("disabled"), 0);
+        disabled.put("a", b"")?;
+        assert_eq!(disabled.get("a"), None);
+        Ok(())
+    }
+}
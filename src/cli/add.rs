@@ -0,0 +1,80 @@
+use std::{env, path::PathBuf};
+
+use anyhow::{bail, Result};
+
+use crate::{
+    attributes,
+    config::Rule,
+    ctx::Context,
+    git::{Error as GitError, Repository},
+};
+
+use super::internal::CommandContext;
+
+impl<C: Context> CommandContext<C> {
+    /// Starts encrypting `paths`: a rule encrypting them to `recipients` and the members of
+    /// `groups` is added to the rules file and `.gitattributes` is updated, then everything is
+    /// staged with the files encrypted.
+    pub(crate) fn add(
+        &self,
+        paths: &[PathBuf],
+        recipients: Vec<String>,
+        groups: Vec<String>,
+    ) -> Result<()> {
+        let repo = self.ctx.repo();
+        if let Err(GitError::NotExist(_)) = repo.get_config("filter.git-agecrypt.clean") {
+            bail!("git-agecrypt isn't set up for this repository, run `git-agecrypt init` first");
+        }
+        let mut cfg = self.ctx.config()?;
+        if let Some(group) = groups.iter().find(|g| !cfg.has_group(g)) {
+            bail!(
+                "No group '{}' in {}, define it in the groups table first",
+                group,
+                cfg.path().display()
+            );
+        }
+        let paths = paths
+            .iter()
+            .map(|p| self.repo_path(p))
+            .collect::<Result<Vec<_>>>()?;
+        if let Some(path) = paths.iter().find(|p| !repo.workdir().join(p).is_file()) {
+            bail!("'{}' isn't a file", path.display());
+        }
+
+        let rule = Rule {
+            recipients: recipients.into_iter().chain(groups).collect(),
+            ..Default::default()
+        };
+        for path in &paths {
+            cfg.add_rule(path, rule.clone())?;
+        }
+        cfg.save()?;
+        let attributes_file = repo.workdir().join(".gitattributes");
+        let tracked = repo.list_files()?;
+        attributes::sync(
+            &attributes_file,
+            &cfg.attribute_patterns(&tracked)?,
+            &cfg.attribute_exclusions(&tracked),
+        )?;
+
+        repo.add_files(
+            &[attributes_file, env::current_dir()?.join(cfg.path())],
+            false,
+        )?;
+        // Files tracked in plaintext so far need the clean filter to run again
+        let (tracked, new): (Vec<PathBuf>, Vec<PathBuf>) =
+            paths.iter().cloned().partition(|p| tracked.contains(p));
+        repo.add_files(&new, false)?;
+        repo.add_files(&tracked, true)?;
+        self.ensure_staged_encrypted(&paths)?;
+
+        println!(
+            "Added {} files, review the staged changes and commit them:",
+            paths.len()
+        );
+        for path in &paths {
+            println!("    ✓ {}", path.display());
+        }
+        Ok(())
+    }
+}
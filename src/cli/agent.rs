@@ -0,0 +1,71 @@
+use std::{
+    env, process, thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::agent;
+
+use super::args::AgentCommands;
+
+/// How long `agent start` waits for the detached agent to listen
+const START_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub(crate) fn run(command: &AgentCommands) -> Result<()> {
+    match command {
+        AgentCommands::Start { ttl, foreground } => start(Duration::from_secs(*ttl), *foreground),
+        AgentCommands::Stop => {
+            if agent::stop()? {
+                println!("Agent stopped");
+            } else {
+                println!("No agent is running");
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Runs the agent, in a detached process of its own unless `foreground` is set
+fn start(ttl: Duration, foreground: bool) -> Result<()> {
+    if foreground {
+        return agent::serve(ttl);
+    }
+    let Some(socket) = agent::socket_path() else {
+        bail!("XDG_RUNTIME_DIR isn't set, the agent needs it for its socket");
+    };
+    if agent::is_running() {
+        bail!("An agent is already running on {:?}", socket);
+    }
+    let mut command = process::Command::new(env::current_exe()?);
+    command
+        .args(["agent", "start", "--foreground", "--ttl"])
+        .arg(ttl.as_secs().to_string())
+        .stdin(process::Stdio::null())
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null());
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        // Keeps the agent running when the terminal it was started from is closed
+        command.process_group(0);
+    }
+    let mut child = command.spawn().context("Couldn't start the agent")?;
+
+    let started = Instant::now();
+    while !agent::is_running() {
+        if let Some(status) = child.try_wait()? {
+            bail!("The agent exited with {}", status);
+        }
+        if started.elapsed() > START_TIMEOUT {
+            bail!("The agent didn't listen on {:?} in time", socket);
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    println!(
+        "Agent started on {}, keeping passphrases for {} seconds",
+        socket.display(),
+        ttl.as_secs()
+    );
+    Ok(())
+}
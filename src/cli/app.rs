@@ -1,57 +1,211 @@
+use std::io;
+
 use anyhow::Result;
 
 use crate::ctx::Context;
 
-use super::{internal, public};
+use super::{
+    agent,
+    exit::{ExitCode, Reported},
+    generate, internal,
+    progress::Progress,
+    public,
+    verify::PushedRef,
+};
+
+use super::args::{
+    Args, Commands, ConfigCommands, HookManager, InternalCommands, ModifyConfig, OutputFormat,
+    PublicCommands, QueryConfig,
+};
 
-use super::args::{Args, Commands, InternalCommands, ModifyConfig, PublicCommands, QueryConfig};
+/// Runs a command, opening the repository with `open` only for the commands that need one.
+///
+/// Every command is routed by this one match, so the compiler catches commands without a
+/// route.
+pub(crate) fn run<C: Context>(args: Args, open: impl FnOnce() -> Result<C>) -> Result<()> {
+    let format = args.format;
+    let verbose = args.verbose;
+    let command = match args.command {
+        Commands::Public(command) => command,
+        Commands::Internal(command) => return run_internal_command(command, format, open()?),
+    };
+    match command {
+        // Used by packagers and outside of repositories
+        PublicCommands::Completions { shell } => generate::completions(shell),
+        PublicCommands::Manpages { dir } => generate::manpages(&dir),
+        PublicCommands::Agent(command) => agent::run(&command),
+        PublicCommands::Init {
+            global: true,
+            textconv_args,
+            ..
+        } => public::init_global(textconv_args, args.config),
+        PublicCommands::Deinit { global: true, .. } => public::deinit_global(),
 
-pub(crate) fn run(args: Args, ctx: impl Context) -> Result<()> {
-    match args.command {
-        Commands::Public(c) => run_public_command(c, ctx),
-        Commands::Internal(c) => run_internal_command(c, ctx),
+        // Share the encryption code with the filter commands
+        PublicCommands::Rekey {
+            paths,
+            all,
+            dry_run,
+            recipients_check_decryptable,
+            progress_json,
+        } => internal::CommandContext { ctx: open()? }.rekey(
+            paths,
+            all,
+            dry_run,
+            recipients_check_decryptable,
+            Progress::new(progress_json, verbose),
+            format,
+        ),
+        PublicCommands::Verify {
+            history,
+            quick,
+            remote,
+            pushed,
+            progress_json,
+            ..
+        } => {
+            let pushed = if pushed {
+                Some(PushedRef::read(io::stdin().lock())?)
+            } else {
+                None
+            };
+            internal::CommandContext { ctx: open()? }.verify(
+                history,
+                quick,
+                remote.as_deref(),
+                pushed.as_deref(),
+                Progress::new(progress_json, verbose),
+                format,
+            )
+        }
+        PublicCommands::AuditRecipients { since } => {
+            internal::CommandContext { ctx: open()? }.audit_recipients(since, format)
+        }
+        PublicCommands::Doctor => internal::CommandContext { ctx: open()? }.doctor(format),
+        PublicCommands::ValidateConfig => {
+            internal::CommandContext { ctx: open()? }.validate_config(format)
+        }
+        PublicCommands::Edit { path } => internal::CommandContext { ctx: open()? }.edit(&path),
+        PublicCommands::Show { object } => internal::CommandContext { ctx: open()? }.show(&object),
+        PublicCommands::Export { output, recipient } => {
+            internal::CommandContext { ctx: open()? }.export(&output, &recipient)
+        }
+        PublicCommands::Import { bundle } => {
+            internal::CommandContext { ctx: open()? }.import(&bundle)
+        }
+        PublicCommands::PurgeHistory { paths, output, run } => {
+            internal::CommandContext { ctx: open()? }.purge_history(&paths, &output, run)
+        }
+        PublicCommands::Add {
+            paths,
+            recipient,
+            group,
+        } => internal::CommandContext { ctx: open()? }.add(&paths, recipient, group),
+        PublicCommands::Migrate { from, recipient } => {
+            internal::CommandContext { ctx: open()? }.migrate(from, recipient)
+        }
+        PublicCommands::Deinit {
+            decrypt,
+            keep_encrypted,
+            ..
+        } if decrypt || keep_encrypted => internal::CommandContext { ctx: open()? }.deinit(decrypt),
+
+        PublicCommands::Init {
+            textconv_args,
+            hooks,
+            ..
+        } => {
+            let cmd = public::CommandContext::new(open()?, format);
+            cmd.init(textconv_args, args.config.clone())?;
+            if hooks {
+                cmd.install_hooks(args.config, HookManager::Git)?;
+            }
+            Ok(())
+        }
+        PublicCommands::InstallHooks { manager } => {
+            public::CommandContext::new(open()?, format).install_hooks(args.config, manager)
+        }
+        PublicCommands::Deinit { .. } => public::CommandContext::new(open()?, format).deinit(),
+        PublicCommands::Status { .. } => public::CommandContext::new(open()?, format).status(),
+        PublicCommands::List { file } => public::CommandContext::new(open()?, format).list(file),
+        PublicCommands::SyncAttributes => {
+            public::CommandContext::new(open()?, format).sync_attributes()
+        }
+        PublicCommands::Lock { progress_json } => {
+            public::CommandContext::new(open()?, format).lock(Progress::new(progress_json, verbose))
+        }
+        PublicCommands::Unlock { progress_json, .. } => {
+            public::CommandContext::new(open()?, format)
+                .unlock(Progress::new(progress_json, verbose))
+        }
+        PublicCommands::Config(cfg) => {
+            run_config_command(cfg, public::CommandContext::new(open()?, format))
+        }
     }
 }
 
-fn run_internal_command(commands: InternalCommands, ctx: impl Context) -> Result<()> {
+fn run_internal_command(
+    commands: InternalCommands,
+    format: OutputFormat,
+    ctx: impl Context,
+) -> Result<()> {
     let cmd = internal::CommandContext { ctx };
     match commands {
-        InternalCommands::Clean { file } => cmd.clean(file),
-        InternalCommands::Smudge { file } => cmd.smudge(file),
-        InternalCommands::Textconv { path } => cmd.textconv(path),
+        InternalCommands::Clean {
+            file,
+            recipients_check_decryptable,
+            recipients_output,
+            check,
+        } => match recipients_output {
+            Some(output) => cmd.write_recipients(file, output),
+            None if check => cmd.clean_check(file, recipients_check_decryptable, format),
+            None => cmd.clean(file, recipients_check_decryptable),
+        },
+        InternalCommands::Smudge { file, dump_header } => cmd.smudge(file, dump_header),
+        InternalCommands::Process => cmd.process(),
+        InternalCommands::Merge {
+            ancestor,
+            ours,
+            theirs,
+            output,
+            marker_size,
+            path,
+        } => {
+            let clean = cmd.merge(&ancestor, &ours, &theirs, &output, marker_size, &path)?;
+            if !clean {
+                // Tells git about the conflicts, which it reports itself
+                return Err(Reported(ExitCode::Failure).into());
+            }
+            Ok(())
+        }
+        InternalCommands::Textconv {
+            path,
+            dump_header,
+            diff_format,
+        } => cmd.textconv(path, dump_header, diff_format),
+        InternalCommands::RefreshSidecars { old, new } => cmd.refresh_sidecars(&old, &new),
     }
 }
 
-fn run_public_command(commands: PublicCommands, ctx: impl Context) -> Result<()> {
-    let cmd = public::CommandContext::new(ctx);
-    match commands {
-        PublicCommands::Init => {
-            cmd.init()?;
-        }
-        PublicCommands::Deinit => {
-            cmd.deinit()?;
-        }
-        PublicCommands::Status => {
-            cmd.status()?;
-        }
-        PublicCommands::Config(cfg) => match cfg {
-            super::args::ConfigCommands::Add(what) => match ModifyConfig::from(what) {
-                ModifyConfig::Identity(id) => cmd.add_identity(id)?,
-                ModifyConfig::Recipient(paths, recipients) => {
-                    cmd.add_recipients(recipients, paths)?
-                }
-            },
-            super::args::ConfigCommands::Remove(what) => match ModifyConfig::from(what) {
-                ModifyConfig::Identity(id) => cmd.remove_identity(id)?,
-                ModifyConfig::Recipient(paths, recipients) => {
-                    cmd.remove_recipients(recipients, paths)?
-                }
-            },
-            super::args::ConfigCommands::List(what) => match QueryConfig::from(what) {
-                QueryConfig::Identities => cmd.list_identities()?,
-                QueryConfig::Recipients => cmd.list_recipients()?,
-            },
+fn run_config_command(
+    cfg: ConfigCommands,
+    cmd: public::CommandContext<impl Context>,
+) -> Result<()> {
+    match cfg {
+        ConfigCommands::Add(what) => match ModifyConfig::from(what) {
+            ModifyConfig::Identity(id) => cmd.add_identity(id),
+            ModifyConfig::Recipient(paths, recipients) => cmd.add_recipients(recipients, paths),
+        },
+        ConfigCommands::Remove(what) => match ModifyConfig::from(what) {
+            ModifyConfig::Identity(id) => cmd.remove_identity(id),
+            ModifyConfig::Recipient(paths, recipients) => cmd.remove_recipients(recipients, paths),
+        },
+        ConfigCommands::List(what) => match QueryConfig::from(what) {
+            QueryConfig::Identities => cmd.list_identities(),
+            QueryConfig::Recipients => cmd.list_recipients(),
         },
+        ConfigCommands::AddIdentity { path } => cmd.add_identity(path),
+        ConfigCommands::RemoveIdentity { path } => cmd.remove_identity(path),
+        ConfigCommands::ListIdentities => cmd.list_identities(),
     }
-    Ok(())
 }
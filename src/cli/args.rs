@@ -1,16 +1,48 @@
 use std::path::PathBuf;
 
-use clap::{ArgGroup, Parser, Subcommand};
+use clap::{ArgGroup, Parser, Subcommand, ValueEnum};
 
 /// Transparently encrypt/decrypt age secrets
-#[derive(Parser)]
+#[derive(Clone, Parser)]
 #[clap(author, version, about)]
 pub struct Args {
+    /// Rules file to use instead of git-agecrypt.toml or git-agecrypt.yaml in the repository root
+    #[clap(long, global = true, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Output format of `status`, `verify`, `rekey`, `lock`, `unlock`, `audit-recipients`,
+    /// `doctor`, `validate-config`, `clean --check`, the `list` commands and errors
+    #[clap(long, global = true, value_enum, default_value_t = OutputFormat::Text)]
+    pub format: OutputFormat,
+
+    /// Read an identity (key material, not a path) from stdin in addition to the configured ones
+    #[clap(long, global = true)]
+    pub identity_stdin: bool,
+
+    /// Repository to use instead of discovering it, like `git --git-dir`; defaults to `GIT_DIR`
+    #[clap(long, global = true, value_name = "PATH")]
+    pub git_dir: Option<PathBuf>,
+
+    /// Working tree to use with the repository, like `git --work-tree`; defaults to
+    /// `GIT_WORK_TREE`
+    #[clap(long, global = true, value_name = "PATH")]
+    pub work_tree: Option<PathBuf>,
+
+    /// List each file processed by `rekey`, `verify`, `lock` and `unlock` with the time it took
+    #[clap(short, long, global = true)]
+    pub verbose: bool,
+
     #[clap(subcommand)]
     pub command: Commands,
 }
 
-#[derive(Subcommand)]
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+#[derive(Clone, Subcommand)]
 #[clap(
     after_help = "In addition to the above, The following subcommands are used from git filters:
     clean, smudge, textconv"
@@ -22,23 +54,291 @@ pub enum Commands {
     Internal(InternalCommands),
 }
 
-#[derive(Subcommand)]
+#[derive(Clone, Subcommand)]
 pub enum PublicCommands {
     /// Set-up repository for use with git-agecrypt
-    Init,
+    Init {
+        /// Register the filters in the global git config (~/.gitconfig) for all repositories
+        #[clap(long)]
+        global: bool,
+
+        /// Extra arguments for the textconv command used by diff, e.g. "--dump-header"
+        #[clap(long, allow_hyphen_values = true)]
+        textconv_args: Option<String>,
+
+        /// Also install the git hooks, same as `install-hooks`
+        #[clap(long, conflicts_with = "global")]
+        hooks: bool,
+
+        /// Also run in the submodules, recursively
+        #[clap(long, conflicts_with = "global")]
+        recurse_submodules: bool,
+    },
+
+    /// Install a pre-commit hook refusing to commit plaintext files covered by a rule, and
+    /// post-checkout and post-merge hooks refreshing the sidecars of the files they changed
+    InstallHooks {
+        /// Write the hooks for a hook manager instead of into the hooks directory of git
+        #[clap(long, value_enum, default_value_t = HookManager::Git)]
+        manager: HookManager,
+    },
+
+    /// Start encrypting files: add a rule for them, update .gitattributes and stage them
+    /// encrypted
+    #[clap(group(
+        ArgGroup::new("to")
+            .args(&["recipient", "group"])
+            .required(true)
+            .multiple(true)
+    ))]
+    Add {
+        /// Files to encrypt
+        #[clap(required = true)]
+        paths: Vec<PathBuf>,
+
+        /// Recipient to encrypt the files to, a public key or a recipient source like
+        /// github:<user>
+        #[clap(short, long, alias = "recipients")]
+        recipient: Vec<String>,
+
+        /// Group of the rules file to encrypt the files to
+        #[clap(short, long)]
+        group: Vec<String>,
+    },
 
     /// Display configuration status information
-    Status,
+    Status {
+        /// Also show the status of the submodules, recursively
+        #[clap(long)]
+        recurse_submodules: bool,
+    },
+
+    /// List the rules with the recipients they resolve to and the files they cover
+    List {
+        /// Only show the rule which applies to this file
+        #[arg(long)]
+        file: Option<PathBuf>,
+    },
+
+    /// Diagnose the setup: git version, filters, .gitattributes, rules, identities and sidecars
+    Doctor,
+
+    /// Check the rules files for recipients age can't encrypt to and rules targeting the same file
+    ValidateConfig,
+
+    /// Update the .gitattributes entries of the files covered by the rules
+    SyncAttributes,
+
+    /// Replace the decrypted files in the working copy with their ciphertext
+    Lock {
+        /// Print progress events as JSON lines to stderr
+        #[clap(long)]
+        progress_json: bool,
+    },
+
+    /// Decrypt the files in the working copy again after `lock`
+    Unlock {
+        /// Print progress events as JSON lines to stderr
+        #[clap(long)]
+        progress_json: bool,
+
+        /// Also run in the submodules, recursively
+        #[clap(long)]
+        recurse_submodules: bool,
+    },
+
+    /// Re-encrypt files whose committed recipients differ from the configuration
+    Rekey {
+        /// Files or directories to re-encrypt, every file covered by a rule if omitted
+        paths: Vec<PathBuf>,
+
+        /// Re-encrypt files even if their committed recipients match the configuration
+        #[clap(long)]
+        all: bool,
+
+        /// Only list the files which would be re-encrypted
+        #[clap(long)]
+        dry_run: bool,
+
+        /// Refuse to encrypt unless one of the configured identities is a recipient
+        #[clap(long)]
+        recipients_check_decryptable: bool,
+
+        /// Print progress events as JSON lines to stderr
+        #[clap(long)]
+        progress_json: bool,
+    },
+
+    /// Check that the files covered by a rule are committed encrypted and decryptable
+    #[command(alias = "fsck")]
+    Verify {
+        /// Check every version of the files in the history of HEAD, not just the index
+        #[clap(long)]
+        history: bool,
+
+        /// Only check that the files are encrypted, skipping the recipients and decryption
+        #[clap(long)]
+        quick: bool,
+
+        /// Check the rules which apply when pushing to this remote, given by name or URL,
+        /// instead of the push remote of the branch checked out
+        #[clap(long, value_name = "NAME")]
+        remote: Option<String>,
+
+        /// Check the commits being pushed instead of the index, read from stdin as git passes
+        /// them to the pre-push hook
+        #[clap(long)]
+        pushed: bool,
+
+        /// Print progress events as JSON lines to stderr
+        #[clap(long)]
+        progress_json: bool,
+
+        /// Also run in the submodules, recursively
+        #[clap(long)]
+        recurse_submodules: bool,
+    },
+
+    /// Report committed versions of files encrypted to recipients no longer in their rule
+    AuditRecipients {
+        /// Only check the commits since this revision, e.g. the one removing a recipient
+        #[clap(long, value_name = "REV")]
+        since: Option<String>,
+    },
+
+    /// Edit an encrypted file in $EDITOR, encrypting it again on save
+    Edit {
+        /// File to edit, it need not exist yet but has to be covered by a rule
+        path: PathBuf,
+    },
+
+    /// Print the decrypted contents of a file in the working copy's `HEAD` or another revision
+    #[command(alias = "cat")]
+    Show {
+        /// File to show, as `PATH` for the version in HEAD or `REV:PATH` like `git show`
+        #[clap(value_name = "[REV:]PATH")]
+        object: String,
+    },
+
+    /// Decrypt the files covered by the rules into a single tarball encrypted with age, e.g.
+    /// to hand them to deployment tooling
+    Export {
+        /// File to write the bundle to, "-" for stdout
+        #[clap(short, long, value_name = "FILE")]
+        output: PathBuf,
+
+        /// Recipient to encrypt the bundle to, a public key or a recipient source like
+        /// github:<user>
+        #[clap(short, long, required = true)]
+        recipient: Vec<String>,
+    },
+
+    /// Write the files of a bundle created by `export` into the working copy
+    Import {
+        /// Bundle to import, "-" for stdin
+        #[clap(value_name = "FILE")]
+        bundle: PathBuf,
+    },
+
+    /// Replace the plaintext versions of files committed before they were encrypted with
+    /// their ciphertext, by rewriting the history with git filter-repo
+    PurgeHistory {
+        /// Files covered by a rule whose history is rewritten
+        #[clap(required = true)]
+        paths: Vec<PathBuf>,
+
+        /// File to write the blob callback for git filter-repo to, "-" for stdout
+        #[clap(short, long, value_name = "FILE", default_value = "-")]
+        output: PathBuf,
+
+        /// Rewrite the history with git filter-repo right away and check the result
+        #[clap(long)]
+        run: bool,
+    },
+
+    /// Move the files encrypted with git-crypt, transcrypt or sops over to git-agecrypt
+    Migrate {
+        /// Tool the files are currently encrypted with
+        #[clap(long, value_enum)]
+        from: LegacyTool,
+
+        /// Recipient to encrypt the files to, in addition to the age keys of `.sops.yaml`
+        #[clap(short, long)]
+        recipient: Vec<String>,
+    },
 
     /// Configure encryption settings
     #[command(subcommand)]
     Config(ConfigCommands),
 
+    /// Keep the passphrases and PINs of identities for a while, so the filters ask only once
+    #[command(subcommand)]
+    Agent(AgentCommands),
+
+    /// Print the shell completion script for git-agecrypt
+    Completions {
+        #[clap(value_enum)]
+        shell: clap_complete::Shell,
+    },
+
+    /// Write man pages for git-agecrypt and each of its subcommands into a directory
+    Manpages {
+        /// Directory to write the pages to, it is created if needed
+        dir: PathBuf,
+    },
+
     /// Remove repository specific configuration
-    Deinit,
+    Deinit {
+        /// Remove the filters from the global git config (~/.gitconfig)
+        #[clap(long)]
+        global: bool,
+
+        /// Decrypt the files covered by a rule and stage their plaintext, to stop encrypting
+        /// them with the next commit
+        #[clap(long, conflicts_with_all = ["global", "keep_encrypted"])]
+        decrypt: bool,
+
+        /// Leave the files covered by a rule encrypted, refusing unless the working copy is
+        /// locked
+        #[clap(long, conflicts_with = "global")]
+        keep_encrypted: bool,
+
+        /// Also run in the submodules, recursively
+        #[clap(long, conflicts_with = "global")]
+        recurse_submodules: bool,
+    },
 }
 
-#[derive(Subcommand)]
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum HookManager {
+    /// Hooks in `core.hooksPath` or `.git/hooks`
+    Git,
+    /// Hooks in `.husky`
+    Husky,
+    /// `lefthook-git-agecrypt.yml`, to be listed in `extends` of `lefthook.yml`
+    Lefthook,
+    /// `git-agecrypt.pre-commit-config.yaml`, to be copied into `.pre-commit-config.yaml`
+    PreCommit,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DiffFormat {
+    /// As they are
+    Plain,
+    /// One `key: value` line per value, sorted by key
+    Canonical,
+    /// Like canonical, with the values replaced by `****`
+    Masked,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum LegacyTool {
+    GitCrypt,
+    Transcrypt,
+    Sops,
+}
+
+#[derive(Clone, Subcommand)]
 pub enum ConfigCommands {
     /// Add a configuration entry
     Add(AddConfig),
@@ -48,11 +348,43 @@ pub enum ConfigCommands {
 
     /// List configuration entries
     List(ConfigType),
+
+    /// Add an identity usable for decryption, same as `add --identity`
+    AddIdentity {
+        /// Path of the identity file
+        path: PathBuf,
+    },
+
+    /// Remove an identity, same as `remove --identity`
+    RemoveIdentity {
+        /// Path of the identity file
+        path: PathBuf,
+    },
+
+    /// List the configured identities, same as `list --identity`
+    ListIdentities,
+}
+
+#[derive(Clone, Subcommand)]
+pub enum AgentCommands {
+    /// Start the agent in the background
+    Start {
+        /// Seconds a passphrase is kept after it was entered
+        #[clap(long, value_name = "SECONDS", default_value_t = 900)]
+        ttl: u64,
+
+        /// Run in the foreground instead of detaching, e.g. under a service manager
+        #[clap(long)]
+        foreground: bool,
+    },
+
+    /// Stop the agent, forgetting the passphrases it keeps
+    Stop,
 }
 
-#[derive(clap::Args)]
+#[derive(Clone, clap::Args)]
 #[clap(group(
-    ArgGroup::new("config")
+    ArgGroup::new("entry")
         .args(&["identity", "recipient"])
         .required(true)
 ))]
@@ -63,11 +395,11 @@ pub enum ConfigCommands {
 ))]
 pub struct AddConfig {
     /// Identity usable for decryption
-    #[arg(short, long, num_args = 1.., group = "config")]
+    #[arg(short, long, num_args = 1.., group = "entry")]
     identity: Option<PathBuf>,
 
     /// Recipient for encryption
-    #[arg(short, long, num_args = 1.., group = "config")]
+    #[arg(short, long, num_args = 1.., group = "entry")]
     recipient: Option<Vec<String>>,
 
     /// Path to encrypt for the given recipient
@@ -92,18 +424,18 @@ impl From<AddConfig> for ModifyConfig {
     }
 }
 
-#[derive(clap::Args)]
+#[derive(Clone, clap::Args)]
 #[clap(group(
-    ArgGroup::new("config")
+    ArgGroup::new("entry")
         .args(&["identity", "recipient"])
 ))]
 pub struct RemoveConfig {
     /// Identity usable for decryption
-    #[clap(short, long, group = "config")]
+    #[clap(short, long, group = "entry")]
     identity: Option<PathBuf>,
 
     /// Recipient for encryption
-    #[clap(short, long, group = "config")]
+    #[clap(short, long, group = "entry")]
     recipient: Option<Vec<String>>,
 
     /// Path to encrypt for the given recipient
@@ -125,7 +457,7 @@ impl From<RemoveConfig> for ModifyConfig {
     }
 }
 
-#[derive(clap::Args)]
+#[derive(Clone, clap::Args)]
 #[clap(group(
     ArgGroup::new("type")
         .args(&["identity", "recipient"])
@@ -158,7 +490,7 @@ impl From<ConfigType> for QueryConfig {
     }
 }
 
-#[derive(Subcommand)]
+#[derive(Clone, Subcommand)]
 pub enum InternalCommands {
     /// Encrypt files for commit
     #[command(hide = true)]
@@ -166,6 +498,18 @@ pub enum InternalCommands {
         /// File to clean
         #[clap(short, long)]
         file: PathBuf,
+
+        /// Refuse to encrypt unless one of the configured identities is a recipient
+        #[clap(long)]
+        recipients_check_decryptable: bool,
+
+        /// Write the resolved recipients to a file ("-" for stderr) instead of encrypting
+        #[clap(long, value_name = "FILE")]
+        recipients_output: Option<PathBuf>,
+
+        /// Report how the file would be encrypted and to whom, without writing anything
+        #[clap(long, visible_alias = "dry-run", conflicts_with = "recipients_output")]
+        check: bool,
     },
 
     /// Decrypt files from checkout
@@ -174,6 +518,42 @@ pub enum InternalCommands {
         /// File to smudge
         #[clap(short, long)]
         file: PathBuf,
+
+        /// Print the age header to stderr before decryption
+        #[clap(long)]
+        dump_header: bool,
+    },
+
+    /// Encrypt and decrypt files using git's long-running filter process protocol
+    #[command(hide = true)]
+    Process,
+
+    /// Merge encrypted files, used as git merge driver
+    #[command(hide = true)]
+    Merge {
+        /// Common ancestor's version of the file (%O)
+        #[clap(long)]
+        ancestor: PathBuf,
+
+        /// Current branch's version of the file (%A)
+        #[clap(long)]
+        ours: PathBuf,
+
+        /// Other branch's version of the file (%B)
+        #[clap(long)]
+        theirs: PathBuf,
+
+        /// File to write the merged result to, git expects it in place of --ours
+        #[clap(long)]
+        output: PathBuf,
+
+        /// Length of conflict markers (%L)
+        #[clap(long)]
+        marker_size: Option<usize>,
+
+        /// Path of the file in the repository (%P)
+        #[clap(long)]
+        path: PathBuf,
     },
 
     // Decrypt files for diff
@@ -181,9 +561,39 @@ pub enum InternalCommands {
     Textconv {
         /// File to show
         path: PathBuf,
+
+        /// Print the age header to stderr before decryption
+        #[clap(long)]
+        dump_header: bool,
+
+        /// How to show decrypted YAML, JSON and env files
+        #[clap(long, value_enum, default_value_t = DiffFormat::Plain)]
+        diff_format: DiffFormat,
+    },
+
+    /// Store the sidecars of the files changed by a checkout or merge, used by git hooks
+    #[command(hide = true)]
+    RefreshSidecars {
+        /// Commit checked out before
+        old: String,
+
+        /// Commit checked out now
+        new: String,
     },
 }
 
 pub fn parse_args() -> Args {
     Args::parse()
 }
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+
+    use super::*;
+
+    #[test]
+    fn test_args() {
+        Args::command().debug_assert();
+    }
+}
@@ -0,0 +1,244 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+};
+
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{
+    age::{self, StanzaKind},
+    ctx::Context,
+    git::Repository,
+    kms,
+};
+
+use super::{
+    args::OutputFormat,
+    exit::{ChecksFailed, ExitCode, Reported},
+    internal::CommandContext,
+    output,
+    rekey::committed_stanzas,
+};
+
+/// A committed version of a file encrypted to recipients its rule doesn't list
+#[derive(Serialize)]
+struct Finding {
+    path: PathBuf,
+    /// The first commit containing this version, `None` for the index
+    commit: Option<String>,
+    /// Whether a rule still covers the file
+    covered: bool,
+    recipients: Vec<String>,
+}
+
+impl<C: Context> CommandContext<C> {
+    /// Reports the versions of files reachable from `HEAD` which are encrypted to recipients
+    /// that are no longer in the rule of the file, e.g. the key of someone who left.
+    ///
+    /// With `since`, only commits not reachable from that revision are checked.
+    pub(crate) fn audit_recipients(
+        &self,
+        since: Option<String>,
+        format: OutputFormat,
+    ) -> Result<()> {
+        let repo = self.ctx.repo();
+        let mut blobs = repo.history_blobs(since.as_deref())?;
+        let seen: HashSet<(PathBuf, String)> = blobs
+            .iter()
+            .map(|b| (b.path.clone(), b.id.clone()))
+            .collect();
+        blobs.extend(
+            repo.index_blobs()?
+                .into_iter()
+                .filter(|b| !seen.contains(&(b.path.clone(), b.id.clone()))),
+        );
+
+        let cfg = self.ctx.config()?;
+        let mut known = HashMap::new();
+        for entry in cfg.rules(&repo.list_files()?)? {
+            for recipient in self.ctx.recipients().resolve_rule(&entry.rule)? {
+                if let Ok(StanzaKind::Ssh { tag, .. }) = age::recipient_stanza_kind(&recipient) {
+                    known.insert(tag, recipient);
+                }
+            }
+        }
+
+        // Stanzas expected by each rule, resolving the recipients only once per rule
+        let mut expected: HashMap<PathBuf, Vec<StanzaKind>> = HashMap::new();
+        let mut checked = 0;
+        let mut findings = vec![];
+        for blob in blobs {
+            let contents = repo.read_blob(&blob.id)?;
+            let actual = match committed_stanzas(&contents) {
+                Ok(Some(actual)) => actual,
+                // Plaintext is reported by `verify`
+                Ok(None) => continue,
+                Err(err) => {
+                    log::warn!(
+                        "Couldn't read the recipients; file={:?}, commit={:?}, error={:#}",
+                        blob.path,
+                        blob.commit,
+                        err
+                    );
+                    continue;
+                }
+            };
+            checked += 1;
+
+            let path = repo.workdir().join(&blob.path);
+            let rule = cfg.lookup(&path)?;
+            let covered = rule.is_some();
+            let rule_stanzas = match rule {
+                Some((rule_path, rule)) => match expected.get(&rule_path) {
+                    Some(stanzas) => stanzas.clone(),
+                    None => {
+                        let stanzas = self
+                            .ctx
+                            .recipients()
+                            .resolve_rule(&rule)?
+                            .iter()
+                            .map(|pk| age::recipient_stanza_kind(pk))
+                            .collect::<Result<Vec<_>>>()?;
+                        expected.insert(rule_path, stanzas.clone());
+                        stanzas
+                    }
+                },
+                None => vec![],
+            };
+
+            let removed = removed_stanzas(actual, rule_stanzas);
+            if !removed.is_empty() {
+                findings.push(Finding {
+                    path: blob.path,
+                    commit: blob.commit,
+                    covered,
+                    recipients: describe(&removed, &known),
+                });
+            }
+        }
+
+        if format == OutputFormat::Json {
+            output::print(&json!({ "checked": checked, "findings": findings }))?;
+            if !findings.is_empty() {
+                // The report already lists the findings
+                return Err(Reported(ExitCode::ChecksFailed).into());
+            }
+            return Ok(());
+        }
+        if findings.is_empty() {
+            println!(
+                "None of the {} checked versions is encrypted to recipients missing from its rule.",
+                checked
+            );
+            return Ok(());
+        }
+        println!("The following versions are encrypted to recipients missing from their rule:");
+        for finding in &findings {
+            let location = match &finding.commit {
+                Some(commit) => format!("in commit {}", commit),
+                None => "in the index".into(),
+            };
+            let uncovered = if finding.covered {
+                ""
+            } else {
+                ", no rule covers the file anymore"
+            };
+            println!(
+                "    ⨯ {} {} -- {}{}",
+                finding.path.display(),
+                location,
+                finding.recipients.join(", "),
+                uncovered
+            );
+        }
+        println!(
+            "Run `git-agecrypt rekey --all` to encrypt the current versions again, older \
+             versions stay decryptable unless the history is rewritten."
+        );
+        Err(ChecksFailed(format!(
+            "Found {} versions encrypted to removed recipients",
+            findings.len()
+        ))
+        .into())
+    }
+}
+
+/// The stanzas of `actual` left over once each stanza of `expected` is matched with one of them
+fn removed_stanzas(actual: Vec<StanzaKind>, mut expected: Vec<StanzaKind>) -> Vec<StanzaKind> {
+    actual
+        .into_iter()
+        .filter(|kind| match expected.iter().position(|e| e == kind) {
+            Some(i) => {
+                expected.swap_remove(i);
+                false
+            }
+            None => true,
+        })
+        .collect()
+}
+
+/// Names the recipients of `stanzas`, as far as the stanzas and `known` SSH keys by their tag
+/// tell
+fn describe(stanzas: &[StanzaKind], known: &HashMap<String, String>) -> Vec<String> {
+    let mut rv = vec![];
+    let count = |kind: &StanzaKind| stanzas.iter().filter(|s| *s == kind).count();
+    for kind in [StanzaKind::X25519, StanzaKind::Plugin] {
+        let n = count(&kind);
+        let name = match kind {
+            StanzaKind::X25519 => "X25519",
+            _ => "plugin",
+        };
+        match n {
+            0 => {}
+            1 => rv.push(format!("1 {} recipient", name)),
+            n => rv.push(format!("{} {} recipients", n, name)),
+        }
+    }
+    for stanza in stanzas {
+        match stanza {
+            StanzaKind::Ssh { key_type, tag } => rv.push(match known.get(tag) {
+                Some(recipient) => recipient.clone(),
+                None => format!("{} key with tag {}", key_type, tag),
+            }),
+            StanzaKind::Kms { key } => rv.push(format!("{}{}", kms::PREFIX, key)),
+            _ => {}
+        }
+    }
+    rv
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    fn test_removed_stanzas() {
+        let ssh = |tag: &str| StanzaKind::Ssh {
+            key_type: "ssh-ed25519".into(),
+            tag: tag.into(),
+        };
+        let actual = vec![
+            StanzaKind::X25519,
+            StanzaKind::X25519,
+            ssh("alice"),
+            ssh("bob"),
+        ];
+        let expected = vec![ssh("alice"), StanzaKind::X25519, StanzaKind::Plugin];
+        let removed = removed_stanzas(actual, expected);
+        assert_eq!(removed, [StanzaKind::X25519, ssh("bob")]);
+
+        let known = HashMap::from([("bob".to_string(), "ssh-ed25519 AAAA bob".to_string())]);
+        assert_eq!(
+            describe(&removed, &known),
+            ["1 X25519 recipient", "ssh-ed25519 AAAA bob"]
+        );
+        assert_eq!(
+            describe(&[ssh("carol")], &known),
+            ["ssh-ed25519 key with tag carol"]
+        );
+    }
+}
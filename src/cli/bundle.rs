@@ -0,0 +1,112 @@
+use std::{
+    collections::HashSet,
+    fs,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context as _, Result};
+
+use crate::{age, audit::Outcome, ctx::Context, git::Repository, secret::SecretBuf, tar};
+
+use super::internal::CommandContext;
+
+impl<C: Context> CommandContext<C> {
+    /// Decrypts the staged version of every file covered by a rule into a tar archive encrypted
+    /// to `recipients`, written to `output` or stdout for "-"
+    pub(crate) fn export(&self, output: &Path, recipients: &[String]) -> Result<()> {
+        let public_keys = self.ctx.recipients().resolve(recipients)?;
+        age::validate_public_keys(&public_keys)?;
+
+        let repo = self.ctx.repo();
+        let blobs = repo.index_blobs()?;
+        let paths: Vec<PathBuf> = blobs.iter().map(|b| b.path.clone()).collect();
+        let covered: HashSet<PathBuf> = self.ctx.config()?.paths(&paths)?.into_iter().collect();
+        let identities = self.get_identities()?;
+
+        let mut archive = tar::Writer::new(SecretBuf::new());
+        let mut count = 0;
+        for blob in blobs.iter().filter(|b| covered.contains(&b.path)) {
+            let file = repo.workdir().join(&blob.path);
+            let contents = repo.read_blob(&blob.id)?;
+            let plaintext = match self.decrypt_audited(
+                "export",
+                &file,
+                identities.clone(),
+                contents.clone(),
+            )? {
+                Some(plaintext) => plaintext,
+                None => {
+                    log::warn!("{:?} isn't encrypted, exporting as is", blob.path);
+                    contents.into()
+                }
+            };
+            archive.append(&blob.path, &plaintext)?;
+            count += 1;
+        }
+        let archive = archive.finish()?;
+
+        let timeout = self.encryption_timeout(&public_keys)?;
+        let encrypted = age::with_timeout(timeout, move || {
+            age::encrypt(&public_keys, false, &mut &archive[..])
+        })?;
+        if output == Path::new("-") {
+            io::stdout().write_all(&encrypted)?;
+        } else {
+            fs::write(output, encrypted).with_context(|| format!("Couldn't write {:?}", output))?;
+            println!("Exported {} files to {}", count, output.display());
+        }
+        Ok(())
+    }
+
+    /// Writes the files of a bundle created by [`export`](Self::export), read from `bundle` or
+    /// stdin for "-", into the working copy.
+    ///
+    /// Nothing is written unless a rule covers every file of the bundle, so that a bundle can't
+    /// place files like git hooks.
+    pub(crate) fn import(&self, bundle: &Path) -> Result<()> {
+        let encrypted = if bundle == Path::new("-") {
+            let mut encrypted = vec![];
+            io::stdin().read_to_end(&mut encrypted)?;
+            encrypted
+        } else {
+            fs::read(bundle).with_context(|| format!("Couldn't read {:?}", bundle))?
+        };
+        let identities = self.get_identities()?;
+        let timeout = self.decryption_timeout(&identities)?;
+        let archive = age::with_timeout(timeout, move || {
+            age::decrypt(&identities, &mut &encrypted[..])
+        })?;
+        let Some((archive, identity)) = archive else {
+            bail!("{:?} isn't a bundle encrypted with age", bundle);
+        };
+        self.audit(
+            "import",
+            bundle,
+            Some(Outcome::Success {
+                identity: &identity,
+            }),
+        );
+
+        let repo = self.ctx.repo();
+        let cfg = self.ctx.config()?;
+        let files = tar::read(&archive)?;
+        for (path, _) in &files {
+            if cfg.lookup(&repo.workdir().join(path))?.is_none() {
+                bail!(
+                    "No rule covers {:?} of the bundle, nothing was imported",
+                    path
+                );
+            }
+        }
+        for (path, contents) in &files {
+            let file = repo.workdir().join(path);
+            if let Some(dir) = file.parent() {
+                fs::create_dir_all(dir).with_context(|| format!("Couldn't create {:?}", dir))?;
+            }
+            fs::write(&file, contents).with_context(|| format!("Couldn't write {:?}", file))?;
+        }
+        println!("Imported {} files", files.len());
+        Ok(())
+    }
+}
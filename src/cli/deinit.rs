@@ -0,0 +1,105 @@
+use std::{fs, path::PathBuf};
+
+use anyhow::{bail, Context as _, Result};
+
+use crate::{ctx::Context, git::Repository};
+
+use super::{
+    internal::CommandContext,
+    public::{is_encrypted, remove_setup},
+};
+
+impl<C: Context> CommandContext<C> {
+    /// Removes git-agecrypt from the repository, leaving the files covered by a rule in a
+    /// defined state: with `decrypt`, their plaintext is written to the working copy and staged,
+    /// otherwise the working copy has to be locked, so that they stay encrypted.
+    pub(crate) fn deinit(&self, decrypt: bool) -> Result<()> {
+        let repo = self.ctx.repo();
+        let tracked = repo.list_files()?;
+        let files: Vec<PathBuf> = self
+            .ctx
+            .config()?
+            .paths(&tracked)?
+            .into_iter()
+            .filter(|f| tracked.contains(f))
+            .collect();
+
+        if !decrypt {
+            if !self.ctx.settings().locked()? {
+                bail!("The working copy isn't locked, run `git-agecrypt lock` first");
+            }
+            let mut plaintext = vec![];
+            for file in &files {
+                let contents = fs::read(repo.workdir().join(file))
+                    .with_context(|| format!("Couldn't read {:?}", file))?;
+                if !is_encrypted(&contents) {
+                    plaintext.push(file.display().to_string());
+                }
+            }
+            if !plaintext.is_empty() {
+                bail!(
+                    "Refusing to deinit as the following files aren't encrypted in the working copy, run `git-agecrypt lock` again: {}",
+                    plaintext.join(", ")
+                );
+            }
+            remove_setup(&self.ctx)?;
+            println!(
+                "Removed git-agecrypt, {} files stay encrypted in the working copy",
+                files.len()
+            );
+            return Ok(());
+        }
+
+        let modified = repo.modified_files(&files)?;
+        if !modified.is_empty() {
+            let list: Vec<_> = modified.iter().map(|f| f.display().to_string()).collect();
+            bail!(
+                "Refusing to deinit as the following files have uncommitted changes, commit or stash them first: {}",
+                list.join(", ")
+            );
+        }
+        // Everything is decrypted before anything is changed, a file which can't be decrypted
+        // leaves the repository as it was
+        let mut decrypted = vec![];
+        for file in &files {
+            let path = repo.workdir().join(file);
+            let staged = repo.get_staged_contents(&path)?;
+            if !is_encrypted(&staged) {
+                log::info!("File is staged in plaintext already; file={:?}", file);
+                continue;
+            }
+            let identities = self.identities_for(&path)?;
+            let Some(plaintext) = self.decrypt_audited("deinit", &path, identities, staged)? else {
+                bail!("Couldn't decrypt {}", file.display());
+            };
+            decrypted.push((file, plaintext));
+        }
+
+        remove_setup(&self.ctx)?;
+        for (file, plaintext) in &decrypted {
+            let path = repo.workdir().join(file);
+            fs::write(&path, plaintext).with_context(|| format!("Couldn't write {:?}", path))?;
+        }
+        if self.ctx.settings().locked()? {
+            self.ctx.settings().set_locked(false)?;
+        }
+        let attributes_file = PathBuf::from(".gitattributes");
+        if tracked.contains(&attributes_file) || repo.workdir().join(&attributes_file).exists() {
+            repo.add_files(&[repo.workdir().join(attributes_file)], false)?;
+        }
+        let paths: Vec<PathBuf> = decrypted.iter().map(|(f, _)| f.to_path_buf()).collect();
+        repo.add_files(&paths, true)?;
+
+        println!(
+            "Decrypted {} files and staged them, commit them to finish leaving git-agecrypt:",
+            paths.len()
+        );
+        for path in &paths {
+            println!("    ✓ {}", path.display());
+        }
+        println!(
+            "Earlier versions stay encrypted in the history, keep the identities to read them"
+        );
+        Ok(())
+    }
+}
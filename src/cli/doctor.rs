@@ -0,0 +1,354 @@
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Result};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{age, ctx::Context, git, git::Repository};
+
+use super::{
+    args::OutputFormat,
+    exit::{ChecksFailed, ExitCode, Reported},
+    internal::CommandContext,
+    output,
+};
+
+/// Git version needed for the `process` filter and `git add --renormalize`
+const MIN_GIT_VERSION: (u32, u32) = (2, 16);
+
+/// Configuration entries `init` writes, without which files silently stay unencrypted or
+/// aren't decrypted
+const FILTER_KEYS: &[&str] = &[
+    "filter.git-agecrypt.required",
+    "filter.git-agecrypt.clean",
+    "filter.git-agecrypt.smudge",
+    "filter.git-agecrypt.process",
+    "diff.git-agecrypt.textconv",
+    "merge.git-agecrypt.driver",
+];
+
+/// Plaintext encrypted to each identity to check that it can decrypt
+const PROBE: &[u8] = b"git-agecrypt doctor probe";
+
+/// The outcome of a single check
+#[derive(Serialize)]
+struct Check {
+    name: String,
+    ok: bool,
+    /// What is wrong, or details on a passed check
+    message: Option<String>,
+    /// How to fix the problem
+    fix: Option<String>,
+}
+
+impl Check {
+    fn ok(name: impl Into<String>, message: Option<String>) -> Self {
+        Self {
+            name: name.into(),
+            ok: true,
+            message,
+            fix: None,
+        }
+    }
+
+    fn failed(name: impl Into<String>, message: impl Into<String>, fix: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            ok: false,
+            message: Some(message.into()),
+            fix: Some(fix.into()),
+        }
+    }
+}
+
+impl<C: Context> CommandContext<C> {
+    /// Checks the setup for the mistakes which make the filters fail or silently not run,
+    /// suggesting a fix for each problem found
+    pub(crate) fn doctor(&self, format: OutputFormat) -> Result<()> {
+        let mut checks = vec![self.check_git_version()];
+        checks.extend(self.check_filters());
+        checks.extend(self.check_rules());
+        checks.extend(self.check_identities()?);
+        checks.push(self.check_sidecars());
+
+        let failed = checks.iter().filter(|c| !c.ok).count();
+        if format == OutputFormat::Json {
+            output::print(&json!({ "checks": checks }))?;
+            if failed > 0 {
+                // The report already lists the problems
+                return Err(Reported(ExitCode::ChecksFailed).into());
+            }
+            return Ok(());
+        }
+        for check in &checks {
+            match (check.ok, &check.message) {
+                (true, None) => println!("    ✓ {}", check.name),
+                (true, Some(message)) => println!("    ✓ {} -- {}", check.name, message),
+                (false, message) => {
+                    println!(
+                        "    ⨯ {} -- {}",
+                        check.name,
+                        message.as_deref().unwrap_or("")
+                    );
+                }
+            }
+            if let Some(fix) = &check.fix {
+                println!("      {}", fix);
+            }
+        }
+        if failed > 0 {
+            return Err(ChecksFailed(format!("Found {} problems", failed)).into());
+        }
+        println!("No problems found.");
+        Ok(())
+    }
+
+    fn check_git_version(&self) -> Check {
+        let name = "git version";
+        match git::version() {
+            Ok(version) if version >= MIN_GIT_VERSION => {
+                Check::ok(name, Some(format!("{}.{}", version.0, version.1)))
+            }
+            Ok(version) => Check::failed(
+                name,
+                format!(
+                    "git {}.{} is too old, git-agecrypt needs at least {}.{}",
+                    version.0, version.1, MIN_GIT_VERSION.0, MIN_GIT_VERSION.1
+                ),
+                "Upgrade git",
+            ),
+            Err(err) => Check::failed(name, format!("{:#}", err), "Install git into PATH"),
+        }
+    }
+
+    /// Checks that the filters are configured and run an existing executable
+    fn check_filters(&self) -> Vec<Check> {
+        FILTER_KEYS
+            .iter()
+            .map(|&key| match self.ctx.repo().get_config(key) {
+                Err(_) => Check::failed(key, "not set", "Run `git-agecrypt init`"),
+                Ok(value) if key == "filter.git-agecrypt.required" => {
+                    if value == "true" {
+                        Check::ok(key, None)
+                    } else {
+                        Check::failed(
+                            key,
+                            format!("is {:?}, so git ignores filter errors", value),
+                            "Run `git-agecrypt init`",
+                        )
+                    }
+                }
+                Ok(value) => {
+                    let exe = command_executable(&value);
+                    if executable_exists(&exe) {
+                        Check::ok(key, Some(value))
+                    } else {
+                        Check::failed(
+                            key,
+                            format!("runs '{}', which doesn't exist", exe),
+                            "Run `git-agecrypt init` again with the installed git-agecrypt",
+                        )
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// Checks that the rules file can be loaded and `.gitattributes` assigns the filters to
+    /// every file covered by a rule
+    fn check_rules(&self) -> Vec<Check> {
+        let cfg = match self.ctx.config() {
+            Ok(cfg) => cfg,
+            Err(err) => {
+                return vec![Check::failed(
+                    "rules file",
+                    format!("{:#}", err),
+                    "Fix the rules file",
+                )]
+            }
+        };
+        let mut rv = vec![];
+        let warnings = cfg.warnings();
+        if !cfg.path().exists() {
+            rv.push(Check::failed(
+                "rules file",
+                format!(
+                    "'{}' doesn't exist, so no file is encrypted",
+                    cfg.path().display()
+                ),
+                "Run `git-agecrypt config add -r <recipient> -p <path>`",
+            ));
+        } else if warnings.is_empty() {
+            rv.push(Check::ok(
+                "rules file",
+                Some(cfg.path().display().to_string()),
+            ));
+        } else {
+            rv.push(Check::failed(
+                "rules file",
+                warnings.join("; "),
+                "Remove the duplicate rules",
+            ));
+        }
+
+        let repo = self.ctx.repo();
+        let uncovered = repo
+            .list_files()
+            .map_err(anyhow::Error::from)
+            .and_then(|files| {
+                Ok(cfg
+                    .paths(&files)?
+                    .into_iter()
+                    .filter(|p| {
+                        repo.get_attribute(p, "filter").ok().flatten().as_deref()
+                            != Some("git-agecrypt")
+                    })
+                    .collect::<Vec<PathBuf>>())
+            });
+        rv.push(match uncovered {
+            Ok(uncovered) if uncovered.is_empty() => Check::ok(".gitattributes", None),
+            Ok(uncovered) => {
+                let paths: Vec<String> =
+                    uncovered.iter().map(|p| p.display().to_string()).collect();
+                Check::failed(
+                    ".gitattributes",
+                    format!("the filter isn't assigned to {}", paths.join(", ")),
+                    "Run `git-agecrypt sync-attributes`",
+                )
+            }
+            Err(err) => Check::failed(
+                ".gitattributes",
+                format!("{:#}", err),
+                "Check that the repository isn't corrupt",
+            ),
+        });
+        rv
+    }
+
+    /// Checks that each identity can decrypt a file encrypted to its public key
+    fn check_identities(&self) -> Result<Vec<Check>> {
+        let identities = self.get_identities()?;
+        if identities.is_empty() {
+            return Ok(vec![Check::failed(
+                "identities",
+                "none are configured, so files can't be decrypted",
+                "Run `git-agecrypt config add-identity <path>`",
+            )]);
+        }
+        let in_memory = age::in_memory_identities();
+        Ok(identities
+            .iter()
+            .map(|identity| {
+                let name = format!("identity '{}'", identity);
+                if age::identities_use_plugins(&[identity]) {
+                    return Check::ok(name, Some("not probed, it needs an age plugin".into()));
+                }
+                let probe = if in_memory.contains(identity) {
+                    probe_identity(identity)
+                } else {
+                    age::validate_identity(identity).and_then(|_| probe_identity(identity))
+                };
+                match probe {
+                    Ok(()) => Check::ok(name, None),
+                    Err(err) => Check::failed(
+                        name,
+                        format!("{:#}", err),
+                        "Fix or replace the identity, see `git-agecrypt config list-identities`",
+                    ),
+                }
+            })
+            .collect())
+    }
+
+    /// Checks that the hashes of encrypted files can be stored
+    fn check_sidecars(&self) -> Check {
+        let name = "sidecar directory";
+        let path = self.ctx.repo().workdir().join("git-agecrypt-doctor");
+        match self
+            .ctx
+            .store_sidecar(&path, "probe", b"")
+            .and_then(|_| self.ctx.remove_sidecar(&path, "probe"))
+        {
+            Ok(()) => Check::ok(name, None),
+            Err(err) => Check::failed(
+                name,
+                format!("{:#}", err),
+                "Make the .git/git-agecrypt directory writable",
+            ),
+        }
+    }
+}
+
+/// Encrypts a probe to the public key of `identity` and decrypts it again
+fn probe_identity(identity: &str) -> Result<()> {
+    let recipients = age::identity_recipients(&[identity])?;
+    if recipients.is_empty() {
+        bail!("its public key is unknown, so it can't be checked");
+    }
+    let encrypted = age::encrypt(&recipients, false, &mut &PROBE[..])?;
+    match age::decrypt(&[identity], &mut &encrypted[..])? {
+        Some((decrypted, _)) if decrypted == PROBE => Ok(()),
+        _ => bail!("it can't decrypt a file encrypted to its own public key"),
+    }
+}
+
+/// The program a filter command line runs, unquoting it as `init` quotes it
+fn command_executable(command: &str) -> String {
+    let command = command.trim_start();
+    if !command.starts_with('\'') {
+        return command.split_whitespace().next().unwrap_or_default().into();
+    }
+    // Quoted as `'...'`, with `'` written as `'\''`
+    let mut rv = String::new();
+    let mut rest = &command[1..];
+    while let Some(end) = rest.find('\'') {
+        rv.push_str(&rest[..end]);
+        match rest[end..].strip_prefix("'\\''") {
+            Some(tail) => {
+                rv.push('\'');
+                rest = tail;
+            }
+            None => break,
+        }
+    }
+    rv
+}
+
+/// Whether `exe` names an existing file, looking it up in `PATH` if it is a bare name
+fn executable_exists(exe: &str) -> bool {
+    let path = Path::new(exe);
+    if exe.is_empty() {
+        return false;
+    }
+    if path.components().count() > 1 {
+        return path.is_file();
+    }
+    env::var_os("PATH")
+        .map(|paths| {
+            env::split_paths(&paths).any(|dir| {
+                dir.join(path).is_file()
+                    || (cfg!(windows) && dir.join(path).with_extension("exe").is_file())
+            })
+        })
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case("/usr/bin/git-agecrypt smudge -f %f", "/usr/bin/git-agecrypt")]
+    #[case("git-agecrypt process", "git-agecrypt")]
+    #[case("'/opt/my tools/git-agecrypt' process", "/opt/my tools/git-agecrypt")]
+    #[case("'/home/o'\\''neil/git-agecrypt' process", "/home/o'neil/git-agecrypt")]
+    #[case("", "")]
+    fn test_command_executable(#[case] command: &str, #[case] expected: &str) {
+        assert_eq!(command_executable(command), expected);
+    }
+}
@@ -0,0 +1,208 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+};
+
+use anyhow::{Context as _, Result};
+
+use crate::{
+    ctx::Context,
+    git::{Error as GitError, Repository},
+    secret::SecretBuf,
+};
+
+use super::internal::CommandContext;
+
+impl<C: Context> CommandContext<C> {
+    /// Decrypts a file into a temporary file for editing and encrypts the result again.
+    ///
+    /// The working copy is updated in the form it was in, so a file left encrypted by `lock`
+    /// stays encrypted. The sidecars are updated too, so `git add` picks up the new ciphertext
+    /// as is.
+    pub(crate) fn edit(&self, path: &Path) -> Result<()> {
+        self.edit_with(path, |temp| Ok(self.ctx.repo().edit_file(temp)?))
+    }
+
+    /// Like [`Self::edit`], with `editor` changing the decrypted temporary file
+    fn edit_with(&self, path: &Path, editor: impl FnOnce(&Path) -> Result<()>) -> Result<()> {
+        let relpath = self.repo_path(path)?;
+        let file = self.ctx.repo().workdir().join(&relpath);
+        // Fails early for files not covered by a rule
+        self.ctx.config()?.get_rule(&file)?;
+
+        let working_copy = match fs::read(&file) {
+            Ok(contents) => Some(contents),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        let exists = working_copy.is_some();
+        let current = match working_copy {
+            Some(contents) => Some(contents),
+            None => self.committed_contents(&file)?,
+        };
+        let (plaintext, decrypted) = match current {
+            Some(contents) => {
+                let identities = self.identities_for(&file)?;
+                match self.decrypt_audited("edit", &file, identities, contents.clone())? {
+                    Some(plaintext) => (plaintext, true),
+                    None => (contents.into(), false),
+                }
+            }
+            None => (SecretBuf::new(), false),
+        };
+        // New and deleted files are written the way a checkout would write them
+        let encrypted = if exists {
+            decrypted
+        } else {
+            self.leave_encrypted(&file)?
+        };
+
+        // Kept in the git directory instead of the world readable temporary directory, with
+        // the file name as suffix so that editors can pick the syntax
+        let name = relpath
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let mut temp = tempfile::Builder::new()
+            .prefix("git-agecrypt-edit-")
+            .suffix(&format!("-{}", name))
+            .tempfile_in(self.ctx.repo().path())?;
+        temp.write_all(&plaintext)?;
+        temp.flush()?;
+        editor(temp.path())?;
+        // Editors may replace the file instead of writing to it
+        let edited = SecretBuf::from(
+            fs::read(temp.path())
+                .with_context(|| format!("Couldn't read edited file {:?}", temp.path()))?,
+        );
+        drop(temp);
+
+        if edited == plaintext && exists {
+            println!("{} is unchanged", relpath.display());
+            return Ok(());
+        }
+
+        let (public_keys, options) =
+            self.prepare_encryption(&file, edited.len() as u64, &edited, false)?;
+        let ciphertext = self.encrypt(&file, public_keys, &options, edited.clone(), true)?;
+        let contents: &[u8] = if encrypted { &ciphertext } else { &edited };
+        if let Some(dir) = file.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&file, contents)
+            .with_context(|| format!("Couldn't write '{}'", file.display()))?;
+        self.ctx.store_sidecar(&file, "age", &ciphertext)?;
+        self.ctx
+            .store_sidecar(&file, "hash", blake3::hash(contents).as_bytes())?;
+        println!("Updated {}", relpath.display());
+        Ok(())
+    }
+
+    /// The version of `file` in `HEAD`, `None` if it isn't committed
+    fn committed_contents(&self, file: &Path) -> Result<Option<Vec<u8>>> {
+        match self.ctx.repo().get_file_contents(file) {
+            Ok(contents) => Ok(Some(contents)),
+            Err(GitError::NotExist(_)) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::age::secrecy::ExposeSecret;
+    use assert_fs::prelude::*;
+    use assert_fs::TempDir;
+    use duct::cmd;
+    use rstest::rstest;
+
+    use super::*;
+    use crate::{age, config::AgeIdentity, ctx::ContextWrapper, git::LibGit2Repository};
+
+    /// A repository with `secret.txt` covered by a rule, its working copy left encrypted as by
+    /// `lock` if `locked` is set
+    fn repository(
+        dir: &TempDir,
+        locked: bool,
+    ) -> anyhow::Result<CommandContext<ContextWrapper<LibGit2Repository>>> {
+        cmd!("git", "init").dir(dir.path()).run()?;
+        let identity = ::age::x25519::Identity::generate();
+        let key = dir.child("key.txt");
+        key.write_str(&format!("{}\n", identity.to_string().expose_secret()))?;
+        dir.child("git-agecrypt.toml").write_str(&format!(
+            "[config]\n\"secret.txt\" = [\"{}\"]\n",
+            identity.to_public()
+        ))?;
+        let contents = if locked {
+            age::encrypt(
+                &[identity.to_public().to_string()],
+                false,
+                &mut &b"password=1\n"[..],
+            )?
+        } else {
+            b"password=1\n".to_vec()
+        };
+        dir.child("secret.txt").write_binary(&contents)?;
+
+        let repo = LibGit2Repository::from_dir(dir.path().to_path_buf())?;
+        let ctx = ContextWrapper::new(repo, None);
+        ctx.age_identities()
+            .add(AgeIdentity::try_from(key.path().to_path_buf())?)?;
+        Ok(CommandContext { ctx })
+    }
+
+    fn decrypt(dir: &TempDir, ciphertext: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let (plaintext, _) = age::decrypt(&[dir.child("key.txt").path()], &mut &ciphertext[..])?
+            .expect("not encrypted");
+        Ok(plaintext.to_vec())
+    }
+
+    #[rstest]
+    #[case(false)]
+    #[case(true)]
+    fn test_edit(#[case] locked: bool) -> anyhow::Result<()> {
+        let dir = TempDir::new()?;
+        let cmd = repository(&dir, locked)?;
+        let file = dir.child("secret.txt");
+
+        cmd.edit_with(file.path(), |temp| {
+            assert_eq!(fs::read(temp)?, b"password=1\n");
+            Ok(fs::write(temp, "password=2\n")?)
+        })?;
+        let ciphertext = cmd
+            .ctx
+            .load_sidecar(file.path(), "age")?
+            .expect("ciphertext sidecar");
+        assert_eq!(decrypt(&dir, &ciphertext)?, b"password=2\n");
+        let working_copy = fs::read(file.path())?;
+        if locked {
+            assert_eq!(working_copy, ciphertext);
+        } else {
+            assert_eq!(working_copy, b"password=2\n");
+        }
+        let hash = cmd.ctx.load_sidecar(file.path(), "hash")?;
+        assert_eq!(
+            hash.as_deref(),
+            Some(&blake3::hash(&working_copy).as_bytes()[..])
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    #[case(false)]
+    #[case(true)]
+    fn test_edit_unchanged(#[case] locked: bool) -> anyhow::Result<()> {
+        let dir = TempDir::new()?;
+        let cmd = repository(&dir, locked)?;
+        let file = dir.child("secret.txt");
+        let contents = fs::read(file.path())?;
+        let modified = fs::metadata(file.path())?.modified()?;
+
+        cmd.edit_with(file.path(), |_| Ok(()))?;
+        assert_eq!(fs::read(file.path())?, contents);
+        assert_eq!(fs::metadata(file.path())?.modified()?, modified);
+        assert_eq!(cmd.ctx.load_sidecar(file.path(), "age")?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,121 @@
+//! Exit statuses telling scripts what kind of failure stopped a command, see the README.
+
+use thiserror::Error;
+
+use crate::{age::PluginError, config, git};
+
+/// Exit status of each category of failure. Invalid command line arguments are reported by
+/// clap, exiting with 2.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ExitCode {
+    /// Any failure without a category of its own, also a merge with conflicts
+    Failure = 1,
+    /// The rules file, the settings or an identity can't be read or changed
+    Config = 3,
+    /// No rule covers the file
+    NoRule = 4,
+    /// Accessing the repository failed
+    Git = 5,
+    /// None of the identities can decrypt the file, or its ciphertext is corrupt
+    Decryption = 6,
+    /// A program git-agecrypt relies on is missing or didn't respond: git or an age plugin
+    External = 7,
+    /// `verify`, `audit-recipients`, `doctor`, `validate-config` or `purge-history` found problems
+    ChecksFailed = 8,
+}
+
+/// Problems found by a command checking the repository, see [`ExitCode::ChecksFailed`]
+#[derive(Error, Debug)]
+#[error("{0}")]
+pub(crate) struct ChecksFailed(pub String);
+
+/// A failure the command already reported, e.g. in its JSON report or to git, so only the exit
+/// status is left to set
+#[derive(Error, Debug)]
+#[error("Failed with exit status {}", *.0 as i32)]
+pub(crate) struct Reported(pub ExitCode);
+
+impl ExitCode {
+    /// The category of `err`, that of the deepest error in its chain which has one, as that is
+    /// the most specific
+    pub fn of(err: &anyhow::Error) -> Self {
+        Self::find(err).unwrap_or(Self::Failure)
+    }
+
+    fn find(err: &anyhow::Error) -> Option<Self> {
+        err.chain().filter_map(Self::category).last()
+    }
+
+    fn category(cause: &(dyn std::error::Error + 'static)) -> Option<Self> {
+        if let Some(Reported(code)) = cause.downcast_ref() {
+            return Some(*code);
+        }
+        if cause.is::<ChecksFailed>() {
+            return Some(Self::ChecksFailed);
+        }
+        if cause.is::<PluginError>() {
+            return Some(Self::External);
+        }
+        // Errors wrapped by `Other` aren't part of the chain, as it is transparent
+        if let Some(err) = cause.downcast_ref::<config::Error>() {
+            return Some(match err {
+                config::Error::NoRule(_) => Self::NoRule,
+                config::Error::Other(inner) => Self::find(inner).unwrap_or(Self::Config),
+                _ => Self::Config,
+            });
+        }
+        if let Some(err) = cause.downcast_ref::<git::Error>() {
+            return Some(match err {
+                git::Error::NotInstalled => Self::External,
+                git::Error::Other(inner) => Self::find(inner).unwrap_or(Self::Git),
+                _ => Self::Git,
+            });
+        }
+        if let Some(err) = cause.downcast_ref::<::age::DecryptError>() {
+            return Some(match err {
+                ::age::DecryptError::MissingPlugin { .. } => Self::External,
+                _ => Self::Decryption,
+            });
+        }
+        if let Some(::age::EncryptError::MissingPlugin { .. }) = cause.downcast_ref() {
+            return Some(Self::External);
+        }
+        None
+    }
+}
+
+impl From<ExitCode> for std::process::ExitCode {
+    fn from(code: ExitCode) -> Self {
+        Self::from(code as u8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use anyhow::{anyhow, Context};
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case(anyhow!("plain"), ExitCode::Failure)]
+    #[case(config::Error::NoRule(PathBuf::from("a")).into(), ExitCode::NoRule)]
+    #[case(config::Error::NotExist("a".into()).into(), ExitCode::Config)]
+    #[case(git::Error::NotExist("HEAD".into()).into(), ExitCode::Git)]
+    #[case(git::Error::NotInstalled.into(), ExitCode::External)]
+    #[case(config::Error::from(git::Error::NotInstalled).into(), ExitCode::External)]
+    #[case(git::Error::Other(anyhow!("corrupt")).into(), ExitCode::Git)]
+    #[case(PluginError::Timeout(1).into(), ExitCode::External)]
+    #[case(::age::DecryptError::NoMatchingKeys.into(), ExitCode::Decryption)]
+    #[case(ChecksFailed("Found 1 problems".into()).into(), ExitCode::ChecksFailed)]
+    #[case(Reported(ExitCode::ChecksFailed).into(), ExitCode::ChecksFailed)]
+    #[case(Reported(ExitCode::Failure).into(), ExitCode::Failure)]
+    fn test_of(#[case] err: anyhow::Error, #[case] expected: ExitCode) {
+        assert_eq!(ExitCode::of(&err), expected);
+        // Context added on the way up doesn't change the category
+        let err = Err::<(), _>(err).context("Couldn't smudge").unwrap_err();
+        assert_eq!(ExitCode::of(&err), expected);
+    }
+}
@@ -0,0 +1,68 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use clap::{Command, CommandFactory};
+use clap_complete::Shell;
+
+use super::args::Args;
+
+/// Prints the completion script for `shell` to stdout
+pub(crate) fn completions(shell: Shell) -> Result<()> {
+    let mut cmd = Args::command();
+    let name = cmd.get_name().to_string();
+    let mut stdout = io::stdout();
+    clap_complete::generate(shell, &mut cmd, name, &mut stdout);
+    Ok(stdout.flush()?)
+}
+
+/// Writes a man page for the command and one for each of its subcommands into `dir`, named like
+/// `git-agecrypt-config-add.1`
+pub(crate) fn manpages(dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir).with_context(|| format!("Couldn't create {:?}", dir))?;
+    let mut cmd = Args::command();
+    // Propagates the global options and sets the full command line as the name of subcommands
+    cmd.build();
+    let name = cmd.get_name().to_string();
+    write_manpages(&cmd, &name, dir)
+}
+
+fn write_manpages(cmd: &Command, name: &str, dir: &Path) -> Result<()> {
+    let path = dir.join(format!("{}.1", name));
+    let mut page = vec![];
+    clap_mangen::Man::new(cmd.clone().name(name.to_string())).render(&mut page)?;
+    fs::write(&path, page).with_context(|| format!("Couldn't write {:?}", path))?;
+    println!("{}", path.display());
+
+    for sub in cmd
+        .get_subcommands()
+        .filter(|c| !c.is_hide_set() && c.get_name() != "help")
+    {
+        write_manpages(sub, &format!("{}-{}", name, sub.get_name()), dir)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_fs::TempDir;
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    fn test_manpages() -> Result<()> {
+        let dir = TempDir::new()?;
+        manpages(dir.path())?;
+        assert!(dir.path().join("git-agecrypt.1").is_file());
+        let page = fs::read_to_string(dir.path().join("git-agecrypt-config-add.1"))?;
+        assert!(page.contains("git\\-agecrypt config add"));
+        // Commands run by git aren't documented
+        assert!(!dir.path().join("git-agecrypt-clean.1").exists());
+        assert!(!dir.path().join("git-agecrypt-help.1").exists());
+        Ok(())
+    }
+}
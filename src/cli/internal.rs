@@ -1,101 +1,766 @@
 use std::{
+    collections::HashMap,
+    env,
     fs::File,
     io::{self, Read, Seek, Write},
     path::{Path, PathBuf},
+    sync::mpsc,
+    time::Duration,
 };
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context as _, Result};
 use blake3::Hash;
+use serde_json::json;
 
-use crate::{age, ctx::Context, git::Error as GitError, git::Repository};
+use crate::{
+    age,
+    audit::{self, Outcome},
+    cache::BlobCache,
+    compress::{self, DecompressWriter},
+    config::{normalize_path, GuardAction, MissingIdentity, Mode, RuleOptions},
+    ctx::Context,
+    deterministic, git,
+    git::Error as GitError,
+    git::Repository,
+    magic,
+    mmap::Mapped,
+    pktline,
+    secret::SecretBuf,
+    sidecars::HASH_LEN,
+    stream::{self, TeeReader, TeeWriter},
+    threshold, values,
+};
+
+use super::{
+    args::{DiffFormat, OutputFormat},
+    output,
+    public::{is_encrypted, options_apply},
+};
+
+/// Bytes of the input needed to recognize file types and the threshold format
+const PREFIX_LEN: usize = 64;
 
 pub(crate) struct CommandContext<C: Context> {
     pub ctx: C,
 }
 
 impl<C: Context> CommandContext<C> {
-    pub(crate) fn clean(&self, file: impl AsRef<Path>) -> Result<()> {
+    pub(crate) fn clean(&self, file: impl AsRef<Path>, check_decryptable: bool) -> Result<()> {
         log::info!("Encrypting file");
         let file = self.ctx.repo().workdir().join(file);
+        if !check_decryptable {
+            if let Some(encrypted) = self.stat_clean_ciphertext(&file)? {
+                // Git doesn't mind the plaintext on stdin going unread
+                return Ok(io::stdout().write_all(&encrypted)?);
+            }
+        }
 
-        log::debug!("Looking for saved has information. target={:?}", file,);
-        let mut existing_hash = [0u8; 32];
-        if let Some(hash_buffer) = self.ctx.load_sidecar(&file, "hash")? {
-            existing_hash = hash_buffer.as_slice().try_into()?
+        // Spooled to an anonymous file instead of memory, the plaintext may be large
+        let mut spool = tempfile::tempfile_in(self.ctx.repo().path())?;
+        let mut hasher = blake3::Hasher::new();
+        let settings = self.ctx.settings();
+        // Input over the size limit is refused without reading the rest, which may take long
+        let limit = match settings.max_size()? {
+            0 => u64::MAX,
+            max if settings.on_max_size()? == GuardAction::Fail => max + 1,
+            _ => u64::MAX,
+        };
+        let size = io::copy(
+            &mut io::stdin().take(limit),
+            &mut TeeWriter::new(&mut spool, &mut hasher),
+        )?;
+        if size == limit {
+            self.check_size(&file, size)?;
+        }
+        let hash = hasher.finalize();
+
+        if !self.rule_applies(&file)? {
+            log::info!("Rule doesn't apply here, keeping file in plaintext; file={file:?}");
+            spool.rewind()?;
+            io::copy(&mut spool, &mut io::stdout())?;
+            return Ok(());
+        }
+        if let Some(mut encrypted) = self.unchanged_ciphertext(&file, hash)? {
+            io::copy(&mut encrypted, &mut io::stdout())?;
+            return Ok(());
+        }
+
+        spool.rewind()?;
+        let prefix = stream::read_prefix(&mut spool, PREFIX_LEN)?;
+        spool.rewind()?;
+        if self.leave_encrypted(&file)? {
+            let encrypted = threshold::is_threshold(&prefix)
+                || matches!(age::read_header(&mut spool), Ok(Some(_)));
+            spool.rewind()?;
+            if encrypted {
+                // E.g. written by `edit`, its sidecars are stale once `git diff` smudged the
+                // index version, so it would be encrypted twice otherwise
+                log::info!("File is left encrypted, keeping its ciphertext; file={file:?}");
+                let mut sidecar = self.ctx.create_sidecar(&file, "age")?;
+                io::copy(&mut spool, &mut TeeWriter::new(io::stdout(), &mut sidecar))?;
+                self.ctx.finish_sidecar(sidecar)?;
+                self.ctx.store_sidecar(&file, "hash", hash.as_bytes())?;
+                return Ok(());
+            }
+        }
+        let (public_keys, options) =
+            self.prepare_encryption(&file, size, &prefix, check_decryptable)?;
+        if options.mode == Some(Mode::Values) {
+            let mut contents = SecretBuf::with_capacity(size as usize);
+            io::copy(&mut spool, &mut contents)?;
+            let res = self.encrypt(&file, public_keys, &options, contents, true)?;
+            self.ctx.store_sidecar(&file, "age", &res)?;
+            self.ctx.store_sidecar(&file, "hash", hash.as_bytes())?;
+            return Ok(io::stdout().write_all(&res)?);
+        }
+
+        // The hash is only stored once the ciphertext sidecar is complete
+        self.ctx.remove_sidecar(&file, "hash")?;
+        let sidecar = self.ctx.create_sidecar(&file, "age")?;
+        let (_, sidecar) = self
+            .encrypt_to(
+                public_keys,
+                &options,
+                spool,
+                TeeWriter::new(io::stdout(), sidecar),
+            )?
+            .into_inner();
+        self.ctx.finish_sidecar(sidecar)?;
+        self.ctx.store_sidecar(&file, "hash", hash.as_bytes())?;
+        Ok(())
+    }
+
+    pub(crate) fn clean_contents(
+        &self,
+        file: &Path,
+        contents: SecretBuf,
+        check_decryptable: bool,
+    ) -> Result<Vec<u8>> {
+        log::info!("Encrypting file");
+        let file = self.ctx.repo().workdir().join(file);
+        if !check_decryptable {
+            if let Some(encrypted) = self.stat_clean_ciphertext(&file)? {
+                return Ok(encrypted);
+            }
+        }
+        let hash = blake3::hash(&contents);
+
+        if !self.rule_applies(&file)? {
+            log::info!("Rule doesn't apply here, keeping file in plaintext; file={file:?}");
+            return Ok(contents.to_vec());
+        }
+        if let Some(mut encrypted) = self.unchanged_ciphertext(&file, hash)? {
+            let mut rv = vec![];
+            encrypted.read_to_end(&mut rv)?;
+            return Ok(rv);
+        }
+        if self.leave_encrypted(&file)? && is_encrypted(&contents) {
+            log::info!("File is left encrypted, keeping its ciphertext; file={file:?}");
+            self.ctx.store_sidecar(&file, "age", &contents)?;
+            self.ctx.store_sidecar(&file, "hash", hash.as_bytes())?;
+            return Ok(contents.to_vec());
+        }
+
+        let (public_keys, options) =
+            self.prepare_encryption(&file, contents.len() as u64, &contents, check_decryptable)?;
+        let res = self.encrypt(&file, public_keys, &options, contents, true)?;
+        self.ctx.store_sidecar(&file, "age", &res)?;
+        self.ctx.store_sidecar(&file, "hash", hash.as_bytes())?;
+        Ok(res)
+    }
+
+    /// Reports what `clean` would do with the plaintext on stdin and to whom it would be
+    /// encrypted, running the same checks but writing neither ciphertext nor sidecars
+    pub(crate) fn clean_check(
+        &self,
+        file: impl AsRef<Path>,
+        check_decryptable: bool,
+        format: OutputFormat,
+    ) -> Result<()> {
+        let relpath = file.as_ref();
+        let file = self.ctx.repo().workdir().join(relpath);
+        let mut contents = SecretBuf::new();
+        io::copy(&mut io::stdin(), &mut contents)?;
+        let hash = blake3::hash(&contents);
+
+        let (public_keys, options) =
+            self.prepare_encryption(&file, contents.len() as u64, &contents, check_decryptable)?;
+        age::validate_public_keys(&public_keys)?;
+        let options = self.resolve_options(&options)?;
+        let action = if !self.rule_applies(&file)? {
+            "plaintext"
+        } else if self.leave_encrypted(&file)? && is_encrypted(&contents) {
+            "keep"
+        } else if self.is_unchanged(&file, hash)? {
+            "reuse"
         } else {
-            log::debug!("No saved hash file found");
+            "encrypt"
+        };
+
+        if format == OutputFormat::Json {
+            return output::print(&json!({
+                "path": relpath,
+                "action": action,
+                "recipients": public_keys,
+                "options": options,
+            }));
+        }
+        match action {
+            "plaintext" => println!(
+                "{} is kept in plaintext, its rule doesn't apply on this branch or remote",
+                relpath.display()
+            ),
+            "keep" => println!(
+                "{} is left encrypted, its ciphertext would be kept",
+                relpath.display()
+            ),
+            "reuse" => println!(
+                "{} is unchanged, its existing ciphertext would be reused",
+                relpath.display()
+            ),
+            _ => println!("{} would be encrypted", relpath.display()),
+        }
+        println!("Recipients:");
+        for key in &public_keys {
+            println!("    {}", key);
         }
+        let options = toml::to_string(&options)?;
+        if !options.is_empty() {
+            println!("Options:");
+            for line in options.lines() {
+                println!("    {}", line);
+            }
+        }
+        Ok(())
+    }
 
-        let mut hasher = blake3::Hasher::new();
-        let mut contents = vec![];
-        io::stdin().read_to_end(&mut contents)?;
-        let hash = hasher.update(&contents).finalize();
+    /// The staged ciphertext of `file` if git's stat information in the index tells that the
+    /// working copy didn't change since it was staged, so that `clean` can hand it out without
+    /// reading and hashing the plaintext.
+    ///
+    /// Plaintext staged before the file was covered by a rule is encrypted as usual.
+    fn stat_clean_ciphertext(&self, file: &Path) -> Result<Option<Vec<u8>>> {
+        let repo = self.ctx.repo();
+        let Some(id) = repo.stat_clean_blob(file)? else {
+            return Ok(None);
+        };
+        let staged = repo.read_blob(&id)?;
+        if !is_encrypted(&staged) {
+            return Ok(None);
+        }
+        log::debug!("File unchanged according to the index, reusing its ciphertext; blob={id}");
+        Ok(Some(staged))
+    }
 
-        let old_hash = Hash::from(existing_hash);
+    /// Whether `clean` would hand out existing ciphertext for plaintext with the given `hash`,
+    /// like [`unchanged_ciphertext`](Self::unchanged_ciphertext) but without storing anything
+    fn is_unchanged(&self, file: &Path, hash: Hash) -> Result<bool> {
+        let stored = self.ctx.load_sidecar(file, "hash")?;
+        if stored.as_deref() == Some(hash.as_bytes())
+            && self.ctx.open_sidecar(file, "age")?.is_some()
+        {
+            return Ok(true);
+        }
+        let identities = self.get_identities()?;
+        if identities.is_empty() {
+            return Ok(false);
+        }
+        for tracked in self.tracked_versions(file)? {
+            if self.decrypted_hash(identities.clone(), tracked)?.0 == Some(hash) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// The ciphertext to hand out for plaintext with the given `hash` without encrypting it
+    /// again: the output of the last encryption, or else the staged version or the one in
+    /// `HEAD` if it decrypts to the same plaintext.
+    ///
+    /// The sidecars are missing or stale e.g. after a clone, rebase or `git stash`, comparing
+    /// the plaintext keeps the ciphertext from changing although the file didn't.
+    fn unchanged_ciphertext(&self, file: &Path, hash: Hash) -> Result<Option<Box<dyn Read>>> {
+        log::debug!("Looking for saved has information. target={:?}", file,);
+        let old_hash = match self.ctx.load_sidecar(file, "hash")? {
+            Some(stored) => match <[u8; HASH_LEN]>::try_from(stored.as_slice()) {
+                Ok(stored) => Some(Hash::from(stored)),
+                Err(_) => {
+                    // Left behind by an interrupted write of an earlier version
+                    log::warn!(
+                        "Removing truncated hash sidecar; file={:?}, len={}",
+                        file,
+                        stored.len()
+                    );
+                    self.ctx.remove_sidecar(file, "hash")?;
+                    None
+                }
+            },
+            None => {
+                log::debug!("No saved hash file found");
+                None
+            }
+        };
         log::debug!(
-            "Comparing hashes for file; old_hash={}, new_hash={:?}",
-            old_hash.to_hex().as_str(),
+            "Comparing hashes for file; old_hash={:?}, new_hash={:?}",
+            old_hash.map(|h| h.to_hex()),
             hash.to_hex().as_str()
         );
 
-        let saved = if hash == old_hash {
-            self.ctx.load_sidecar(&file, "age")?
-        } else {
-            None
+        if old_hash == Some(hash) {
+            if let Some(saved) = self.ctx.open_sidecar(file, "age")? {
+                log::debug!("File didn't change since last encryption, loading from git HEAD");
+                return Ok(Some(Box::new(saved)));
+            }
+        }
+
+        log::debug!("Encrypted content changed, checking decrypted versions");
+        let identities = self.get_identities()?;
+        if identities.is_empty() {
+            log::debug!("No identities to decrypt the tracked versions, re-encrypting");
+            return Ok(None);
+        }
+        for tracked in self.tracked_versions(file)? {
+            let (decrypted_hash, tracked) = self.decrypted_hash(identities.clone(), tracked)?;
+            if decrypted_hash == Some(hash) {
+                log::debug!("Decrypted content matches, reusing its ciphertext");
+                self.ctx.store_sidecar(file, "age", &tracked)?;
+                self.ctx.store_sidecar(file, "hash", hash.as_bytes())?;
+                return Ok(Some(Box::new(io::Cursor::new(tracked))));
+            }
+        }
+
+        log::debug!("File changed since last encryption, re-encrypting");
+        Ok(None)
+    }
+
+    /// The staged version of `file` and the one in `HEAD`, once if they are the same
+    fn tracked_versions(&self, file: &Path) -> Result<Vec<Vec<u8>>> {
+        let repo = self.ctx.repo();
+        let mut rv = vec![];
+        for version in [repo.get_staged_contents(file), repo.get_file_contents(file)] {
+            match version {
+                Ok(contents) if !rv.contains(&contents) => rv.push(contents),
+                Ok(_) => {}
+                Err(GitError::NotExist(s)) => log::debug!("{}", s),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(rv)
+    }
+
+    /// Stores the sidecars of the files covered by a rule which changed between the commits
+    /// `old` and `new`, run by the post-checkout and post-merge hooks, so that `clean` hands out
+    /// the ciphertext of `new` instead of encrypting the files again.
+    ///
+    /// Files whose working copy doesn't match their version in `new` are left alone.
+    pub(crate) fn refresh_sidecars(&self, old: &str, new: &str) -> Result<()> {
+        let repo = self.ctx.repo();
+        let cfg = self.ctx.config()?;
+        let mut refreshed = 0;
+        for path in repo.changed_files(old, new)? {
+            let file = repo.workdir().join(&path);
+            if cfg.lookup(&file)?.is_none() {
+                continue;
+            }
+            match self.refresh_sidecar(&file, &format!("{}:{}", new, path.display())) {
+                Ok(true) => refreshed += 1,
+                Ok(false) => {}
+                // A hook failing doesn't undo the checkout, only makes git report an error
+                Err(err) => log::warn!("Couldn't refresh sidecars; file={file:?}, error={err:#}"),
+            }
+        }
+        log::info!("Refreshed sidecars; files={refreshed}");
+        Ok(())
+    }
+
+    /// Stores the version of `file` named by the revision expression `spec` as its sidecars if
+    /// it matches the working copy, returns whether it did
+    fn refresh_sidecar(&self, file: &Path, spec: &str) -> Result<bool> {
+        let contents = match std::fs::read(file) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err).with_context(|| format!("Couldn't read {:?}", file)),
         };
+        let committed = match self.ctx.repo().read_revision(spec) {
+            Ok(committed) => committed,
+            Err(GitError::NotExist(_)) => return Ok(false),
+            Err(err) => return Err(err.into()),
+        };
+        let hash = blake3::hash(&contents);
+        let saved = self.ctx.load_sidecar(file, "age")?;
+        if saved.as_ref() == Some(&committed)
+            && self.ctx.load_sidecar(file, "hash")?.as_deref() == Some(hash.as_bytes())
+        {
+            return Ok(false);
+        }
+        // A file left encrypted is stored as its own plaintext, see `clean`
+        let matches = contents == committed
+            || self
+                .decrypted_hash(self.get_identities()?, committed.clone())?
+                .0
+                == Some(hash);
+        if matches {
+            self.ctx.store_sidecar(file, "age", &committed)?;
+            self.ctx.store_sidecar(file, "hash", hash.as_bytes())?;
+        }
+        Ok(matches)
+    }
 
-        let result = self.get_content(contents, hash, file, saved)?;
-        Ok(io::stdout().write_all(&result)?)
+    /// Looks up and checks the recipients of a file about to be encrypted.
+    ///
+    /// `prefix` holds at least the first [`PREFIX_LEN`] bytes of the plaintext.
+    pub(super) fn prepare_encryption(
+        &self,
+        file: &Path,
+        size: u64,
+        prefix: &[u8],
+        check_decryptable: bool,
+    ) -> Result<(Vec<String>, RuleOptions)> {
+        let rule = self.ctx.config()?.get_rule(file)?;
+        let public_keys = self.ctx.recipients().resolve_rule(&rule)?;
+        if check_decryptable || self.ctx.settings().check_decryptable()? {
+            self.ensure_decryptable(file, &public_keys)?;
+        }
+        self.check_size(file, size)?;
+        self.check_double_encryption(file, prefix)?;
+        self.check_binary_type(file, size, prefix)?;
+        self.check_lfs_candidate(file, size, prefix)?;
+        Ok((public_keys, rule.options))
     }
 
-    fn get_content(
+    /// Writes the recipients a file would be encrypted to in recipients file format
+    pub(crate) fn write_recipients(
         &self,
-        contents: Vec<u8>,
-        hash: Hash,
-        file: PathBuf,
-        saved_content: Option<Vec<u8>>,
-    ) -> Result<Vec<u8>> {
-        if let Some(saved_content) = saved_content {
-            log::debug!("File didn't change since last encryption, loading from git HEAD");
-            return Ok(saved_content);
+        file: impl AsRef<Path>,
+        output: impl AsRef<Path>,
+    ) -> Result<()> {
+        let path = self.ctx.repo().workdir().join(&file);
+        let rule = self.ctx.config()?.get_rule(&path)?;
+        let public_keys = self.ctx.recipients().resolve_rule(&rule)?;
+
+        let mut listing = format!("# Recipients of {}\n", file.as_ref().display());
+        if let Some(k) = rule.options.threshold {
+            listing.push_str(&format!(
+                "# Any {} of them are required for decryption\n",
+                k
+            ));
+        }
+        for key in public_keys {
+            listing.push_str(&key);
+            listing.push('\n');
         }
 
-        log::debug!("Encrypted content changed, checking decrypted version");
-        let repo_contents = match self.ctx.repo().get_file_contents(&file) {
-            Ok(v) => Some(v),
-            Err(GitError::NotExist(s)) => {
-                log::debug!("{}", s);
-                None
+        let output = output.as_ref();
+        if output == Path::new("-") {
+            io::stderr().write_all(listing.as_bytes())?;
+        } else {
+            std::fs::write(output, listing)
+                .with_context(|| format!("Couldn't write recipients to {:?}", output))?;
+        }
+        Ok(())
+    }
+
+    fn decrypt_tracked(
+        &self,
+        identities: Vec<String>,
+        encrypted: Vec<u8>,
+    ) -> Result<Option<(SecretBuf, String)>> {
+        let timeout = self.decryption_timeout(&identities)?;
+        age::with_timeout(timeout, move || decrypt_any(&identities, &encrypted))
+    }
+
+    /// Decrypts into a hash of the plaintext instead of memory, handing back the ciphertext
+    fn decrypted_hash(
+        &self,
+        identities: Vec<String>,
+        encrypted: Vec<u8>,
+    ) -> Result<(Option<Hash>, Vec<u8>)> {
+        let timeout = self.decryption_timeout(&identities)?;
+        age::with_timeout(timeout, move || {
+            if threshold::is_threshold(&encrypted) || values::is_encrypted(&encrypted) {
+                let decrypted = if threshold::is_threshold(&encrypted) {
+                    threshold::decrypt(&identities, &encrypted)?
+                } else {
+                    values::decrypt(&identities, &encrypted)?
+                };
+                let hash = match decrypted {
+                    Some((plaintext, _)) => Some(blake3::hash(&compress::decompress(plaintext)?)),
+                    None => None,
+                };
+                return Ok((hash, encrypted));
             }
-            Err(e) => return Err(e.into()),
+            let mut output = DecompressWriter::new(blake3::Hasher::new());
+            let decrypted = age::decrypt_to(&identities, &mut &encrypted[..], &mut output)?;
+            let hasher = output.finish()?;
+            Ok((decrypted.map(|_| hasher.finalize()), encrypted))
+        })
+    }
+
+    pub(super) fn decryption_timeout(&self, identities: &[String]) -> Result<Option<Duration>> {
+        if age::identities_use_plugins(identities) {
+            Ok(self.ctx.settings().plugin_timeout()?)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Decrypts files handed out to the user, recording the access in the audit log
+    pub(super) fn decrypt_audited(
+        &self,
+        operation: &str,
+        file: &Path,
+        identities: Vec<String>,
+        encrypted: Vec<u8>,
+    ) -> Result<Option<SecretBuf>> {
+        let rv = self.decrypt_tracked(identities, encrypted);
+        self.audit_decryption(operation, file, &rv);
+        Ok(rv?.map(|(plaintext, _)| plaintext))
+    }
+
+    /// Records the result of decrypting a file handed out to the user in the audit log
+    fn audit_decryption(
+        &self,
+        operation: &str,
+        file: &Path,
+        decrypted: &Result<Option<(SecretBuf, String)>>,
+    ) {
+        let outcome = match decrypted {
+            Ok(Some((_, identity))) => Some(Outcome::Success { identity }),
+            Ok(None) => None,
+            Err(_) => Some(Outcome::Failure),
         };
+        self.audit(operation, file, outcome);
+    }
 
-        if let Some(repo_contents) = repo_contents {
-            let identities = self.get_identities()?;
-            let mut cur = io::Cursor::new(repo_contents);
-            let decrypted = age::decrypt(&identities, &mut cur)?.unwrap_or_default();
-            if decrypted == contents {
-                log::debug!("Decrypted content matches, using from working copy");
-                self.ctx.store_sidecar(&file, "hash", hash.as_bytes())?;
-                self.ctx.store_sidecar(&file, "age", cur.get_ref())?;
-                return Ok(cur.into_inner());
+    /// Appends to the audit log if one is configured, `None` if the file wasn't encrypted
+    pub(super) fn audit(&self, operation: &str, file: &Path, outcome: Option<Outcome>) {
+        if let Some(log) = self.ctx.settings().audit_log().unwrap_or_else(|err| {
+            log::warn!("Couldn't determine audit log location; error={:?}", err);
+            None
+        }) {
+            if let Some(outcome) = outcome {
+                audit::record(&log, operation, file, outcome);
             }
         }
+    }
 
-        log::debug!("File changed since last encryption, re-encrypting");
+    /// Encrypts a file according to the rule's `options`.
+    ///
+    /// In values mode, the data key and the ciphertext of unchanged values are taken over from
+    /// the last version of the file if `reuse` is set and its recipients are still the same.
+    pub(super) fn encrypt(
+        &self,
+        file: &Path,
+        public_keys: Vec<String>,
+        options: &RuleOptions,
+        contents: SecretBuf,
+        reuse: bool,
+    ) -> Result<Vec<u8>> {
+        let options = self.resolve_options(options)?;
+        let previous = match values::Format::of(file) {
+            Some(format) if reuse && options.mode == Some(Mode::Values) => {
+                self.previous_values(file, format)?
+            }
+            _ => None,
+        };
+        let timeout = self.encryption_timeout(&public_keys)?;
+        let file = file.to_path_buf();
+        age::with_timeout(timeout, move || {
+            encrypt_contents(&file, &public_keys, &options, previous, contents)
+        })
+    }
 
-        let cfg = self.ctx.config()?;
-        let public_keys = cfg.get_public_keys(&file)?;
+    /// Fills the options which default to a setting with the value of the setting
+    pub(super) fn resolve_options(&self, options: &RuleOptions) -> Result<RuleOptions> {
+        let mut options = options.clone();
+        let settings = self.ctx.settings();
+        options.armor = Some(match options.armor {
+            Some(armor) => armor,
+            None => settings.armor()?,
+        });
+        options.deterministic = Some(match options.deterministic {
+            Some(deterministic) => deterministic,
+            None => settings.deterministic()?,
+        });
+        Ok(options)
+    }
 
-        let res = age::encrypt(public_keys, &mut &contents[..])?;
-        self.ctx.store_sidecar(&file, "hash", hash.as_bytes())?;
-        self.ctx.store_sidecar(&file, "age", &res)?;
-        Ok(res)
+    pub(super) fn encryption_timeout(&self, public_keys: &[String]) -> Result<Option<Duration>> {
+        if age::recipients_use_plugins(public_keys) {
+            Ok(self.ctx.settings().plugin_timeout()?)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// How many files to encrypt or decrypt at the same time, `None` for one per CPU core.
+    ///
+    /// Plugins are only used one at a time, as they may need the user to interact with a device.
+    pub(super) fn jobs(&self, uses_plugins: bool) -> Result<Option<usize>> {
+        if uses_plugins {
+            return Ok(Some(1));
+        }
+        Ok(self.ctx.settings().jobs()?)
+    }
+
+    /// The last version of a file encrypted in values mode, see [`values::Previous`]
+    fn previous_values(
+        &self,
+        file: &Path,
+        format: values::Format,
+    ) -> Result<Option<values::Previous>> {
+        let last = match self.ctx.load_sidecar(file, "age")? {
+            Some(contents) => Some(contents),
+            None => match self.ctx.repo().get_file_contents(file) {
+                Ok(contents) => Some(contents),
+                Err(GitError::NotExist(_)) => None,
+                Err(e) => return Err(e.into()),
+            },
+        };
+        let Some(last) = last.filter(|c| values::is_encrypted(c)) else {
+            return Ok(None);
+        };
+        let identities = self.get_identities()?;
+        let timeout = self.decryption_timeout(&identities)?;
+        let previous = age::with_timeout(timeout, move || {
+            values::previous(format, &identities, &last)
+        });
+        // Encrypting everything again is safe, just noisy
+        Ok(previous.unwrap_or_else(|err| {
+            log::debug!("Couldn't decrypt the last version; file={file:?}, error={err:?}");
+            None
+        }))
+    }
+
+    /// Encrypts `input` as a stream into `output` according to the rule's `options`
+    fn encrypt_to<R, W>(
+        &self,
+        public_keys: Vec<String>,
+        options: &RuleOptions,
+        input: R,
+        output: W,
+    ) -> Result<W>
+    where
+        R: Read + Send + 'static,
+        W: Write + Send + 'static,
+    {
+        let options = self.resolve_options(options)?;
+        let timeout = self.encryption_timeout(&public_keys)?;
+        age::with_timeout(timeout, move || {
+            let mut input = input;
+            encrypt_stream(&public_keys, &options, &mut input, output)
+        })
+    }
+
+    pub(super) fn ensure_decryptable(&self, file: &Path, public_keys: &[String]) -> Result<()> {
+        let identities = self.get_identities()?;
+        let own_keys = age::identity_recipients(&identities)?;
+        log::debug!(
+            "Checking that the file stays decryptable; own_keys={:?}",
+            own_keys
+        );
+        let decryptable = public_keys
+            .iter()
+            .filter_map(|pk| age::normalize_recipient(pk).ok())
+            .any(|pk| own_keys.contains(&pk));
+        if !decryptable {
+            bail!(
+                "None of the configured identities is a recipient of '{}', refusing to encrypt",
+                file.display()
+            );
+        }
+        Ok(())
+    }
+
+    /// Catches build artifacts that were matched by a too broad `.gitattributes` pattern
+    fn check_binary_type(&self, file: &Path, size: u64, prefix: &[u8]) -> Result<()> {
+        let settings = self.ctx.settings();
+        if size < settings.binary_check_size()? {
+            return Ok(());
+        }
+        let file_type = match magic::detect(prefix) {
+            Some(t) => t,
+            None => return Ok(()),
+        };
+        if settings.reject_binary_types()?.contains(&file_type) {
+            log::error!(
+                "Refusing to encrypt binary file; file={:?}, type={}",
+                file,
+                file_type
+            );
+            bail!(
+                "'{}' is a binary file of type '{}' which is configured to be rejected, refusing to encrypt",
+                file.display(),
+                file_type
+            );
+        }
+        // Printed rather than logged, so it's seen without enabling logging
+        eprintln!(
+            "Warning: '{}' is a binary file of type '{}', it's probably not meant to be tracked as a secret",
+            file.display(),
+            file_type
+        );
+        Ok(())
+    }
+
+    fn check_size(&self, file: &Path, size: u64) -> Result<()> {
+        let settings = self.ctx.settings();
+        let max_size = settings.max_size()?;
+        if max_size == 0 || size <= max_size {
+            return Ok(());
+        }
+        guard(
+            settings.on_max_size()?,
+            file,
+            &format!("is larger than the maximum of {} bytes", max_size),
+        )
+    }
+
+    fn check_double_encryption(&self, file: &Path, prefix: &[u8]) -> Result<()> {
+        if !looks_encrypted(prefix) || self.leave_encrypted(file)? {
+            return Ok(());
+        }
+        guard(
+            self.ctx.settings().on_double_encryption()?,
+            file,
+            "is encrypted already",
+        )
     }
 
-    fn get_identities(&self) -> Result<Vec<String>> {
+    fn check_lfs_candidate(&self, file: &Path, size: u64, prefix: &[u8]) -> Result<()> {
+        let settings = self.ctx.settings();
+        let lfs_size = settings.lfs_size()?;
+        if lfs_size == 0 || size < lfs_size || !magic::is_binary(prefix) {
+            return Ok(());
+        }
+        guard(
+            settings.on_lfs_candidate()?,
+            file,
+            &format!(
+                "is a binary file of {} bytes, it's probably meant for git-lfs",
+                size
+            ),
+        )
+    }
+
+    /// Turns a path given on the command line into one relative to the repository root
+    pub(super) fn repo_path(&self, path: &Path) -> Result<PathBuf> {
+        let path = normalize_path(&env::current_dir()?.join(path));
+        path.strip_prefix(self.ctx.repo().workdir())
+            .map(Path::to_path_buf)
+            .with_context(|| format!("Path {:?} is outside of the repository", path))
+    }
+
+    pub(super) fn get_identities(&self) -> Result<Vec<String>> {
         log::debug!("Loading identities from config");
-        let all_identities = self.ctx.repo().list_config("identity")?;
+        let mut all_identities = age::in_memory_identities();
+        all_identities.extend(
+            self.ctx
+                .age_identities()
+                .list()?
+                .into_iter()
+                .map(|i| i.path),
+        );
         log::debug!(
             "Loaded identities from config; identities='{:?}'",
             all_identities
@@ -103,51 +768,810 @@ impl<C: Context> CommandContext<C> {
         Ok(all_identities)
     }
 
-    pub(crate) fn smudge(&self, file: impl AsRef<Path>) -> Result<()> {
+    /// The identities to decrypt `file` with, those the `identityHint` of its rule names first,
+    /// so that age doesn't try e.g. a hardware key prompting for a PIN before the right one
+    pub(super) fn identities_for(&self, file: &Path) -> Result<Vec<String>> {
+        let identities = self.get_identities()?;
+        // Decryption doesn't depend on the rules otherwise, broken ones shouldn't stop it
+        let hint = match self.ctx.config().and_then(|cfg| Ok(cfg.lookup(file)?)) {
+            Ok(rule) => rule.and_then(|(_, rule)| rule.options.identity_hint),
+            Err(err) => {
+                log::debug!("Couldn't look up identity hint; file={file:?}, error={err:#}");
+                None
+            }
+        };
+        let Some(hint) = hint else {
+            return Ok(identities);
+        };
+        let mut rv = self.ctx.settings().identity_hint(&hint)?;
+        if rv.is_empty() {
+            log::warn!("No identity configured for the identity hint; hint={hint:?}");
+        }
+        for identity in identities {
+            if !rv.contains(&identity) {
+                rv.push(identity);
+            }
+        }
+        log::debug!("Identities in order of the hint; hint={hint:?}, identities={rv:?}");
+        Ok(rv)
+    }
+
+    pub(crate) fn smudge(&self, file: impl AsRef<Path>, dump_header: bool) -> Result<()> {
+        let mut stdin = io::stdin();
+        let prefix = stream::read_prefix(&mut stdin, PREFIX_LEN)?;
+        if !looks_encrypted(&prefix) && self.is_scoped(file.as_ref())? {
+            // Committed where the rule doesn't apply
+            log::info!("File is stored in plaintext, checking it out as is");
+            io::stdout().write_all(&prefix)?;
+            io::copy(&mut stdin, &mut io::stdout())?;
+            return Ok(());
+        }
+        // Falling back to the ciphertext needs it after decryption failed
+        let fallback = self.ctx.settings().on_missing_identity()? != MissingIdentity::Fail;
+        if dump_header
+            || fallback
+            || threshold::is_threshold(&prefix)
+            || values::is_encrypted(&prefix)
+        {
+            // These need the whole input at once
+            let mut encrypted = prefix;
+            stdin.read_to_end(&mut encrypted)?;
+            let result = self.smudge_contents(file.as_ref(), encrypted, dump_header)?;
+            return Ok(io::stdout().write_all(&result)?);
+        }
+
         log::info!("Decrypting file");
         let file = self.ctx.repo().workdir().join(file);
+        let input = io::Cursor::new(prefix).chain(stdin);
+        // The ciphertext sidecar is written while reading the input, the hash once it is complete
+        self.ctx.remove_sidecar(&file, "hash")?;
+        let mut sidecar = self.ctx.create_sidecar(&file, "age")?;
 
-        let mut encrypted = vec![];
-        io::stdin().read_to_end(&mut encrypted)?;
-        let mut cur = io::Cursor::new(encrypted);
-        let all_identities = self.get_identities()?;
-        if let Some(rv) = age::decrypt(&all_identities, &mut cur)? {
-            log::info!("Decrypted file");
+        if self.leave_encrypted(&file)? {
             let mut hasher = blake3::Hasher::new();
-            let hash = hasher.update(&rv).finalize();
+            let mut input = TeeReader::new(input, TeeWriter::new(&mut sidecar, &mut hasher));
+            io::copy(&mut input, &mut io::stdout())?;
+            input.finish()?;
+            self.ctx.finish_sidecar(sidecar)?;
+            let hash = hasher.finalize();
+            self.ctx.store_sidecar(&file, "hash", hash.as_bytes())?;
+            return Ok(());
+        }
 
+        let identities = self.identities_for(&file)?;
+        let timeout = self.decryption_timeout(&identities)?;
+        let rv = age::with_timeout(timeout, move || {
+            let mut input = TeeReader::new(input, sidecar);
+            let mut output =
+                DecompressWriter::new(TeeWriter::new(io::stdout(), blake3::Hasher::new()));
+            let Some(identity) = age::decrypt_to(&identities, &mut input, &mut output)? else {
+                return Ok(None);
+            };
+            let sidecar = input.finish()?;
+            let output = output.finish()?;
+            Ok(Some((identity, output.into_inner().1.finalize(), sidecar)))
+        });
+        let outcome = match &rv {
+            Ok(Some((identity, ..))) => Some(Outcome::Success { identity }),
+            Ok(None) => None,
+            Err(_) => Some(Outcome::Failure),
+        };
+        self.audit("smudge", &file, outcome);
+
+        if let Some((_, hash, sidecar)) = rv? {
+            log::info!("Decrypted file");
+            self.ctx.finish_sidecar(sidecar)?;
             log::debug!("Storing hash for file; hash={:?}", hash.to_hex().as_str(),);
             self.ctx.store_sidecar(&file, "hash", hash.as_bytes())?;
-            self.ctx.store_sidecar(&file, "age", cur.get_ref())?;
-
-            Ok(io::stdout().write_all(&rv)?)
+            Ok(())
         } else {
             bail!("Input isn't encrypted")
         }
     }
 
-    pub(crate) fn textconv(&self, path: impl AsRef<Path>) -> Result<()> {
-        log::info!("Decrypting file to show in diff");
+    /// Whether the rule of `file` applies on the branch checked out, pushed to its push remote.
+    /// Files whose rule doesn't apply are kept in plaintext.
+    pub(super) fn rule_applies(&self, file: &Path) -> Result<bool> {
+        self.rule_applies_with(file, |options| options_apply(&self.ctx, options, None))
+    }
+
+    /// Whether the rule of `file` applies according to `applies`, see [`options_apply`]
+    pub(super) fn rule_applies_with(
+        &self,
+        file: &Path,
+        applies: impl Fn(&RuleOptions) -> Result<bool>,
+    ) -> Result<bool> {
+        match self.ctx.config()?.lookup(file)? {
+            Some((_, rule)) => applies(&rule.options),
+            None => Ok(true),
+        }
+    }
 
-        let all_identities: Vec<String> = self
+    /// Whether the rule of `file` is limited to some branches or remotes, so that it may be
+    /// committed in plaintext
+    fn is_scoped(&self, file: &Path) -> Result<bool> {
+        let file = self.ctx.repo().workdir().join(file);
+        Ok(self
             .ctx
-            .age_identities()
-            .list()?
-            .into_iter()
-            .map(|i| i.path)
-            .collect();
-
-        let mut f = File::open(path)?;
-        let result = if let Some(rv) = age::decrypt(&all_identities, &mut f)? {
+            .config()?
+            .lookup(&file)?
+            .is_some_and(|(_, rule)| rule.options.is_scoped()))
+    }
+
+    /// Whether a file is checked out encrypted, as the repository is locked or the file is
+    /// excluded
+    pub(super) fn leave_encrypted(&self, file: &Path) -> Result<bool> {
+        if self.ctx.settings().locked()? {
+            log::info!("Repository is locked, leaving file encrypted; file={file:?}");
+            Ok(true)
+        } else if self.is_smudge_excluded(file)? {
+            log::info!("File is excluded from decryption, leaving it encrypted; file={file:?}");
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    pub(crate) fn smudge_contents(
+        &self,
+        file: &Path,
+        encrypted: Vec<u8>,
+        dump_header: bool,
+    ) -> Result<SecretBuf> {
+        let file = self.ctx.repo().workdir().join(file);
+        match self.prepare_smudge(&file, encrypted, dump_header)? {
+            Smudge::Done(rv) => Ok(rv),
+            Smudge::Decrypt(job) => self.finish_smudge(&file, job.run()),
+        }
+    }
+
+    /// The part of `smudge` which needs the repository, leaving the decryption to a
+    /// [`SmudgeJob`] for files which have to be decrypted
+    fn prepare_smudge(&self, file: &Path, encrypted: Vec<u8>, dump_header: bool) -> Result<Smudge> {
+        log::info!("Decrypting file");
+
+        if !looks_encrypted(&encrypted) && self.is_scoped(file)? {
+            log::info!("File is stored in plaintext, checking it out as is; file={file:?}");
+            return Ok(Smudge::Done(encrypted.into()));
+        }
+        if dump_header {
+            dump_age_header(file, &encrypted[..])?;
+        }
+        if self.leave_encrypted(file)? {
+            // Makes `clean` return the ciphertext as is while the working copy is unchanged
+            let hash = blake3::hash(&encrypted);
+            self.ctx.store_sidecar(file, "hash", hash.as_bytes())?;
+            self.ctx.store_sidecar(file, "age", &encrypted)?;
+            return Ok(Smudge::Done(encrypted.into()));
+        }
+        let identities = self.identities_for(file)?;
+        let timeout = self.decryption_timeout(&identities)?;
+        Ok(Smudge::Decrypt(SmudgeJob {
+            identities,
+            encrypted,
+            timeout,
+        }))
+    }
+
+    /// Stores the sidecars of a file decrypted by a [`SmudgeJob`], or checks it out according
+    /// to `onMissingIdentity` if it couldn't be decrypted
+    fn finish_smudge(&self, file: &Path, decrypted: SmudgeResult) -> Result<SecretBuf> {
+        let SmudgeResult {
+            encrypted,
+            decrypted,
+        } = decrypted;
+        self.audit_decryption("smudge", file, &decrypted);
+        match decrypted {
+            Ok(Some((rv, _))) => {
+                log::info!("Decrypted file");
+                let mut hasher = blake3::Hasher::new();
+                let hash = hasher.update(&rv).finalize();
+
+                log::debug!("Storing hash for file; hash={:?}", hash.to_hex().as_str(),);
+                self.ctx.store_sidecar(file, "hash", hash.as_bytes())?;
+                self.ctx.store_sidecar(file, "age", &encrypted)?;
+
+                Ok(rv)
+            }
+            Ok(None) => bail!("Input isn't encrypted"),
+            Err(err) => self.smudge_undecryptable(file, encrypted, err),
+        }
+    }
+
+    /// Checks out a file none of the identities can decrypt according to the
+    /// `onMissingIdentity` setting, so clones without access to the secrets still work
+    fn smudge_undecryptable(
+        &self,
+        file: &Path,
+        encrypted: Vec<u8>,
+        err: anyhow::Error,
+    ) -> Result<SecretBuf> {
+        let (checkout, state) = match self.ctx.settings().on_missing_identity()? {
+            MissingIdentity::Fail => return Err(err),
+            MissingIdentity::Passthrough => (encrypted.clone(), "encrypted"),
+            MissingIdentity::Empty => (vec![], "empty"),
+        };
+        eprintln!(
+            "Warning: couldn't decrypt '{}', checking it out {}: {:#}",
+            file.display(),
+            state,
+            err
+        );
+        // Makes `clean` return the ciphertext as is while the working copy is unchanged
+        let hash = blake3::hash(&checkout);
+        self.ctx.store_sidecar(file, "hash", hash.as_bytes())?;
+        self.ctx.store_sidecar(file, "age", &encrypted)?;
+        Ok(checkout.into())
+    }
+
+    /// Serves git's long-running filter process protocol on stdin/stdout.
+    ///
+    /// A single process handles all files of a git command, so the configuration is only
+    /// parsed once instead of for each file. Where git allows it, files are decrypted on worker
+    /// threads while git goes on with the next files, see [`Delayed`].
+    pub(crate) fn process(&self) -> Result<()> {
+        let mut reader = pktline::Reader::new(io::stdin().lock());
+        let mut writer = pktline::Writer::new(io::stdout().lock());
+
+        let welcome = reader.read_lines()?;
+        if welcome.first().map(String::as_str) != Some("git-filter-client")
+            || !welcome.iter().any(|l| l == "version=2")
+        {
+            bail!("Unsupported filter protocol; handshake={:?}", welcome);
+        }
+        writer.write_line("git-filter-server")?;
+        writer.write_line("version=2")?;
+        writer.flush()?;
+
+        let capabilities = reader.read_lines()?;
+        for capability in ["capability=clean", "capability=smudge", "capability=delay"] {
+            if capabilities.iter().any(|c| c == capability) {
+                writer.write_line(capability)?;
+            }
+        }
+        writer.flush()?;
+
+        let mut delayed = Delayed::new();
+        loop {
+            let headers = match reader.read_lines() {
+                Ok(headers) => headers,
+                Err(err) if is_eof(&err) => break,
+                Err(err) => return Err(err),
+            };
+            let header = |name: &str| {
+                headers
+                    .iter()
+                    .find_map(|h| h.strip_prefix(name)?.strip_prefix('='))
+            };
+            if header("command") == Some("list_available_blobs") {
+                for (pathname, decrypted) in delayed.wait() {
+                    let file = self.ctx.repo().workdir().join(&pathname);
+                    let result = self.finish_smudge(&file, decrypted);
+                    writer.write_line(&format!("pathname={}", pathname))?;
+                    delayed.done.insert(pathname, result);
+                }
+                writer.flush()?;
+                writer.write_line("status=success")?;
+                writer.flush()?;
+                continue;
+            }
+            let content = reader.read_content()?;
+            let pathname = header("pathname").context("Filter request is missing pathname")?;
+            let result = match header("command") {
+                Some("clean") => self
+                    .clean_contents(Path::new(pathname), content, false)
+                    .map(SecretBuf::from),
+                Some("smudge") => match delayed.done.remove(pathname) {
+                    Some(result) => result,
+                    None => {
+                        let can_delay = header("can-delay") == Some("1");
+                        match self.smudge_request(
+                            pathname,
+                            content.to_vec(),
+                            can_delay,
+                            &mut delayed,
+                        ) {
+                            Ok(Some(result)) => Ok(result),
+                            Ok(None) => {
+                                writer.write_line("status=delayed")?;
+                                writer.flush()?;
+                                continue;
+                            }
+                            Err(err) => Err(err),
+                        }
+                    }
+                },
+                command => Err(anyhow::anyhow!("Unsupported filter command {:?}", command)),
+            };
+            match result {
+                Ok(result) => {
+                    writer.write_line("status=success")?;
+                    writer.flush()?;
+                    writer.write_content(&result)?;
+                    writer.flush()?;
+                    // Keep the status
+                    writer.flush()?;
+                }
+                Err(err) => {
+                    log::error!("Filtering failed; file={:?}, error={:?}", pathname, err);
+                    writer.write_line("status=error")?;
+                    writer.flush()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Smudges a file for the filter process, `None` if its decryption was handed to a worker
+    /// thread of `delayed`.
+    ///
+    /// Files decrypted with plugins aren't delayed, as plugins may need the user to interact
+    /// with a device.
+    fn smudge_request(
+        &self,
+        pathname: &str,
+        encrypted: Vec<u8>,
+        can_delay: bool,
+        delayed: &mut Delayed,
+    ) -> Result<Option<SecretBuf>> {
+        let file = self.ctx.repo().workdir().join(pathname);
+        let job = match self.prepare_smudge(&file, encrypted, false)? {
+            Smudge::Done(rv) => return Ok(Some(rv)),
+            Smudge::Decrypt(job) => job,
+        };
+        let job = if can_delay && !age::identities_use_plugins(&job.identities) {
+            match delayed.spawn(pathname.to_string(), job, || self.jobs(false))? {
+                Some(job) => job,
+                None => return Ok(None),
+            }
+        } else {
+            job
+        };
+        self.finish_smudge(&file, job.run()).map(Some)
+    }
+
+    fn is_smudge_excluded(&self, file: &Path) -> Result<bool> {
+        let relpath = file.strip_prefix(self.ctx.repo().workdir())?;
+        let options = glob::MatchOptions {
+            require_literal_separator: true,
+            ..Default::default()
+        };
+        for pattern in self.ctx.settings().smudge_exclude()? {
+            let matches = glob::Pattern::new(&pattern)
+                .with_context(|| format!("Invalid smudgeExclude pattern '{}'", pattern))?
+                .matches_path_with(relpath, options);
+            if matches {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    pub(crate) fn textconv(
+        &self,
+        path: impl AsRef<Path>,
+        dump_header: bool,
+        diff_format: DiffFormat,
+    ) -> Result<()> {
+        log::info!("Decrypting file to show in diff");
+
+        let all_identities = self.get_identities()?;
+
+        let mut file = File::open(&path)?;
+        let prefix = stream::read_prefix(&mut file, PREFIX_LEN)?;
+        file.rewind()?;
+        if dump_header {
+            dump_age_header(path.as_ref(), &mut file)?;
+            file.rewind()?;
+        }
+        if diff_format == DiffFormat::Plain && !looks_encrypted(&prefix) {
+            log::info!("File isn't encrypted, probably a working copy; showing as is.");
+            // Lets the kernel copy the file, with `splice` or `sendfile` on Linux
+            io::copy(&mut file, &mut io::stdout().lock())?;
+            return Ok(());
+        }
+        let contents = self.textconv_input(path.as_ref(), &file)?;
+        let cache = self.textconv_cache()?;
+        // Files the cache can't hold are decrypted straight to stdout instead of into memory
+        if diff_format == DiffFormat::Plain
+            && age::has_age_prefix(&prefix)
+            && !cache
+                .as_ref()
+                .is_some_and(|c| c.fits(contents.len() as u64))
+        {
+            return self.textconv_streaming(path.as_ref(), all_identities, contents);
+        }
+        let contents = contents.to_vec();
+        let id = git::blob_id(&contents)?;
+        if let Some(rv) = cache.as_ref().and_then(|c| c.get(&id)) {
+            log::info!("Showing decrypted file from cache; blob={}", id);
+            return Ok(io::stdout().write_all(&diff_view(
+                path.as_ref(),
+                rv.into(),
+                diff_format,
+            )?)?);
+        }
+        // The output only depends on the blob, so git can cache it with `cachetextconv`; on
+        // errors nothing is written, so failures are never cached
+        let result = if let Some(rv) =
+            self.decrypt_audited("textconv", path.as_ref(), all_identities, contents.clone())?
+        {
             log::info!("Decrypted file to show in diff");
+            if let Some(cache) = cache {
+                if let Err(err) = cache.put(&id, &rv) {
+                    log::warn!(
+                        "Couldn't cache decrypted file; blob={}, error={:?}",
+                        id,
+                        err
+                    );
+                }
+            }
             rv
         } else {
             log::info!("File isn't encrypted, probably a working copy; showing as is.");
-            f.rewind()?;
-            let mut buff = vec![];
-            f.read_to_end(&mut buff)?;
-            buff
+            contents.into()
+        };
+        Ok(io::stdout().write_all(&diff_view(path.as_ref(), result, diff_format)?)?)
+    }
+
+    /// The contents of `file` at `path`, mapped into memory if it is a temporary file git
+    /// created for `textconv`.
+    ///
+    /// Git also hands over files of the working tree, which are read into memory instead, as
+    /// one truncated while mapped would kill the process with `SIGBUS`.
+    fn textconv_input(&self, path: &Path, file: &File) -> Result<Mapped> {
+        let workdir = std::fs::canonicalize(self.ctx.repo().workdir());
+        let temporary = match (std::fs::canonicalize(path), workdir) {
+            (Ok(path), Ok(workdir)) => !path.starts_with(workdir),
+            _ => false,
+        };
+        if !temporary {
+            return Ok(Mapped::read(file)?);
+        }
+        // SAFETY: git writes the temporary file before running the command and removes it
+        // after it exited, nothing changes it in between
+        Ok(unsafe { Mapped::new(file)? })
+    }
+
+    /// Decrypts `encrypted` to stdout as it is read, for files too large to be cached.
+    ///
+    /// Unlike the other ways, part of the output may be written before decryption fails. Git
+    /// doesn't cache the output of a textconv command which failed, so it is never taken for
+    /// the plaintext.
+    fn textconv_streaming(
+        &self,
+        path: &Path,
+        identities: Vec<String>,
+        encrypted: Mapped,
+    ) -> Result<()> {
+        let timeout = self.decryption_timeout(&identities)?;
+        let rv = age::with_timeout(timeout, move || {
+            let mut output = DecompressWriter::new(io::stdout().lock());
+            let identity = age::decrypt_to(&identities, &mut &encrypted[..], &mut output)?;
+            output.finish()?.flush()?;
+            Ok((identity, encrypted))
+        });
+        let outcome = match &rv {
+            Ok((Some(identity), _)) => Some(Outcome::Success { identity }),
+            Ok((None, _)) => None,
+            Err(_) => Some(Outcome::Failure),
+        };
+        self.audit("textconv", path, outcome);
+
+        match rv? {
+            (Some(_), _) => log::info!("Decrypted file to show in diff"),
+            (None, encrypted) => {
+                log::info!("File isn't encrypted, probably a working copy; showing as is.");
+                io::stdout().write_all(&encrypted)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The cache of decrypted files for `textconv`, `None` when every decryption has to be
+    /// recorded in the audit log
+    fn textconv_cache(&self) -> Result<Option<BlobCache>> {
+        if self.ctx.settings().audit_log()?.is_some() {
+            return Ok(None);
+        }
+        let size = self.ctx.settings().textconv_cache_size()?;
+        Ok(Some(self.ctx.textconv_cache(size)))
+    }
+
+    /// Merges three versions of an encrypted file as a git merge driver.
+    ///
+    /// The versions are decrypted, merged as text and the result encrypted again. It is written
+    /// even if there are conflicts, so that the conflict markers show up in the working copy.
+    /// Returns whether the merge was free of conflicts.
+    pub(crate) fn merge(
+        &self,
+        ancestor: &Path,
+        ours: &Path,
+        theirs: &Path,
+        output: &Path,
+        marker_size: Option<usize>,
+        path: &Path,
+    ) -> Result<bool> {
+        log::info!("Merging file; path={path:?}");
+        let file = self.ctx.repo().workdir().join(path);
+        let identities = self.identities_for(&file)?;
+
+        let mut encrypted = vec![];
+        let mut decrypted = vec![];
+        for (name, version) in [("ancestor", ancestor), ("ours", ours), ("theirs", theirs)] {
+            let contents = std::fs::read(version)
+                .with_context(|| format!("Couldn't read {} version from {:?}", name, version))?;
+            let plaintext = self
+                .decrypt_audited("merge", &file, identities.clone(), contents.clone())
+                .with_context(|| {
+                    format!("Couldn't decrypt {} version of '{}'", name, path.display())
+                })?
+                // Not encrypted, e.g. committed before the rule was added
+                .unwrap_or_else(|| contents.clone().into());
+            encrypted.push(contents);
+            decrypted.push(plaintext);
+        }
+
+        let (merged, clean) = self.ctx.repo().merge_file(
+            &decrypted[0],
+            &decrypted[1],
+            &decrypted[2],
+            ["ours", "ancestor", "theirs"],
+            marker_size,
+        )?;
+
+        let result = if let Some(i) = (1..=2).find(|i| merged[..] == decrypted[*i][..]) {
+            log::debug!("Merge result matches one side, keeping its ciphertext");
+            encrypted.swap_remove(i)
+        } else {
+            let rule = self.ctx.config()?.get_rule(&file)?;
+            let public_keys = self.ctx.recipients().resolve_rule(&rule)?;
+            if self.ctx.settings().check_decryptable()? {
+                self.ensure_decryptable(&file, &public_keys)?;
+            }
+            self.encrypt(&file, public_keys, &rule.options, merged.into(), true)?
+        };
+        std::fs::write(output, result)
+            .with_context(|| format!("Couldn't write merge result to {:?}", output))?;
+        Ok(clean)
+    }
+}
+
+/// Decrypts any of the formats written by `clean`, returning the plaintext and the identity
+/// which could decrypt it
+pub(super) fn decrypt_any(
+    identities: &[String],
+    encrypted: &[u8],
+) -> Result<Option<(SecretBuf, String)>> {
+    let decrypted = if threshold::is_threshold(encrypted) {
+        threshold::decrypt(identities, encrypted)?
+    } else if values::is_encrypted(encrypted) {
+        values::decrypt(identities, encrypted)?
+    } else {
+        age::decrypt(identities, &mut &encrypted[..])?
+    };
+    match decrypted {
+        Some((plaintext, identity)) => Ok(Some((compress::decompress(plaintext)?, identity))),
+        None => Ok(None),
+    }
+}
+
+/// What `smudge` checks out, once the repository has been consulted
+enum Smudge {
+    /// The file is checked out as it is
+    Done(SecretBuf),
+    Decrypt(SmudgeJob),
+}
+
+/// Decryption of a file being checked out, which doesn't need the repository and can run on a
+/// worker thread
+struct SmudgeJob {
+    identities: Vec<String>,
+    encrypted: Vec<u8>,
+    timeout: Option<Duration>,
+}
+
+impl SmudgeJob {
+    fn run(self) -> SmudgeResult {
+        let Self {
+            identities,
+            encrypted,
+            timeout,
+        } = self;
+        let ciphertext = encrypted.clone();
+        let decrypted = age::with_timeout(timeout, move || decrypt_any(&identities, &ciphertext));
+        SmudgeResult {
+            encrypted,
+            decrypted,
+        }
+    }
+}
+
+struct SmudgeResult {
+    encrypted: Vec<u8>,
+    decrypted: Result<Option<(SecretBuf, String)>>,
+}
+
+/// Files git allowed the filter process to delay, see "Delay" in gitattributes(5).
+///
+/// They are decrypted on a pool of worker threads, while git sends the next files. Git then
+/// asks which files are available, and requests them again to get their contents.
+struct Delayed {
+    pool: Option<rayon::ThreadPool>,
+    sender: mpsc::Sender<(String, SmudgeResult)>,
+    receiver: mpsc::Receiver<(String, SmudgeResult)>,
+    /// How many files are being decrypted
+    pending: usize,
+    /// Files git was told are available, by their pathname
+    done: HashMap<String, Result<SecretBuf>>,
+}
+
+impl Delayed {
+    fn new() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        Self {
+            pool: None,
+            sender,
+            receiver,
+            pending: 0,
+            done: HashMap::new(),
+        }
+    }
+
+    /// Decrypts a file on a worker thread, handing back the job if no worker threads could be
+    /// started. `jobs` is the size of the pool, which is started with the first file.
+    fn spawn(
+        &mut self,
+        pathname: String,
+        job: SmudgeJob,
+        jobs: impl FnOnce() -> Result<Option<usize>>,
+    ) -> Result<Option<SmudgeJob>> {
+        if self.pool.is_none() {
+            self.pool = age::thread_pool(jobs()?);
+        }
+        let Some(pool) = &self.pool else {
+            return Ok(Some(job));
         };
-        Ok(io::stdout().write_all(&result)?)
+        let sender = self.sender.clone();
+        pool.spawn(move || {
+            // The receiver lives as long as the filter process
+            let _ = sender.send((pathname, job.run()));
+        });
+        self.pending += 1;
+        Ok(None)
+    }
+
+    /// Waits until at least one file has been decrypted and returns all which are, nothing if
+    /// no files are pending
+    fn wait(&mut self) -> Vec<(String, SmudgeResult)> {
+        let mut rv = vec![];
+        if self.pending == 0 {
+            return rv;
+        }
+        // Workers always send their result, even if decryption fails
+        rv.extend(self.receiver.recv().ok());
+        rv.extend(self.receiver.try_iter());
+        self.pending -= rv.len();
+        rv
+    }
+}
+
+/// Encrypts a whole file, `options` have to be resolved by
+/// [`CommandContext::resolve_options`]. In values mode, `previous` is the last version of the
+/// file, see [`values::Previous`].
+pub(super) fn encrypt_contents(
+    file: &Path,
+    public_keys: &[String],
+    options: &RuleOptions,
+    previous: Option<values::Previous>,
+    contents: SecretBuf,
+) -> Result<Vec<u8>> {
+    if options.mode != Some(Mode::Values) {
+        return encrypt_stream(public_keys, options, &mut &contents[..], vec![]);
+    }
+    if options.threshold.is_some() {
+        bail!(
+            "Threshold encryption can't be combined with values mode, see the rule of '{}'",
+            file.display()
+        );
+    }
+    if options.compress.is_some() {
+        bail!(
+            "Compression can't be combined with values mode, see the rule of '{}'",
+            file.display()
+        );
+    }
+    let format = values::Format::of(file).with_context(|| {
+        format!(
+            "Values mode requires a YAML, JSON or env file, '{}' is neither",
+            file.display()
+        )
+    })?;
+    values::encrypt(format, &contents, public_keys, previous)
+}
+
+/// Encrypts `input` as a stream into `output`, see [`encrypt_contents`]
+fn encrypt_stream<W: Write>(
+    public_keys: &[String],
+    options: &RuleOptions,
+    input: &mut impl Read,
+    mut output: W,
+) -> Result<W> {
+    let mut compressed;
+    let mut input: &mut dyn Read = match options.compress {
+        Some(compression) => {
+            compressed = compress::compress(compression, input)?;
+            &mut compressed
+        }
+        None => input,
+    };
+    let armor = options.armor.unwrap_or_default();
+    let deterministic = options.deterministic.unwrap_or_default();
+    match options.threshold {
+        Some(_) if deterministic => {
+            bail!("Threshold encryption can't be deterministic, see the `deterministic` option")
+        }
+        Some(k) => output.write_all(&threshold::encrypt(public_keys, k, armor, &mut input)?)?,
+        None if deterministic => {
+            let mut plaintext = SecretBuf::new();
+            io::copy(input, &mut plaintext)?;
+            output.write_all(&deterministic::encrypt(public_keys, armor, &plaintext)?)?
+        }
+        None => age::encrypt_to(public_keys, armor, &mut input, &mut output)?,
+    }
+    output.flush()?;
+    Ok(output)
+}
+
+/// What `textconv` shows of the decrypted `contents` of `path`, see [`values::canonical`].
+///
+/// Masking hides files of other formats entirely, as their values can't be told apart.
+fn diff_view(path: &Path, contents: SecretBuf, diff_format: DiffFormat) -> Result<SecretBuf> {
+    if diff_format == DiffFormat::Plain {
+        return Ok(contents);
+    }
+    let mask = diff_format == DiffFormat::Masked;
+    let text = std::str::from_utf8(&contents).ok();
+    match (values::Format::of(path), text) {
+        (Some(format), Some(text)) => {
+            Ok(values::canonical(format, text, mask)?.into_bytes().into())
+        }
+        _ if mask => Ok(b"****\n".to_vec().into()),
+        _ => Ok(contents),
+    }
+}
+
+/// Whether `prefix`, the first bytes of a file, is in one of the formats written by `clean`
+fn looks_encrypted(prefix: &[u8]) -> bool {
+    age::has_age_prefix(prefix) || threshold::is_threshold(prefix) || values::is_encrypted(prefix)
+}
+
+/// Handles `file` being caught by a guard of `clean`, `problem` says what is wrong with it
+fn guard(action: GuardAction, file: &Path, problem: &str) -> Result<()> {
+    match action {
+        GuardAction::Ignore => Ok(()),
+        GuardAction::Warn => {
+            eprintln!(
+                "Warning: '{}' {problem}, encrypting it anyway",
+                file.display()
+            );
+            Ok(())
+        }
+        GuardAction::Fail => {
+            log::error!("Refusing to encrypt file, it {problem}; file={file:?}");
+            bail!("'{}' {}, refusing to encrypt", file.display(), problem)
+        }
+    }
+}
+
+fn is_eof(err: &anyhow::Error) -> bool {
+    matches!(err.downcast_ref::<io::Error>(), Some(e) if e.kind() == io::ErrorKind::UnexpectedEof)
+}
+
+fn dump_age_header(file: &Path, encrypted: impl Read) -> Result<()> {
+    match age::read_header(encrypted)? {
+        Some(header) => eprint!("{}", header),
+        None => eprintln!("{}: no age header found", file.display()),
     }
+    Ok(())
 }
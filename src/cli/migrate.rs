@@ -0,0 +1,423 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    env, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+};
+
+use anyhow::{bail, Context as _, Result};
+use regex::Regex;
+use serde_yaml::Value;
+
+use crate::{
+    attributes,
+    config::{Mode, Rule, RuleOptions},
+    ctx::Context,
+    git::{Error as GitError, Repository},
+    values,
+};
+
+use super::{args::LegacyTool, internal::CommandContext, public::is_encrypted};
+
+/// Configuration file of sops, naming the files it encrypts and their recipients
+const SOPS_CONFIG: &str = ".sops.yaml";
+
+/// A file encrypted with another tool
+struct LegacyFile {
+    path: PathBuf,
+    /// Filter driver decrypting the file on checkout, `None` for sops
+    driver: Option<String>,
+    recipients: Vec<String>,
+}
+
+/// Path pattern and age recipients of a creation rule in `.sops.yaml`
+struct SopsRule {
+    pattern: Option<Regex>,
+    recipients: Vec<String>,
+}
+
+impl LegacyTool {
+    fn name(self) -> &'static str {
+        match self {
+            Self::GitCrypt => "git-crypt",
+            Self::Transcrypt => "transcrypt",
+            Self::Sops => "sops",
+        }
+    }
+
+    /// Whether a filter driver belongs to the tool. Keys other than the default one of
+    /// git-crypt, and contexts of transcrypt, have drivers named like `git-crypt-<name>`.
+    fn owns_driver(self, driver: &str) -> bool {
+        let base = match self {
+            Self::GitCrypt => "git-crypt",
+            Self::Transcrypt => "crypt",
+            Self::Sops => return false,
+        };
+        driver == base
+            || driver
+                .strip_prefix(base)
+                .is_some_and(|rest| rest.starts_with('-'))
+    }
+}
+
+impl<C: Context> CommandContext<C> {
+    /// Decrypts the files encrypted with another tool and encrypts them with age instead.
+    ///
+    /// Rules for the files are added to the configuration and `.gitattributes` is updated,
+    /// then everything is staged so that the switch can be reviewed and committed at once.
+    pub(crate) fn migrate(&self, from: LegacyTool, recipients: Vec<String>) -> Result<()> {
+        let repo = self.ctx.repo();
+        if let Err(GitError::NotExist(_)) = repo.get_config("filter.git-agecrypt.clean") {
+            bail!("git-agecrypt isn't set up for this repository, run `git-agecrypt init` first");
+        }
+        let files = match from {
+            LegacyTool::Sops => self.sops_files(&recipients)?,
+            _ => self.filtered_files(from, &recipients)?,
+        };
+        if files.is_empty() {
+            bail!("No files encrypted with {} found", from.name());
+        }
+        if let Some(file) = files.iter().find(|f| f.recipients.is_empty()) {
+            bail!(
+                "No recipients for '{}', pass them with --recipient",
+                file.path.display()
+            );
+        }
+        let paths: Vec<PathBuf> = files.iter().map(|f| f.path.clone()).collect();
+        let modified = repo.modified_files(&paths)?;
+        if !modified.is_empty() {
+            let list: Vec<_> = modified.iter().map(|f| f.display().to_string()).collect();
+            bail!(
+                "Refusing to migrate as the following files have uncommitted changes, commit or stash them first: {}",
+                list.join(", ")
+            );
+        }
+
+        // Nothing is changed unless every file can be decrypted
+        let staged: HashMap<PathBuf, String> = repo
+            .index_blobs()?
+            .into_iter()
+            .map(|b| (b.path, b.id))
+            .collect();
+        let mut plaintexts = vec![];
+        for file in &files {
+            let plaintext = self.legacy_decrypt(from, file, &staged).with_context(|| {
+                format!(
+                    "Couldn't decrypt '{}' with {}",
+                    file.path.display(),
+                    from.name()
+                )
+            })?;
+            plaintexts.push(plaintext);
+        }
+
+        let mut cfg = self.ctx.config()?;
+        for file in &files {
+            // sops encrypts the values of structured files too
+            let mode = match from {
+                LegacyTool::Sops if values::Format::of(&file.path).is_some() => Some(Mode::Values),
+                _ => None,
+            };
+            let rule = Rule {
+                recipients: file.recipients.clone(),
+                options: RuleOptions {
+                    mode,
+                    ..Default::default()
+                },
+            };
+            cfg.add_rule(&file.path, rule)?;
+        }
+        for (file, plaintext) in files.iter().zip(&plaintexts) {
+            let path = repo.workdir().join(&file.path);
+            fs::write(&path, plaintext)
+                .with_context(|| format!("Couldn't write '{}'", path.display()))?;
+        }
+        cfg.save()?;
+        let attributes_file = repo.workdir().join(".gitattributes");
+        let drivers: BTreeSet<String> = files.iter().filter_map(|f| f.driver.clone()).collect();
+        attributes::remove_drivers(&attributes_file, &Vec::from_iter(drivers.clone()))?;
+        let tracked = repo.list_files()?;
+        attributes::sync(
+            &attributes_file,
+            &cfg.attribute_patterns(&tracked)?,
+            &cfg.attribute_exclusions(&tracked),
+        )?;
+
+        repo.add_files(
+            &[attributes_file, env::current_dir()?.join(cfg.path())],
+            false,
+        )?;
+        repo.add_files(&paths, true)?;
+        self.ensure_staged_encrypted(&paths)?;
+
+        println!(
+            "Migrated {} files from {}, review the staged changes and commit them:",
+            files.len(),
+            from.name()
+        );
+        for path in &paths {
+            println!("    ✓ {}", path.display());
+        }
+        println!(
+            "Earlier commits keep the files encrypted with {}, so its keys can still decrypt those versions. Rotate the secrets if that is a concern.",
+            from.name()
+        );
+        match from {
+            LegacyTool::Sops => println!("{} is no longer needed.", SOPS_CONFIG),
+            _ => {
+                for driver in drivers {
+                    println!(
+                        "The {} filter can be removed with `git config --remove-section filter.{}`.",
+                        from.name(),
+                        driver
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Tracked files assigned a filter driver of git-crypt or transcrypt
+    fn filtered_files(&self, from: LegacyTool, recipients: &[String]) -> Result<Vec<LegacyFile>> {
+        let repo = self.ctx.repo();
+        let mut rv = vec![];
+        for path in repo.list_files()? {
+            match repo.get_attribute(&path, "filter")? {
+                Some(driver) if from.owns_driver(&driver) => rv.push(LegacyFile {
+                    path,
+                    driver: Some(driver),
+                    recipients: recipients.to_vec(),
+                }),
+                _ => {}
+            }
+        }
+        Ok(rv)
+    }
+
+    /// Tracked files encrypted with sops, with the age recipients of their creation rule
+    fn sops_files(&self, recipients: &[String]) -> Result<Vec<LegacyFile>> {
+        let repo = self.ctx.repo();
+        let rules = match fs::read_to_string(repo.workdir().join(SOPS_CONFIG)) {
+            Ok(contents) => {
+                sops_rules(&contents).with_context(|| format!("Couldn't parse {}", SOPS_CONFIG))?
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e.into()),
+        };
+        let mut rv = vec![];
+        for path in repo.list_files()? {
+            let Ok(contents) = fs::read(repo.workdir().join(&path)) else {
+                continue;
+            };
+            if !is_sops_file(&contents) {
+                continue;
+            }
+            let name = path.to_string_lossy();
+            let mut keys = rules
+                .iter()
+                .find(|r| r.pattern.as_ref().is_none_or(|p| p.is_match(&name)))
+                .map(|r| r.recipients.clone())
+                .unwrap_or_default();
+            for recipient in recipients {
+                if !keys.contains(recipient) {
+                    keys.push(recipient.clone());
+                }
+            }
+            rv.push(LegacyFile {
+                path,
+                driver: None,
+                recipients: keys,
+            });
+        }
+        Ok(rv)
+    }
+
+    /// Decrypts a file with the other tool, using its keys
+    fn legacy_decrypt(
+        &self,
+        from: LegacyTool,
+        file: &LegacyFile,
+        staged: &HashMap<PathBuf, String>,
+    ) -> Result<Vec<u8>> {
+        let repo = self.ctx.repo();
+        let Some(driver) = &file.driver else {
+            // sops files are kept encrypted in the working copy
+            let mut command = process::Command::new("sops");
+            command
+                .current_dir(repo.workdir())
+                .arg("--decrypt")
+                .arg(&file.path);
+            return run(command, None);
+        };
+        let key = format!("filter.{}.smudge", driver);
+        let smudge = match repo.get_config(&key) {
+            Ok(smudge) => smudge,
+            Err(GitError::NotExist(_)) => bail!(
+                "{} isn't configured, unlock the repository with {} first",
+                key,
+                from.name()
+            ),
+            Err(e) => return Err(e.into()),
+        };
+        let id = staged.get(&file.path).context("The file isn't staged")?;
+        let ciphertext = repo.read_blob(id)?;
+        // Run through the shell like git does, which substitutes `%f` with the quoted path
+        let mut command = process::Command::new("sh");
+        command
+            .current_dir(repo.workdir())
+            .arg("-c")
+            .arg(smudge.replace("%f", &shell_quote(&file.path)));
+        run(command, Some(&ciphertext))
+    }
+
+    /// Makes sure no migrated or added file is about to be committed in plaintext
+    pub(super) fn ensure_staged_encrypted(&self, paths: &[PathBuf]) -> Result<()> {
+        let repo = self.ctx.repo();
+        let staged: HashMap<PathBuf, String> = repo
+            .index_blobs()?
+            .into_iter()
+            .map(|b| (b.path, b.id))
+            .collect();
+        let mut plaintext = vec![];
+        for path in paths {
+            let encrypted = match staged.get(path) {
+                Some(id) => is_encrypted(&repo.read_blob(id)?),
+                None => false,
+            };
+            if !encrypted {
+                plaintext.push(path.display().to_string());
+            }
+        }
+        if !plaintext.is_empty() {
+            bail!(
+                "The following files were staged without being encrypted, unstage them with `git restore --staged` and check .gitattributes: {}",
+                plaintext.join(", ")
+            );
+        }
+        Ok(())
+    }
+}
+
+/// The creation rules of `.sops.yaml`, with the age keys of their key groups
+fn sops_rules(contents: &str) -> Result<Vec<SopsRule>> {
+    let config: Value = serde_yaml::from_str(contents)?;
+    let age_keys = |value: Option<&Value>| -> Vec<String> {
+        let keys: Vec<&str> = match value {
+            Some(Value::String(keys)) => keys.split(',').collect(),
+            Some(Value::Sequence(keys)) => keys.iter().filter_map(|k| k.as_str()).collect(),
+            _ => vec![],
+        };
+        keys.into_iter()
+            .map(|k| k.trim().to_string())
+            .filter(|k| !k.is_empty())
+            .collect()
+    };
+    let mut rv = vec![];
+    let rules = config.get("creation_rules").and_then(|r| r.as_sequence());
+    for rule in rules.into_iter().flatten() {
+        let pattern = rule
+            .get("path_regex")
+            .and_then(|p| p.as_str())
+            .map(Regex::new)
+            .transpose()?;
+        let mut recipients = age_keys(rule.get("age"));
+        let groups = rule.get("key_groups").and_then(|g| g.as_sequence());
+        for group in groups.into_iter().flatten() {
+            for key in age_keys(group.get("age")) {
+                if !recipients.contains(&key) {
+                    recipients.push(key);
+                }
+            }
+        }
+        rv.push(SopsRule {
+            pattern,
+            recipients,
+        });
+    }
+    Ok(rv)
+}
+
+/// Whether `contents` hold values encrypted by sops along with its metadata
+fn is_sops_file(contents: &[u8]) -> bool {
+    let contains = |needle: &[u8]| contents.windows(needle.len()).any(|w| w == needle);
+    contains(b"ENC[AES256_GCM,") && contains(b"sops")
+}
+
+fn shell_quote(path: &Path) -> String {
+    format!("'{}'", path.to_string_lossy().replace('\'', "'\\''"))
+}
+
+/// Runs a command of another tool, returning its output
+fn run(mut command: process::Command, input: Option<&[u8]>) -> Result<Vec<u8>> {
+    command
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped());
+    let mut child = command
+        .spawn()
+        .with_context(|| format!("Couldn't run {:?}", command))?;
+    if let Some(input) = input {
+        child.stdin.take().unwrap().write_all(input)?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        bail!(
+            "Command {:?} failed with {}: {}",
+            command,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(output.stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    fn test_sops_rules() -> Result<()> {
+        let rules = sops_rules(
+            r#"
+creation_rules:
+  - path_regex: ^prod/.*\.yaml$
+    key_groups:
+      - age: [age1prod, age1ops]
+      - pgp: [FINGERPRINT]
+  - path_regex: \.env$
+    age: "age1dev, age1ops"
+  - kms: arn:aws:kms:eu-west-1:1:key/1
+"#,
+        )?;
+        assert_eq!(rules.len(), 3);
+        assert!(rules[0].pattern.as_ref().unwrap().is_match("prod/db.yaml"));
+        assert_eq!(rules[0].recipients, ["age1prod", "age1ops"]);
+        assert_eq!(rules[1].recipients, ["age1dev", "age1ops"]);
+        assert!(rules[2].pattern.is_none());
+        assert!(rules[2].recipients.is_empty());
+        Ok(())
+    }
+
+    #[rstest]
+    #[case(LegacyTool::GitCrypt, "git-crypt", true)]
+    #[case(LegacyTool::GitCrypt, "git-crypt-ci", true)]
+    #[case(LegacyTool::GitCrypt, "git-cryptic", false)]
+    #[case(LegacyTool::Transcrypt, "crypt", true)]
+    #[case(LegacyTool::Transcrypt, "crypt-prod", true)]
+    #[case(LegacyTool::Transcrypt, "git-crypt", false)]
+    #[case(LegacyTool::Sops, "sops", false)]
+    fn test_owns_driver(#[case] tool: LegacyTool, #[case] driver: &str, #[case] owned: bool) {
+        assert_eq!(tool.owns_driver(driver), owned);
+    }
+
+    #[rstest]
+    fn test_is_sops_file() {
+        assert!(is_sops_file(
+            b"password: ENC[AES256_GCM,data:abc,type:str]\nsops:\n    version: 3.8.1\n"
+        ));
+        assert!(!is_sops_file(b"password: hunter2\n"));
+    }
+}
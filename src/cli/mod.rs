@@ -1,6 +1,196 @@
+mod add;
+mod agent;
 mod app;
 mod args;
-mod internal;
+mod audit_recipients;
+mod bundle;
+mod deinit;
+mod doctor;
+mod edit;
+mod exit;
+mod generate;
+pub(crate) mod internal;
+mod migrate;
+mod output;
+mod progress;
 mod public;
-pub(crate) use app::run;
-pub(crate) use args::parse_args;
+mod purge_history;
+mod rekey;
+mod show;
+mod validate_config;
+mod verify;
+
+use std::{
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+
+pub(crate) use args::{parse_args, Args};
+
+use crate::{
+    age, ctx,
+    git::{self, Repository},
+};
+
+use args::{Commands, InternalCommands, OutputFormat, PublicCommands};
+use exit::ExitCode;
+
+/// Environment variable holding an identity (key material, not a path), e.g. a deploy key
+/// injected by CI from its secret store
+const IDENTITY_ENV: &str = "GIT_AGECRYPT_IDENTITY";
+
+/// Runs a command in the repository containing the current directory, returning the exit
+/// status of its category of failure, see [`ExitCode`]
+pub(crate) fn run(args: Args) -> std::process::ExitCode {
+    let format = args.format;
+    match run_in_current_dir(args) {
+        Ok(()) => std::process::ExitCode::SUCCESS,
+        // Already reported by the command
+        Err(err) if err.is::<exit::Reported>() => ExitCode::of(&err).into(),
+        Err(err) => {
+            if format == OutputFormat::Json {
+                output::print_error(&err);
+            } else {
+                eprintln!("Error: {:?}", err);
+            }
+            ExitCode::of(&err).into()
+        }
+    }
+}
+
+fn run_in_current_dir(args: Args) -> Result<()> {
+    let recurse = recurse_submodules(&args.command);
+    if recurse && args.format == OutputFormat::Json {
+        bail!("--recurse-submodules can't be combined with --format json");
+    }
+    let config = args
+        .config
+        .as_ref()
+        .map(|p| std::env::current_dir().map(|cwd| cwd.join(p)))
+        .transpose()?;
+    if recurse {
+        let repo = open_repository(&args)?;
+        return run_recursively(args, repo, config, Path::new(""));
+    }
+    // Completions, manpages, the agent and the global set-up run outside of repositories, so
+    // the repository is only opened for the commands asking for it
+    let opened = args.clone();
+    app::run(args, move || {
+        open_repository(&opened).map(|repo| ctx::new(repo, config))
+    })
+}
+
+fn open_repository(args: &Args) -> Result<git::LibGit2Repository> {
+    add_in_memory_identities(args)?;
+    let repo = git::LibGit2Repository::open(args.git_dir.as_deref(), args.work_tree.as_deref())?;
+    if repo.is_bare() && !supports_bare(&args.command) {
+        bail!("This command needs a working tree, run it in a checkout or pass --work-tree");
+    }
+    Ok(repo)
+}
+
+/// Runs the command in `repo` and then in each of its submodules, named relative to the
+/// superproject the command was run in by `prefix`. The submodules use their own rules file.
+///
+/// A failure doesn't stop the command from running in the other repositories, the first one
+/// is returned once it ran everywhere.
+fn run_recursively(
+    args: Args,
+    repo: git::LibGit2Repository,
+    config: Option<PathBuf>,
+    prefix: &Path,
+) -> Result<()> {
+    let workdir = repo.workdir().to_path_buf();
+    let submodules = repo.submodules()?;
+    let mut rv = app::run(args.clone(), || Ok(ctx::new(repo, config)));
+    for dir in submodules {
+        let name = prefix.join(dir.strip_prefix(&workdir).unwrap_or(&dir));
+        println!("Entering '{}'", name.display());
+        let result = git::LibGit2Repository::from_dir(dir)
+            .map_err(anyhow::Error::from)
+            .and_then(|repo| run_recursively(args.clone(), repo, None, &name))
+            .with_context(|| format!("Failed in submodule '{}'", name.display()));
+        match (&rv, result) {
+            (_, Ok(())) => {}
+            (Ok(()), Err(err)) => rv = Err(err),
+            (Err(_), Err(err)) => eprintln!("Error: {:?}", err),
+        }
+    }
+    rv
+}
+
+/// Whether the command was asked to run in the submodules as well
+fn recurse_submodules(command: &Commands) -> bool {
+    matches!(
+        command,
+        Commands::Public(
+            PublicCommands::Init {
+                recurse_submodules: true,
+                ..
+            } | PublicCommands::Deinit {
+                recurse_submodules: true,
+                ..
+            } | PublicCommands::Status {
+                recurse_submodules: true
+            } | PublicCommands::Verify {
+                recurse_submodules: true,
+                ..
+            } | PublicCommands::Unlock {
+                recurse_submodules: true,
+                ..
+            }
+        )
+    )
+}
+
+/// Whether a command works with the committed files only, so it can run in bare repositories
+fn supports_bare(command: &Commands) -> bool {
+    matches!(
+        command,
+        Commands::Internal(InternalCommands::Textconv { .. })
+            | Commands::Public(
+                PublicCommands::Show { .. }
+                    | PublicCommands::Verify { history: true, .. }
+                    | PublicCommands::Verify { pushed: true, .. }
+            )
+    )
+}
+
+/// Registers the identities given in `GIT_AGECRYPT_IDENTITY` and with `--identity-stdin`, so
+/// they are used along with the configured identity files
+fn add_in_memory_identities(args: &Args) -> Result<()> {
+    if let Some(identity) = std::env::var_os(IDENTITY_ENV).filter(|i| !i.is_empty()) {
+        age::add_in_memory_identity(&format!("${}", IDENTITY_ENV), identity.into_encoded_bytes());
+    }
+    if args.identity_stdin {
+        if matches!(
+            args.command,
+            Commands::Internal(
+                InternalCommands::Clean { .. }
+                    | InternalCommands::Smudge { .. }
+                    | InternalCommands::Process
+            )
+        ) {
+            bail!(
+                "--identity-stdin can't be used with the git filters, which read files from \
+                 stdin; use {} instead",
+                IDENTITY_ENV
+            );
+        }
+        if let Commands::Public(PublicCommands::Verify { pushed: true, .. }) = args.command {
+            bail!(
+                "--identity-stdin can't be used with verify --pushed, which reads the refs \
+                 being pushed from stdin; use {} instead",
+                IDENTITY_ENV
+            );
+        }
+        let mut identity = vec![];
+        io::stdin()
+            .read_to_end(&mut identity)
+            .context("Couldn't read identity from stdin")?;
+        age::add_in_memory_identity("<stdin>", identity);
+    }
+    Ok(())
+}
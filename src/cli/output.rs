@@ -0,0 +1,19 @@
+//! Machine readable output of informational commands and errors, enabled with `--format json`.
+//!
+//! Each command prints a single JSON object on its own line on stdout, see the README for the
+//! schema.
+
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::json;
+
+pub(crate) fn print(value: &impl Serialize) -> Result<()> {
+    println!("{}", serde_json::to_string(value)?);
+    Ok(())
+}
+
+/// Reports an error along with the errors that caused it
+pub(crate) fn print_error(err: &anyhow::Error) {
+    let causes: Vec<String> = err.chain().skip(1).map(ToString::to_string).collect();
+    println!("{}", json!({ "error": err.to_string(), "causes": causes }));
+}
@@ -0,0 +1,126 @@
+//! Progress of bulk commands.
+//!
+//! With `--progress-json`, each event is a JSON object on its own line on stderr, see the README
+//! for the schema. Otherwise a progress bar is drawn on stderr if it is a terminal, and with
+//! `--verbose` each file is listed with the time it took.
+
+use std::{
+    collections::HashMap,
+    io::{self, IsTerminal, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Instant,
+};
+
+use serde_json::json;
+
+/// Width of the progress bar in characters
+const BAR_WIDTH: usize = 30;
+
+pub(crate) struct Progress {
+    json: bool,
+    verbose: bool,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Whether the bar is drawn
+    bar: bool,
+    command: String,
+    total: usize,
+    done: usize,
+    started: HashMap<PathBuf, Instant>,
+}
+
+impl Progress {
+    pub fn new(json: bool, verbose: bool) -> Self {
+        Self {
+            json,
+            verbose,
+            state: Mutex::default(),
+        }
+    }
+
+    pub fn start(&self, command: &str, total: usize) {
+        self.emit(json!({ "event": "start", "command": command, "total": total }));
+        let mut state = self.state.lock().unwrap();
+        *state = State {
+            bar: !self.json && total > 0 && io::stderr().is_terminal(),
+            command: command.into(),
+            total,
+            ..State::default()
+        };
+        state.draw();
+    }
+
+    pub fn begin(&self, file: &Path) {
+        self.emit(json!({ "event": "begin", "file": file }));
+        let mut state = self.state.lock().unwrap();
+        state.started.insert(file.into(), Instant::now());
+    }
+
+    pub fn complete(&self, file: &Path, status: &str, message: Option<&str>) {
+        self.emit(json!({
+            "event": "complete",
+            "file": file,
+            "status": status,
+            "message": message,
+        }));
+        let mut state = self.state.lock().unwrap();
+        state.done += 1;
+        let started = state.started.remove(file);
+        if self.verbose && !self.json {
+            state.clear();
+            let elapsed = started
+                .map(|s| format!(" in {:.1?}", s.elapsed()))
+                .unwrap_or_default();
+            let message = message.map(|m| format!(" -- {}", m)).unwrap_or_default();
+            eprintln!("{:>10} {}{}{}", status, file.display(), elapsed, message);
+        }
+        state.draw();
+    }
+
+    /// `counts` holds the number of files for each status
+    pub fn summary(&self, total: usize, counts: &[(&str, usize)]) {
+        let mut event = json!({ "event": "summary", "total": total });
+        for (status, count) in counts {
+            event[status] = json!(count);
+        }
+        self.emit(event);
+        let mut state = self.state.lock().unwrap();
+        state.clear();
+        state.bar = false;
+    }
+
+    fn emit(&self, event: serde_json::Value) {
+        if self.json {
+            let _ = writeln!(io::stderr().lock(), "{}", event);
+        }
+    }
+}
+
+impl State {
+    fn draw(&self) {
+        if !self.bar {
+            return;
+        }
+        let filled = BAR_WIDTH * self.done / self.total;
+        let _ = write!(
+            io::stderr().lock(),
+            "\r\x1b[K{} [{}{}] {}/{}",
+            self.command,
+            "=".repeat(filled),
+            " ".repeat(BAR_WIDTH - filled),
+            self.done,
+            self.total
+        );
+    }
+
+    /// Removes the bar, so that other output starts at the beginning of the line
+    fn clear(&self) {
+        if self.bar {
+            let _ = write!(io::stderr().lock(), "\r\x1b[K");
+        }
+    }
+}
@@ -1,55 +1,453 @@
-use std::path::PathBuf;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
 
-use crate::{git, Result};
+use anyhow::{bail, Context as _, Result};
+use serde::{Serialize, Serializer};
+use serde_json::json;
 
-use crate::config::Validated;
+use crate::{age, attributes, git, hooks, threshold, values};
+
+use crate::config::{AppConfig, RuleOptions, Validated};
 use crate::git::Repository;
 use crate::{config::AgeIdentity, ctx::Context};
 
+use super::{
+    args::{HookManager, OutputFormat},
+    output,
+    progress::Progress,
+};
+
+/// Files checked out by a single git command in `lock` and `unlock`
+const CHECKOUT_BATCH_SIZE: usize = 32;
+
+/// Hook configurations written by `install-hooks` for hook managers
+const LEFTHOOK_CONFIG: &str = "lefthook-git-agecrypt.yml";
+const PRE_COMMIT_CONFIG: &str = "git-agecrypt.pre-commit-config.yaml";
+
 pub(crate) struct CommandContext<C: Context> {
     ctx: C,
+    format: OutputFormat,
 }
 
 impl<C: Context> CommandContext<C> {
-    pub fn new(ctx: C) -> Self {
-        Self { ctx }
+    pub fn new(ctx: C, format: OutputFormat) -> Self {
+        Self { ctx, format }
     }
 
-    pub(crate) fn init(&self) -> Result<()> {
-        let exe = self.ctx.current_exe()?;
+    /// Registers the filters in the repository's git config and writes `.gitattributes`, see
+    /// [`init_global`] for registering them for all repositories
+    pub(crate) fn init(
+        &self,
+        textconv_args: Option<String>,
+        config: Option<PathBuf>,
+    ) -> Result<()> {
+        let exe = self.command_line(config)?;
         let repo = self.ctx.repo();
-        ensure_state(repo.set_config("filter.git-agecrypt.required", "true"))?;
-        ensure_state(repo.set_config(
-            "filter.git-agecrypt.smudge",
-            &format!("{} smudge -f %f", exe),
-        ))?;
-        ensure_state(
-            repo.set_config("filter.git-agecrypt.clean", &format!("{} clean -f %f", exe)),
-        )?;
-        ensure_state(repo.set_config("diff.git-agecrypt.textconv", &format!("{} textconv", exe)))?;
-        Ok(())
+        for (key, value) in filter_config(&exe, textconv_args) {
+            ensure_state(repo.set_config(key, &value))?;
+        }
+        self.sync_attributes()
     }
 
-    pub(crate) fn deinit(&self) -> Result<()> {
+    /// Installs the git hooks, see [`hooks::HOOKS`], or writes them for a hook manager.
+    ///
+    /// Hooks in the working tree, e.g. in a `core.hooksPath` shared through the repository, run
+    /// `git-agecrypt` from `PATH` instead of this executable.
+    pub(crate) fn install_hooks(
+        &self,
+        config: Option<PathBuf>,
+        manager: HookManager,
+    ) -> Result<()> {
         let repo = self.ctx.repo();
-        ensure_state(repo.remove_config_section("fiter.git-agecrypt"))?;
-        ensure_state(repo.remove_config_section("diff.git-agecrypt"))?;
+        let (dir, shared) = match manager {
+            HookManager::Git => {
+                let dir = repo.hooks_dir()?;
+                let shared = is_shared(repo, &dir);
+                (dir, shared)
+            }
+            _ => (repo.workdir().to_path_buf(), true),
+        };
+        let exe = if shared {
+            self.shared_command_line(config)?
+        } else {
+            self.command_line(config)?
+        };
+        let commands: Vec<(&str, String)> = hooks::HOOKS
+            .iter()
+            .map(|(name, args)| (*name, format!("{} {}", exe, args)))
+            .collect();
+        let relative = |path: &Path| path.strip_prefix(repo.workdir()).unwrap_or(path).to_owned();
 
-        self.ctx.remove_sidecar_files()?;
+        match manager {
+            HookManager::Git | HookManager::Husky => {
+                let dir = match manager {
+                    HookManager::Husky => dir.join(".husky"),
+                    _ => dir,
+                };
+                for (name, command) in &commands {
+                    hooks::install(&dir, name, command)?;
+                    println!("Installed {} hook in {}", name, relative(&dir).display());
+                }
+            }
+            HookManager::Lefthook => {
+                let path = dir.join(LEFTHOOK_CONFIG);
+                hooks::write_snippet(&path, &hooks::lefthook_config(&commands))?;
+                println!(
+                    "Wrote {}, add it to `extends` in lefthook.yml",
+                    LEFTHOOK_CONFIG
+                );
+            }
+            HookManager::PreCommit => {
+                let path = dir.join(PRE_COMMIT_CONFIG);
+                hooks::write_snippet(&path, &hooks::pre_commit_config(&commands))?;
+                println!(
+                    "Wrote {}, copy its hooks into .pre-commit-config.yaml",
+                    PRE_COMMIT_CONFIG
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// The git-agecrypt invocation used by filters and hooks
+    fn command_line(&self, config: Option<PathBuf>) -> Result<String> {
+        command_line(&self.ctx.current_exe()?, config)
+    }
+
+    /// The git-agecrypt invocation used by hooks shared through the repository, which run from
+    /// the root of the working tree on every clone
+    fn shared_command_line(&self, config: Option<PathBuf>) -> Result<String> {
+        let mut exe = "git-agecrypt".to_string();
+        if let Some(config) = config {
+            let config = std::env::current_dir()?.join(config);
+            let config = config
+                .strip_prefix(self.ctx.repo().workdir())
+                .unwrap_or(&config);
+            exe = format!(
+                "{} --config {}",
+                exe,
+                shell_quote(&config.to_string_lossy())
+            );
+        }
+        Ok(exe)
+    }
+
+    /// Writes `.gitattributes` entries for the files covered by the rules
+    pub(crate) fn sync_attributes(&self) -> Result<()> {
+        let tracked = self.ctx.repo().list_files()?;
+        let cfg = self.ctx.config()?;
+        let patterns = cfg.attribute_patterns(&tracked)?;
+        let exclusions = cfg.attribute_exclusions(&tracked);
+        let path = self.ctx.repo().workdir().join(".gitattributes");
+        if attributes::sync(&path, &patterns, &exclusions)? {
+            println!("Updated .gitattributes");
+        }
+        Ok(())
+    }
+
+    /// Replaces the decrypted files in the working copy with their ciphertext
+    pub(crate) fn lock(&self, progress: Progress) -> Result<()> {
+        if self.ctx.settings().locked()? {
+            bail!("The repository is already locked");
+        }
+        let files = self.checkout_candidates("lock")?;
+        self.ctx.settings().set_locked(true)?;
+        self.checkout(&files, "lock", "locked", &progress)?;
+        if self.format == OutputFormat::Json {
+            return output::print(&json!({ "total": files.len(), "locked": files.len() }));
+        }
+        println!("Locked {} files", files.len());
+        Ok(())
+    }
+
+    /// Decrypts the files left encrypted by `lock`
+    pub(crate) fn unlock(&self, progress: Progress) -> Result<()> {
+        let files = self.checkout_candidates("unlock")?;
+        self.ctx.settings().set_locked(false)?;
+        self.checkout(&files, "unlock", "unlocked", &progress)?;
+        if self.format == OutputFormat::Json {
+            return output::print(&json!({ "total": files.len(), "unlocked": files.len() }));
+        }
+        println!("Unlocked {} files", files.len());
+        Ok(())
+    }
+
+    /// Checks out `files` a batch at a time to report the progress, each file of a batch
+    /// completes with its batch
+    fn checkout(
+        &self,
+        files: &[PathBuf],
+        command: &str,
+        status: &str,
+        progress: &Progress,
+    ) -> Result<()> {
+        progress.start(command, files.len());
+        let mut done = 0;
+        for batch in files.chunks(CHECKOUT_BATCH_SIZE) {
+            batch.iter().for_each(|f| progress.begin(f));
+            if let Err(err) = self.ctx.repo().checkout_files(batch) {
+                let message = format!("{:#}", err);
+                batch
+                    .iter()
+                    .for_each(|f| progress.complete(f, "failed", Some(&message)));
+                progress.summary(
+                    files.len(),
+                    &[(status, done), ("failed", files.len() - done)],
+                );
+                return Err(err.into());
+            }
+            batch
+                .iter()
+                .for_each(|f| progress.complete(f, status, None));
+            done += batch.len();
+        }
+        progress.summary(files.len(), &[(status, done), ("failed", 0)]);
         Ok(())
     }
 
+    /// Tracked files covered by a rule, which are safe to overwrite from the index
+    fn checkout_candidates(&self, action: &str) -> Result<Vec<PathBuf>> {
+        let repo = self.ctx.repo();
+        let tracked = repo.list_files()?;
+        let files: Vec<PathBuf> = self
+            .ctx
+            .config()?
+            .paths(&tracked)?
+            .into_iter()
+            .filter(|f| tracked.contains(f))
+            .collect();
+        let modified = repo.modified_files(&files)?;
+        if !modified.is_empty() {
+            let list: Vec<_> = modified.iter().map(|f| f.display().to_string()).collect();
+            bail!(
+                "Refusing to {} as the following files have uncommitted changes, commit or stash them first: {}",
+                action,
+                list.join(", ")
+            );
+        }
+        Ok(files)
+    }
+
+    pub(crate) fn deinit(&self) -> Result<()> {
+        remove_setup(&self.ctx)
+    }
+
     pub(crate) fn list_identities(&self) -> Result<()> {
-        self.print_identities()
+        let identities = self.identity_statuses()?;
+        if self.format == OutputFormat::Json {
+            return output::print(&json!({ "identities": identities }));
+        }
+        print_identities(&identities);
+        Ok(())
     }
 
     pub(crate) fn status(&self) -> Result<()> {
-        self.list_identities()?;
+        let identities = self.identity_statuses()?;
+        let cfg = self.ctx.config()?;
+        let filters = self.filter_statuses();
+        let locked = self.ctx.settings().locked()?;
+        let files = self.file_statuses(locked)?;
+        if self.format == OutputFormat::Json {
+            return output::print(&json!({
+                "identities": identities,
+                "recipients": recipient_entries(&cfg),
+                "warnings": cfg.warnings(),
+                "filters": filters,
+                "locked": locked,
+                "files": files,
+            }));
+        }
+
+        print_identities(&identities);
+        println!();
+        print_recipients(&cfg);
         println!();
-        self.list_recipients()?;
+        println!("The following git filters are configured:");
+        for filter in &filters {
+            match &filter.value {
+                Some(value) => println!("    ✓ {} = {}", filter.key, value),
+                None => println!("    ⨯ {} is not set, run `git-agecrypt init`", filter.key),
+            }
+        }
+        println!();
+        if locked {
+            println!("The repository is locked, run `git-agecrypt unlock` to decrypt the files");
+        }
+        println!("The following files are covered by a rule:");
+        for file in &files {
+            if file.problems.is_empty() {
+                println!("    ✓ {}", file.path.display());
+            } else {
+                println!(
+                    "    ⨯ {} -- {}",
+                    file.path.display(),
+                    file.problems.join(", ")
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Lists the rules with their resolved recipients and the files they match, or only the
+    /// rule applying to `file`
+    pub(crate) fn list(&self, file: Option<PathBuf>) -> Result<()> {
+        let repo = self.ctx.repo();
+        let tracked = repo.list_files()?;
+        let cfg = self.ctx.config()?;
+        let mut rules = cfg.rules(&tracked)?;
+        if let Some(file) = file {
+            let path = std::env::current_dir()?.join(&file);
+            let Some((rule_path, _)) = cfg.lookup(&path)? else {
+                bail!("No rule applies to '{}'", file.display());
+            };
+            rules.retain(|r| r.path == rule_path);
+        }
+
+        let mut entries = vec![];
+        for entry in rules {
+            let recipients = self
+                .ctx
+                .recipients()
+                .resolve_rule(&entry.rule)
+                .with_context(|| {
+                    format!("Couldn't resolve recipients of '{}'", entry.path.display())
+                })?;
+            let uncovered: Vec<PathBuf> = entry
+                .files
+                .iter()
+                .filter(|f| {
+                    repo.get_attribute(f, "filter").ok().flatten().as_deref()
+                        != Some("git-agecrypt")
+                })
+                .cloned()
+                .collect();
+            entries.push(RuleStatus {
+                rules_file: entry
+                    .rules_file
+                    .strip_prefix(repo.workdir())
+                    .unwrap_or(&entry.rules_file)
+                    .into(),
+                exists: !entry.files.is_empty(),
+                covered: !entry.files.is_empty() && uncovered.is_empty(),
+                path: entry.path,
+                recipients,
+                threshold: entry.rule.options.threshold,
+                files: entry.files,
+                uncovered,
+            });
+        }
+
+        if self.format == OutputFormat::Json {
+            return output::print(&json!({ "rules": entries }));
+        }
+        for (i, entry) in entries.iter().enumerate() {
+            if i > 0 {
+                println!();
+            }
+            println!("{} ({})", entry.path.display(), entry.rules_file.display());
+            let files = match entry.files.len() {
+                1 => "1 file".to_string(),
+                n => format!("{} files", n),
+            };
+            if !entry.exists {
+                println!("    ⨯ matches no file");
+            } else if entry.covered {
+                println!("    ✓ {}, all assigned the filter in .gitattributes", files);
+            } else {
+                let paths: Vec<String> = entry
+                    .uncovered
+                    .iter()
+                    .map(|p| p.display().to_string())
+                    .collect();
+                println!(
+                    "    ⨯ {}, .gitattributes doesn't assign the filter to {}",
+                    files,
+                    paths.join(", ")
+                );
+            }
+            match entry.threshold {
+                Some(k) => println!("    Any {} of these recipients can decrypt:", k),
+                None => println!("    These recipients can decrypt:"),
+            }
+            for recipient in &entry.recipients {
+                println!("        {}", recipient);
+            }
+        }
         Ok(())
     }
 
+    fn filter_statuses(&self) -> Vec<FilterStatus> {
+        [
+            "filter.git-agecrypt.clean",
+            "filter.git-agecrypt.smudge",
+            "filter.git-agecrypt.process",
+            "diff.git-agecrypt.textconv",
+            "merge.git-agecrypt.driver",
+        ]
+        .into_iter()
+        .map(|key| FilterStatus {
+            key,
+            value: self.ctx.repo().get_config(key).ok(),
+        })
+        .collect()
+    }
+
+    fn file_statuses(&self, locked: bool) -> Result<Vec<FileStatus>> {
+        let repo = self.ctx.repo();
+        let mut rv = vec![];
+        for relpath in self.ctx.config()?.paths(&repo.list_files()?)? {
+            let path = repo.workdir().join(&relpath);
+            if let Some((_, rule)) = self.ctx.config()?.lookup(&path)? {
+                if !options_apply(&self.ctx, &rule.options, None)? {
+                    // Kept in plaintext on this branch
+                    rv.push(FileStatus {
+                        path: relpath,
+                        problems: vec![],
+                    });
+                    continue;
+                }
+            }
+            let mut problems = vec![];
+
+            let working_copy = match fs::read(&path) {
+                Ok(contents) if is_encrypted(&contents) && !locked => {
+                    problems.push("working copy is not decrypted".to_string());
+                    Some(contents)
+                }
+                Ok(contents) => Some(contents),
+                Err(_) => {
+                    problems.push("missing from working copy".to_string());
+                    None
+                }
+            };
+
+            match repo.get_file_contents(&path) {
+                Ok(blob) if !is_encrypted(&blob) => {
+                    problems.push("committed to HEAD unencrypted".into())
+                }
+                Ok(_) => {}
+                Err(git::Error::NotExist(_)) => problems.push("not committed yet".into()),
+                Err(err) => problems.push(format!("couldn't read from HEAD: {}", err)),
+            }
+
+            if let Some(contents) = working_copy {
+                match self.ctx.load_sidecar(&path, "hash")? {
+                    Some(hash) if hash == blake3::hash(&contents).as_bytes() => {}
+                    Some(_) => problems.push("changed since last encryption".into()),
+                    None => problems.push("never encrypted in this checkout".into()),
+                }
+            }
+
+            rv.push(FileStatus {
+                path: relpath,
+                problems,
+            });
+        }
+        Ok(rv)
+    }
+
     pub(crate) fn add_identity(&self, identity: PathBuf) -> Result<()> {
         self.ctx
             .age_identities()
@@ -64,19 +462,20 @@ impl<C: Context> CommandContext<C> {
         Ok(())
     }
 
-    fn print_identities(&self) -> Result<()> {
-        let identities = self.ctx.age_identities().list()?;
-
-        let padding = identities.iter().map(|i| i.path.len()).max().unwrap_or(0);
-        println!("The following identities are currently configured:");
-        for i in &identities {
-            if let Err(err) = i.validate() {
-                println!("    ⨯ {:padding$} -- {:?}", i.path, err, padding = padding);
-            } else {
-                println!("    ✓ {}", i.path);
-            }
-        }
-        Ok(())
+    fn identity_statuses(&self) -> Result<Vec<IdentityStatus>> {
+        Ok(self
+            .ctx
+            .age_identities()
+            .list()?
+            .into_iter()
+            .map(|i| {
+                let error = i.validate().err().map(anyhow::Error::from);
+                IdentityStatus {
+                    path: i.path,
+                    error,
+                }
+            })
+            .collect())
     }
 
     pub fn add_recipients(&self, recipients: Vec<String>, paths: Vec<PathBuf>) -> Result<()> {
@@ -97,15 +496,250 @@ impl<C: Context> CommandContext<C> {
 
     pub fn list_recipients(&self) -> Result<()> {
         let cfg = self.ctx.config()?;
-        let recipients = cfg.list();
-
-        println!("The following recipients are configured:");
-        for (p, r) in recipients {
-            println!("    {}: {}", p, r);
+        if self.format == OutputFormat::Json {
+            return output::print(&json!({
+                "recipients": recipient_entries(&cfg),
+                "warnings": cfg.warnings(),
+            }));
         }
+        print_recipients(&cfg);
         Ok(())
     }
 }
+
+/// An identity and why it can't be used, if it can't
+#[derive(Serialize)]
+struct IdentityStatus {
+    path: String,
+    #[serde(serialize_with = "serialize_error")]
+    error: Option<anyhow::Error>,
+}
+
+#[derive(Serialize)]
+struct FilterStatus {
+    key: &'static str,
+    /// `None` if the filter is not set
+    value: Option<String>,
+}
+
+/// A rule with its resolved recipients and the files it matches
+#[derive(Serialize)]
+struct RuleStatus {
+    path: PathBuf,
+    rules_file: PathBuf,
+    recipients: Vec<String>,
+    threshold: Option<u8>,
+    /// Whether the rule matches any file
+    exists: bool,
+    /// Whether `.gitattributes` assigns the filter to all files the rule matches
+    covered: bool,
+    files: Vec<PathBuf>,
+    /// Files which `.gitattributes` doesn't assign the filter to
+    uncovered: Vec<PathBuf>,
+}
+
+/// A file covered by a rule and the problems found with it
+#[derive(Serialize)]
+struct FileStatus {
+    path: PathBuf,
+    problems: Vec<String>,
+}
+
+fn serialize_error<S: Serializer>(
+    error: &Option<anyhow::Error>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    error
+        .as_ref()
+        .map(|err| format!("{:#}", err))
+        .serialize(serializer)
+}
+
+fn print_identities(identities: &[IdentityStatus]) {
+    let padding = identities.iter().map(|i| i.path.len()).max().unwrap_or(0);
+    println!("The following identities are currently configured:");
+    for i in identities {
+        if let Some(err) = &i.error {
+            println!("    ⨯ {:padding$} -- {:?}", i.path, err, padding = padding);
+        } else {
+            println!("    ✓ {}", i.path);
+        }
+    }
+}
+
+fn recipient_entries(cfg: &AppConfig) -> Vec<serde_json::Value> {
+    cfg.list()
+        .into_iter()
+        .map(|(path, recipient)| json!({ "path": path, "recipient": recipient }))
+        .collect()
+}
+
+fn print_recipients(cfg: &AppConfig) {
+    println!("The following recipients are configured:");
+    for (p, r) in cfg.list() {
+        println!("    {}: {}", p, r);
+    }
+    for warning in cfg.warnings() {
+        println!("    ⚠ {}", warning);
+    }
+}
+
+/// Whether the contents are in one of the formats written by `clean`
+pub(super) fn is_encrypted(contents: &[u8]) -> bool {
+    threshold::is_threshold(contents)
+        || values::is_encrypted(contents)
+        || matches!(age::read_header(contents), Ok(Some(_)))
+}
+
+/// Whether a rule with `options` applies on the branch checked out, pushed to `remote` or else
+/// to the branch's push remote. Files whose rule doesn't apply are kept in plaintext.
+pub(super) fn options_apply(
+    ctx: &impl Context,
+    options: &RuleOptions,
+    remote: Option<&str>,
+) -> Result<bool> {
+    if !options.is_scoped() {
+        return Ok(true);
+    }
+    let branch = ctx.repo().current_branch()?;
+    options_apply_on(ctx, options, branch.as_deref(), remote)
+}
+
+/// Whether a rule with `options` applies to the commits of `branch` pushed to `remote`, or else
+/// to the branch's push remote.
+///
+/// The remote may be given by name or by URL, as git passes it to the `pre-push` hook. One
+/// which isn't configured is taken as unknown, so the rule applies.
+pub(super) fn options_apply_on(
+    ctx: &impl Context,
+    options: &RuleOptions,
+    branch: Option<&str>,
+    remote: Option<&str>,
+) -> Result<bool> {
+    if !options.is_scoped() {
+        return Ok(true);
+    }
+    let repo = ctx.repo();
+    let remote = match (remote, branch) {
+        (Some(remote), _) => Some(remote.to_string()),
+        (None, Some(branch)) => repo.push_remote(branch)?,
+        (None, None) => None,
+    };
+    let name = match &remote {
+        Some(remote) => repo.remote_name(remote)?,
+        None => None,
+    };
+    let applies = options.applies_to(branch, name.as_deref());
+    log::debug!(
+        "Scoped rule; branch={branch:?}, remote={remote:?}, name={name:?}, applies={applies}"
+    );
+    Ok(applies)
+}
+
+/// Quotes `arg` for the shell git runs filters, drivers and hooks with.
+///
+/// That is `sh` on Windows too, where backslashes would be taken as escapes, so paths are
+/// given with forward slashes, which Windows accepts as well.
+fn shell_quote(arg: &str) -> String {
+    let arg = if cfg!(windows) {
+        arg.replace('\\', "/")
+    } else {
+        arg.to_string()
+    };
+    if !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "/._-+:,@=".contains(c))
+    {
+        arg
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// Removes the filters, the `.gitattributes` entries, the hooks and the sidecars of the
+/// repository, leaving the files as they are
+pub(super) fn remove_setup(ctx: &impl Context) -> Result<()> {
+    let repo = ctx.repo();
+    ensure_state(repo.remove_config_section("filter.git-agecrypt"))?;
+    ensure_state(repo.remove_config_section("diff.git-agecrypt"))?;
+    ensure_state(repo.remove_config_section("merge.git-agecrypt"))?;
+    attributes::sync(&repo.workdir().join(".gitattributes"), &[], &[])?;
+    // Hooks shared through the repository are left to be removed by a commit
+    let hooks_dir = repo.hooks_dir()?;
+    if !is_shared(repo, &hooks_dir) {
+        for (name, _) in hooks::HOOKS {
+            hooks::uninstall(&hooks_dir, name)?;
+        }
+    }
+
+    ctx.remove_sidecar_files()?;
+    Ok(())
+}
+
+/// Whether hooks in `dir` are shared through the repository, as it is in the working tree
+fn is_shared(repo: &impl Repository, dir: &Path) -> bool {
+    dir.starts_with(repo.workdir()) && !dir.starts_with(repo.path())
+}
+
+/// Registers the filters in the global git config (~/.gitconfig) for all repositories. This
+/// runs outside of repositories, so it must not need one.
+pub(crate) fn init_global(textconv_args: Option<String>, config: Option<PathBuf>) -> Result<()> {
+    let exe = std::env::current_exe()?;
+    let exe = command_line(&exe.to_string_lossy(), config)?;
+    for (key, value) in filter_config(&exe, textconv_args) {
+        ensure_state(git::set_global_config(key, &value))?;
+    }
+    Ok(())
+}
+
+/// Removes the filters from the global git config (~/.gitconfig), see [`init_global`]
+pub(crate) fn deinit_global() -> Result<()> {
+    ensure_state(git::remove_global_config_section("filter.git-agecrypt"))?;
+    ensure_state(git::remove_global_config_section("diff.git-agecrypt"))?;
+    ensure_state(git::remove_global_config_section("merge.git-agecrypt"))?;
+    Ok(())
+}
+
+/// The git config entries registering the filters, diff and merge drivers run by `exe`
+fn filter_config(exe: &str, textconv_args: Option<String>) -> Vec<(&'static str, String)> {
+    let textconv = match textconv_args {
+        Some(args) if !args.trim().is_empty() => format!("{} textconv {}", exe, args.trim()),
+        _ => format!("{} textconv", exe),
+    };
+    vec![
+        ("filter.git-agecrypt.required", "true".into()),
+        ("filter.git-agecrypt.smudge", format!("{} smudge -f %f", exe)),
+        ("filter.git-agecrypt.clean", format!("{} clean -f %f", exe)),
+        ("filter.git-agecrypt.process", format!("{} process", exe)),
+        ("diff.git-agecrypt.textconv", textconv),
+        ("diff.git-agecrypt.cachetextconv", "true".into()),
+        ("merge.git-agecrypt.name", "git-agecrypt merge driver".into()),
+        (
+            "merge.git-agecrypt.driver",
+            format!(
+                "{} merge --ancestor %O --ours %A --theirs %B --output %A --marker-size %L --path %P",
+                exe
+            ),
+        ),
+    ]
+}
+
+/// The invocation of `exe` used by the filters, with the `--config` given to this command
+fn command_line(exe: &str, config: Option<PathBuf>) -> Result<String> {
+    let mut exe = shell_quote(exe);
+    if let Some(config) = config {
+        // The filters are run from the repository root
+        let config = std::env::current_dir()?.join(config);
+        exe = format!(
+            "{} --config {}",
+            exe,
+            shell_quote(&config.to_string_lossy())
+        );
+    }
+    Ok(exe)
+}
+
 fn ensure_state(result: git::Result<()>) -> Result<()> {
     match result {
         Ok(()) => Ok(()),
@@ -116,3 +750,20 @@ fn ensure_state(result: git::Result<()>) -> Result<()> {
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case("/usr/bin/git-agecrypt", "/usr/bin/git-agecrypt")]
+    #[case("", "''")]
+    #[case("/opt/my tools/git-agecrypt", "'/opt/my tools/git-agecrypt'")]
+    #[case("/home/o'neil/git-agecrypt", "'/home/o'\\''neil/git-agecrypt'")]
+    #[case("/tmp/$HOME/git-agecrypt", "'/tmp/$HOME/git-agecrypt'")]
+    fn test_shell_quote(#[case] arg: &str, #[case] expected: &str) {
+        assert_eq!(shell_quote(arg), expected);
+    }
+}
@@ -0,0 +1,281 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+};
+
+use anyhow::{bail, Context as _, Result};
+
+use crate::{
+    ctx::Context,
+    git::{Blob, Repository},
+    values,
+};
+
+use super::{exit::ChecksFailed, internal::CommandContext, public::is_encrypted};
+
+impl<C: Context> CommandContext<C> {
+    /// Encrypts the plaintext versions of `paths` in the history of `HEAD` and writes a blob
+    /// callback for `git filter-repo` replacing them with the ciphertext to `output`, or to
+    /// stdout for "-".
+    ///
+    /// With `run`, the history is rewritten with the callback right away and checked for
+    /// plaintext versions left afterwards.
+    pub(crate) fn purge_history(&self, paths: &[PathBuf], output: &Path, run: bool) -> Result<()> {
+        let repo = self.ctx.repo();
+        let cfg = self.ctx.config()?;
+        let paths = paths
+            .iter()
+            .map(|p| self.repo_path(p))
+            .collect::<Result<Vec<_>>>()?;
+        for path in &paths {
+            cfg.get_rule(&repo.workdir().join(path))?;
+        }
+
+        let history = repo.history_blobs(None)?;
+        let mut replacements = BTreeMap::new();
+        // The path each replaced blob was encrypted for
+        let mut origins = BTreeMap::new();
+        for blob in &history {
+            if !paths.contains(&blob.path) || replacements.contains_key(&blob.id) {
+                continue;
+            }
+            let contents = repo.read_blob(&blob.id)?;
+            if is_encrypted(&contents) {
+                continue;
+            }
+            let file = repo.workdir().join(&blob.path);
+            let (public_keys, options) =
+                self.prepare_encryption(&file, contents.len() as u64, &contents, false)?;
+            let encrypted = self.encrypt(&file, public_keys, &options, contents.into(), false)?;
+            log::info!(
+                "Encrypted plaintext version; file={:?}, blob={}, commit={:?}",
+                blob.path,
+                blob.id,
+                blob.commit
+            );
+            replacements.insert(blob.id.clone(), repo.write_blob(&encrypted)?);
+            origins.insert(&blob.id, &blob.path);
+        }
+        if replacements.is_empty() {
+            println!("No plaintext versions of these files in the history of HEAD");
+            return Ok(());
+        }
+        self.check_shared_blobs(&history, &origins, &paths)?;
+        let callback = blob_callback(&replacements);
+
+        if !run {
+            if output == Path::new("-") {
+                io::stdout().write_all(callback.as_bytes())?;
+            } else {
+                fs::write(output, &callback)
+                    .with_context(|| format!("Couldn't write {:?}", output))?;
+            }
+            let file = match output.to_str() {
+                Some("-") | None => "<file>".into(),
+                Some(_) => output.display().to_string(),
+            };
+            eprintln!(
+                "Found {} plaintext versions. With the callback saved to {}, rewrite the history \
+                 of this repository with\n    \
+                 git filter-repo --force --blob-callback \"$(cat {})\"",
+                replacements.len(),
+                file,
+                file
+            );
+            return Ok(());
+        }
+
+        println!(
+            "Rewriting history to replace {} plaintext versions",
+            replacements.len()
+        );
+        let status = process::Command::new("git")
+            .args(["filter-repo", "--force", "--blob-callback", &callback])
+            .current_dir(repo.workdir())
+            .status()
+            .context("Couldn't run git filter-repo")?;
+        if !status.success() {
+            bail!("git filter-repo failed with {}, is it installed?", status);
+        }
+
+        let mut left = vec![];
+        for blob in repo.history_blobs(None)? {
+            if paths.contains(&blob.path) && !is_encrypted(&repo.read_blob(&blob.id)?) {
+                left.push(blob);
+            }
+        }
+        if left.is_empty() {
+            println!("No plaintext versions of these files are left in the history of HEAD");
+            return Ok(());
+        }
+        for blob in &left {
+            println!(
+                "    ⨯ {} in {}",
+                blob.path.display(),
+                blob.commit.as_deref().unwrap_or("the index")
+            );
+        }
+        Err(ChecksFailed(format!("{} plaintext versions are left", left.len())).into())
+    }
+
+    /// Refuses to rewrite the history if a replaced blob is also committed at another path.
+    ///
+    /// The callback replaces blobs by their id wherever they appear, so the other path would
+    /// get the ciphertext too. That's only right if it is purged as well, and encrypted the same
+    /// way as the path the ciphertext was made for.
+    fn check_shared_blobs(
+        &self,
+        history: &[Blob],
+        origins: &BTreeMap<&String, &PathBuf>,
+        paths: &[PathBuf],
+    ) -> Result<()> {
+        let workdir = self.ctx.repo().workdir();
+        let cfg = self.ctx.config()?;
+        let encrypted_like = |a: &Path, b: &Path| -> Result<bool> {
+            Ok(
+                cfg.get_rule(&workdir.join(a))? == cfg.get_rule(&workdir.join(b))?
+                    && values::Format::of(a) == values::Format::of(b),
+            )
+        };
+        let mut shared = vec![];
+        for blob in history {
+            let Some(origin) = origins.get(&blob.id) else {
+                continue;
+            };
+            if **origin == blob.path {
+                continue;
+            }
+            if !paths.contains(&blob.path) {
+                shared.push(format!(
+                    "{} has the same contents as {} but isn't being purged",
+                    blob.path.display(),
+                    origin.display()
+                ));
+            } else if !encrypted_like(origin, &blob.path)? {
+                shared.push(format!(
+                    "{} has the same contents as {} but is encrypted differently",
+                    blob.path.display(),
+                    origin.display()
+                ));
+            }
+        }
+        if shared.is_empty() {
+            return Ok(());
+        }
+        shared.sort();
+        shared.dedup();
+        bail!(
+            "Refusing to rewrite the history, git filter-repo replaces the contents of a file \
+             wherever they are committed:\n    {}",
+            shared.join("\n    ")
+        );
+    }
+}
+
+/// Body of a blob callback for `git filter-repo`, swapping the blobs which are keys of
+/// `replacements` for the blobs with the ciphertext they map to
+fn blob_callback(replacements: &BTreeMap<String, String>) -> String {
+    let mut rv = String::from(
+        "# Written by git-agecrypt purge-history, replaces plaintext versions of secrets with\n\
+         # their ciphertext, which is stored in this repository's object database\n\
+         import subprocess\n\
+         ciphertext = {\n",
+    );
+    for (plaintext, encrypted) in replacements {
+        rv.push_str(&format!("    b\"{}\": \"{}\",\n", plaintext, encrypted));
+    }
+    rv.push_str(
+        "}\n\
+         if blob.original_id in ciphertext:\n    \
+         blob.data = subprocess.check_output(\n        \
+         [\"git\", \"cat-file\", \"blob\", ciphertext[blob.original_id]]\n    \
+         )\n",
+    );
+    rv
+}
+
+#[cfg(test)]
+mod tests {
+    use ::age::secrecy::ExposeSecret;
+    use assert_fs::prelude::*;
+    use assert_fs::TempDir;
+    use duct::cmd;
+    use rstest::rstest;
+
+    use super::*;
+    use crate::{config::AgeIdentity, ctx::ContextWrapper, git::LibGit2Repository};
+
+    /// A repository with `secret.txt` covered by a rule and `other.txt` holding `other`, both
+    /// committed in plaintext
+    fn repository(
+        dir: &TempDir,
+        other: &str,
+    ) -> Result<CommandContext<ContextWrapper<LibGit2Repository>>> {
+        cmd!("git", "init").dir(dir.path()).run()?;
+        cmd!("git", "config", "user.email", "author@example.com")
+            .dir(dir.path())
+            .run()?;
+        cmd!("git", "config", "user.name", "A U Thor")
+            .dir(dir.path())
+            .run()?;
+        let identity = ::age::x25519::Identity::generate();
+        let key = dir.child("key.txt");
+        key.write_str(&format!("{}\n", identity.to_string().expose_secret()))?;
+        dir.child("git-agecrypt.toml").write_str(&format!(
+            "[config]\n\"secret.txt\" = [\"{}\"]\n",
+            identity.to_public()
+        ))?;
+        dir.child("secret.txt").write_str("password=1\n")?;
+        dir.child("other.txt").write_str(other)?;
+        cmd!("git", "add", "secret.txt", "other.txt")
+            .dir(dir.path())
+            .run()?;
+        cmd!("git", "commit", "-m", "plaintext")
+            .dir(dir.path())
+            .run()?;
+
+        let repo = LibGit2Repository::from_dir(dir.path().to_path_buf())?;
+        let ctx = ContextWrapper::new(repo, None);
+        ctx.age_identities()
+            .add(AgeIdentity::try_from(key.path().to_path_buf())?)?;
+        Ok(CommandContext { ctx })
+    }
+
+    #[rstest]
+    #[case("unrelated\n", true)]
+    #[case("password=1\n", false)]
+    fn test_purge_history_shared_blob(#[case] other: &str, #[case] ok: bool) -> Result<()> {
+        let dir = TempDir::new()?;
+        let cmd = repository(&dir, other)?;
+        let callback = dir.child("callback.py");
+
+        let result = cmd.purge_history(&[dir.child("secret.txt").to_path_buf()], &callback, false);
+        if ok {
+            result?;
+            assert!(fs::read_to_string(callback.path())?.contains("ciphertext = {"));
+        } else {
+            let err = format!("{:#}", result.unwrap_err());
+            assert!(
+                err.contains("other.txt has the same contents as secret.txt"),
+                "{}",
+                err
+            );
+            assert!(!callback.path().exists());
+        }
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_blob_callback() {
+        let replacements = BTreeMap::from([("a1".to_string(), "b2".to_string())]);
+        let callback = blob_callback(&replacements);
+        assert!(callback.contains("    b\"a1\": \"b2\",\n"));
+        assert!(callback.ends_with(
+            "if blob.original_id in ciphertext:\n    blob.data = subprocess.check_output(\n        \
+             [\"git\", \"cat-file\", \"blob\", ciphertext[blob.original_id]]\n    )\n"
+        ));
+    }
+}
@@ -0,0 +1,412 @@
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{bail, Result};
+use serde_json::json;
+
+use crate::{
+    age::{self, StanzaKind},
+    config::RuleOptions,
+    ctx::Context,
+    git::{Error as GitError, Repository},
+    secret::SecretBuf,
+    threshold, values,
+};
+
+use super::{
+    args::OutputFormat,
+    exit::{ExitCode, Reported},
+    internal::{encrypt_contents, CommandContext},
+    output,
+    progress::Progress,
+    public::{is_encrypted, options_apply},
+};
+
+enum Plan {
+    Encrypt(Job),
+    Unchanged,
+    NotCommitted,
+}
+
+impl<C: Context> CommandContext<C> {
+    /// Re-encrypts files whose committed recipients differ from the configured ones, only
+    /// listing them with `dry_run`
+    pub(crate) fn rekey(
+        &self,
+        paths: Vec<PathBuf>,
+        all: bool,
+        dry_run: bool,
+        check_decryptable: bool,
+        progress: Progress,
+        format: OutputFormat,
+    ) -> Result<()> {
+        let filters = paths
+            .iter()
+            .map(|p| self.repo_path(p))
+            .collect::<Result<Vec<_>>>()?;
+        let files: Vec<PathBuf> = self
+            .ctx
+            .config()?
+            .paths(&self.ctx.repo().list_files()?)?
+            .into_iter()
+            .filter(|f| filters.is_empty() || filters.iter().any(|p| f.starts_with(p)))
+            .collect();
+        if files.is_empty() && !filters.is_empty() {
+            bail!("None of the given paths is covered by a rule");
+        }
+        let check_decryptable = check_decryptable || self.ctx.settings().check_decryptable()?;
+
+        progress.start("rekey", files.len());
+        let mut rekeyed = vec![];
+        let mut unchanged = 0;
+        let mut not_committed = 0;
+        let mut failed = vec![];
+        // Looking up what to do needs the repository, which stays on this thread, only the
+        // encryption runs on the workers
+        let mut jobs = vec![];
+        for file in &files {
+            match self.plan_rekey(file, all, check_decryptable) {
+                Ok(Plan::Encrypt(job)) => jobs.push((file, job)),
+                Ok(Plan::Unchanged) => {
+                    progress.begin(file);
+                    progress.complete(file, "unchanged", None);
+                    unchanged += 1;
+                }
+                Ok(Plan::NotCommitted) => {
+                    progress.begin(file);
+                    progress.complete(file, "skipped", Some("not committed yet"));
+                    not_committed += 1;
+                }
+                Err(err) => {
+                    progress.begin(file);
+                    progress.complete(file, "failed", Some(&format!("{:#}", err)));
+                    failed.push((file, err));
+                }
+            }
+        }
+
+        if dry_run {
+            return self.report_planned_rekey(
+                &progress,
+                jobs,
+                unchanged,
+                not_committed,
+                failed,
+                format,
+            );
+        }
+
+        let uses_plugins = jobs
+            .iter()
+            .any(|(_, job)| age::recipients_use_plugins(&job.public_keys));
+        let timeout = self.encryption_timeout(
+            &jobs
+                .iter()
+                .flat_map(|(_, job)| job.public_keys.clone())
+                .collect::<Vec<_>>(),
+        )?;
+        let results = age::parallel_map(self.jobs(uses_plugins)?, jobs, |(file, job)| {
+            progress.begin(file);
+            let encrypted = job.encrypt(timeout);
+            (file, job, encrypted)
+        });
+        for (file, job, encrypted) in results {
+            match encrypted.and_then(|encrypted| self.store_rekeyed(&job, &encrypted)) {
+                Ok(()) => {
+                    progress.complete(file, "rekeyed", None);
+                    rekeyed.push(file);
+                }
+                Err(err) => {
+                    progress.complete(file, "failed", Some(&format!("{:#}", err)));
+                    failed.push((file, err));
+                }
+            }
+        }
+        failed.sort_by_key(|(file, _)| *file);
+        progress.summary(
+            files.len(),
+            &[
+                ("rekeyed", rekeyed.len()),
+                ("unchanged", unchanged),
+                ("skipped", not_committed),
+                ("failed", failed.len()),
+            ],
+        );
+
+        if format == OutputFormat::Json {
+            output::print(&json!({
+                "total": files.len(),
+                "rekeyed": rekeyed,
+                "unchanged": unchanged,
+                "skipped": not_committed,
+                "failed": failures(&failed),
+            }))?;
+            if !failed.is_empty() {
+                // The report already lists the failures
+                return Err(Reported(ExitCode::Failure).into());
+            }
+            return Ok(());
+        }
+        if rekeyed.is_empty() {
+            println!("No files needed re-encryption.");
+        } else {
+            println!("The following files were re-encrypted, stage them with `git add` to commit the change:");
+            for file in &rekeyed {
+                println!("    ✓ {}", file.display());
+            }
+        }
+        if !failed.is_empty() {
+            println!("The following files couldn't be re-encrypted:");
+            for (file, err) in &failed {
+                println!("    ⨯ {} -- {:#}", file.display(), err);
+            }
+        }
+        println!(
+            "{} files: {} re-encrypted, {} unchanged, {} not committed yet, {} failed",
+            files.len(),
+            rekeyed.len(),
+            unchanged,
+            not_committed,
+            failed.len()
+        );
+        if !failed.is_empty() {
+            bail!("Re-encrypting {} files failed", failed.len());
+        }
+        Ok(())
+    }
+
+    fn report_planned_rekey(
+        &self,
+        progress: &Progress,
+        jobs: Vec<(&PathBuf, Job)>,
+        unchanged: usize,
+        not_committed: usize,
+        failed: Vec<(&PathBuf, anyhow::Error)>,
+        format: OutputFormat,
+    ) -> Result<()> {
+        let total = jobs.len() + unchanged + not_committed + failed.len();
+        for (file, _) in &jobs {
+            progress.begin(file);
+            progress.complete(file, "planned", None);
+        }
+        progress.summary(
+            total,
+            &[
+                ("planned", jobs.len()),
+                ("unchanged", unchanged),
+                ("skipped", not_committed),
+                ("failed", failed.len()),
+            ],
+        );
+        if format == OutputFormat::Json {
+            let planned: Vec<_> = jobs.iter().map(|(file, _)| file).collect();
+            output::print(&json!({
+                "total": total,
+                "planned": planned,
+                "unchanged": unchanged,
+                "skipped": not_committed,
+                "failed": failures(&failed),
+            }))?;
+            if !failed.is_empty() {
+                return Err(Reported(ExitCode::Failure).into());
+            }
+            return Ok(());
+        }
+        if jobs.is_empty() {
+            println!("No files need re-encryption.");
+        } else {
+            println!("The following files would be re-encrypted:");
+            for (file, _) in &jobs {
+                println!("    • {}", file.display());
+            }
+        }
+        if !failed.is_empty() {
+            println!("The following files couldn't be checked:");
+            for (file, err) in &failed {
+                println!("    ⨯ {} -- {:#}", file.display(), err);
+            }
+        }
+        println!(
+            "{} files: {} to re-encrypt, {} unchanged, {} not committed yet, {} failed",
+            total,
+            jobs.len(),
+            unchanged,
+            not_committed,
+            failed.len()
+        );
+        if !failed.is_empty() {
+            bail!("Checking {} files failed", failed.len());
+        }
+        Ok(())
+    }
+
+    fn plan_rekey(&self, relpath: &Path, all: bool, check_decryptable: bool) -> Result<Plan> {
+        let path = self.ctx.repo().workdir().join(relpath);
+        let rule = self.ctx.config()?.get_rule(&path)?;
+        if !options_apply(&self.ctx, &rule.options, None)? {
+            // Kept in plaintext on this branch
+            return Ok(Plan::Unchanged);
+        }
+        let public_keys = self.ctx.recipients().resolve_rule(&rule)?;
+
+        let committed = match self.ctx.repo().get_file_contents(&path) {
+            Ok(v) => v,
+            Err(GitError::NotExist(_)) => return Ok(Plan::NotCommitted),
+            Err(e) => return Err(e.into()),
+        };
+        if !all && recipients_match(&committed, &public_keys, rule.options.threshold)? {
+            log::debug!(
+                "Committed recipients match the configuration; file={:?}",
+                path
+            );
+            return Ok(Plan::Unchanged);
+        }
+        if check_decryptable {
+            self.ensure_decryptable(&path, &public_keys)?;
+        }
+
+        let contents = SecretBuf::from(fs::read(&path)?);
+        if is_encrypted(&contents) {
+            bail!("The working copy isn't decrypted");
+        }
+        Ok(Plan::Encrypt(Job {
+            options: self.resolve_options(&rule.options)?,
+            path,
+            public_keys,
+            contents,
+        }))
+    }
+
+    fn store_rekeyed(&self, job: &Job, encrypted: &[u8]) -> Result<()> {
+        let hash = blake3::hash(&job.contents);
+        // `clean` hands out the stored ciphertext as long as the hash matches the working copy
+        self.ctx.store_sidecar(&job.path, "hash", hash.as_bytes())?;
+        self.ctx.store_sidecar(&job.path, "age", encrypted)?;
+        // Makes git notice the file and run `clean` again
+        File::options()
+            .write(true)
+            .open(&job.path)?
+            .set_modified(SystemTime::now())?;
+        Ok(())
+    }
+}
+
+/// A file to encrypt again, with its options resolved against the settings
+struct Job {
+    path: PathBuf,
+    public_keys: Vec<String>,
+    options: RuleOptions,
+    contents: SecretBuf,
+}
+
+impl Job {
+    fn encrypt(&self, timeout: Option<Duration>) -> Result<Vec<u8>> {
+        let (path, public_keys, options, contents) = (
+            self.path.clone(),
+            self.public_keys.clone(),
+            self.options.clone(),
+            self.contents.clone(),
+        );
+        age::with_timeout(timeout, move || {
+            encrypt_contents(&path, &public_keys, &options, None, contents)
+        })
+    }
+}
+
+/// Compares the recipients of the committed ciphertext with the configured ones.
+///
+/// Only the kind of each recipient is known for X25519 and plugin recipients, so replacing
+/// such a key with another one of the same kind is not detected.
+pub(super) fn recipients_match(
+    committed: &[u8],
+    public_keys: &[String],
+    threshold: Option<u8>,
+) -> Result<bool> {
+    let mut expected = public_keys
+        .iter()
+        .map(|k| age::recipient_stanza_kind(k))
+        .collect::<Result<Vec<_>>>()?;
+
+    let key_block = values::key_block(committed);
+    let unwrapped = key_block.as_deref().unwrap_or(committed);
+    let committed_threshold = if threshold::is_threshold(unwrapped) {
+        Some(threshold::parse(unwrapped)?.threshold)
+    } else {
+        None
+    };
+    if threshold.map(usize::from) != committed_threshold {
+        return Ok(false);
+    }
+    let Some(mut actual) = committed_stanzas(committed)? else {
+        return Ok(false);
+    };
+
+    expected.sort();
+    actual.sort();
+    Ok(expected == actual)
+}
+
+/// What the stanzas of committed ciphertext in any format reveal about its recipients, `None`
+/// if it isn't encrypted
+pub(super) fn committed_stanzas(committed: &[u8]) -> Result<Option<Vec<StanzaKind>>> {
+    let key_block = values::key_block(committed);
+    let committed = key_block.as_deref().unwrap_or(committed);
+    if !threshold::is_threshold(committed) {
+        return stanza_kinds(committed);
+    }
+    let mut rv = vec![];
+    for share in &threshold::parse(committed)?.shares {
+        match stanza_kinds(share)? {
+            Some(kinds) => rv.extend(kinds),
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(rv))
+}
+
+fn stanza_kinds(encrypted: &[u8]) -> Result<Option<Vec<StanzaKind>>> {
+    Ok(age::read_header(encrypted)?
+        .map(|header| header.stanzas.iter().filter_map(|s| s.kind()).collect()))
+}
+
+/// The files `rekey` couldn't handle, as reported with `--format json`
+fn failures(failed: &[(&PathBuf, anyhow::Error)]) -> Vec<serde_json::Value> {
+    failed
+        .iter()
+        .map(|(file, err)| json!({ "path": file, "message": format!("{:#}", err) }))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    fn test_recipients_match() -> Result<()> {
+        let ssh =
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIHsKLqeplhpW+uObz5dvMgjz1OxfM/XXUB+VHtZ6isGN"
+                .to_string();
+        let x25519 = ::age::x25519::Identity::generate().to_public().to_string();
+        let both = [ssh.clone(), x25519.clone()];
+        let reversed = [x25519, ssh];
+
+        let encrypted = age::encrypt(&both, false, &mut &b"secret"[..])?;
+        assert!(recipients_match(&encrypted, &both, None)?);
+        assert!(recipients_match(&encrypted, &reversed, None)?);
+        assert!(!recipients_match(&encrypted, &both[1..], None)?);
+        assert!(!recipients_match(&encrypted, &both, Some(1))?);
+        assert!(!recipients_match(b"plain", &both, None)?);
+
+        let encrypted = threshold::encrypt(&both, 1, false, &mut &b"secret"[..])?;
+        assert!(recipients_match(&encrypted, &both, Some(1))?);
+        assert!(!recipients_match(&encrypted, &both, Some(2))?);
+        assert!(!recipients_match(&encrypted, &both, None)?);
+        Ok(())
+    }
+}
@@ -0,0 +1,40 @@
+use std::{
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+
+use crate::{ctx::Context, git::Repository};
+
+use super::internal::CommandContext;
+
+impl<C: Context> CommandContext<C> {
+    /// Prints the decrypted contents of a file at some revision.
+    ///
+    /// `object` is either a path relative to the current directory, naming the version in
+    /// `HEAD`, or a `<rev>:<path>` expression as understood by `git show`.
+    pub(crate) fn show(&self, object: &str) -> Result<()> {
+        let (spec, relpath) = match object.split_once(':') {
+            Some((_, path)) => (object.to_string(), PathBuf::from(path)),
+            // Relative to the root, there is no working tree to be in
+            None if self.ctx.repo().is_bare() => (format!("HEAD:{}", object), object.into()),
+            None => {
+                let relpath = self.repo_path(Path::new(object))?;
+                (format!("HEAD:{}", relpath.display()), relpath)
+            }
+        };
+        let file = self.ctx.repo().workdir().join(relpath);
+        let contents = self.ctx.repo().read_revision(&spec)?;
+
+        let identities = self.identities_for(&file)?;
+        let plaintext = match self.decrypt_audited("show", &file, identities, contents.clone())? {
+            Some(plaintext) => plaintext,
+            None => {
+                log::warn!("{} isn't encrypted, showing as is", spec);
+                contents.into()
+            }
+        };
+        Ok(io::stdout().write_all(&plaintext)?)
+    }
+}
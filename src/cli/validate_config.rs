@@ -0,0 +1,89 @@
+use anyhow::Result;
+use serde_json::json;
+
+use crate::{config::InvalidRecipient, ctx::Context, git::Repository, recipients};
+
+use super::{
+    args::OutputFormat,
+    exit::{ChecksFailed, ExitCode, Reported},
+    internal::CommandContext,
+    output,
+};
+
+impl<C: Context> CommandContext<C> {
+    /// Checks the rules file of the repository and those in its directories, reporting each
+    /// recipient age can't encrypt to and the rules targeting the same file
+    pub(crate) fn validate_config(&self, format: OutputFormat) -> Result<()> {
+        let repo = self.ctx.repo();
+        let cfg = self.ctx.config()?;
+        let nested = cfg.nested(&repo.list_files()?)?;
+        let files: Vec<_> = std::iter::once(cfg).chain(nested).collect();
+
+        let mut invalid: Vec<InvalidRecipient> = vec![];
+        let mut warnings = vec![];
+        for file in &files {
+            // Members of inherited groups are reported with the file defining them
+            invalid.extend(
+                file.invalid_recipients()
+                    .iter()
+                    .filter(|i| i.file == file.path())
+                    .cloned(),
+            );
+            warnings.extend(file.warnings());
+        }
+        let paths: Vec<_> = files
+            .iter()
+            .map(|f| f.path())
+            .filter(|p| p.exists())
+            .map(|p| p.strip_prefix(repo.workdir()).unwrap_or(p))
+            .collect();
+
+        let failed = invalid.len() + warnings.len();
+        if format == OutputFormat::Json {
+            output::print(&json!({
+                "files": paths,
+                "invalid": invalid,
+                "warnings": warnings,
+            }))?;
+            if failed > 0 {
+                // The report already lists the problems
+                return Err(Reported(ExitCode::ChecksFailed).into());
+            }
+            return Ok(());
+        }
+        if failed == 0 {
+            let names: Vec<_> = paths.iter().map(|p| format!("'{}'", p.display())).collect();
+            if names.is_empty() {
+                println!("There is no rules file to check.");
+            } else {
+                println!("The rules in {} are valid.", names.join(", "));
+            }
+            return Ok(());
+        }
+        println!("The following problems were found in the rules:");
+        for i in &invalid {
+            let target = match (&i.rule, &i.group) {
+                (Some(rule), _) => format!("rule '{}'", rule.display()),
+                (None, Some(group)) => format!("group '{}'", group),
+                (None, None) => "rules".into(),
+            };
+            println!(
+                "    ⨯ '{}' in {} of '{}' -- {}",
+                i.recipient,
+                target,
+                i.file
+                    .strip_prefix(repo.workdir())
+                    .unwrap_or(&i.file)
+                    .display(),
+                i.reason
+            );
+        }
+        for warning in &warnings {
+            println!("    ⨯ {}", warning);
+        }
+        if !invalid.is_empty() {
+            println!("Recipients are {}.", recipients::FORMATS);
+        }
+        Err(ChecksFailed(format!("Found {} problems in the rules", failed)).into())
+    }
+}
@@ -0,0 +1,327 @@
+use std::{collections::HashSet, io::BufRead, path::PathBuf, time::Duration};
+
+use anyhow::{bail, Result};
+use serde_json::json;
+
+use crate::{
+    age,
+    audit::Outcome,
+    config::RuleOptions,
+    ctx::Context,
+    git::{Blob, Repository},
+};
+
+use super::{
+    args::OutputFormat,
+    exit::{ChecksFailed, ExitCode, Reported},
+    internal::{decrypt_any, CommandContext},
+    output,
+    progress::Progress,
+    public::{is_encrypted, options_apply, options_apply_on},
+    rekey::recipients_match,
+};
+
+impl<C: Context> CommandContext<C> {
+    /// Checks that the files covered by a rule are stored encrypted to the configured recipients
+    ///
+    /// With `quick` set, only checks that the files are encrypted at all. Files whose rule
+    /// doesn't apply on the branch checked out, pushed to `remote` or else to its push remote,
+    /// are skipped.
+    ///
+    /// With `pushed`, the commits being pushed to `remote` are checked instead of the index,
+    /// each against the rules which apply to the branch they are pushed from.
+    pub(crate) fn verify(
+        &self,
+        history: bool,
+        quick: bool,
+        remote: Option<&str>,
+        pushed: Option<&[PushedRef]>,
+        progress: Progress,
+        format: OutputFormat,
+    ) -> Result<()> {
+        let repo = self.ctx.repo();
+        let current = |options: &RuleOptions| options_apply(&self.ctx, options, remote);
+        let mut blobs = vec![];
+        match pushed {
+            Some(refs) => {
+                let name = match remote {
+                    Some(remote) => repo.remote_name(remote)?,
+                    None => None,
+                };
+                for pushed in refs {
+                    let Some(local) = &pushed.local else {
+                        continue;
+                    };
+                    let branch = pushed.branch();
+                    let applies = |options: &RuleOptions| {
+                        options_apply_on(&self.ctx, options, branch, remote)
+                    };
+                    let found =
+                        repo.pushed_blobs(local, pushed.remote.as_deref(), name.as_deref())?;
+                    blobs.extend(self.covered(found, applies)?);
+                }
+            }
+            // Bare repositories have no index
+            None if repo.is_bare() => {}
+            None => blobs.extend(self.covered(repo.index_blobs()?, current)?),
+        }
+        if history {
+            blobs.extend(self.covered(repo.history_blobs(None)?, current)?);
+        }
+        let identities = if quick {
+            vec![]
+        } else {
+            self.get_identities()?
+        };
+        if identities.is_empty() && !quick {
+            log::warn!("No identities are configured, skipping the decryption check");
+        }
+
+        let jobs = self.jobs(age::identities_use_plugins(&identities))?;
+        let timeout = self.decryption_timeout(&identities)?;
+
+        // Reading the blobs and the rules needs the repository, which stays on this thread
+        let checks = blobs
+            .into_iter()
+            .map(|blob| self.prepare_check(blob, quick))
+            .collect::<Result<Vec<_>>>()?;
+        let total = checks.len();
+
+        progress.start("verify", total);
+        let results = age::parallel_map(jobs, checks, |check| {
+            progress.begin(&check.blob.path);
+            let (problems, decrypted) = check.run(&identities, timeout);
+            if problems.is_empty() {
+                progress.complete(&check.blob.path, "ok", None);
+            } else {
+                progress.complete(&check.blob.path, "failed", Some(&problems.join(", ")));
+            }
+            (check.blob, problems, decrypted)
+        });
+
+        let mut failed = vec![];
+        for (blob, problems, decrypted) in results {
+            let path = self.ctx.repo().workdir().join(&blob.path);
+            let outcome = match &decrypted {
+                Some(Ok(identity)) => Some(Outcome::Success { identity }),
+                Some(Err(())) => Some(Outcome::Failure),
+                None => None,
+            };
+            self.audit("verify", &path, outcome);
+            if !problems.is_empty() {
+                let mut message = problems.join(", ");
+                if let Some(commit) = &blob.commit {
+                    message = format!("{} in commit {}", message, commit);
+                }
+                failed.push((blob, message));
+            }
+        }
+        progress.summary(
+            total,
+            &[("ok", total - failed.len()), ("failed", failed.len())],
+        );
+
+        if format == OutputFormat::Json {
+            let failed: Vec<_> = failed
+                .iter()
+                .map(|(blob, message)| {
+                    json!({ "path": blob.path, "commit": blob.commit, "message": message })
+                })
+                .collect();
+            output::print(&json!({
+                "checked": total,
+                "ok": total - failed.len(),
+                "failed": failed,
+            }))?;
+            if !failed.is_empty() {
+                // The report already lists the failures
+                return Err(Reported(ExitCode::ChecksFailed).into());
+            }
+            return Ok(());
+        }
+        if failed.is_empty() {
+            println!("All {} checked files are properly encrypted.", total);
+            return Ok(());
+        }
+        println!("The following files failed verification:");
+        for (blob, message) in &failed {
+            println!("    ⨯ {} -- {}", blob.path.display(), message);
+        }
+        Err(ChecksFailed(format!("Verification of {} files failed", failed.len())).into())
+    }
+
+    /// Reads a blob and the recipients it should be encrypted to
+    fn prepare_check(&self, blob: Blob, quick: bool) -> Result<Check> {
+        let contents = self.ctx.repo().read_blob(&blob.id)?;
+        let mut check = Check {
+            blob,
+            contents,
+            recipients: None,
+            problems: vec![],
+            quick,
+        };
+        if !is_encrypted(&check.contents) {
+            check.problems.push("stored as plaintext".into());
+            return Ok(check);
+        }
+        // Files in history were encrypted to the recipients at the time of the commit
+        if quick || check.blob.commit.is_some() {
+            return Ok(check);
+        }
+        let path = self.ctx.repo().workdir().join(&check.blob.path);
+        let rule = self.ctx.config()?.get_rule(&path)?;
+        match self.ctx.recipients().resolve_rule(&rule) {
+            Ok(public_keys) => check.recipients = Some((public_keys, rule.options.threshold)),
+            Err(err) => check
+                .problems
+                .push(format!("couldn't resolve recipients: {:#}", err)),
+        }
+        Ok(check)
+    }
+
+    /// Keeps the blobs of files covered by a rule which `applies`, see [`options_apply_on`]
+    fn covered(
+        &self,
+        blobs: Vec<Blob>,
+        applies: impl Fn(&RuleOptions) -> Result<bool>,
+    ) -> Result<Vec<Blob>> {
+        let paths: Vec<PathBuf> = blobs.iter().map(|b| b.path.clone()).collect();
+        let covered: HashSet<PathBuf> = self.ctx.config()?.paths(&paths)?.into_iter().collect();
+        let mut rv = vec![];
+        for blob in blobs.into_iter().filter(|b| covered.contains(&b.path)) {
+            let path = self.ctx.repo().workdir().join(&blob.path);
+            if self.rule_applies_with(&path, &applies)? {
+                rv.push(blob);
+            } else {
+                log::debug!("Rule doesn't apply, skipping file; file={:?}", blob.path);
+            }
+        }
+        Ok(rv)
+    }
+}
+
+/// A ref being pushed, as git passes it to the `pre-push` hook on stdin
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct PushedRef {
+    /// The local ref pushed, e.g. `refs/heads/main`
+    local_ref: String,
+    /// The commit pushed, `None` when the remote ref is deleted
+    local: Option<String>,
+    /// The commit the remote ref points to, `None` when it is created
+    remote: Option<String>,
+}
+
+impl PushedRef {
+    /// Reads the lines `<local ref> <local sha> <remote ref> <remote sha>` git writes to the
+    /// `pre-push` hook
+    pub(crate) fn read(input: impl BufRead) -> Result<Vec<Self>> {
+        let mut rv = vec![];
+        for line in input.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            // pre-commit leaves the remote ref and commit empty for a new branch
+            let (local_ref, local, remote) = match fields[..] {
+                [local_ref, local, _, remote] => (local_ref, local, Some(remote)),
+                [local_ref, local] | [local_ref, local, _] => (local_ref, local, None),
+                _ => bail!("Invalid line of refs being pushed: {:?}", line),
+            };
+            let commit = |sha: &str| Some(sha.to_string()).filter(|s| s.bytes().any(|b| b != b'0'));
+            rv.push(Self {
+                local_ref: local_ref.into(),
+                local: commit(local),
+                remote: remote.and_then(commit),
+            });
+        }
+        Ok(rv)
+    }
+
+    /// The branch pushed, `None` for other refs like tags
+    fn branch(&self) -> Option<&str> {
+        self.local_ref.strip_prefix("refs/heads/")
+    }
+}
+
+/// A blob to check, along with what is needed to check it on a worker thread
+struct Check {
+    blob: Blob,
+    contents: Vec<u8>,
+    /// The recipients and threshold of the rule, if the recipients are to be compared
+    recipients: Option<(Vec<String>, Option<u8>)>,
+    problems: Vec<String>,
+    quick: bool,
+}
+
+impl Check {
+    /// Returns the problems found and, if decryption was attempted, the identity that could
+    /// decrypt the blob
+    fn run(
+        &self,
+        identities: &[String],
+        timeout: Option<Duration>,
+    ) -> (Vec<String>, Option<std::result::Result<String, ()>>) {
+        let mut problems = self.problems.clone();
+        if !problems.is_empty() || self.quick {
+            return (problems, None);
+        }
+        if let Some((public_keys, threshold)) = &self.recipients {
+            match recipients_match(&self.contents, public_keys, *threshold) {
+                Ok(true) => {}
+                Ok(false) => {
+                    problems.push("encrypted to different recipients than the rule".into())
+                }
+                Err(err) => problems.push(format!("couldn't read recipients: {:#}", err)),
+            }
+        }
+        if identities.is_empty() {
+            return (problems, None);
+        }
+        let (identities, contents) = (identities.to_vec(), self.contents.clone());
+        match age::with_timeout(timeout, move || decrypt_any(&identities, &contents)) {
+            Ok(Some((_, identity))) => (problems, Some(Ok(identity))),
+            Ok(None) => (problems, None),
+            Err(err) => {
+                problems.push(format!(
+                    "can't be decrypted with the configured identities: {:#}",
+                    err
+                ));
+                (problems, Some(Err(())))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    fn test_pushed_ref_read() -> Result<()> {
+        let (local, remote, zero) = ("a".repeat(40), "b".repeat(40), "0".repeat(40));
+        let input = format!(
+            "refs/heads/main {local} refs/heads/main {remote}\n\
+             refs/tags/v1 {local} refs/tags/v1 {zero}\n\
+             (delete) {zero} refs/heads/old {remote}\n\
+             \n\
+             refs/heads/new {local} \n"
+        );
+        let pushed = PushedRef::read(input.as_bytes())?;
+        assert_eq!(pushed.len(), 4);
+        assert_eq!(pushed[0].branch(), Some("main"));
+        assert_eq!(pushed[0].local.as_deref(), Some(&local[..]));
+        assert_eq!(pushed[0].remote.as_deref(), Some(&remote[..]));
+        assert_eq!(pushed[1].branch(), None);
+        assert_eq!(pushed[1].remote, None);
+        assert_eq!(pushed[2].local, None);
+        // pre-commit leaves out the remote ref and commit of a new branch
+        assert_eq!(pushed[3].branch(), Some("new"));
+        assert_eq!(pushed[3].remote, None);
+
+        assert!(PushedRef::read(&b"refs/heads/main\n"[..]).is_err());
+        Ok(())
+    }
+}
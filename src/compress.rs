@@ -0,0 +1,156 @@
+//! Compression of the plaintext before encryption, see [`Compression`].
+//!
+//! Compressed plaintext starts with a header, so that plaintext written before compression was
+//! enabled, or with it disabled again, still decrypts unchanged:
+//!
+//! ```text
+//! git-agecrypt.org/zstd/v1
+//! <zstd frame>
+//! ```
+//!
+//! The header is part of the plaintext, it isn't visible without decrypting the file.
+
+use std::io::{self, Read, Write};
+
+use anyhow::Result;
+
+use crate::{config::Compression, secret::SecretBuf};
+
+const MAGIC: &[u8] = b"git-agecrypt.org/zstd/v1\n";
+
+/// The default zstd level, a good tradeoff for text files
+const LEVEL: i32 = 3;
+
+/// Whether `plaintext` was compressed by [`compress`]
+pub(crate) fn is_compressed(plaintext: &[u8]) -> bool {
+    plaintext.starts_with(MAGIC)
+}
+
+/// Compresses `input` as it is read
+pub(crate) fn compress<'a>(
+    compression: Compression,
+    input: impl Read + 'a,
+) -> Result<impl Read + 'a> {
+    let Compression::Zstd = compression;
+    let encoder = zstd::stream::read::Encoder::new(input, LEVEL)?;
+    Ok(io::Cursor::new(MAGIC).chain(encoder))
+}
+
+/// Decompresses `plaintext` if it was compressed, otherwise hands it back unchanged
+pub(crate) fn decompress(plaintext: SecretBuf) -> Result<SecretBuf> {
+    if !is_compressed(&plaintext) {
+        return Ok(plaintext);
+    }
+    let mut rv = SecretBuf::new();
+    zstd::stream::copy_decode(&plaintext[MAGIC.len()..], &mut rv)?;
+    Ok(rv)
+}
+
+/// Writes plaintext to `inner`, decompressing it if it turns out to be compressed.
+///
+/// [`DecompressWriter::finish`] has to be called once all plaintext was written.
+pub(crate) struct DecompressWriter<W: Write> {
+    state: State<W>,
+}
+
+enum State<W: Write> {
+    /// The header hasn't been seen completely yet
+    Undecided(W, SecretBuf),
+    Plain(W),
+    Compressed(zstd::stream::write::Decoder<'static, W>),
+    Finished,
+}
+
+impl<W: Write> DecompressWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            state: State::Undecided(inner, SecretBuf::new()),
+        }
+    }
+
+    /// Writes the rest of the plaintext and hands back the inner writer
+    pub fn finish(mut self) -> io::Result<W> {
+        let mut inner = match std::mem::replace(&mut self.state, State::Finished) {
+            State::Undecided(mut inner, head) => {
+                inner.write_all(&head)?;
+                inner
+            }
+            State::Plain(inner) => inner,
+            State::Compressed(mut decoder) => {
+                decoder.flush()?;
+                decoder.into_inner()
+            }
+            State::Finished => unreachable!(),
+        };
+        inner.flush()?;
+        Ok(inner)
+    }
+}
+
+impl<W: Write> Write for DecompressWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = match &mut self.state {
+            State::Plain(inner) => return inner.write(buf),
+            State::Compressed(decoder) => return decoder.write(buf),
+            State::Finished => unreachable!(),
+            State::Undecided(_, head) => {
+                let n = buf.len().min(MAGIC.len() - head.len());
+                head.extend_from_slice(&buf[..n]);
+                if head.len() < MAGIC.len() && MAGIC.starts_with(head) {
+                    return Ok(n);
+                }
+                n
+            }
+        };
+        // The rest of `buf` is passed on by the next call
+        let State::Undecided(mut inner, head) = std::mem::replace(&mut self.state, State::Finished)
+        else {
+            unreachable!()
+        };
+        self.state = if is_compressed(&head) {
+            State::Compressed(zstd::stream::write::Decoder::new(inner)?)
+        } else {
+            inner.write_all(&head)?;
+            State::Plain(inner)
+        };
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.state {
+            State::Undecided(..) | State::Finished => Ok(()),
+            State::Plain(inner) => inner.flush(),
+            State::Compressed(decoder) => decoder.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case(b"")]
+    #[case(b"git")]
+    #[case(b"git-agecrypt.org/threshold/v1\n")]
+    #[case(b"some contents\nsome contents\nsome contents\n")]
+    fn test_roundtrip(#[case] plaintext: &[u8]) -> Result<()> {
+        let mut compressed = vec![];
+        compress(Compression::Zstd, plaintext)?.read_to_end(&mut compressed)?;
+        assert!(is_compressed(&compressed));
+        assert_eq!(decompress(compressed.clone().into())?, plaintext);
+        assert_eq!(decompress(plaintext.to_vec().into())?, plaintext);
+
+        // Written in small chunks, so that the header is split
+        for input in [&compressed[..], plaintext] {
+            let mut writer = DecompressWriter::new(vec![]);
+            for chunk in input.chunks(7) {
+                writer.write_all(chunk)?;
+            }
+            assert_eq!(writer.finish()?, plaintext);
+        }
+        Ok(())
+    }
+}
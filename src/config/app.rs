@@ -1,55 +1,143 @@
 use std::{
-    collections::HashMap,
-    fs, io,
-    path::{Path, PathBuf},
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt, fs, io,
+    path::{Component, Path, PathBuf},
 };
 
 use anyhow::{anyhow, Context};
 use serde::{Deserialize, Serialize};
 
-use crate::age;
+use crate::recipients;
 
-use super::Result;
+use super::{Error, Result, Rule};
 
-#[derive(Serialize, Deserialize, PartialEq, Eq)]
-pub struct RecipientEntry {
-    paths: Vec<PathBuf>,
-    recipients: Vec<String>,
-}
+/// Rules files looked for in the repository root, in order of preference. Subdirectories can
+/// have their own rules file with one of these names, see [`AppConfig::get_rule`].
+pub(crate) const CONFIG_FILES: &[&str] =
+    &["git-agecrypt.toml", "git-agecrypt.yaml", "git-agecrypt.yml"];
+
+/// Recipients file of a subdirectory, providing the recipients of the files below it no rule
+/// covers, see [`AppConfig::lookup`]
+pub(crate) const RECIPIENTS_FILE: &str = ".agrecipients";
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct AppConfig {
-    config: HashMap<PathBuf, Vec<String>>,
+    /// Named lists of recipients, which rules can reference by name
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    groups: HashMap<String, Vec<String>>,
+    config: HashMap<PathBuf, Rule>,
     #[serde(skip)]
     path: PathBuf,
     #[serde(skip)]
     prefix: PathBuf,
+    /// Directory of a per-directory rules file relative to the repository root, empty for the
+    /// rules file of the repository
+    #[serde(skip)]
+    dir: PathBuf,
+    /// Recipients of this rules file age can't encrypt to, found when it is loaded
+    #[serde(skip)]
+    invalid: Vec<InvalidRecipient>,
+}
+
+/// A recipient of a rules file age can't encrypt to, e.g. a mistyped key
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct InvalidRecipient {
+    pub file: PathBuf,
+    /// The rule listing the recipient, relative to the directory of the rules file
+    pub rule: Option<PathBuf>,
+    /// The group listing the recipient
+    pub group: Option<String>,
+    pub recipient: String,
+    /// What is wrong with it
+    pub reason: String,
+}
+
+impl fmt::Display for InvalidRecipient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid recipient '{}' in ", self.recipient)?;
+        match (&self.rule, &self.group) {
+            (Some(rule), _) => write!(f, "rule '{}'", rule.display())?,
+            (None, Some(group)) => write!(f, "group '{}'", group)?,
+            (None, None) => write!(f, "rules")?,
+        }
+        write!(
+            f,
+            " of '{}': {} (recipients are {})",
+            self.file.display(),
+            self.reason,
+            recipients::FORMATS
+        )
+    }
 }
 
 impl AppConfig {
     pub fn load(path: &Path, repo_prefix: &Path) -> Result<Self> {
         match fs::read_to_string(path) {
-            Ok(contents) => {
-                let mut cfg: AppConfig = toml::from_str(&contents).with_context(|| {
-                    format!("Couldn't load configuration file '{}'", path.display())
-                })?;
-                cfg.path = path.into();
-                cfg.prefix = repo_prefix.into();
-                Ok(cfg)
-            }
-            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self {
-                config: HashMap::new(),
-                path: path.into(),
-                prefix: repo_prefix.into(),
-            }),
+            Ok(contents) => Self::parse(path, &contents, repo_prefix),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::empty(path, repo_prefix)),
             Err(err) => Ok(Err(err).with_context(|| {
                 format!("Couldn't read configuration file '{}'", path.display())
             })?),
         }
     }
 
+    /// The rules in `contents`, as read from `path`, e.g. from a commit
+    pub fn parse(path: &Path, contents: &str, repo_prefix: &Path) -> Result<Self> {
+        let mut cfg = Format::of(path)
+            .parse(contents)
+            .with_context(|| format!("Couldn't load configuration file '{}'", path.display()))?;
+        cfg.path = path.into();
+        cfg.prefix = repo_prefix.into();
+        cfg.invalid = cfg.find_invalid_recipients();
+        Ok(cfg)
+    }
+
+    fn find_invalid_recipients(&self) -> Vec<InvalidRecipient> {
+        let groups = self
+            .groups
+            .iter()
+            .flat_map(|(name, members)| members.iter().map(move |m| (None, Some(name), m)));
+        let rules = self
+            .config
+            .iter()
+            .flat_map(|(key, rule)| rule.recipients.iter().map(move |r| (Some(key), None, r)));
+        let mut rv: Vec<InvalidRecipient> = groups
+            .chain(rules)
+            .filter(|(_, _, recipient)| !self.groups.contains_key(*recipient))
+            .filter_map(|(rule, group, recipient)| {
+                let err = recipients::check(recipient).err()?;
+                Some(InvalidRecipient {
+                    file: self.path.clone(),
+                    rule: rule.cloned(),
+                    group: group.cloned(),
+                    recipient: recipient.clone(),
+                    reason: format!("{:#}", err),
+                })
+            })
+            .collect();
+        rv.sort_by(|a, b| (&a.rule, &a.group).cmp(&(&b.rule, &b.group)));
+        rv
+    }
+
+    /// The recipients of this rules file age can't encrypt to
+    pub fn invalid_recipients(&self) -> &[InvalidRecipient] {
+        &self.invalid
+    }
+
+    /// No rules, to be saved to `path`
+    pub fn empty(path: &Path, repo_prefix: &Path) -> Self {
+        Self {
+            groups: HashMap::new(),
+            config: HashMap::new(),
+            path: path.into(),
+            prefix: repo_prefix.into(),
+            dir: PathBuf::new(),
+            invalid: vec![],
+        }
+    }
+
     pub fn save(&self) -> Result<()> {
-        let cfg = toml::to_string_pretty(self).context("Coldn't format configuration as TOML")?;
+        let cfg = Format::of(&self.path).format(self)?;
         fs::write(&self.path, cfg).with_context(|| {
             format!("Couldn't save configuration file '{}'", self.path.display())
         })?;
@@ -57,10 +145,14 @@ impl AppConfig {
     }
 
     pub fn add(&mut self, recipients: Vec<String>, paths: Vec<PathBuf>) -> Result<()> {
-        age::validate_public_keys(&recipients)?;
+        let plain: Vec<&String> = recipients
+            .iter()
+            .filter(|r| !self.groups.contains_key(*r))
+            .collect();
+        recipients::validate(&plain)?;
         let invalid_paths: Vec<String> = paths
             .iter()
-            .filter(|&p| !p.is_file())
+            .filter(|&p| !p.exists() && !is_pattern(p))
             .map(|f| f.to_string_lossy().to_string())
             .collect();
         if !invalid_paths.is_empty() {
@@ -71,23 +163,58 @@ impl AppConfig {
             .into());
         }
         for path in paths {
-            let entry = self.config.entry(path).or_default();
-            entry.extend(recipients.clone().into_iter());
+            let entry = &mut self
+                .config
+                .entry(normalize_path(&path))
+                .or_default()
+                .recipients;
+            entry.extend(recipients.clone());
             entry.dedup();
         }
         Ok(())
     }
 
+    /// Adds a rule for a file relative to the repository root, merging it into an existing one
+    pub fn add_rule(&mut self, path: &Path, rule: Rule) -> Result<()> {
+        let plain: Vec<&String> = rule
+            .recipients
+            .iter()
+            .filter(|r| !self.groups.contains_key(*r))
+            .collect();
+        recipients::validate(&plain)?;
+        let entry = self.config.entry(normalize_path(path)).or_default();
+        entry.recipients.extend(rule.recipients);
+        entry.recipients.dedup();
+        let mut options = rule.options;
+        options.merge(&entry.options);
+        entry.options = options;
+        Ok(())
+    }
+
+    /// Whether the rules file defines a group named `name`
+    pub fn has_group(&self, name: &str) -> bool {
+        self.groups.contains_key(name)
+    }
+
+    /// The configuration file, which need not exist yet
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
     pub fn remove(&mut self, recipients: Vec<String>, paths: Vec<PathBuf>) -> Result<()> {
         if paths.is_empty() {
-            for rs in self.config.values_mut() {
-                rs.retain(|r| !recipients.contains(r));
+            for rule in self.config.values_mut() {
+                rule.recipients.retain(|r| !recipients.contains(r));
             }
         } else {
             for path in paths {
-                let rs = self.config.get_mut(&path).with_context(|| {
-                    format!("No configuration entry found for {}", path.display())
-                })?;
+                let rs = &mut self
+                    .config
+                    .get_mut(&path)
+                    .with_context(|| {
+                        format!("No configuration entry found for {}", path.display())
+                    })?
+                    .recipients;
                 if recipients.is_empty() {
                     rs.clear();
                 } else {
@@ -96,31 +223,965 @@ impl AppConfig {
             }
         }
 
-        self.config.retain(|_, rs| !rs.is_empty());
+        self.config.retain(|_, rule| !rule.recipients.is_empty());
 
         Ok(())
     }
 
     pub fn list(&self) -> Vec<(String, String)> {
         let mut rv = vec![];
-        for (p, rs) in &self.config {
-            for r in rs {
+        for (p, rule) in &self.config {
+            for r in &rule.recipients {
                 rv.push((p.to_string_lossy().to_string(), r.clone()));
             }
         }
         rv
     }
 
-    pub fn get_public_keys(&self, path: &Path) -> Result<&[String]> {
-        let pubk = self
-            .config
-            .get(path.strip_prefix(&self.prefix).with_context(|| {
+    /// Files covered by a rule, relative to the repository root.
+    ///
+    /// These are the paths of rules naming a single file, and the `tracked` files matching a
+    /// pattern or directory rule, including the rules of per-directory rules files.
+    pub fn paths(&self, tracked: &[PathBuf]) -> Result<Vec<PathBuf>> {
+        let mut rv = self.own_paths(tracked);
+        for nested in self.nested(tracked)? {
+            rv.extend(nested.own_paths(tracked));
+        }
+        let matches = self.recipients_file_matches(tracked, &rv);
+        rv.extend(matches.into_values().flatten());
+        rv.sort();
+        rv.dedup();
+        Ok(rv)
+    }
+
+    fn own_paths(&self, tracked: &[PathBuf]) -> Vec<PathBuf> {
+        let keys: Vec<PathBuf> = self.config.keys().map(|p| normalize_path(p)).collect();
+        keys.iter()
+            .filter(|k| !is_pattern(k) && !self.prefix.join(k).is_dir())
+            .map(|k| self.dir.join(k))
+            .chain(tracked.iter().map(|f| normalize_path(f)).filter(|f| {
+                f.strip_prefix(&self.dir)
+                    .is_ok_and(|relpath| keys.iter().any(|k| self.rule_match(k, relpath).is_some()))
+            }))
+            .collect()
+    }
+
+    /// `.gitattributes` patterns matching the files covered by the rules, including the rules
+    /// of the per-directory rules files and the recipients files among the `tracked` files
+    pub fn attribute_patterns(&self, tracked: &[PathBuf]) -> Result<Vec<String>> {
+        let mut rv = self.own_attribute_patterns();
+        for nested in self.nested(tracked)? {
+            rv.extend(nested.own_attribute_patterns());
+        }
+        for file in tracked.iter().map(|f| normalize_path(f)) {
+            let dir = file.parent().unwrap_or(Path::new(""));
+            if file.file_name().is_some_and(|n| n == RECIPIENTS_FILE) {
+                if !dir.as_os_str().is_empty() {
+                    rv.push(attribute_pattern(&format!("{}/**", slash_path(dir))));
+                }
+            } else if file.extension().is_some_and(|e| e == "pub") {
+                let secret = file.with_extension("");
+                if tracked.contains(&secret) || self.prefix.join(&secret).is_file() {
+                    rv.push(attribute_pattern(&slash_path(&secret)));
+                }
+            }
+        }
+        rv.sort();
+        rv.dedup();
+        Ok(rv)
+    }
+
+    /// `.gitattributes` patterns of the files in the directories of the `.agrecipients` files
+    /// among the `tracked` files which must stay plaintext, as git-agecrypt reads them
+    pub fn attribute_exclusions(&self, tracked: &[PathBuf]) -> Vec<String> {
+        let mut rv = vec![];
+        for file in tracked.iter().map(|f| normalize_path(f)) {
+            let Some(dir) = file.parent().filter(|d| !d.as_os_str().is_empty()) else {
+                continue;
+            };
+            if file.file_name().is_some_and(|n| n == RECIPIENTS_FILE) {
+                let names = [RECIPIENTS_FILE, "*.pub", ".gitattributes"];
+                for name in names.iter().chain(CONFIG_FILES) {
+                    rv.push(attribute_pattern(&format!(
+                        "{}/**/{}",
+                        slash_path(dir),
+                        name
+                    )));
+                }
+            }
+        }
+        rv.sort();
+        rv.dedup();
+        rv
+    }
+
+    fn own_attribute_patterns(&self) -> Vec<String> {
+        self.config
+            .keys()
+            .map(|p| {
+                let key = normalize_path(p);
+                let mut pattern = slash_path(&self.dir.join(&key));
+                if !is_pattern(&key) && self.prefix.join(&key).is_dir() {
+                    pattern.push_str("/**");
+                }
+                attribute_pattern(&pattern)
+            })
+            .collect()
+    }
+
+    /// The per-directory rules files among the `tracked` files
+    pub fn nested(&self, tracked: &[PathBuf]) -> Result<Vec<Self>> {
+        let mut dirs: Vec<&Path> = tracked
+            .iter()
+            .filter(|f| {
+                f.file_name()
+                    .is_some_and(|name| CONFIG_FILES.iter().any(|c| name == *c))
+            })
+            .filter_map(|f| f.parent())
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .collect();
+        dirs.sort();
+        dirs.dedup();
+        let mut rv = vec![];
+        for dir in dirs {
+            rv.extend(self.load_nested(dir)?);
+        }
+        Ok(rv)
+    }
+
+    /// Loads the rules file of the repository subdirectory `dir`, if it has one. Its rules are
+    /// relative to `dir`, and the groups it doesn't define itself are taken over from `self`.
+    fn load_nested(&self, dir: &Path) -> Result<Option<Self>> {
+        let prefix = self.prefix.join(dir);
+        let Some(path) = CONFIG_FILES
+            .iter()
+            .map(|name| prefix.join(name))
+            .find(|path| path.is_file() && *path != self.path)
+        else {
+            return Ok(None);
+        };
+        let mut cfg = Self::load(&path, &prefix)?;
+        cfg.dir = normalize_path(dir);
+        for (name, members) in &self.groups {
+            if cfg.groups.contains_key(name) {
+                continue;
+            }
+            cfg.groups.insert(name.clone(), members.clone());
+            // Reported for this rules file too, so that its rules using the group fail
+            cfg.invalid.extend(
+                self.invalid
+                    .iter()
+                    .filter(|i| i.group.as_ref() == Some(name))
+                    .cloned(),
+            );
+        }
+        // Names of inherited groups were taken for recipients when the file was loaded
+        let groups = &cfg.groups;
+        cfg.invalid.retain(|i| !groups.contains_key(&i.recipient));
+        Ok(Some(cfg))
+    }
+
+    /// Lists groups of rule keys which refer to the same file, e.g. `./foo` and `foo`
+    pub fn duplicates(&self) -> Vec<Vec<PathBuf>> {
+        let mut targets: HashMap<PathBuf, Vec<PathBuf>> = HashMap::new();
+        for p in self.config.keys() {
+            targets
+                .entry(normalize_path(p))
+                .or_default()
+                .push(p.clone());
+        }
+        let mut rv: Vec<Vec<PathBuf>> = targets
+            .into_values()
+            .filter(|keys| keys.len() > 1)
+            .map(|mut keys| {
+                keys.sort();
+                keys
+            })
+            .collect();
+        rv.sort();
+        rv
+    }
+
+    /// Human readable descriptions of problems found in the configuration
+    pub fn warnings(&self) -> Vec<String> {
+        self.duplicates()
+            .into_iter()
+            .map(|keys| {
+                let keys: Vec<String> = keys.iter().map(|k| format!("'{}'", k.display())).collect();
                 format!(
-                    "Not a path inside git repository, path={path:?}, repo={:?}",
-                    self.prefix
+                    "Rules {} target the same file, their recipients are merged",
+                    keys.join(", ")
                 )
-            })?)
-            .with_context(|| format!("No public key can be found for '{}'", path.display()))?;
-        Ok(&pubk[..])
+            })
+            .collect()
+    }
+
+    /// Looks up the rule of a file, merging all rules which target it.
+    ///
+    /// The closest rules file in the directories of the file with a matching rule is used, the
+    /// rules file of the repository if there is none. When several rules of a file match, the
+    /// one naming the file exactly wins, or else the most specific directory or glob pattern,
+    /// see [`Precedence`].
+    pub fn get_rule(&self, path: &Path) -> Result<Rule> {
+        let rv = self.lookup(path)?.map(|(_, rule)| rule);
+        rv.ok_or_else(|| Error::NoRule(path.to_path_buf()))
+    }
+
+    /// Like [`get_rule`](Self::get_rule), also returning the path of the rule relative to the
+    /// repository root, `None` if no rule matches.
+    ///
+    /// Files without a rule are encrypted to the recipients in the `<file>.pub` next to them, or
+    /// else in the closest `.agrecipients` of the subdirectories they are in, like agenix does.
+    /// The path is then the file or the directory of the `.agrecipients`.
+    pub fn lookup(&self, path: &Path) -> Result<Option<(PathBuf, Rule)>> {
+        let relpath = normalize_path(
+            normalize_path(path)
+                .strip_prefix(normalize_path(&self.prefix))
+                .with_context(|| {
+                    format!(
+                        "Not a path inside git repository, path={path:?}, repo={:?}",
+                        self.prefix
+                    )
+                })?,
+        );
+        for dir in relpath
+            .ancestors()
+            .skip(1)
+            .filter(|dir| !dir.as_os_str().is_empty())
+        {
+            if let Some(nested) = self.load_nested(dir)? {
+                let rule = nested.find_rule(relpath.strip_prefix(dir).unwrap())?;
+                if rule.is_some() {
+                    log::debug!("Using rule of {:?}; file={:?}", nested.path, relpath);
+                    return Ok(rule);
+                }
+            }
+        }
+        if let Some(rule) = self.find_rule(&relpath)? {
+            return Ok(Some(rule));
+        }
+        let found = recipients_file(&relpath, |f| self.prefix.join(f).is_file());
+        Ok(found.map(|(path, file)| {
+            log::debug!("Using recipients file {:?}; file={:?}", file, relpath);
+            (path, recipients_file_rule(&file))
+        }))
+    }
+
+    /// The `tracked` files not among the `covered` ones with a recipients file among the
+    /// `tracked` files, keyed by the path of the rule and the recipients file
+    fn recipients_file_matches(
+        &self,
+        tracked: &[PathBuf],
+        covered: &[PathBuf],
+    ) -> BTreeMap<(PathBuf, PathBuf), Vec<PathBuf>> {
+        let tracked: Vec<PathBuf> = tracked.iter().map(|f| normalize_path(f)).collect();
+        let files: HashSet<&PathBuf> = tracked.iter().filter(|f| is_recipients_file(f)).collect();
+        let mut rv: BTreeMap<_, Vec<PathBuf>> = BTreeMap::new();
+        if files.is_empty() {
+            return rv;
+        }
+        for file in tracked.iter().filter(|f| !covered.contains(f)) {
+            if let Some(key) = recipients_file(file, |f| files.contains(&f.to_path_buf())) {
+                rv.entry(key).or_default().push(file.clone());
+            }
+        }
+        rv
+    }
+
+    /// The path relative to the repository root and the merged rules matching `relpath` of
+    /// this rules file, with groups expanded
+    fn find_rule(&self, relpath: &Path) -> Result<Option<(PathBuf, Rule)>> {
+        let best = self
+            .config
+            .keys()
+            .map(|p| normalize_path(p))
+            .filter_map(|p| Some((self.rule_match(&p, relpath)?, p)))
+            .max();
+        let Some((_, key)) = best else {
+            return Ok(None);
+        };
+        let rule = self.merged_rule(&key)?;
+        if let Some(invalid) = self
+            .invalid
+            .iter()
+            .find(|i| rule.recipients.contains(&i.recipient))
+        {
+            return Err(Error::InvalidRecipient(invalid.clone()));
+        }
+        Ok(Some((self.dir.join(&key), rule)))
+    }
+
+    /// Merges the rules whose normalized path is `key`, expanding groups
+    fn merged_rule(&self, key: &Path) -> Result<Rule> {
+        let mut rv = Rule::default();
+        for (p, rule) in &self.config {
+            if normalize_path(p) == key {
+                for r in &rule.recipients {
+                    if !rv.recipients.contains(r) {
+                        rv.recipients.push(r.clone());
+                    }
+                }
+                rv.options.merge(&rule.options);
+            }
+        }
+        rv.recipients = self.expand_groups(&rv.recipients)?;
+        Ok(rv)
+    }
+
+    /// The rules of this and the per-directory rules files among the `tracked` files, with
+    /// rules targeting the same file merged and groups expanded
+    pub fn rules(&self, tracked: &[PathBuf]) -> Result<Vec<RuleEntry>> {
+        let mut rv = self.own_rules(tracked)?;
+        for nested in self.nested(tracked)? {
+            rv.extend(nested.own_rules(tracked)?);
+        }
+        let covered: Vec<PathBuf> = rv.iter().flat_map(|r| r.files.clone()).collect();
+        for ((path, file), files) in self.recipients_file_matches(tracked, &covered) {
+            rv.push(RuleEntry {
+                path,
+                rules_file: self.prefix.join(&file),
+                rule: recipients_file_rule(&file),
+                files,
+            });
+        }
+        Ok(rv)
+    }
+
+    fn own_rules(&self, tracked: &[PathBuf]) -> Result<Vec<RuleEntry>> {
+        let mut keys: Vec<PathBuf> = self.config.keys().map(|p| normalize_path(p)).collect();
+        keys.sort();
+        keys.dedup();
+        let mut rv = vec![];
+        for key in keys {
+            let files = if is_pattern(&key) || self.prefix.join(&key).is_dir() {
+                tracked
+                    .iter()
+                    .map(|f| normalize_path(f))
+                    .filter(|f| {
+                        f.strip_prefix(&self.dir)
+                            .is_ok_and(|relpath| self.rule_match(&key, relpath).is_some())
+                    })
+                    .collect()
+            } else if self.prefix.join(&key).is_file() || tracked.contains(&self.dir.join(&key)) {
+                vec![self.dir.join(&key)]
+            } else {
+                vec![]
+            };
+            rv.push(RuleEntry {
+                path: self.dir.join(&key),
+                rules_file: self.path.clone(),
+                rule: self.merged_rule(&key)?,
+                files,
+            });
+        }
+        Ok(rv)
+    }
+
+    /// Replaces the names of groups among `recipients` with their members
+    fn expand_groups(&self, recipients: &[String]) -> Result<Vec<String>> {
+        let mut rv = vec![];
+        self.expand_into(recipients, &mut vec![], &mut rv)?;
+        Ok(rv)
+    }
+
+    fn expand_into<'a>(
+        &'a self,
+        recipients: &'a [String],
+        expanding: &mut Vec<&'a str>,
+        rv: &mut Vec<String>,
+    ) -> Result<()> {
+        for recipient in recipients {
+            match self.groups.get(recipient) {
+                Some(members) => {
+                    if expanding.contains(&recipient.as_str()) {
+                        return Err(
+                            anyhow!("Recipient group '{}' includes itself", recipient).into()
+                        );
+                    }
+                    expanding.push(recipient);
+                    self.expand_into(members, expanding, rv)?;
+                    expanding.pop();
+                }
+                None if !rv.contains(recipient) => rv.push(recipient.clone()),
+                None => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Matches a normalized rule key against a normalized file path
+    fn rule_match(&self, key: &Path, relpath: &Path) -> Option<Precedence> {
+        if key == relpath {
+            return Some(Precedence::Exact);
+        }
+        let key_str = slash_path(key);
+        let specificity = key_str.chars().filter(|c| !"*?[]".contains(*c)).count();
+        let matches = if is_pattern(key) {
+            glob::Pattern::new(&key_str)
+                .map(|p| {
+                    let options = glob::MatchOptions {
+                        require_literal_separator: true,
+                        ..Default::default()
+                    };
+                    p.matches_with(&slash_path(relpath), options)
+                })
+                .unwrap_or(false)
+        } else {
+            relpath.starts_with(key) && self.prefix.join(key).is_dir()
+        };
+        matches.then_some(Precedence::Pattern(specificity))
+    }
+}
+
+/// A rule of a rules file, see [`AppConfig::rules`]
+pub struct RuleEntry {
+    /// The path or pattern of the rule relative to the repository root
+    pub path: PathBuf,
+    /// The rules file or recipients file the rule comes from
+    pub rules_file: PathBuf,
+    pub rule: Rule,
+    /// The existing files the rule matches, relative to the repository root
+    pub files: Vec<PathBuf>,
+}
+
+/// How well a rule matches a file, higher is better
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Precedence {
+    /// Directory or glob pattern, ranked by the number of non-wildcard characters
+    Pattern(usize),
+    /// The rule names the file itself
+    Exact,
+}
+
+fn is_pattern(path: &Path) -> bool {
+    path.to_string_lossy().contains(['*', '?', '['])
+}
+
+fn is_recipients_file(path: &Path) -> bool {
+    path.file_name().is_some_and(|n| n == RECIPIENTS_FILE)
+        || path.extension().is_some_and(|e| e == "pub")
+}
+
+/// The path of the rule and the recipients file providing the recipients of `relpath`, the
+/// `<file>.pub` next to it or else the closest `.agrecipients` below the repository root for
+/// which `exists` holds. Recipients, rules and attributes files are never encrypted this way.
+fn recipients_file(relpath: &Path, exists: impl Fn(&Path) -> bool) -> Option<(PathBuf, PathBuf)> {
+    let name = relpath.file_name()?;
+    if is_recipients_file(relpath)
+        || name == ".gitattributes"
+        || CONFIG_FILES.iter().any(|c| name == *c)
+    {
+        return None;
+    }
+    let mut public_keys = relpath.as_os_str().to_owned();
+    public_keys.push(".pub");
+    std::iter::once((relpath.to_path_buf(), PathBuf::from(public_keys)))
+        .chain(
+            relpath
+                .ancestors()
+                .skip(1)
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .map(|dir| (dir.to_path_buf(), dir.join(RECIPIENTS_FILE))),
+        )
+        .find(|(_, file)| exists(file))
+}
+
+/// A rule encrypting to the public keys in the recipients file `file`
+fn recipients_file_rule(file: &Path) -> Rule {
+    Rule {
+        recipients: vec![format!("file:{}", glob::Pattern::escape(&slash_path(file)))],
+        ..Rule::default()
+    }
+}
+
+/// A `.gitattributes` pattern for `path` relative to the repository root, quoted if needed
+fn attribute_pattern(path: &str) -> String {
+    let pattern = format!("/{}", path);
+    if pattern.contains(char::is_whitespace) || pattern.contains('"') {
+        format!("\"{}\"", pattern.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        pattern
+    }
+}
+
+/// Serialization formats of the configuration file, chosen by its extension
+#[derive(Debug, PartialEq, Eq)]
+enum Format {
+    Toml,
+    Yaml,
+}
+
+impl Format {
+    fn of(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("yaml" | "yml") => Self::Yaml,
+            _ => Self::Toml,
+        }
+    }
+
+    fn parse(&self, contents: &str) -> anyhow::Result<AppConfig> {
+        Ok(match self {
+            Self::Toml => toml::from_str(contents)?,
+            Self::Yaml => serde_yaml::from_str(contents)?,
+        })
+    }
+
+    fn format(&self, cfg: &AppConfig) -> anyhow::Result<String> {
+        Ok(match self {
+            Self::Toml => {
+                toml::to_string_pretty(cfg).context("Coldn't format configuration as TOML")?
+            }
+            Self::Yaml => {
+                serde_yaml::to_string(cfg).context("Couldn't format configuration as YAML")?
+            }
+        })
+    }
+}
+
+/// Lexically normalizes a repository relative path, so `./foo/../bar` and `bar` compare equal
+pub(crate) fn normalize_path(path: &Path) -> PathBuf {
+    let mut rv = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !rv.pop() {
+                    rv.push(component);
+                }
+            }
+            // Drive letters are case insensitive, `c:\repo` and `C:\repo` are the same
+            Component::Prefix(prefix) => rv.push(prefix.as_os_str().to_ascii_uppercase()),
+            c => rv.push(c),
+        }
+    }
+    rv
+}
+
+/// The path with `/` separators, as git and the glob patterns of rules use them on every
+/// platform
+fn slash_path(path: &Path) -> String {
+    let rv = path.to_string_lossy();
+    if cfg!(windows) {
+        rv.replace('\\', "/")
+    } else {
+        rv.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use assert_matches::assert_matches;
+    use rstest::rstest;
+
+    use super::*;
+    use crate::config::Mode;
+
+    #[rstest]
+    fn test_rule_formats() -> Result<()> {
+        let cfg = parse(
+            r#"
+            [config]
+            "plain" = ["a"]
+            "detailed" = { recipients = ["b", "c"], threshold = 2 }
+            "armored" = { recipients = ["d"], armor = true }
+            "values.yaml" = { recipients = ["e"], mode = "values" }
+            "hinted" = { recipients = ["f"], identityHint = "yubikey" }
+            "kms" = { recipients = [], kms = "arn:aws:kms:eu-west-1:111122223333:key/ci" }
+            "#,
+        );
+        let plain = cfg.get_rule(Path::new("/repo/plain"))?;
+        assert_eq!(plain.recipients, ["a"]);
+        assert_eq!(plain.options.threshold, None);
+        let detailed = cfg.get_rule(Path::new("/repo/detailed"))?;
+        assert_eq!(detailed.recipients, ["b", "c"]);
+        assert_eq!(detailed.options.threshold, Some(2));
+        assert_eq!(detailed.options.armor, None);
+        let armored = cfg.get_rule(Path::new("/repo/armored"))?;
+        assert_eq!(armored.options.armor, Some(true));
+        assert_eq!(armored.options.mode, None);
+        let values = cfg.get_rule(Path::new("/repo/values.yaml"))?;
+        assert_eq!(values.options.mode, Some(Mode::Values));
+        let hinted = cfg.get_rule(Path::new("/repo/hinted"))?;
+        assert_eq!(hinted.options.identity_hint.as_deref(), Some("yubikey"));
+        let kms = cfg.get_rule(Path::new("/repo/kms"))?;
+        assert!(kms.recipients.is_empty());
+        assert_eq!(
+            kms.options.kms.as_deref(),
+            Some("arn:aws:kms:eu-west-1:111122223333:key/ci")
+        );
+
+        let saved = toml::to_string(&cfg)?;
+        let reloaded: AppConfig = toml::from_str(&saved)?;
+        assert_eq!(reloaded.config, cfg.config);
+        assert!(saved.contains(r#"plain = ["a"]"#));
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_scoped_rules() -> Result<()> {
+        let cfg = parse(
+            r#"
+            [config]
+            "everywhere" = ["a"]
+            "release" = { recipients = ["b"], branches = ["main", "release/*"] }
+            "public" = { recipients = ["c"], remotes = ["public"] }
+            "#,
+        );
+        let everywhere = cfg.get_rule(Path::new("/repo/everywhere"))?.options;
+        assert!(!everywhere.is_scoped());
+        assert!(everywhere.applies_to(Some("internal"), Some("origin")));
+
+        let release = cfg.get_rule(Path::new("/repo/release"))?.options;
+        assert!(release.is_scoped());
+        assert!(release.applies_to(Some("main"), Some("origin")));
+        assert!(release.applies_to(Some("release/1.0"), None));
+        assert!(!release.applies_to(Some("internal"), Some("origin")));
+        assert!(release.applies_to(None, Some("origin")));
+
+        let public = cfg.get_rule(Path::new("/repo/public"))?.options;
+        assert!(public.applies_to(Some("internal"), Some("public")));
+        assert!(!public.applies_to(Some("main"), Some("origin")));
+        assert!(public.applies_to(Some("main"), None));
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_groups() -> Result<()> {
+        let cfg = parse(
+            r#"
+            [groups]
+            admins = ["a", "b"]
+            everyone = ["admins", "c"]
+            loop = ["loop"]
+
+            [config]
+            "admin" = ["admins", "b", "d"]
+            "all" = { recipients = ["everyone"], armor = true }
+            "broken" = ["loop"]
+            "#,
+        );
+        let rule = |p: &str| cfg.get_rule(&Path::new("/repo").join(p));
+        assert_eq!(rule("admin")?.recipients, ["a", "b", "d"]);
+        assert_eq!(rule("all")?.recipients, ["a", "b", "c"]);
+        assert!(rule("broken").is_err());
+
+        let saved = toml::to_string(&cfg)?;
+        let reloaded: AppConfig = toml::from_str(&saved)?;
+        assert_eq!(reloaded.groups, cfg.groups);
+        assert!(!toml::to_string(&parse("[config]"))?.contains("groups"));
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_yaml() -> Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let path = dir.path().join("git-agecrypt.yaml");
+        fs::write(
+            &path,
+            "config:\n  plain: ['github:a']\n  detailed:\n    recipients: ['github:b']\n    threshold: 1\n",
+        )?;
+
+        let cfg = AppConfig::load(&path, dir.path())?;
+        assert_eq!(cfg.get_rule(&dir.path().join("plain"))?.recipients, ["github:a"]);
+        let detailed = cfg.get_rule(&dir.path().join("detailed"))?;
+        assert_eq!(detailed.options.threshold, Some(1));
+
+        cfg.save()?;
+        assert_eq!(AppConfig::load(&path, dir.path())?.config, cfg.config);
+        assert!(fs::read_to_string(&path)?.starts_with("config:"));
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_patterns() -> Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        fs::create_dir_all(dir.path().join("secrets/prod"))?;
+        let mut cfg: AppConfig = toml::from_str(
+            r#"
+            [config]
+            "secrets" = ["dir"]
+            "secrets/**/*.env" = ["env"]
+            "secrets/prod/*.env" = ["prod"]
+            "secrets/prod/db.env" = ["db"]
+            "#,
+        )?;
+        cfg.prefix = dir.path().into();
+        let rule = |p: &str| cfg.get_rule(&dir.path().join(p)).unwrap().recipients;
+
+        assert_eq!(rule("secrets/prod/db.env"), ["db"]);
+        assert_eq!(rule("secrets/prod/api.env"), ["prod"]);
+        assert_eq!(rule("secrets/dev/api.env"), ["env"]);
+        assert_eq!(rule("secrets/prod/key.pem"), ["dir"]);
+        assert!(cfg.get_rule(&dir.path().join("other.env")).is_err());
+
+        assert_eq!(
+            cfg.attribute_patterns(&[])?,
+            [
+                "/secrets/**",
+                "/secrets/**/*.env",
+                "/secrets/prod/*.env",
+                "/secrets/prod/db.env"
+            ]
+        );
+
+        let tracked = ["secrets/a.env", "secrets/x/key", "other.env"].map(PathBuf::from);
+        assert_eq!(
+            cfg.paths(&tracked)?,
+            ["secrets/a.env", "secrets/prod/db.env", "secrets/x/key"].map(PathBuf::from)
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_nested() -> Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        fs::create_dir_all(dir.path().join("apps/web/secrets"))?;
+        fs::write(
+            dir.path().join("git-agecrypt.toml"),
+            r#"
+            groups = { ops = ["github:alice", "github:bob"] }
+            [config]
+            "**/*.env" = ["github:root"]
+            "apps/web/db.env" = ["github:exact"]
+            "#,
+        )?;
+        fs::write(
+            dir.path().join("apps/web/git-agecrypt.yaml"),
+            "config:\n  'secrets': [ops, 'github:web']\n",
+        )?;
+        let cfg = AppConfig::load(&dir.path().join("git-agecrypt.toml"), dir.path())?;
+        let rule = |p: &str| cfg.get_rule(&dir.path().join(p)).unwrap().recipients;
+
+        assert_eq!(rule("apps/web/secrets/api.env"), ["github:alice", "github:bob", "github:web"]);
+        assert_eq!(rule("apps/web/secrets/deeper/key"), ["github:alice", "github:bob", "github:web"]);
+        // Falls back to the repository's rules if the closest rules file has no matching rule
+        assert_eq!(rule("apps/web/db.env"), ["github:exact"]);
+        assert_eq!(rule("apps/web/other.env"), ["github:root"]);
+
+        let tracked = [
+            "apps/web/git-agecrypt.yaml",
+            "apps/web/secrets/api.env",
+            "apps/web/db.env",
+            "readme.md",
+        ]
+        .map(PathBuf::from);
+        assert_eq!(
+            cfg.paths(&tracked)?,
+            ["apps/web/db.env", "apps/web/secrets/api.env"].map(PathBuf::from)
+        );
+        assert_eq!(
+            cfg.attribute_patterns(&tracked)?,
+            ["/**/*.env", "/apps/web/db.env", "/apps/web/secrets/**"]
+        );
+
+        let rules: Vec<_> = cfg
+            .rules(&tracked)?
+            .into_iter()
+            .map(|r| (r.path, r.rule.recipients, r.files))
+            .collect();
+        assert_eq!(
+            rules,
+            [
+                (
+                    "**/*.env".into(),
+                    vec!["github:root".to_string()],
+                    ["apps/web/secrets/api.env", "apps/web/db.env"]
+                        .map(PathBuf::from)
+                        .to_vec()
+                ),
+                (
+                    "apps/web/db.env".into(),
+                    vec!["github:exact".into()],
+                    vec!["apps/web/db.env".into()]
+                ),
+                (
+                    "apps/web/secrets".into(),
+                    vec!["github:alice".into(), "github:bob".into(), "github:web".into()],
+                    vec!["apps/web/secrets/api.env".into()]
+                ),
+            ]
+        );
+        let lookup = cfg.lookup(&dir.path().join("apps/web/secrets/api.env"))?;
+        assert_eq!(lookup.map(|(p, _)| p), Some("apps/web/secrets".into()));
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_recipients_files() -> Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        fs::create_dir_all(dir.path().join("secrets/prod"))?;
+        for file in [
+            "secrets/.agrecipients",
+            "secrets/prod/db.env.pub",
+            "secrets/ruled.env",
+        ] {
+            fs::write(dir.path().join(file), "")?;
+        }
+        let cfg = parse_in(
+            dir.path(),
+            r#"config = { "secrets/ruled.env" = ["github:a"] }"#,
+        );
+        let lookup = |p: &str| {
+            cfg.lookup(&dir.path().join(p))
+                .unwrap()
+                .map(|(path, rule)| (path, rule.recipients))
+        };
+
+        assert_eq!(
+            lookup("secrets/ruled.env"),
+            Some(("secrets/ruled.env".into(), vec!["github:a".into()]))
+        );
+        assert_eq!(
+            lookup("secrets/prod/db.env"),
+            Some((
+                "secrets/prod/db.env".into(),
+                vec!["file:secrets/prod/db.env.pub".into()]
+            ))
+        );
+        assert_eq!(
+            lookup("secrets/prod/api.env"),
+            Some(("secrets".into(), vec!["file:secrets/.agrecipients".into()]))
+        );
+        assert_eq!(lookup("secrets/prod/db.env.pub"), None);
+        assert_eq!(lookup("secrets/.agrecipients"), None);
+        assert_eq!(lookup("other.env"), None);
+
+        let tracked = [
+            "secrets/.agrecipients",
+            "secrets/prod/db.env",
+            "secrets/prod/db.env.pub",
+            "secrets/prod/api.env",
+            "secrets/ruled.env",
+            "other.env",
+        ]
+        .map(PathBuf::from);
+        assert_eq!(
+            cfg.paths(&tracked)?,
+            [
+                "secrets/prod/api.env",
+                "secrets/prod/db.env",
+                "secrets/ruled.env"
+            ]
+            .map(PathBuf::from)
+        );
+        assert_eq!(
+            cfg.attribute_patterns(&tracked)?,
+            ["/secrets/**", "/secrets/prod/db.env", "/secrets/ruled.env"]
+        );
+        assert!(cfg
+            .attribute_exclusions(&tracked)
+            .contains(&"/secrets/**/.agrecipients".to_string()));
+
+        let rules: Vec<_> = cfg
+            .rules(&tracked)?
+            .into_iter()
+            .map(|r| (r.path, r.rules_file, r.files))
+            .collect();
+        assert_eq!(
+            rules,
+            [
+                (
+                    "secrets/ruled.env".into(),
+                    dir.path().join("git-agecrypt.toml"),
+                    vec!["secrets/ruled.env".into()]
+                ),
+                (
+                    "secrets".into(),
+                    dir.path().join("secrets/.agrecipients"),
+                    vec!["secrets/prod/api.env".into()]
+                ),
+                (
+                    "secrets/prod/db.env".into(),
+                    dir.path().join("secrets/prod/db.env.pub"),
+                    vec!["secrets/prod/db.env".into()]
+                ),
+            ]
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_invalid_recipients() -> Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let path = dir.path().join("git-agecrypt.toml");
+        fs::write(
+            &path,
+            r#"
+            groups = { ops = ["github:alice", "age1mistyped"] }
+            [config]
+            "a.txt" = ["github:bob"]
+            "b.txt" = ["ops"]
+            "c.txt" = ["ssh-ed25519 AAAA"]
+            "#,
+        )?;
+        let cfg = AppConfig::load(&path, dir.path())?;
+        let invalid: Vec<_> = cfg
+            .invalid_recipients()
+            .iter()
+            .map(|i| (i.rule.clone(), i.group.as_deref(), i.recipient.as_str()))
+            .collect();
+        assert_eq!(
+            invalid,
+            [
+                (None, Some("ops"), "age1mistyped"),
+                (Some("c.txt".into()), None, "ssh-ed25519 AAAA")
+            ]
+        );
+
+        assert!(cfg.get_rule(&dir.path().join("a.txt")).is_ok());
+        let err = cfg.get_rule(&dir.path().join("b.txt")).unwrap_err();
+        assert_matches!(&err, Error::InvalidRecipient(i) if i.recipient == "age1mistyped");
+        assert!(err.to_string().starts_with(&format!(
+            "Invalid recipient 'age1mistyped' in group 'ops' of '{}': not a valid age public key",
+            path.display()
+        )));
+        Ok(())
+    }
+
+    fn parse_in(dir: &Path, contents: &str) -> AppConfig {
+        let mut cfg: AppConfig = toml::from_str(contents).unwrap();
+        cfg.path = dir.join("git-agecrypt.toml");
+        cfg.prefix = dir.into();
+        cfg
+    }
+
+    fn parse(contents: &str) -> AppConfig {
+        let mut cfg: AppConfig = toml::from_str(contents).unwrap();
+        cfg.prefix = "/repo".into();
+        cfg
+    }
+
+    #[rstest]
+    fn test_duplicate_rules() -> Result<()> {
+        let cfg = parse(
+            r#"
+            [config]
+            "foo" = ["a", "b"]
+            "./foo" = ["b", "c"]
+            "bar/../baz" = ["d"]
+            "baz" = ["e"]
+            "other" = ["f"]
+            "#,
+        );
+        assert_eq!(
+            cfg.duplicates(),
+            [
+                vec![PathBuf::from("./foo"), PathBuf::from("foo")],
+                vec![PathBuf::from("bar/../baz"), PathBuf::from("baz")],
+            ]
+        );
+        assert_eq!(cfg.warnings().len(), 2);
+        assert_eq!(cfg.paths(&[])?, ["baz", "foo", "other"].map(PathBuf::from));
+
+        let mut keys = cfg.get_rule(Path::new("/repo/foo"))?.recipients;
+        keys.sort();
+        assert_eq!(keys, ["a", "b", "c"]);
+        assert_eq!(cfg.get_rule(Path::new("/repo/./other"))?.recipients, ["f"]);
+        assert_matches!(
+            cfg.get_rule(Path::new("/repo/missing")),
+            Err(Error::NoRule(_))
+        );
+        Ok(())
     }
 }
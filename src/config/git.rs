@@ -49,6 +49,7 @@ impl From<crate::git::Error> for super::Error {
             crate::git::Error::AlreadyExists(v) => Self::AlreadyExists(v),
             crate::git::Error::NotExist(v) => Self::NotExist(v),
             crate::git::Error::Other(e) => Self::Other(e),
+            e @ crate::git::Error::NotInstalled => Self::Other(e.into()),
         }
     }
 }
@@ -73,10 +74,11 @@ where
     }
 
     fn list(&self) -> Result<Vec<Self::Item>> {
+        let entry_name = format!("{}.{}", CONFIG_PATH, self.ns);
         Ok(self
             .ctx
             .repo()
-            .list_config(&self.ns)?
+            .get_config_multivar(&entry_name)?
             .into_iter()
             .map(GitConfigEntry::new)
             .collect())
@@ -1,10 +1,15 @@
 mod age_identities;
 mod app;
 mod git;
+mod rule;
+mod settings;
 
 pub(crate) use age_identities::{AgeIdentities, AgeIdentity};
-pub(crate) use app::AppConfig;
+pub use app::{AppConfig, InvalidRecipient};
+pub(crate) use app::{normalize_path, CONFIG_FILES};
 pub(crate) use git::GitConfig;
+pub use rule::{Compression, Mode, Rule, RuleOptions};
+pub(crate) use settings::{GuardAction, MissingIdentity, Settings};
 
 use thiserror::Error;
 
@@ -14,6 +19,10 @@ pub enum Error {
     AlreadyExists(String),
     #[error("{:?} doesn't exist.", .0)]
     NotExist(String),
+    #[error("No public key can be found for '{}'", .0.display())]
+    NoRule(std::path::PathBuf),
+    #[error("{0}")]
+    InvalidRecipient(InvalidRecipient),
     #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
@@ -0,0 +1,167 @@
+use serde::{Deserialize, Serialize};
+
+/// Recipients and settings of a file in `git-agecrypt.toml`.
+///
+/// Rules without settings are stored as a plain list of recipients, otherwise as a table:
+///
+/// ```toml
+/// [config]
+/// "plain.txt" = ["age1..."]
+/// "quorum.txt" = { recipients = ["age1...", "age1...", "age1..."], threshold = 2 }
+/// "settings.yaml" = { recipients = ["age1..."], mode = "values" }
+/// "internal.env" = { recipients = ["age1..."], remotes = ["public"] }
+/// ```
+#[derive(Clone, Default, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(from = "RuleRepr", into = "RuleRepr")]
+pub struct Rule {
+    pub recipients: Vec<String>,
+    pub options: RuleOptions,
+}
+
+#[derive(Clone, Default, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RuleOptions {
+    /// Number of recipients required to cooperate for decryption (experimental)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub threshold: Option<u8>,
+
+    /// Write PEM-armored ciphertext instead of binary, defaults to the `armor` setting
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub armor: Option<bool>,
+
+    /// What to encrypt, the whole file by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<Mode>,
+
+    /// Derive the ciphertext from the plaintext, defaults to the `deterministic` setting
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deterministic: Option<bool>,
+
+    /// Compress the plaintext before encrypting it, files written without it still decrypt
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compress: Option<Compression>,
+
+    /// Name of the identities to try first when decrypting, mapped to identity files by the
+    /// `identityHint` setting
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identity_hint: Option<String>,
+
+    /// Key of a key management service to encrypt to as well, e.g. an AWS KMS key ARN, so that
+    /// whoever may use the key can decrypt
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kms: Option<String>,
+
+    /// Branches the rule applies on, as glob patterns, it applies on every branch if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub branches: Option<Vec<String>>,
+
+    /// Remotes the rule applies to the branches pushed to, it applies to every remote if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remotes: Option<Vec<String>>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// The file is encrypted as a whole
+    File,
+    /// Only the values of a YAML, JSON or env file are encrypted, keeping the keys readable
+    Values,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    /// Zstandard at its default level
+    Zstd,
+}
+
+impl RuleOptions {
+    /// Fills settings missing from `self` with the ones from `other`
+    pub fn merge(&mut self, other: &RuleOptions) {
+        self.threshold = self.threshold.or(other.threshold);
+        self.armor = self.armor.or(other.armor);
+        self.mode = self.mode.or(other.mode);
+        self.deterministic = self.deterministic.or(other.deterministic);
+        self.compress = self.compress.or(other.compress);
+        if self.identity_hint.is_none() {
+            self.identity_hint.clone_from(&other.identity_hint);
+        }
+        if self.kms.is_none() {
+            self.kms.clone_from(&other.kms);
+        }
+        if self.branches.is_none() {
+            self.branches.clone_from(&other.branches);
+        }
+        if self.remotes.is_none() {
+            self.remotes.clone_from(&other.remotes);
+        }
+    }
+
+    /// Whether the rule is limited to some branches or remotes, files are kept in plaintext
+    /// elsewhere
+    pub fn is_scoped(&self) -> bool {
+        self.branches.is_some() || self.remotes.is_some()
+    }
+
+    /// Whether the rule applies on `branch`, pushed to `remote`.
+    ///
+    /// A scoped rule applies as well when the branch or remote isn't known, e.g. with a
+    /// detached `HEAD` or a remote which isn't configured, as is the case for patterns which
+    /// aren't valid, so that files are rather encrypted than not.
+    pub fn applies_to(&self, branch: Option<&str>, remote: Option<&str>) -> bool {
+        let on_branch = match (&self.branches, branch) {
+            (Some(patterns), Some(branch)) => patterns
+                .iter()
+                .any(|p| glob::Pattern::new(p).map_or(true, |p| p.matches(branch))),
+            _ => true,
+        };
+        let to_remote = match (&self.remotes, remote) {
+            (Some(remotes), Some(remote)) => remotes.iter().any(|r| r == remote),
+            _ => true,
+        };
+        on_branch && to_remote
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum RuleRepr {
+    Recipients(Vec<String>),
+    Detailed {
+        recipients: Vec<String>,
+        #[serde(flatten)]
+        options: RuleOptions,
+    },
+}
+
+impl From<RuleRepr> for Rule {
+    fn from(value: RuleRepr) -> Self {
+        match value {
+            RuleRepr::Recipients(recipients) => Self {
+                recipients,
+                options: RuleOptions::default(),
+            },
+            RuleRepr::Detailed {
+                recipients,
+                options,
+            } => Self {
+                recipients,
+                options,
+            },
+        }
+    }
+}
+
+impl From<Rule> for RuleRepr {
+    fn from(value: Rule) -> Self {
+        if value.options == RuleOptions::default() {
+            Self::Recipients(value.recipients)
+        } else {
+            Self::Detailed {
+                recipients: value.recipients,
+                options: value.options,
+            }
+        }
+    }
+}
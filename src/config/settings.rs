@@ -0,0 +1,303 @@
+use std::{path::PathBuf, str::FromStr, time::Duration};
+
+use anyhow::bail;
+
+use crate::{
+    git::{self, Repository},
+    magic::FileType,
+};
+
+use super::Result;
+
+const SETTINGS_PATH: &str = "git-agecrypt.config";
+
+/// What `smudge` checks out when none of the identities can decrypt a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MissingIdentity {
+    /// Fail the checkout
+    Fail,
+    /// Check out the ciphertext, as for files excluded from decryption
+    Passthrough,
+    /// Check out an empty file
+    Empty,
+}
+
+impl FromStr for MissingIdentity {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(match s.trim().to_lowercase().as_str() {
+            "fail" => Self::Fail,
+            "passthrough" => Self::Passthrough,
+            "empty" => Self::Empty,
+            _ => bail!(
+                "Invalid value for {}.onMissingIdentity: '{}', expected fail, passthrough or empty",
+                SETTINGS_PATH,
+                s
+            ),
+        })
+    }
+}
+
+/// What `clean` does with a file caught by one of its guards
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum GuardAction {
+    /// Encrypt it without a word
+    Ignore,
+    /// Encrypt it, printing a warning
+    Warn,
+    /// Refuse to encrypt it
+    Fail,
+}
+
+/// Checkout specific behaviour tweaks stored in the repository's git config
+pub(crate) struct Settings<'a, R>
+where
+    R: Repository,
+{
+    repo: &'a R,
+}
+
+impl<'a, R> Settings<'a, R>
+where
+    R: Repository,
+{
+    pub fn new(repo: &'a R) -> Self {
+        Self { repo }
+    }
+
+    /// Refuse to encrypt when none of the configured identities is among the recipients, set
+    /// as `checkDecryptable` or `requireSelf`
+    pub fn check_decryptable(&self) -> Result<bool> {
+        Ok(self.get_bool("checkDecryptable", false)? || self.get_bool("requireSelf", false)?)
+    }
+
+    /// Treat configuration problems, e.g. duplicate rules, as errors
+    pub fn strict(&self) -> Result<bool> {
+        self.get_bool("strict", false)
+    }
+
+    /// How long to wait for age plugins before giving up, `None` if waiting indefinitely
+    pub fn plugin_timeout(&self) -> Result<Option<Duration>> {
+        let secs = self.get_u64("pluginTimeout", 120)?;
+        Ok((secs > 0).then(|| Duration::from_secs(secs)))
+    }
+
+    /// File to append a record of every decryption to
+    pub fn audit_log(&self) -> Result<Option<PathBuf>> {
+        Ok(self
+            .get("auditLog")?
+            .filter(|p| !p.is_empty())
+            .map(|p| self.repo.workdir().join(p)))
+    }
+
+    /// Identity files to try first for the rules with the given `identityHint`, configured as
+    /// `<hint>=<path>` values of `identityHint`
+    pub fn identity_hint(&self, hint: &str) -> Result<Vec<String>> {
+        let mut rv = vec![];
+        for value in self.get_list("identityHint")? {
+            let Some((name, path)) = value.split_once('=') else {
+                return Err(anyhow::anyhow!(
+                    "Invalid value for {}.identityHint: '{}', expected <hint>=<path>",
+                    SETTINGS_PATH,
+                    value
+                )
+                .into());
+            };
+            if name.trim() == hint {
+                rv.push(path.trim().to_string());
+            }
+        }
+        Ok(rv)
+    }
+
+    /// Patterns of files which are left encrypted on checkout
+    pub fn smudge_exclude(&self) -> Result<Vec<String>> {
+        self.get_list("smudgeExclude")
+    }
+
+    /// What to check out when no identity can decrypt a file, see [`MissingIdentity`]
+    pub fn on_missing_identity(&self) -> Result<MissingIdentity> {
+        match self.get("onMissingIdentity")? {
+            Some(v) => Ok(v.parse()?),
+            None => Ok(MissingIdentity::Fail),
+        }
+    }
+
+    /// Binary file types which `clean` refuses to encrypt
+    pub fn reject_binary_types(&self) -> Result<Vec<FileType>> {
+        let mut rv = vec![];
+        for value in self.get_list("rejectBinaryTypes")? {
+            for name in value.split(',').filter(|n| !n.trim().is_empty()) {
+                rv.push(name.parse().map_err(|e: anyhow::Error| {
+                    e.context(format!(
+                        "Invalid value for {}.rejectBinaryTypes",
+                        SETTINGS_PATH
+                    ))
+                })?);
+            }
+        }
+        Ok(rv)
+    }
+
+    /// Files smaller than this many bytes aren't checked for binary file types
+    pub fn binary_check_size(&self) -> Result<u64> {
+        self.get_u64("binaryCheckSize", 0)
+    }
+
+    /// Files larger than this many bytes are caught by the size guard, 0 for no limit
+    pub fn max_size(&self) -> Result<u64> {
+        self.get_size("maxSize", 0)
+    }
+
+    /// What to do with files larger than [`Self::max_size`]
+    pub fn on_max_size(&self) -> Result<GuardAction> {
+        self.get_guard_action("onMaxSize", GuardAction::Fail)
+    }
+
+    /// What to do with files which are age encrypted already
+    pub fn on_double_encryption(&self) -> Result<GuardAction> {
+        self.get_guard_action("onDoubleEncryption", GuardAction::Warn)
+    }
+
+    /// Binary files of at least this many bytes are taken as meant for git-lfs, 0 to never
+    /// take them as such
+    pub fn lfs_size(&self) -> Result<u64> {
+        self.get_size("lfsSize", 0)
+    }
+
+    /// What to do with binary files of at least [`Self::lfs_size`]
+    pub fn on_lfs_candidate(&self) -> Result<GuardAction> {
+        self.get_guard_action("onLfsCandidate", GuardAction::Warn)
+    }
+
+    /// Write PEM-armored ciphertext for rules which don't set `armor` themselves
+    pub fn armor(&self) -> Result<bool> {
+        self.get_bool("armor", false)
+    }
+
+    /// Encrypt identical plaintext to identical ciphertext for rules which don't set
+    /// `deterministic` themselves
+    pub fn deterministic(&self) -> Result<bool> {
+        self.get_bool("deterministic", false)
+    }
+
+    /// How many files bulk commands encrypt or decrypt at the same time, `None` for one per
+    /// CPU core
+    pub fn jobs(&self) -> Result<Option<usize>> {
+        let jobs = self.get_u64("jobs", 0)?;
+        Ok((jobs > 0).then_some(jobs as usize))
+    }
+
+    /// How many bytes of decrypted files `textconv` keeps cached, 0 to disable the cache
+    pub fn textconv_cache_size(&self) -> Result<u64> {
+        self.get_u64("textconvCacheSize", 64 * 1024 * 1024)
+    }
+
+    /// Whether `lock` was used to leave the files encrypted in the working copy
+    pub fn locked(&self) -> Result<bool> {
+        self.get_bool("locked", false)
+    }
+
+    pub fn set_locked(&self, locked: bool) -> Result<()> {
+        Ok(self
+            .repo
+            .set_config(&format!("{}.locked", SETTINGS_PATH), &locked.to_string())?)
+    }
+
+    fn get(&self, name: &str) -> Result<Option<String>> {
+        match self.repo.get_config(&format!("{}.{}", SETTINGS_PATH, name)) {
+            Ok(v) => Ok(Some(v)),
+            Err(git::Error::NotExist(_)) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn get_bool(&self, name: &str, default: bool) -> Result<bool> {
+        let value = match self.get(name)? {
+            Some(v) => v,
+            None => return Ok(default),
+        };
+        match value.to_lowercase().as_str() {
+            "true" | "yes" | "on" | "1" => Ok(true),
+            "false" | "no" | "off" | "0" | "" => Ok(false),
+            _ => Err(anyhow::anyhow!(
+                "Invalid boolean value for {}.{}: '{}'",
+                SETTINGS_PATH,
+                name,
+                value
+            )
+            .into()),
+        }
+    }
+
+    fn get_u64(&self, name: &str, default: u64) -> Result<u64> {
+        match self.get(name)? {
+            Some(v) => Ok(v.trim().parse().map_err(|_| {
+                anyhow::anyhow!(
+                    "Invalid numeric value for {}.{}: '{}'",
+                    SETTINGS_PATH,
+                    name,
+                    v
+                )
+            })?),
+            None => Ok(default),
+        }
+    }
+
+    /// A number of bytes, with git's `k`, `m` and `g` suffixes
+    fn get_size(&self, name: &str, default: u64) -> Result<u64> {
+        let Some(value) = self.get(name)? else {
+            return Ok(default);
+        };
+        let trimmed = value.trim().to_lowercase();
+        let (number, unit) = match trimmed.strip_suffix(['k', 'm', 'g']) {
+            Some(number) => (number, trimmed.chars().last()),
+            None => (trimmed.as_str(), None),
+        };
+        let factor: u64 = match unit {
+            Some('k') => 1 << 10,
+            Some('m') => 1 << 20,
+            Some('g') => 1 << 30,
+            _ => 1,
+        };
+        number
+            .trim()
+            .parse::<u64>()
+            .ok()
+            .and_then(|n| n.checked_mul(factor))
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Invalid size for {}.{}: '{}', expected a number of bytes with an optional k, m or g suffix",
+                    SETTINGS_PATH,
+                    name,
+                    value
+                )
+                .into()
+            })
+    }
+
+    fn get_guard_action(&self, name: &str, default: GuardAction) -> Result<GuardAction> {
+        let Some(value) = self.get(name)? else {
+            return Ok(default);
+        };
+        match value.trim().to_lowercase().as_str() {
+            "ignore" => Ok(GuardAction::Ignore),
+            "warn" => Ok(GuardAction::Warn),
+            "fail" => Ok(GuardAction::Fail),
+            _ => Err(anyhow::anyhow!(
+                "Invalid value for {}.{}: '{}', expected ignore, warn or fail",
+                SETTINGS_PATH,
+                name,
+                value
+            )
+            .into()),
+        }
+    }
+
+    fn get_list(&self, name: &str) -> Result<Vec<String>> {
+        Ok(self
+            .repo
+            .get_config_multivar(&format!("{}.{}", SETTINGS_PATH, name))?)
+    }
+}
@@ -1,14 +1,17 @@
 use std::{
-    fs::{self, File},
-    io::{self, Read, Write},
+    cell::{Cell, RefCell},
+    fs, io,
     path::{Path, PathBuf},
+    time::SystemTime,
 };
 
 use anyhow::{bail, Result};
 
 use crate::{
-    config::{AgeIdentities, AgeIdentity, AppConfig, Container, GitConfig},
-    git,
+    cache::BlobCache,
+    config::{AgeIdentities, AgeIdentity, AppConfig, Container, GitConfig, Settings, CONFIG_FILES},
+    git, recipients,
+    sidecars::{SidecarIndex, SidecarReader, SidecarWriter},
 };
 
 pub(crate) trait Context {
@@ -24,6 +27,17 @@ pub(crate) trait Context {
         extension: &str,
     ) -> Result<Option<Vec<u8>>>;
 
+    /// Starts writing the content of a sidecar as a stream, it is stored by
+    /// [`finish_sidecar`](Self::finish_sidecar) once complete
+    fn create_sidecar(&self, for_path: &Path, extension: &str) -> Result<SidecarWriter>;
+
+    fn finish_sidecar(&self, sidecar: SidecarWriter) -> Result<()>;
+
+    /// Opens a sidecar for reading its content as a stream, `None` if it doesn't exist
+    fn open_sidecar(&self, for_path: &Path, extension: &str) -> Result<Option<SidecarReader>>;
+
+    fn remove_sidecar(&self, for_path: &Path, extension: &str) -> Result<()>;
+
     fn current_exe(&self) -> Result<String>;
 
     fn remove_sidecar_files(&self) -> Result<()>;
@@ -31,30 +45,109 @@ pub(crate) trait Context {
     fn age_identities(&self) -> Box<dyn Container<Item = AgeIdentity> + '_>;
 
     fn config(&self) -> Result<AppConfig>;
+
+    fn settings(&self) -> Settings<'_, Self::Repo>;
+
+    fn recipients(&self) -> recipients::Resolver;
+
+    /// Decrypted blobs shown by `textconv`, holding at most `max_size` bytes
+    fn textconv_cache(&self, max_size: u64) -> BlobCache;
 }
 
-struct ContextWrapper<R: git::Repository> {
+/// Identifies a version of the configuration file by its modification time and size
+type ConfigStamp = Option<(SystemTime, u64)>;
+
+/// Directory below the sidecar directory holding one sidecar file per file in earlier versions
+const LEGACY_SIDECARS_DIR: &str = "sidecars";
+
+/// Extensions of the sidecars stored directly in the sidecar directory by earlier versions
+const LEGACY_SIDECARS: &[&str] = &["hash", "age"];
+
+pub(crate) struct ContextWrapper<R: git::Repository> {
     repo: R,
+    config_path: Option<PathBuf>,
+    config_cache: RefCell<Option<(ConfigStamp, AppConfig)>>,
+    sidecars: SidecarIndex,
+    sidecars_migrated: Cell<bool>,
 }
 
 impl<R: git::Repository> ContextWrapper<R> {
-    pub(crate) fn new(repo: R) -> Self {
-        Self { repo }
+    pub(crate) fn new(repo: R, config_path: Option<PathBuf>) -> Self {
+        let sidecars = SidecarIndex::new(repo.path().join("git-agecrypt"));
+        Self {
+            repo,
+            config_path,
+            sidecars,
+            config_cache: RefCell::new(None),
+            sidecars_migrated: Cell::new(false),
+        }
     }
+
+    fn config_file(&self) -> PathBuf {
+        if let Some(path) = &self.config_path {
+            return path.clone();
+        }
+        let workdir = self.repo.workdir();
+        CONFIG_FILES
+            .iter()
+            .map(|name| workdir.join(name))
+            .find(|path| path.exists())
+            .unwrap_or_else(|| workdir.join(CONFIG_FILES[0]))
+    }
+    /// Private data of this checkout, `repo.path()` is the worktree's own git directory for
+    /// linked worktrees, so each of them keeps their sidecars apart
     fn sidecar_directory(&self) -> PathBuf {
         self.repo.path().join("git-agecrypt")
     }
 
-    fn get_sidecar(&self, path: &Path, extension: &str) -> Result<PathBuf> {
+    /// The rules file committed to `HEAD`, rules files of subdirectories aren't looked up
+    fn committed_config(&self) -> Result<AppConfig> {
+        let workdir = self.repo.workdir();
+        for name in CONFIG_FILES {
+            match self.repo.read_revision(&format!("HEAD:{}", name)) {
+                Ok(contents) => {
+                    let contents = String::from_utf8(contents)?;
+                    return Ok(AppConfig::parse(&workdir.join(name), &contents, workdir)?);
+                }
+                Err(git::Error::NotExist(_)) => continue,
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(AppConfig::empty(&workdir.join(CONFIG_FILES[0]), workdir))
+    }
+
+    /// The sidecar index, keyed by paths relative to the working tree
+    fn sidecars(&self, path: &Path) -> Result<(&SidecarIndex, PathBuf)> {
+        self.migrate_sidecars()?;
         let relpath = path.strip_prefix(self.repo.workdir())?;
-        let name = relpath.to_string_lossy().replace('/', "!");
+        Ok((&self.sidecars, relpath.to_path_buf()))
+    }
 
+    /// Imports the sidecar files of earlier versions into the index. The flat sidecar files of
+    /// even older versions are removed, their names can't be mapped back to the files they
+    /// belong to, so they are rebuilt by the filters instead.
+    fn migrate_sidecars(&self) -> Result<()> {
+        if self.sidecars_migrated.replace(true) {
+            return Ok(());
+        }
         let dir = self.sidecar_directory();
-        fs::create_dir_all(&dir)?;
-
-        let mut rv = dir.join(name);
-        rv.set_extension(extension);
-        Ok(rv)
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => bail!(e),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let path = entry.path();
+            let legacy = path
+                .extension()
+                .is_some_and(|ext| LEGACY_SIDECARS.iter().any(|l| ext == *l));
+            if legacy && entry.file_type()?.is_file() {
+                log::debug!("Removing legacy sidecar {}", path.display());
+                fs::remove_file(path)?;
+            }
+        }
+        self.sidecars.import(&dir.join(LEGACY_SIDECARS_DIR))
     }
 }
 
@@ -65,9 +158,8 @@ impl<R: git::Repository> Context for ContextWrapper<R> {
     }
 
     fn store_sidecar(&self, for_path: &Path, extension: &str, content: &[u8]) -> Result<()> {
-        let sidecar_path = self.get_sidecar(for_path, extension)?;
-        File::create(sidecar_path)?.write_all(content)?;
-        Ok(())
+        let (sidecars, path) = self.sidecars(for_path)?;
+        sidecars.set(&path, extension, content)
     }
 
     fn load_sidecar(
@@ -75,18 +167,27 @@ impl<R: git::Repository> Context for ContextWrapper<R> {
         for_path: &Path,
         extension: &str,
     ) -> Result<Option<Vec<u8>>> {
-        let sidecar_path = self.get_sidecar(for_path, extension)?;
-        match File::open(sidecar_path) {
-            Ok(mut f) => {
-                let mut buff = Vec::new();
-                f.read_to_end(&mut buff)?;
-                Ok(Some(buff))
-            }
-            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
-            Err(e) => {
-                bail!(e)
-            }
-        }
+        let (sidecars, path) = self.sidecars(for_path)?;
+        sidecars.get(&path, extension)
+    }
+
+    fn create_sidecar(&self, for_path: &Path, extension: &str) -> Result<SidecarWriter> {
+        let (sidecars, path) = self.sidecars(for_path)?;
+        sidecars.create(&path, extension)
+    }
+
+    fn finish_sidecar(&self, sidecar: SidecarWriter) -> Result<()> {
+        self.sidecars.finish(sidecar)
+    }
+
+    fn open_sidecar(&self, for_path: &Path, extension: &str) -> Result<Option<SidecarReader>> {
+        let (sidecars, path) = self.sidecars(for_path)?;
+        sidecars.open(&path, extension)
+    }
+
+    fn remove_sidecar(&self, for_path: &Path, extension: &str) -> Result<()> {
+        let (sidecars, path) = self.sidecars(for_path)?;
+        sidecars.remove(&path, extension)
     }
 
     fn current_exe(&self) -> Result<String> {
@@ -113,13 +214,110 @@ impl<R: git::Repository> Context for ContextWrapper<R> {
     }
 
     fn config(&self) -> Result<AppConfig> {
-        Ok(AppConfig::load(
-            &PathBuf::from("git-agecrypt.toml"),
-            self.repo.workdir(),
-        )?)
+        let path = self.config_file();
+        // Bare repositories have no working tree, the committed rules are used instead
+        let committed = self.repo.is_bare() && self.config_path.is_none();
+        // The configuration can change while running as a filter process, e.g. on checkout
+        let stamp = fs::metadata(&path)
+            .and_then(|m| Ok((m.modified()?, m.len())))
+            .ok();
+        if let Some((cached_stamp, cfg)) = &*self.config_cache.borrow() {
+            if committed || stamp.is_some() && *cached_stamp == stamp {
+                return Ok(cfg.clone());
+            }
+        }
+
+        let cfg = if committed {
+            self.committed_config()?
+        } else {
+            AppConfig::load(&path, self.repo.workdir())?
+        };
+        let warnings = cfg.warnings();
+        if !warnings.is_empty() && self.settings().strict()? {
+            bail!("Invalid configuration: {}", warnings.join("; "));
+        }
+        for warning in warnings {
+            log::warn!("{}", warning);
+        }
+        *self.config_cache.borrow_mut() = Some((stamp, cfg.clone()));
+        Ok(cfg)
+    }
+
+    fn settings(&self) -> Settings<'_, R> {
+        Settings::new(&self.repo)
+    }
+
+    fn recipients(&self) -> recipients::Resolver {
+        recipients::Resolver::new(
+            self.sidecar_directory().join("recipients"),
+            self.repo.workdir().into(),
+        )
     }
+
+    fn textconv_cache(&self, max_size: u64) -> BlobCache {
+        BlobCache::new(self.sidecar_directory().join("textconv-cache"), max_size)
+    }
+}
+
+pub(crate) fn new(
+    repo: git::LibGit2Repository,
+    config_path: Option<PathBuf>,
+) -> impl Context<Repo = git::LibGit2Repository> {
+    ContextWrapper::new(repo, config_path)
 }
 
-pub(crate) fn new(repo: git::LibGit2Repository) -> impl Context<Repo = git::LibGit2Repository> {
-    ContextWrapper::new(repo)
+#[cfg(test)]
+mod tests {
+    use assert_fs::prelude::*;
+    use assert_fs::TempDir;
+    use duct::cmd;
+    use rstest::rstest;
+
+    use super::*;
+    use crate::git::Repository;
+
+    fn context(dir: &Path) -> Result<ContextWrapper<git::LibGit2Repository>> {
+        let repo = git::LibGit2Repository::from_dir(dir.to_path_buf())?;
+        Ok(ContextWrapper::new(repo, None))
+    }
+
+    #[rstest]
+    fn test_sidecars() -> Result<()> {
+        let dir = TempDir::new()?;
+        cmd!("git", "init").dir(dir.path()).run()?;
+        cmd!("git", "commit", "--allow-empty", "-m", "init")
+            .env("GIT_AUTHOR_NAME", "test")
+            .env("GIT_AUTHOR_EMAIL", "test@example.com")
+            .env("GIT_COMMITTER_NAME", "test")
+            .env("GIT_COMMITTER_EMAIL", "test@example.com")
+            .dir(dir.path())
+            .run()?;
+        let legacy = dir.child(".git/git-agecrypt/s.hash");
+        legacy.write_str("legacy")?;
+        let imported = dir.child(".git/git-agecrypt/sidecars/old/s.txt.age");
+        imported.write_str("ciphertext")?;
+
+        let ctx = context(dir.path())?;
+        let workdir = ctx.repo().workdir().to_path_buf();
+        for name in ["s.txt", "s.yaml", "dir/s.txt"] {
+            ctx.store_sidecar(&workdir.join(name), "hash", name.as_bytes())?;
+        }
+        assert!(!legacy.path().exists());
+        for name in ["s.txt", "s.yaml", "dir/s.txt"] {
+            let content = ctx.load_sidecar(&workdir.join(name), "hash")?;
+            assert_eq!(content.as_deref(), Some(name.as_bytes()));
+        }
+        assert!(!imported.path().exists());
+        let content = ctx.load_sidecar(&workdir.join("old/s.txt"), "age")?;
+        assert_eq!(content.as_deref(), Some(&b"ciphertext"[..]));
+
+        let worktree = dir.child("worktree");
+        cmd!("git", "worktree", "add", "--detach", worktree.path())
+            .dir(dir.path())
+            .run()?;
+        let other = context(worktree.path())?;
+        let path = other.repo().workdir().join("s.txt");
+        assert_eq!(other.load_sidecar(&path, "hash")?, None);
+        Ok(())
+    }
 }
@@ -0,0 +1,215 @@
+//! Deterministic age encryption, so that the same plaintext and recipients always produce the
+//! same ciphertext, no matter which machine encrypts it.
+//!
+//! The output is a regular age file, decryptable with any age implementation. Everything age
+//! normally chooses at random is derived from the plaintext instead:
+//!
+//! ```text
+//! seed          = HMAC-SHA-256(key = SHA-256(recipients), plaintext)
+//! file key      = HKDF-SHA-256(salt = "", ikm = seed, "git-agecrypt.org/deterministic/v1 file key")[..16]
+//! payload nonce = HKDF-SHA-256(salt = "", ikm = seed, "git-agecrypt.org/deterministic/v1 nonce")[..16]
+//! ephemeral key = HKDF-SHA-256(salt = recipient, ikm = file key, "git-agecrypt.org/deterministic/v1 ephemeral")
+//! ```
+//!
+//! `recipients` are the normalized recipients, sorted and separated by newlines. The stanzas
+//! are written in that order, too.
+//!
+//! The recipients are public, so anyone who knows them can confirm a guess of the plaintext and
+//! tell whether two files have the same contents. Only X25519 recipients are supported, as the
+//! stanzas of SSH and plugin recipients can't be derived like this.
+
+use std::io::Write;
+
+use ::age::armor::{ArmoredWriter, Format};
+use age_core::primitives::{aead_encrypt, hkdf};
+use anyhow::{bail, Context, Result};
+use base64::{prelude::BASE64_STANDARD_NO_PAD, Engine};
+use bech32::FromBase32;
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::age;
+
+const LABEL_FILE_KEY: &[u8] = b"git-agecrypt.org/deterministic/v1 file key";
+const LABEL_NONCE: &[u8] = b"git-agecrypt.org/deterministic/v1 nonce";
+const LABEL_EPHEMERAL: &[u8] = b"git-agecrypt.org/deterministic/v1 ephemeral";
+
+const X25519_LABEL: &[u8] = b"age-encryption.org/v1/X25519";
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Encrypts `plaintext` to all `public_keys`, as PEM-armored text if `armor` is set
+pub(crate) fn encrypt(
+    public_keys: &[impl AsRef<str>],
+    armor: bool,
+    plaintext: &[u8],
+) -> Result<Vec<u8>> {
+    let mut recipients = public_keys
+        .iter()
+        .map(|pk| age::normalize_recipient(pk.as_ref()))
+        .collect::<Result<Vec<_>>>()?;
+    recipients.sort();
+    recipients.dedup();
+    if recipients.is_empty() {
+        bail!("No recipients to encrypt to");
+    }
+
+    let seed = hmac(&Sha256::digest(recipients.join("\n")), plaintext);
+    let file_key: [u8; 16] = hkdf(&[], LABEL_FILE_KEY, &seed)[..16].try_into()?;
+    let nonce: [u8; 16] = hkdf(&[], LABEL_NONCE, &seed)[..16].try_into()?;
+
+    let mut header = "age-encryption.org/v1\n".to_string();
+    for recipient in &recipients {
+        let (epk, body) = wrap_file_key(recipient, &file_key)?;
+        header.push_str(&format!("-> X25519 {}\n", epk));
+        header.push_str(&wrap_lines(&body));
+    }
+    header.push_str("---");
+    let mac = hmac(&hkdf(&[], b"header", &file_key), header.as_bytes());
+    header.push_str(&format!(" {}\n", BASE64_STANDARD_NO_PAD.encode(mac)));
+
+    let format = if armor {
+        Format::AsciiArmor
+    } else {
+        Format::Binary
+    };
+    let mut output = ArmoredWriter::wrap_output(vec![], format)?;
+    output.write_all(header.as_bytes())?;
+    output.write_all(&nonce)?;
+    let payload_key = hkdf(&nonce, b"payload", &file_key);
+    output.write_all(&encrypt_payload(&payload_key, plaintext)?)?;
+    Ok(output.finish()?)
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+/// Creates the X25519 stanza for `recipient`, returning its argument and body
+fn wrap_file_key(recipient: &str, file_key: &[u8; 16]) -> Result<(String, Vec<u8>)> {
+    let pk = x25519_public_key(recipient)?;
+    let esk = StaticSecret::from(hkdf(pk.as_bytes(), LABEL_EPHEMERAL, file_key));
+    let epk = PublicKey::from(&esk);
+    let shared_secret = esk.diffie_hellman(&pk);
+    if !shared_secret.was_contributory() {
+        bail!("Invalid X25519 recipient '{}'", recipient);
+    }
+
+    let mut salt = [0; 64];
+    salt[..32].copy_from_slice(epk.as_bytes());
+    salt[32..].copy_from_slice(pk.as_bytes());
+    let wrap_key = hkdf(&salt, X25519_LABEL, shared_secret.as_bytes());
+    Ok((
+        BASE64_STANDARD_NO_PAD.encode(epk.as_bytes()),
+        aead_encrypt(&wrap_key, file_key),
+    ))
+}
+
+fn x25519_public_key(recipient: &str) -> Result<PublicKey> {
+    if recipient.parse::<::age::x25519::Recipient>().is_err() {
+        bail!(
+            "Deterministic encryption only supports X25519 recipients, '{}' isn't one",
+            recipient
+        );
+    }
+    let (_, data, _) = bech32::decode(recipient)?;
+    let bytes: [u8; 32] = Vec::<u8>::from_base32(&data)?
+        .try_into()
+        .ok()
+        .context("Invalid X25519 recipient")?;
+    Ok(PublicKey::from(bytes))
+}
+
+/// Encodes a stanza body as base64 lines of 64 columns, the last one always shorter
+fn wrap_lines(body: &[u8]) -> String {
+    let encoded = BASE64_STANDARD_NO_PAD.encode(body);
+    let mut rv = String::new();
+    let mut rest = encoded.as_str();
+    loop {
+        let (line, tail) = rest.split_at(rest.len().min(64));
+        rv.push_str(line);
+        rv.push('\n');
+        if line.len() < 64 {
+            return rv;
+        }
+        rest = tail;
+    }
+}
+
+/// Encrypts the payload in chunks as described by the STREAM construction of age
+fn encrypt_payload(key: &[u8; 32], plaintext: &[u8]) -> Result<Vec<u8>> {
+    let cipher = ChaCha20Poly1305::new(key.into());
+    let chunks: Vec<&[u8]> = if plaintext.is_empty() {
+        vec![&[]]
+    } else {
+        plaintext.chunks(CHUNK_SIZE).collect()
+    };
+    let mut rv = Vec::with_capacity(plaintext.len() + chunks.len() * 16);
+    for (counter, chunk) in chunks.iter().enumerate() {
+        let mut nonce = [0; 12];
+        nonce[3..11].copy_from_slice(&(counter as u64).to_be_bytes());
+        if counter + 1 == chunks.len() {
+            nonce[11] = 1;
+        }
+        let ciphertext = cipher
+            .encrypt(&nonce.into(), *chunk)
+            .map_err(|_| anyhow::anyhow!("Couldn't encrypt payload"))?;
+        rv.extend(ciphertext);
+    }
+    Ok(rv)
+}
+
+#[cfg(test)]
+mod tests {
+    use ::age::secrecy::ExposeSecret;
+    use anyhow::Result;
+    use assert_fs::prelude::*;
+    use assert_fs::TempDir;
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case(b"")]
+    #[case(b"secret")]
+    #[case(&[7; CHUNK_SIZE])]
+    #[case(&[7; CHUNK_SIZE * 2 + 1])]
+    fn test_encrypt(#[case] plaintext: &[u8]) -> Result<()> {
+        let dir = TempDir::new()?;
+        let identity = ::age::x25519::Identity::generate();
+        let identity_file = dir.child("key.txt");
+        identity_file.write_str(&format!("{}\n", identity.to_string().expose_secret()))?;
+        let other = ::age::x25519::Identity::generate().to_public().to_string();
+        let recipient = identity.to_public().to_string();
+
+        let encrypted = encrypt(&[&recipient, &other], false, plaintext)?;
+        assert_eq!(encrypt(&[&other, &recipient], false, plaintext)?, encrypted);
+        let (decrypted, _) = age::decrypt(&[identity_file.path()], &mut &encrypted[..])?.unwrap();
+        assert_eq!(decrypted, plaintext);
+
+        let armored = encrypt(&[&recipient], true, plaintext)?;
+        assert!(armored.starts_with(b"-----BEGIN AGE ENCRYPTED FILE-----\n"));
+        let (decrypted, _) = age::decrypt(&[identity_file.path()], &mut &armored[..])?.unwrap();
+        assert_eq!(decrypted, plaintext);
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_differs() -> Result<()> {
+        let recipient = ::age::x25519::Identity::generate().to_public().to_string();
+        let other = ::age::x25519::Identity::generate().to_public().to_string();
+        let encrypted = encrypt(&[&recipient], false, b"secret")?;
+        assert_ne!(encrypt(&[&recipient], false, b"secret2")?, encrypted);
+        assert_ne!(encrypt(&[&other], false, b"secret")?, encrypted);
+
+        let ssh =
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIHsKLqeplhpW+uObz5dvMgjz1OxfM/XXUB+VHtZ6isGN";
+        let err = encrypt(&[ssh], false, b"secret").unwrap_err();
+        assert!(err.to_string().contains("only supports X25519"));
+        Ok(())
+    }
+}
@@ -0,0 +1,32 @@
+use thiserror::Error;
+
+use crate::{config, git};
+
+/// Errors of the library interface
+#[derive(Error, Debug)]
+pub enum Error {
+    /// Reading or changing the rules or the git configuration failed
+    #[error(transparent)]
+    Config(#[from] config::Error),
+    /// Accessing the repository failed
+    #[error(transparent)]
+    Git(#[from] git::Error),
+    /// Encryption or decryption failed
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl From<anyhow::Error> for Error {
+    fn from(err: anyhow::Error) -> Self {
+        let err = match err.downcast::<config::Error>() {
+            Ok(err) => return Self::Config(err),
+            Err(err) => err,
+        };
+        match err.downcast::<git::Error>() {
+            Ok(err) => Self::Git(err),
+            Err(err) => Self::Other(err),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
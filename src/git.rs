@@ -1,7 +1,12 @@
 use std::{
-    env, io,
+    collections::HashSet,
+    env,
+    ffi::OsStr,
+    fs,
+    io::{self, Write},
     path::{Path, PathBuf},
     process,
+    time::UNIX_EPOCH,
 };
 
 use anyhow::{anyhow, Context};
@@ -13,6 +18,8 @@ pub enum Error {
     AlreadyExists(String),
     #[error("{:?} doesn't exist.", .0)]
     NotExist(String),
+    #[error("git is not installed or not in PATH")]
+    NotInstalled,
     #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
@@ -31,13 +38,177 @@ impl From<io::Error> for Error {
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// A version of a file stored in the repository
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Blob {
+    /// Path relative to the working directory
+    pub path: PathBuf,
+    pub id: String,
+    /// The first commit containing this version, `None` for the index
+    pub commit: Option<String>,
+}
+
+/// The id git gives a blob with these contents
+pub(crate) fn blob_id(contents: &[u8]) -> Result<String> {
+    Ok(git2::Oid::hash_object(git2::ObjectType::Blob, contents)?.to_string())
+}
+
+/// Major and minor version of the `git` executable in `PATH`
+pub(crate) fn version() -> Result<(u32, u32)> {
+    let output = process::Command::new("git")
+        .arg("--version")
+        .output()
+        .map_err(spawn_error)?;
+    let output = String::from_utf8_lossy(&output.stdout);
+    parse_version(&output)
+        .ok_or_else(|| Error::Other(anyhow!("Unknown git version {:?}", output.trim())))
+}
+
+/// Sets a value in the global git config (~/.gitconfig), which needs no repository
+pub(crate) fn set_global_config(key: &str, value: &str) -> Result<()> {
+    let mut cfg = git2::Config::open_default()?.open_level(git2::ConfigLevel::Global)?;
+    cfg.set_str(key, value)?;
+    Ok(())
+}
+
+/// Removes a section from the global git config (~/.gitconfig), which needs no repository
+pub(crate) fn remove_global_config_section(key: &str) -> Result<()> {
+    let mut command = process::Command::new("git");
+    command.arg("config").arg("--global");
+    remove_config_section_with(command, key)
+}
+
+/// Runs a `git config` command with `--remove-section key` appended, as there is unfortunately
+/// no `git config --remove-section <section>` equivalent in libgit2
+fn remove_config_section_with(mut command: process::Command, key: &str) -> Result<()> {
+    command.arg("--remove-section").arg(key);
+    let output = command.output().map_err(spawn_error)?;
+
+    if !output.status.success() {
+        log::error!(
+            "Failed to execute command. This may not be an issue; command='{:?}' status='{}', stdout={:?}, stderr={:?}",
+            command,
+            output.status,
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        );
+        return Err(Error::NotExist(key.into()));
+    }
+    Ok(())
+}
+
+/// Tells a missing git executable apart from other failures to run it
+fn spawn_error(err: io::Error) -> Error {
+    match err.kind() {
+        io::ErrorKind::NotFound => Error::NotInstalled,
+        _ => Error::Other(anyhow::Error::new(err).context("Couldn't run git")),
+    }
+}
+
+/// Parses the output of `git --version`, e.g. `git version 2.45.1.windows.1`
+fn parse_version(output: &str) -> Option<(u32, u32)> {
+    let version = output.trim().strip_prefix("git version ")?;
+    let mut parts = version.split(['.', ' ']);
+    Some((parts.next()?.parse().ok()?, parts.next()?.parse().ok()?))
+}
+
 pub(crate) trait Repository {
+    /// Root of the working tree, the git directory in bare repositories
     fn workdir(&self) -> &Path;
 
+    /// Whether the repository has no working tree, so commands can only read committed files
+    fn is_bare(&self) -> bool;
+
     fn path(&self) -> &Path;
 
+    /// Directory git runs hooks from, `core.hooksPath` if it is set
+    fn hooks_dir(&self) -> Result<PathBuf>;
+
     fn get_file_contents(&self, path: &Path) -> Result<Vec<u8>>;
 
+    /// Contents of the version of `path` staged in the index, which differs from the one in
+    /// `HEAD` e.g. after `git add` or while a rebase or `git stash pop` applies changes
+    fn get_staged_contents(&self, path: &Path) -> Result<Vec<u8>>;
+
+    /// ID of the blob staged for `path` if git's stat information in the index tells that the
+    /// working copy didn't change since, `None` if it may have or the file isn't staged
+    fn stat_clean_blob(&self, path: &Path) -> Result<Option<String>>;
+
+    /// Paths of the files in the index, relative to the working directory
+    fn list_files(&self) -> Result<Vec<PathBuf>>;
+
+    /// Working trees of the submodules which are checked out
+    fn submodules(&self) -> Result<Vec<PathBuf>>;
+
+    /// Name of the branch checked out, `None` with a detached `HEAD`
+    fn current_branch(&self) -> Result<Option<String>>;
+
+    /// Remote `git push` pushes `branch` to, as configured by `branch.<name>.pushRemote`,
+    /// `remote.pushDefault` or `branch.<name>.remote`, `None` if none of them is set
+    fn push_remote(&self, branch: &str) -> Result<Option<String>>;
+
+    /// Name of the configured remote `remote` refers to, either by its name or its URL as git
+    /// passes it to the `pre-push` hook, `None` if there is no such remote
+    fn remote_name(&self, remote: &str) -> Result<Option<String>>;
+
+    /// The files staged in the index
+    fn index_blobs(&self) -> Result<Vec<Blob>>;
+
+    /// Every distinct version of each file in the history of `HEAD`, oldest first. With
+    /// `since`, only the commits not reachable from that revision are walked.
+    fn history_blobs(&self, since: Option<&str>) -> Result<Vec<Blob>>;
+
+    /// Every distinct version of each file in the commits `git push` sends when updating a
+    /// branch of the remote `name` from `remote` to `local`, both commit IDs. Without `remote`,
+    /// as for a new branch, the commits not reachable from the remote's remote-tracking
+    /// branches are walked, all of them if the remote isn't known.
+    fn pushed_blobs(
+        &self,
+        local: &str,
+        remote: Option<&str>,
+        name: Option<&str>,
+    ) -> Result<Vec<Blob>>;
+
+    fn read_blob(&self, id: &str) -> Result<Vec<u8>>;
+
+    /// Stores `contents` in the object database, returning the ID of the blob
+    fn write_blob(&self, contents: &[u8]) -> Result<String>;
+
+    /// Contents of the file named by a revision expression like `HEAD~1:path/to/file`
+    fn read_revision(&self, spec: &str) -> Result<Vec<u8>>;
+
+    /// Files added, modified or deleted between the commits named by `old` and `new`. The
+    /// all-zero commit ID git passes to hooks for a fresh clone stands for an empty tree.
+    fn changed_files(&self, old: &str, new: &str) -> Result<Vec<PathBuf>>;
+
+    /// Tracked files among `paths` whose working copy differs from the index
+    fn modified_files(&self, paths: &[PathBuf]) -> Result<Vec<PathBuf>>;
+
+    /// Overwrites the working copy of `paths` with their index version, running the filters
+    fn checkout_files(&self, paths: &[PathBuf]) -> Result<()>;
+
+    /// Stages `paths`, running the clean filter again even if their working copy is unchanged
+    /// when `renormalize` is set
+    fn add_files(&self, paths: &[PathBuf], renormalize: bool) -> Result<()>;
+
+    /// Value of a git attribute of `path`, `None` if it is unspecified. Set and unset
+    /// attributes are returned as `true` and `false`.
+    fn get_attribute(&self, path: &Path, name: &str) -> Result<Option<String>>;
+
+    /// Three-way merges `ours` and `theirs`, returning the result and whether it is free of
+    /// conflicts. `labels` name the ours, base and theirs versions in conflict markers.
+    fn merge_file(
+        &self,
+        base: &[u8],
+        ours: &[u8],
+        theirs: &[u8],
+        labels: [&str; 3],
+        marker_size: Option<usize>,
+    ) -> Result<(Vec<u8>, bool)>;
+
+    /// Opens `path` in the editor configured for git and waits for it to exit
+    fn edit_file(&self, path: &Path) -> Result<()>;
+
     fn add_config(&self, key: &str, value: &str) -> Result<()>;
 
     fn contains_config(&self, key: &str, value: &str) -> bool;
@@ -48,6 +219,8 @@ pub(crate) trait Repository {
 
     fn get_config(&self, key: &str) -> Result<String>;
 
+    fn get_config_multivar(&self, key: &str) -> Result<Vec<String>>;
+
     fn set_config(&self, key: &str, value: &str) -> Result<()>;
 
     fn remove_config_section(&self, key: &str) -> Result<()>;
@@ -58,8 +231,27 @@ pub(crate) struct LibGit2Repository {
 }
 
 impl LibGit2Repository {
-    pub(crate) fn from_current_dir() -> Result<Self> {
-        Self::from_dir(env::current_dir().context("Cannot determine current directory")?)
+    /// Opens the repository the way git does: the one in `git_dir`, or in `GIT_DIR`, otherwise
+    /// the one containing the current directory. `work_tree`, or `GIT_WORK_TREE`, overrides its
+    /// working tree. Bare repositories are supported, see [`Repository::is_bare`].
+    pub(crate) fn open(git_dir: Option<&Path>, work_tree: Option<&Path>) -> Result<Self> {
+        let cwd = env::current_dir().context("Cannot determine current directory")?;
+        let inner = match git_dir {
+            Some(dir) => git2::Repository::open(cwd.join(dir))
+                .with_context(|| format!("'{}' Not a git repository", dir.display()))?,
+            None => git2::Repository::open_from_env()
+                .with_context(|| format!("'{}' Not a git repository", cwd.display()))?,
+        };
+        // `open_from_env` resolves a relative `GIT_WORK_TREE` against the git directory, while git
+        // resolves it against the current directory, e.g. the `.` it passes to filters when run
+        // with `--work-tree`
+        let work_tree = work_tree
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("GIT_WORK_TREE").map(PathBuf::from));
+        if let Some(work_tree) = work_tree {
+            inner.set_workdir(&cwd.join(work_tree), false)?;
+        }
+        Ok(Self { inner })
     }
 
     pub(crate) fn from_dir(path: PathBuf) -> Result<Self> {
@@ -70,17 +262,77 @@ impl LibGit2Repository {
         }
         Ok(Self { inner })
     }
+
+    fn find_commit(&self, spec: &str) -> Result<git2::Oid> {
+        Ok(self
+            .inner
+            .revparse_single(spec)
+            .and_then(|object| object.peel_to_commit())
+            .map_err(|e| match e.code() {
+                git2::ErrorCode::NotFound => Error::NotExist(spec.to_string()),
+                _ => Error::Other(e.into()),
+            })?
+            .id())
+    }
+
+    /// The distinct versions of each file in the commits of `revwalk`, oldest first
+    fn walk_blobs(&self, mut revwalk: git2::Revwalk) -> Result<Vec<Blob>> {
+        revwalk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)?;
+
+        let mut seen = HashSet::new();
+        let mut rv = vec![];
+        for commit_id in revwalk {
+            let commit = self.inner.find_commit(commit_id?)?;
+            commit
+                .tree()?
+                .walk(git2::TreeWalkMode::PreOrder, |dir, entry| {
+                    if entry.kind() == Some(git2::ObjectType::Blob) {
+                        let path = Path::new(dir).join(entry.name().unwrap_or_default());
+                        if seen.insert((path.clone(), entry.id())) {
+                            rv.push(Blob {
+                                path,
+                                id: entry.id().to_string(),
+                                commit: Some(commit.id().to_string()),
+                            });
+                        }
+                    }
+                    git2::TreeWalkResult::Ok
+                })?;
+        }
+        Ok(rv)
+    }
 }
 
 impl Repository for LibGit2Repository {
     fn workdir(&self) -> &Path {
-        self.inner.workdir().unwrap() // None in case of bare repo
+        // Paths in bare repositories are relative to the git directory, which has no files
+        // of the working tree
+        self.inner.workdir().unwrap_or_else(|| self.inner.path())
+    }
+
+    fn is_bare(&self) -> bool {
+        self.inner.is_bare()
     }
 
     fn path(&self) -> &Path {
         self.inner.path()
     }
 
+    fn hooks_dir(&self) -> Result<PathBuf> {
+        match self.inner.config()?.get_path("core.hooksPath") {
+            // Relative to where hooks run, the root of the working tree
+            Ok(dir) => Ok(self.workdir().join(dir)),
+            Err(e) if e.code() == git2::ErrorCode::NotFound => {
+                // Linked worktrees share the hooks of the main repository, named in `commondir`
+                let common = fs::read_to_string(self.path().join("commondir"))
+                    .and_then(|dir| self.path().join(dir.trim()).canonicalize())
+                    .unwrap_or_else(|_| self.path().to_path_buf());
+                Ok(common.join("hooks"))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
     fn get_file_contents(&self, path: &Path) -> Result<Vec<u8>> {
         let relpath = path.strip_prefix(self.workdir()).with_context(|| {
             format!(
@@ -89,17 +341,20 @@ impl Repository for LibGit2Repository {
                 self.workdir().display()
             )
         })?;
-        let entry = self
-            .inner
-            .head()
-            .context("Couldn not determine repository head")?
+        let head = self.inner.head().map_err(|e| match e.code() {
+            // Nothing is committed on the branch yet
+            git2::ErrorCode::UnbornBranch | git2::ErrorCode::NotFound => {
+                Error::NotExist(format!("Path {} is not found in HEAD", relpath.display()))
+            }
+            _ => Error::Other(anyhow!(e).context("Couldn not determine repository head")),
+        })?;
+        let entry = head
             .peel_to_tree()?
             .get_path(relpath)
             .map_err(|e| match e.code() {
-                git2::ErrorCode::NotFound => Error::NotExist(format!(
-                    "Path {} is not found in HEAD",
-                    relpath.display(),
-                )),
+                git2::ErrorCode::NotFound => {
+                    Error::NotExist(format!("Path {} is not found in HEAD", relpath.display(),))
+                }
                 _ => Error::Other(e.into()),
             })?;
         let contents = entry.to_object(&self.inner)?;
@@ -107,6 +362,354 @@ impl Repository for LibGit2Repository {
         Ok(contents.as_blob().unwrap().content().into())
     }
 
+    fn get_staged_contents(&self, path: &Path) -> Result<Vec<u8>> {
+        let relpath = path.strip_prefix(self.workdir()).with_context(|| {
+            format!(
+                "Path {} is outside of git repository {}",
+                path.display(),
+                self.workdir().display()
+            )
+        })?;
+        let mut index = self.inner.index()?;
+        index.read(false)?;
+        let entry = index.get_path(relpath, 0).ok_or_else(|| {
+            Error::NotExist(format!(
+                "Path {} is not found in the index",
+                relpath.display()
+            ))
+        })?;
+        Ok(self.inner.find_blob(entry.id)?.content().into())
+    }
+
+    fn stat_clean_blob(&self, path: &Path) -> Result<Option<String>> {
+        let relpath = path.strip_prefix(self.workdir()).with_context(|| {
+            format!(
+                "Path {} is outside of git repository {}",
+                path.display(),
+                self.workdir().display()
+            )
+        })?;
+        let mut index = self.inner.index()?;
+        index.read(false)?;
+        let (Some(entry), Some(index_path)) = (index.get_path(relpath, 0), index.path()) else {
+            return Ok(None);
+        };
+        let Ok(metadata) = fs::symlink_metadata(path) else {
+            return Ok(None);
+        };
+        // Changes in the same tick as the index was written don't show in the stat information,
+        // git calls these entries racy
+        if metadata.modified()? >= fs::metadata(index_path)?.modified()? {
+            return Ok(None);
+        }
+        Ok(stat_matches(&entry, &metadata).then(|| entry.id.to_string()))
+    }
+
+    fn list_files(&self) -> Result<Vec<PathBuf>> {
+        let index = self.inner.index()?;
+        Ok(index
+            .iter()
+            .map(|entry| PathBuf::from(String::from_utf8_lossy(&entry.path).as_ref()))
+            .collect())
+    }
+
+    fn submodules(&self) -> Result<Vec<PathBuf>> {
+        Ok(self
+            .inner
+            .submodules()?
+            .iter()
+            .map(|submodule| self.workdir().join(submodule.path()))
+            // Registered submodules which weren't initialized have an empty directory
+            .filter(|dir| dir.join(".git").exists())
+            .collect())
+    }
+
+    fn current_branch(&self) -> Result<Option<String>> {
+        // Unlike `head()`, this works on a branch without commits
+        let head = self.inner.find_reference("HEAD")?;
+        Ok(head
+            .symbolic_target()
+            .and_then(|target| target.strip_prefix("refs/heads/"))
+            .map(String::from))
+    }
+
+    fn push_remote(&self, branch: &str) -> Result<Option<String>> {
+        let keys = [
+            format!("branch.{}.pushRemote", branch),
+            "remote.pushDefault".to_string(),
+            format!("branch.{}.remote", branch),
+        ];
+        for key in keys {
+            match self.get_config(&key) {
+                Ok(remote) => return Ok(Some(remote)),
+                Err(Error::NotExist(_)) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(None)
+    }
+
+    fn remote_name(&self, remote: &str) -> Result<Option<String>> {
+        let names = self.inner.remotes()?;
+        let names: Vec<&str> = names.iter().flatten().collect();
+        if names.contains(&remote) {
+            return Ok(Some(remote.into()));
+        }
+        for name in names {
+            let found = self.inner.find_remote(name)?;
+            if found.url() == Some(remote) || found.pushurl() == Some(remote) {
+                return Ok(Some(name.into()));
+            }
+        }
+        Ok(None)
+    }
+
+    fn index_blobs(&self) -> Result<Vec<Blob>> {
+        let mut index = self.inner.index()?;
+        // Picks up changes made by git commands since the index was loaded
+        index.read(false)?;
+        Ok(index
+            .iter()
+            .map(|entry| Blob {
+                path: PathBuf::from(String::from_utf8_lossy(&entry.path).as_ref()),
+                id: entry.id.to_string(),
+                commit: None,
+            })
+            .collect())
+    }
+
+    fn history_blobs(&self, since: Option<&str>) -> Result<Vec<Blob>> {
+        if self.inner.head().is_err() {
+            // Nothing committed yet
+            return Ok(vec![]);
+        }
+        let mut revwalk = self.inner.revwalk()?;
+        revwalk.push_head()?;
+        if let Some(since) = since {
+            revwalk.hide(self.find_commit(since)?)?;
+        }
+        self.walk_blobs(revwalk)
+    }
+
+    fn pushed_blobs(
+        &self,
+        local: &str,
+        remote: Option<&str>,
+        name: Option<&str>,
+    ) -> Result<Vec<Blob>> {
+        let mut revwalk = self.inner.revwalk()?;
+        revwalk.push(self.find_commit(local)?)?;
+        // Missing when the remote branch was updated since the last fetch, pushing then fails
+        // unless forced
+        let remote = match remote.map(|remote| self.find_commit(remote)) {
+            Some(Ok(commit)) => Some(commit),
+            Some(Err(Error::NotExist(_))) | None => None,
+            Some(Err(err)) => return Err(err),
+        };
+        match (remote, name) {
+            (Some(commit), _) => revwalk.hide(commit)?,
+            (None, Some(name)) => revwalk.hide_glob(&format!("refs/remotes/{}/*", name))?,
+            (None, None) => {}
+        }
+        self.walk_blobs(revwalk)
+    }
+
+    fn read_blob(&self, id: &str) -> Result<Vec<u8>> {
+        let blob = self.inner.find_blob(git2::Oid::from_str(id)?)?;
+        Ok(blob.content().into())
+    }
+
+    fn write_blob(&self, contents: &[u8]) -> Result<String> {
+        Ok(self.inner.blob(contents)?.to_string())
+    }
+
+    fn read_revision(&self, spec: &str) -> Result<Vec<u8>> {
+        let object = self
+            .inner
+            .revparse_single(spec)
+            .map_err(|e| match e.code() {
+                git2::ErrorCode::NotFound => Error::NotExist(spec.to_string()),
+                _ => Error::Other(e.into()),
+            })?;
+        let blob = object
+            .peel_to_blob()
+            .with_context(|| format!("{} is not a file", spec))?;
+        Ok(blob.content().into())
+    }
+
+    fn changed_files(&self, old: &str, new: &str) -> Result<Vec<PathBuf>> {
+        let tree = |spec: &str| -> Result<Option<git2::Tree>> {
+            if git2::Oid::from_str(spec).is_ok_and(|id| id.is_zero()) {
+                return Ok(None);
+            }
+            let object = self
+                .inner
+                .revparse_single(spec)
+                .map_err(|e| match e.code() {
+                    git2::ErrorCode::NotFound => Error::NotExist(spec.to_string()),
+                    _ => Error::Other(e.into()),
+                })?;
+            Ok(Some(object.peel_to_tree()?))
+        };
+        let diff = self
+            .inner
+            .diff_tree_to_tree(tree(old)?.as_ref(), tree(new)?.as_ref(), None)?;
+        Ok(diff
+            .deltas()
+            .filter_map(|delta| delta.new_file().path().map(PathBuf::from))
+            .collect())
+    }
+
+    fn modified_files(&self, paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+        if paths.is_empty() {
+            return Ok(vec![]);
+        }
+        // libgit2 can't run the clean filter, so a decrypted file would always look modified
+        let mut args: Vec<&OsStr> = ["status", "--porcelain", "-z", "--untracked-files=no", "--"]
+            .iter()
+            .map(OsStr::new)
+            .collect();
+        args.extend(paths.iter().map(|p| p.as_os_str()));
+        let output = self.git(&args, None)?;
+
+        let mut rv = vec![];
+        let mut entries = output.split(|b| *b == 0).filter(|e| !e.is_empty());
+        while let Some(entry) = entries.next() {
+            if entry.len() < 4 {
+                continue;
+            }
+            let (status, path) = entry.split_at(3);
+            if matches!(status[0], b'R' | b'C') {
+                // Followed by the original path
+                entries.next();
+            }
+            if status[1] != b' ' {
+                rv.push(PathBuf::from(String::from_utf8_lossy(path).as_ref()));
+            }
+        }
+        Ok(rv)
+    }
+
+    fn checkout_files(&self, paths: &[PathBuf]) -> Result<()> {
+        if paths.is_empty() {
+            return Ok(());
+        }
+        for path in paths {
+            // git skips files which are up to date with the index, even with `--force`
+            match std::fs::remove_file(self.workdir().join(path)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        // Unlike `checkout-index`, `checkout` lets the filter process delay files and decrypt
+        // them in parallel. Hooks are skipped, like with `checkout-index`.
+        let mut args = [
+            "-c",
+            "core.hooksPath=/dev/null",
+            "--literal-pathspecs",
+            "checkout",
+            "--",
+        ]
+        .map(OsStr::new)
+        .to_vec();
+        args.extend(paths.iter().map(|p| p.as_os_str()));
+        self.git(&args, None)?;
+        Ok(())
+    }
+
+    fn add_files(&self, paths: &[PathBuf], renormalize: bool) -> Result<()> {
+        if paths.is_empty() {
+            return Ok(());
+        }
+        let mut args = vec![OsStr::new("add")];
+        if renormalize {
+            args.push(OsStr::new("--renormalize"));
+        }
+        args.push(OsStr::new("--"));
+        args.extend(paths.iter().map(|p| p.as_os_str()));
+        self.git(&args, None)?;
+        Ok(())
+    }
+
+    fn get_attribute(&self, path: &Path, name: &str) -> Result<Option<String>> {
+        let value = self
+            .inner
+            .get_attr(path, name, git2::AttrCheckFlags::FILE_THEN_INDEX)?;
+        Ok(match git2::AttrValue::from_string(value) {
+            git2::AttrValue::True => Some("true".into()),
+            git2::AttrValue::False => Some("false".into()),
+            git2::AttrValue::String(v) => Some(v.into()),
+            git2::AttrValue::Bytes(v) => Some(String::from_utf8_lossy(v).into()),
+            git2::AttrValue::Unspecified => None,
+        })
+    }
+
+    fn merge_file(
+        &self,
+        base: &[u8],
+        ours: &[u8],
+        theirs: &[u8],
+        labels: [&str; 3],
+        marker_size: Option<usize>,
+    ) -> Result<(Vec<u8>, bool)> {
+        // Neither libgit2 nor git can merge from memory, so the versions are stored in the git
+        // directory instead of the world readable temporary directory
+        let dir = tempfile::Builder::new()
+            .prefix("git-agecrypt-merge")
+            .tempdir_in(self.path())?;
+        let files = [("ours", ours), ("base", base), ("theirs", theirs)].map(|(name, contents)| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, contents).map(|_| path)
+        });
+        let mut command = process::Command::new("git");
+        command
+            .current_dir(self.workdir())
+            .args(["merge-file", "-p"]);
+        if let Some(size) = marker_size {
+            command.arg(format!("--marker-size={}", size));
+        }
+        for label in labels {
+            command.arg("-L").arg(label);
+        }
+        for file in files {
+            command.arg(file?);
+        }
+        let output = command.output()?;
+        match output.status.code() {
+            Some(0) => Ok((output.stdout, true)),
+            // The number of conflicts
+            Some(1..=127) => Ok((output.stdout, false)),
+            _ => Err(Error::Other(anyhow!(
+                "Command {:?} failed with {}: {}",
+                command,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ))),
+        }
+    }
+
+    fn edit_file(&self, path: &Path) -> Result<()> {
+        let editor = self.git(&["var".as_ref(), "GIT_EDITOR".as_ref()], None)?;
+        let editor = String::from_utf8_lossy(&editor).trim().to_string();
+        // Run through the shell like git does, the editor may include arguments
+        let status = process::Command::new("sh")
+            .current_dir(self.workdir())
+            .arg("-c")
+            .arg(format!("{} \"$@\"", editor))
+            .arg(&editor)
+            .arg(path)
+            .status()
+            .with_context(|| format!("Couldn't run editor '{}'", editor))?;
+        if !status.success() {
+            return Err(Error::Other(anyhow!(
+                "Editor '{}' failed with {}",
+                editor,
+                status
+            )));
+        }
+        Ok(())
+    }
+
     fn add_config(&self, key: &str, value: &str) -> Result<()> {
         if self.contains_config(key, value) {
             return Err(Error::AlreadyExists(value.into()));
@@ -155,6 +758,17 @@ impl Repository for LibGit2Repository {
             .map_err(|_e| Error::NotExist(key.into()))
     }
 
+    fn get_config_multivar(&self, key: &str) -> Result<Vec<String>> {
+        let cfg = self.inner.config()?;
+        let mut entries = Vec::new();
+        cfg.multivar(key, None)?.for_each(|e| {
+            if let Some(v) = e.value() {
+                entries.push(v.into())
+            }
+        })?;
+        Ok(entries)
+    }
+
     fn set_config(&self, key: &str, value: &str) -> Result<()> {
         for v in self.list_config(key)? {
             self.remove_config(key, &v)?;
@@ -165,29 +779,72 @@ impl Repository for LibGit2Repository {
     }
 
     fn remove_config_section(&self, key: &str) -> Result<()> {
-        // Unfortunately there is no `git config --remove-section <section>` equivalent in libgit2
+        let mut command = self.git_command();
+        command.arg("config");
+        remove_config_section_with(command, key)
+    }
+}
+
+impl LibGit2Repository {
+    /// Runs a git command in the working directory, returning its output
+    /// A git command working on this repository, even if it was opened with overrides
+    fn git_command(&self) -> process::Command {
         let mut command = process::Command::new("git");
         command
             .current_dir(self.workdir())
-            .arg("config")
-            .arg("--remove-section")
-            .arg(key);
-        let output = command.output()?;
+            .arg("--git-dir")
+            .arg(self.path());
+        if let Some(workdir) = self.inner.workdir() {
+            command.arg("--work-tree").arg(workdir);
+        }
+        command
+    }
 
+    fn git(&self, args: &[&OsStr], input: Option<&[u8]>) -> Result<Vec<u8>> {
+        let mut command = self.git_command();
+        command
+            .args(args)
+            .stdin(process::Stdio::piped())
+            .stdout(process::Stdio::piped())
+            .stderr(process::Stdio::piped());
+        let mut child = command.spawn().map_err(spawn_error)?;
+        if let Some(input) = input {
+            child.stdin.take().unwrap().write_all(input)?;
+        }
+        let output = child.wait_with_output()?;
         if !output.status.success() {
-            log::error!(
-                "Failed to execute command. This may not be an issue; command='{:?}' status='{}', stdout={:?}, stderr={:?}",
+            return Err(Error::Other(anyhow!(
+                "Command {:?} failed with {}: {}",
                 command,
                 output.status,
-                String::from_utf8_lossy(&output.stdout),
-                String::from_utf8_lossy(&output.stderr)
-            );
-            return Err(Error::NotExist(key.into()));
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
         }
-        Ok(())
+        Ok(output.stdout)
     }
 }
 
+/// Whether the stat information of an index entry matches the file it was staged from
+fn stat_matches(entry: &git2::IndexEntry, metadata: &fs::Metadata) -> bool {
+    let Ok(mtime) = metadata
+        .modified()
+        .map(|t| t.duration_since(UNIX_EPOCH).unwrap_or_default())
+    else {
+        return false;
+    };
+    // The index only keeps the lower 32 bits of sizes and times
+    let matches = entry.file_size == metadata.len() as u32
+        && entry.mtime.seconds() == mtime.as_secs() as i32
+        && entry.mtime.nanoseconds() == mtime.subsec_nanos();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        matches && entry.ino == metadata.ino() as u32
+    }
+    #[cfg(not(unix))]
+    matches
+}
+
 #[cfg(test)]
 mod tests {
     use std::ops::Deref;
@@ -252,6 +909,22 @@ mod tests {
         Ok(())
     }
 
+    #[rstest]
+    fn test_open_overrides(tempdir: TempDir) -> Result<()> {
+        let bare = tempdir.child("repo.git");
+        cmd!("git", "init", "--bare", bare.path()).run()?;
+        let repo = LibGit2Repository::open(Some(bare.path()), None)?;
+        assert!(repo.is_bare());
+        assert_eq!(repo.workdir(), repo.path());
+
+        let work_tree = tempdir.child("work");
+        work_tree.create_dir_all()?;
+        let repo = LibGit2Repository::open(Some(bare.path()), Some(work_tree.path()))?;
+        assert!(!repo.is_bare());
+        assert_eq!(repo.workdir(), work_tree.path());
+        Ok(())
+    }
+
     #[rstest]
     fn test_get_file_contents(git_repo: Repo) -> Result<()> {
         cmd!("git", "config", "user.email", "author@example.com")
@@ -262,11 +935,16 @@ mod tests {
             .run()?;
         let path = PathBuf::from("subdir/file.txt");
         let file_contents = "file contents";
+        assert_matches!(
+            git_repo.get_file_contents(&git_repo.dir.join(&path)),
+            Err(Error::NotExist(_))
+        );
 
         let repo_file = git_repo.dir.child(&path);
         repo_file.touch()?;
         repo_file.write_str(file_contents)?;
         cmd!("git", "add", &path).dir(git_repo.dir.path()).run()?;
+        assert_eq!(git_repo.list_files()?, std::slice::from_ref(&path));
         cmd!("git", "commit", "-m", "adding file")
             .dir(git_repo.dir.path())
             .run()?;
@@ -282,6 +960,16 @@ mod tests {
             file_contents.as_bytes()
         );
 
+        cmd!("git", "add", &path).dir(git_repo.dir.path()).run()?;
+        assert_eq!(
+            git_repo.get_staged_contents(&git_repo.dir.join(&path))?,
+            b"additional_contents"
+        );
+        assert_matches!(
+            git_repo.get_staged_contents(&git_repo.dir.join("missing")),
+            Err(Error::NotExist(_))
+        );
+
         assert!(git_repo
             .get_file_contents(git_repo.dir.path().parent().unwrap())
             .is_err());
@@ -289,6 +977,306 @@ mod tests {
         Ok(())
     }
 
+    #[rstest]
+    fn test_checkout_files(git_repo: Repo) -> Result<()> {
+        let paths = [PathBuf::from("a.txt"), PathBuf::from("b.txt")];
+        for path in &paths {
+            git_repo.dir.child(path).write_str("original")?;
+        }
+        cmd!("git", "add", "a.txt", "b.txt")
+            .dir(git_repo.dir.path())
+            .run()?;
+        assert_eq!(git_repo.modified_files(&paths)?, [] as [PathBuf; 0]);
+
+        git_repo.dir.child("b.txt").write_str("changed")?;
+        assert_eq!(git_repo.modified_files(&paths)?, paths[1..]);
+
+        git_repo.checkout_files(&paths)?;
+        git_repo.dir.child("b.txt").assert("original");
+        assert_eq!(git_repo.modified_files(&paths)?, [] as [PathBuf; 0]);
+
+        // Paths aren't taken as patterns
+        git_repo.dir.child("*.txt").write_str("original")?;
+        cmd!("git", "add", "*.txt").dir(git_repo.dir.path()).run()?;
+        git_repo.dir.child("a.txt").write_str("changed")?;
+        git_repo.checkout_files(&[PathBuf::from("*.txt")])?;
+        git_repo.dir.child("a.txt").assert("changed");
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_stat_clean_blob(git_repo: Repo) -> Result<()> {
+        let file = git_repo.dir.child("a.txt");
+        file.write_str("original")?;
+        // Written before the index, so that its entry isn't racy
+        fs::File::options()
+            .write(true)
+            .open(file.path())?
+            .set_modified(std::time::SystemTime::now() - std::time::Duration::from_secs(10))?;
+        assert_eq!(git_repo.stat_clean_blob(file.path())?, None);
+
+        cmd!("git", "add", "a.txt").dir(git_repo.dir.path()).run()?;
+        assert_eq!(
+            git_repo.stat_clean_blob(file.path())?,
+            Some(blob_id(b"original")?)
+        );
+
+        file.write_str("changed")?;
+        assert_eq!(git_repo.stat_clean_blob(file.path())?, None);
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_submodules(git_repo: Repo, tempdir: TempDir) -> Result<()> {
+        let git = |dir: &Path, args: &[&str]| {
+            cmd(
+                "git",
+                [
+                    "-c",
+                    "user.name=A U Thor",
+                    "-c",
+                    "user.email=author@example.com",
+                ]
+                .iter()
+                .chain(args),
+            )
+            .dir(dir)
+            .stdout_null()
+            .stderr_null()
+            .run()
+        };
+        git(tempdir.path(), &["init"])?;
+        git(tempdir.path(), &["commit", "--allow-empty", "-m", "init"])?;
+        assert_eq!(git_repo.submodules()?, [] as [PathBuf; 0]);
+
+        let url = tempdir.path().to_string_lossy();
+        let args = ["-c", "protocol.file.allow=always", "submodule", "add", &url];
+        git(git_repo.dir.path(), &[&args[..], &["checked-out"]].concat())?;
+        git(git_repo.dir.path(), &[&args[..], &["removed"]].concat())?;
+        git(
+            git_repo.dir.path(),
+            &["submodule", "deinit", "--force", "removed"],
+        )?;
+        assert_eq!(git_repo.submodules()?, [git_repo.dir.join("checked-out")]);
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_push_remote(git_repo: Repo) -> Result<()> {
+        let dir = git_repo.dir.path();
+        cmd!("git", "checkout", "-q", "-b", "internal")
+            .dir(dir)
+            .run()?;
+        assert_eq!(git_repo.current_branch()?.as_deref(), Some("internal"));
+        assert_eq!(git_repo.push_remote("internal")?, None);
+
+        git_repo.set_config("branch.internal.remote", "origin")?;
+        assert_eq!(git_repo.push_remote("internal")?.as_deref(), Some("origin"));
+        git_repo.set_config("remote.pushDefault", "mirror")?;
+        assert_eq!(git_repo.push_remote("internal")?.as_deref(), Some("mirror"));
+        git_repo.set_config("branch.internal.pushRemote", "public")?;
+        assert_eq!(git_repo.push_remote("internal")?.as_deref(), Some("public"));
+        assert_eq!(git_repo.push_remote("main")?.as_deref(), Some("mirror"));
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_remote_name(git_repo: Repo) -> Result<()> {
+        let dir = git_repo.dir.path();
+        cmd!(
+            "git",
+            "remote",
+            "add",
+            "public",
+            "https://example.com/public.git"
+        )
+        .dir(dir)
+        .run()?;
+        cmd!(
+            "git",
+            "remote",
+            "add",
+            "origin",
+            "https://example.com/internal.git"
+        )
+        .dir(dir)
+        .run()?;
+        git_repo.set_config("remote.origin.pushurl", "ssh://example.com/internal.git")?;
+        assert_eq!(git_repo.remote_name("public")?.as_deref(), Some("public"));
+        assert_eq!(
+            git_repo
+                .remote_name("https://example.com/public.git")?
+                .as_deref(),
+            Some("public")
+        );
+        assert_eq!(
+            git_repo
+                .remote_name("ssh://example.com/internal.git")?
+                .as_deref(),
+            Some("origin")
+        );
+        assert_eq!(git_repo.remote_name("mirror")?, None);
+        assert_eq!(git_repo.remote_name("https://example.com/other.git")?, None);
+        Ok(())
+    }
+
+    #[rstest]
+    #[case("git version 2.39.2\n", Some((2, 39)))]
+    #[case("git version 2.45.1.windows.1\n", Some((2, 45)))]
+    #[case("git version 2.39.3 (Apple Git-145)\n", Some((2, 39)))]
+    #[case("hub version 2.14.2\n", None)]
+    fn test_parse_version(#[case] output: &str, #[case] expected: Option<(u32, u32)>) {
+        assert_eq!(parse_version(output), expected);
+    }
+
+    #[rstest]
+    fn test_attributes(git_repo: Repo) -> Result<()> {
+        git_repo
+            .dir
+            .child(".gitattributes")
+            .write_str("*.txt filter=rot13 -diff\n")?;
+        let path = Path::new("a.txt");
+        let attribute = |name| git_repo.get_attribute(path, name);
+        assert_eq!(attribute("filter")?.as_deref(), Some("rot13"));
+        assert_eq!(attribute("diff")?.as_deref(), Some("false"));
+        assert_eq!(attribute("merge")?, None);
+
+        // Runs the clean filter again once it is configured for the committed file
+        git_repo.dir.child("a.txt").write_str("abc")?;
+        git_repo.add_files(&[path.to_path_buf()], false)?;
+        cmd!("git", "config", "filter.rot13.clean", "tr a-z n-za-m")
+            .dir(git_repo.dir.path())
+            .run()?;
+        git_repo.add_files(&[path.to_path_buf()], true)?;
+        let blob = &git_repo.index_blobs()?[0];
+        assert_eq!(git_repo.read_blob(&blob.id)?, b"nop");
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_merge_file(git_repo: Repo) -> Result<()> {
+        let labels = ["ours", "base", "theirs"];
+        let (merged, clean) =
+            git_repo.merge_file(b"a\nb\nc\n", b"A\nb\nc\n", b"a\nb\nC\n", labels, None)?;
+        assert!(clean);
+        assert_eq!(merged, b"A\nb\nC\n");
+
+        let (merged, clean) = git_repo.merge_file(b"a\n", b"b\n", b"c\n", labels, Some(3))?;
+        assert!(!clean);
+        assert_eq!(merged, b"<<< ours\nb\n===\nc\n>>> theirs\n");
+        for entry in std::fs::read_dir(git_repo.path())? {
+            let name = entry?.file_name();
+            assert!(!name.to_string_lossy().starts_with("git-agecrypt-merge"));
+        }
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_history_blobs(git_repo: Repo) -> Result<()> {
+        assert_eq!(git_repo.history_blobs(None)?, []);
+        let dir = git_repo.dir.path();
+        cmd!("git", "config", "user.email", "author@example.com")
+            .dir(dir)
+            .run()?;
+        cmd!("git", "config", "user.name", "A U Thor")
+            .dir(dir)
+            .run()?;
+        let file = git_repo.dir.child("subdir/file.txt");
+        for contents in ["first", "second", "first"] {
+            file.write_str(contents)?;
+            cmd!("git", "add", file.path()).dir(dir).run()?;
+            cmd!("git", "commit", "-m", contents).dir(dir).run()?;
+        }
+
+        let blobs = git_repo.history_blobs(None)?;
+        let contents: Vec<_> = blobs
+            .iter()
+            .map(|b| git_repo.read_blob(&b.id))
+            .collect::<Result<_, _>>()?;
+        assert_eq!(contents, [b"first".to_vec(), b"second".to_vec()]);
+        assert!(blobs.iter().all(|b| b.path == Path::new("subdir/file.txt")));
+        assert_ne!(blobs[0].commit, blobs[1].commit);
+
+        let since = git_repo.history_blobs(Some("HEAD~1"))?;
+        assert_eq!(since.len(), 1);
+        assert_eq!(since[0].id, blobs[0].id);
+        assert_ne!(since[0].commit, blobs[0].commit);
+        assert_matches!(
+            git_repo.history_blobs(Some("no-such-rev")),
+            Err(Error::NotExist(_))
+        );
+
+        let head = cmd!("git", "rev-parse", "HEAD").dir(dir).read()?;
+        let parent = cmd!("git", "rev-parse", "HEAD~1").dir(dir).read()?;
+        let pushed = git_repo.pushed_blobs(&head, Some(&parent), None)?;
+        assert_eq!(pushed, since);
+        // Nothing was fetched from the remote, so a new branch pushes every commit
+        assert_eq!(git_repo.pushed_blobs(&head, None, Some("public"))?, blobs);
+        cmd!("git", "update-ref", "refs/remotes/public/main", "HEAD~1")
+            .dir(dir)
+            .run()?;
+        cmd!("git", "update-ref", "refs/remotes/origin/main", "HEAD")
+            .dir(dir)
+            .run()?;
+        assert_eq!(git_repo.pushed_blobs(&head, None, Some("public"))?, since);
+        assert_eq!(git_repo.pushed_blobs(&head, None, None)?, blobs);
+        // The remote branch was updated by someone else since the last fetch
+        let unknown = "1".repeat(40);
+        assert_eq!(
+            git_repo.pushed_blobs(&head, Some(&unknown), Some("public"))?,
+            since
+        );
+
+        let index = git_repo.index_blobs()?;
+        assert_eq!(index.len(), 1);
+        assert_eq!(index[0].id, blobs[0].id);
+
+        assert_eq!(git_repo.read_revision("HEAD~1:subdir/file.txt")?, b"second");
+        let id = git_repo.write_blob(b"third")?;
+        assert_eq!(id, blob_id(b"third")?);
+        assert_eq!(git_repo.read_blob(&id)?, b"third");
+        assert_matches!(
+            git_repo.read_revision("HEAD:missing"),
+            Err(Error::NotExist(_))
+        );
+        assert!(git_repo.read_revision("HEAD:subdir").is_err());
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_changed_files(git_repo: Repo) -> Result<()> {
+        let dir = git_repo.dir.path();
+        cmd!("git", "config", "user.email", "author@example.com")
+            .dir(dir)
+            .run()?;
+        cmd!("git", "config", "user.name", "A U Thor")
+            .dir(dir)
+            .run()?;
+        git_repo.dir.child("a").write_str("a")?;
+        git_repo.dir.child("b").write_str("b")?;
+        cmd!("git", "add", "a", "b").dir(dir).run()?;
+        cmd!("git", "commit", "-m", "first").dir(dir).run()?;
+        git_repo.dir.child("a").write_str("changed")?;
+        cmd!("git", "rm", "-q", "b").dir(dir).run()?;
+        cmd!("git", "commit", "-am", "second").dir(dir).run()?;
+
+        let zero = git2::Oid::zero().to_string();
+        assert_eq!(
+            git_repo.changed_files(&zero, "HEAD~1")?,
+            [PathBuf::from("a"), PathBuf::from("b")]
+        );
+        assert_eq!(
+            git_repo.changed_files("HEAD~1", "HEAD")?,
+            [PathBuf::from("a"), PathBuf::from("b")]
+        );
+        assert_eq!(git_repo.changed_files("HEAD", "HEAD")?, [] as [PathBuf; 0]);
+        assert_matches!(
+            git_repo.changed_files("no-such-rev", "HEAD"),
+            Err(Error::NotExist(_))
+        );
+        Ok(())
+    }
+
     #[rstest]
     fn test_config(git_repo: Repo) -> Result<()> {
         // At first there are no entries under the "foo" section
@@ -307,6 +1295,11 @@ mod tests {
 
         // Returns the last set config
         assert_eq!(git_repo.get_config("foo.bar")?, "snafu");
+        assert_eq!(
+            git_repo.get_config_multivar("foo.Bar")?,
+            ["foobar", "snafu"]
+        );
+        assert_eq!(git_repo.get_config_multivar("foo.baz")?, [] as [String; 0]);
 
         // Set overrides multivalue entries
         git_repo.set_config("foo.bar", "FOOBAR")?;
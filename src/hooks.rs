@@ -0,0 +1,220 @@
+//! Installs the git hooks running git-agecrypt checks, or writes their configuration for
+//! hook managers like lefthook and pre-commit
+
+use std::{fs, io, path::Path};
+
+use anyhow::{bail, Context, Result};
+
+const MARKER: &str = "# Installed by git-agecrypt, changes are overwritten";
+
+/// Hooks installed by `init --hooks` and the git-agecrypt arguments they run
+pub(crate) const HOOKS: &[(&str, &str)] = &[
+    ("pre-commit", "verify --quick"),
+    ("post-checkout", "refresh-sidecars \"$1\" \"$2\""),
+    ("post-merge", "refresh-sidecars ORIG_HEAD HEAD"),
+    ("pre-push", "verify --quick --remote \"$1\" --pushed"),
+];
+
+/// Writes a hook running `command`, replacing one written earlier by git-agecrypt.
+///
+/// Hooks not written by git-agecrypt are left alone and reported as an error.
+pub(crate) fn install(dir: &Path, name: &str, command: &str) -> Result<()> {
+    let path = dir.join(name);
+    if let Some(contents) = read(&path)? {
+        if !contents.contains(MARKER) {
+            bail!(
+                "A {} hook already exists at {:?}, add `{}` to it manually",
+                name,
+                path,
+                command
+            );
+        }
+    }
+    fs::create_dir_all(dir).with_context(|| format!("Couldn't create {:?}", dir))?;
+    fs::write(&path, format!("#!/bin/sh\n{}\nexec {}\n", MARKER, command))
+        .with_context(|| format!("Couldn't write {:?}", path))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+    }
+    Ok(())
+}
+
+/// Writes `contents` to `path`, replacing a file written earlier by git-agecrypt.
+///
+/// Files not written by git-agecrypt are left alone and reported as an error.
+pub(crate) fn write_snippet(path: &Path, contents: &str) -> Result<()> {
+    if let Some(existing) = read(path)? {
+        if !existing.contains(MARKER) {
+            bail!(
+                "{:?} already exists and wasn't written by git-agecrypt",
+                path
+            );
+        }
+    }
+    fs::write(path, contents).with_context(|| format!("Couldn't write {:?}", path))
+}
+
+/// Configuration for [lefthook](https://github.com/evilmartians/lefthook) running the hook
+/// `commands`, to be listed in `extends` of `lefthook.yml`
+pub(crate) fn lefthook_config(commands: &[(&str, String)]) -> String {
+    let mut rv = format!("{}\n", MARKER);
+    for (name, command) in commands {
+        rv.push_str(&format!(
+            "{}:\n  commands:\n    git-agecrypt:\n      run: {}\n",
+            name,
+            yaml_string(&positional(command, "{1}", "{2}"))
+        ));
+        // The refs being pushed are passed on stdin
+        if *name == "pre-push" {
+            rv.push_str("      use_stdin: true\n");
+        }
+    }
+    rv
+}
+
+/// Configuration for [pre-commit](https://pre-commit.com) running the hook `commands`, to be
+/// copied into `.pre-commit-config.yaml`
+pub(crate) fn pre_commit_config(commands: &[(&str, String)]) -> String {
+    let mut rv = format!(
+        "{}\n# Copy the hooks into .pre-commit-config.yaml and install them with\n\
+         #   pre-commit install --hook-type pre-commit --hook-type post-checkout --hook-type post-merge \\\n\
+         #     --hook-type pre-push\n\
+         repos:\n  - repo: local\n    hooks:\n",
+        MARKER
+    );
+    for (name, command) in commands {
+        // pre-commit passes the arguments of git in environment variables
+        let command = match *name {
+            // The refs being pushed too, one ref per run
+            "pre-push" => format!(
+                "echo \"$PRE_COMMIT_LOCAL_BRANCH $PRE_COMMIT_TO_REF $PRE_COMMIT_REMOTE_BRANCH \
+                 $PRE_COMMIT_FROM_REF\" | {}",
+                positional(command, "\"$PRE_COMMIT_REMOTE_NAME\"", "")
+            ),
+            _ => positional(
+                command,
+                "\"$PRE_COMMIT_FROM_REF\"",
+                "\"$PRE_COMMIT_TO_REF\"",
+            ),
+        };
+        let entry = format!("sh -c '{}'", command.replace('\'', "'\\''"));
+        rv.push_str(&format!(
+            "      - id: git-agecrypt-{name}\n        name: git-agecrypt {name}\n        \
+             entry: {}\n        language: system\n        pass_filenames: false\n        \
+             always_run: true\n        stages: [{name}]\n",
+            yaml_string(&entry)
+        ));
+    }
+    rv
+}
+
+/// Replaces the arguments git passes to hooks, `"$1"` and `"$2"`, in a hook command
+fn positional(command: &str, first: &str, second: &str) -> String {
+    command.replace("\"$1\"", first).replace("\"$2\"", second)
+}
+
+/// `value` as double quoted YAML string, which JSON strings are
+fn yaml_string(value: &str) -> String {
+    serde_json::Value::from(value).to_string()
+}
+
+/// Removes a hook written by git-agecrypt, returns whether there was one
+pub(crate) fn uninstall(dir: &Path, name: &str) -> Result<bool> {
+    let path = dir.join(name);
+    match read(&path)? {
+        Some(contents) if contents.contains(MARKER) => {
+            fs::remove_file(&path).with_context(|| format!("Couldn't remove {:?}", path))?;
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+fn read(path: &Path) -> Result<Option<String>> {
+    match fs::read(path) {
+        Ok(contents) => Ok(Some(String::from_utf8_lossy(&contents).into_owned())),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err).with_context(|| format!("Couldn't read {:?}", path)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_fs::{prelude::*, TempDir};
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    fn test_install() -> Result<()> {
+        let dir = TempDir::new()?;
+        let hooks = dir.child("hooks");
+
+        install(hooks.path(), "pre-commit", "git-agecrypt verify --quick")?;
+        let hook = hooks.child("pre-commit");
+        assert!(fs::read_to_string(hook.path())?.ends_with("exec git-agecrypt verify --quick\n"));
+        install(hooks.path(), "pre-commit", "other verify --quick")?;
+        assert!(fs::read_to_string(hook.path())?.ends_with("exec other verify --quick\n"));
+
+        assert!(uninstall(hooks.path(), "pre-commit")?);
+        assert!(!hook.path().exists());
+        assert!(!uninstall(hooks.path(), "pre-commit")?);
+
+        hook.write_str("#!/bin/sh\nmake lint\n")?;
+        assert!(install(hooks.path(), "pre-commit", "git-agecrypt verify --quick").is_err());
+        assert!(!uninstall(hooks.path(), "pre-commit")?);
+        hook.assert("#!/bin/sh\nmake lint\n");
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_snippets() -> Result<()> {
+        let commands: Vec<(&str, String)> = HOOKS
+            .iter()
+            .map(|(name, args)| (*name, format!("git-agecrypt {}", args)))
+            .collect();
+
+        let lefthook: serde_yaml::Value = serde_yaml::from_str(&lefthook_config(&commands))?;
+        assert_eq!(
+            lefthook["post-checkout"]["commands"]["git-agecrypt"]["run"],
+            "git-agecrypt refresh-sidecars {1} {2}"
+        );
+        assert_eq!(
+            lefthook["pre-commit"]["commands"]["git-agecrypt"]["run"],
+            "git-agecrypt verify --quick"
+        );
+        assert_eq!(
+            lefthook["pre-push"]["commands"]["git-agecrypt"]["run"],
+            "git-agecrypt verify --quick --remote {1} --pushed"
+        );
+        assert_eq!(
+            lefthook["pre-push"]["commands"]["git-agecrypt"]["use_stdin"],
+            true
+        );
+
+        let pre_commit: serde_yaml::Value = serde_yaml::from_str(&pre_commit_config(&commands))?;
+        let hooks = &pre_commit["repos"][0]["hooks"];
+        assert_eq!(hooks[0]["stages"][0], "pre-commit");
+        assert_eq!(
+            hooks[1]["entry"],
+            "sh -c 'git-agecrypt refresh-sidecars \"$PRE_COMMIT_FROM_REF\" \"$PRE_COMMIT_TO_REF\"'"
+        );
+        assert_eq!(
+            hooks[3]["entry"],
+            "sh -c 'echo \"$PRE_COMMIT_LOCAL_BRANCH $PRE_COMMIT_TO_REF $PRE_COMMIT_REMOTE_BRANCH \
+             $PRE_COMMIT_FROM_REF\" | git-agecrypt verify --quick --remote \
+             \"$PRE_COMMIT_REMOTE_NAME\" --pushed'"
+        );
+        assert_eq!(hooks[3]["stages"][0], "pre-push");
+
+        let dir = TempDir::new()?;
+        let file = dir.child("lefthook-git-agecrypt.yml");
+        write_snippet(file.path(), &lefthook_config(&commands))?;
+        write_snippet(file.path(), &lefthook_config(&commands[..1]))?;
+        file.write_str("pre-commit: {}\n")?;
+        assert!(write_snippet(file.path(), &lefthook_config(&commands)).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,290 @@
+//! Recipients backed by a key management service, so that files can be decrypted by a cloud
+//! role instead of a personal key, like sops does.
+//!
+//! A rule's `kms` key is encrypted to as the recipient `kms:<key>`. The age file key is wrapped
+//! by the service, and the result is stored in a stanza of its own:
+//!
+//! ```text
+//! -> git-agecrypt-kms <key>
+//! <wrapped file key, base64>
+//! ```
+//!
+//! Decryption always tries these stanzas, so a machine allowed to use the key (e.g. a CI
+//! runner with the right IAM role) needs no identity file. The services are called through
+//! their command line tools, which take care of the credentials:
+//!
+//! - AWS KMS key ARNs (`arn:aws:kms:<region>:<account>:key/<id>`) with `aws kms`
+//! - GCP KMS key ids (`projects/<project>/locations/<location>/keyRings/<ring>/cryptoKeys/<key>`)
+//!   with `gcloud kms`
+//! - Vault transit keys, as URL of the key (`https://<host>:<port>/v1/<mount>/keys/<name>`) with
+//!   `vault write`
+
+use std::{
+    io::{self, Write},
+    process,
+};
+
+use ::age::{secrecy::ExposeSecret, DecryptError, EncryptError};
+use age_core::format::{FileKey, Stanza};
+use anyhow::{bail, Context, Result};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use zeroize::Zeroize;
+
+/// Prefix of KMS keys among the recipients
+pub(crate) const PREFIX: &str = "kms:";
+
+/// Name reported for files decrypted with a KMS key, in place of an identity file
+pub(crate) const IDENTITY: &str = "kms";
+
+/// Tag of the stanzas holding the file key wrapped by the service
+pub(crate) const TAG: &str = "git-agecrypt-kms";
+
+#[derive(Debug, PartialEq, Eq)]
+enum Service<'a> {
+    Aws,
+    Gcp,
+    /// Vault server address and transit mount
+    Vault {
+        address: &'a str,
+        mount: &'a str,
+        name: &'a str,
+    },
+}
+
+impl<'a> Service<'a> {
+    fn of(key: &'a str) -> Result<Self> {
+        if key.starts_with("arn:") && key.split(':').nth(2) == Some("kms") {
+            return Ok(Self::Aws);
+        }
+        let segments: Vec<&str> = key.split('/').collect();
+        if let ["projects", _, "locations", _, "keyRings", _, "cryptoKeys", _] = segments[..] {
+            return Ok(Self::Gcp);
+        }
+        if key.starts_with("https://") || key.starts_with("http://") {
+            if let Some((address, path)) = key.split_once("/v1/") {
+                if let Some((mount, name)) = path.rsplit_once("/keys/") {
+                    if !mount.is_empty() && !name.is_empty() && !name.contains('/') {
+                        return Ok(Self::Vault {
+                            address,
+                            mount,
+                            name,
+                        });
+                    }
+                }
+            }
+        }
+        bail!(
+            "unknown kind of KMS key '{}', expected an AWS KMS key ARN, a GCP KMS key id or the \
+             URL of a Vault transit key",
+            key
+        )
+    }
+
+    fn wrap(&self, key: &str, file_key: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Self::Aws => {
+                let mut command = process::Command::new("aws");
+                command.args(["kms", "encrypt", "--key-id", key]).args([
+                    "--plaintext",
+                    "fileb:///dev/stdin",
+                    "--output",
+                    "text",
+                    "--query",
+                    "CiphertextBlob",
+                ]);
+                decode(&run(command, file_key)?)
+            }
+            Self::Gcp => {
+                let mut command = process::Command::new("gcloud");
+                command.args(["kms", "encrypt", "--key", key]).args([
+                    "--plaintext-file",
+                    "-",
+                    "--ciphertext-file",
+                    "-",
+                ]);
+                run(command, file_key)
+            }
+            Self::Vault {
+                address,
+                mount,
+                name,
+            } => {
+                let mut command = process::Command::new("vault");
+                command
+                    .arg("write")
+                    .arg(format!("-address={}", address))
+                    .args(["-field=ciphertext", &format!("{}/encrypt/{}", mount, name)])
+                    .arg("plaintext=-");
+                let ciphertext = run(command, BASE64_STANDARD.encode(file_key).as_bytes())?;
+                Ok(String::from_utf8_lossy(&ciphertext)
+                    .trim()
+                    .as_bytes()
+                    .to_vec())
+            }
+        }
+    }
+
+    fn unwrap(&self, key: &str, wrapped: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Self::Aws => {
+                let mut command = process::Command::new("aws");
+                command.args(["kms", "decrypt", "--key-id", key]).args([
+                    "--ciphertext-blob",
+                    "fileb:///dev/stdin",
+                    "--output",
+                    "text",
+                    "--query",
+                    "Plaintext",
+                ]);
+                decode(&run(command, wrapped)?)
+            }
+            Self::Gcp => {
+                let mut command = process::Command::new("gcloud");
+                command.args(["kms", "decrypt", "--key", key]).args([
+                    "--ciphertext-file",
+                    "-",
+                    "--plaintext-file",
+                    "-",
+                ]);
+                run(command, wrapped)
+            }
+            Self::Vault {
+                address,
+                mount,
+                name,
+            } => {
+                let mut command = process::Command::new("vault");
+                command
+                    .arg("write")
+                    .arg(format!("-address={}", address))
+                    .args(["-field=plaintext", &format!("{}/decrypt/{}", mount, name)])
+                    .arg("ciphertext=-");
+                decode(&run(command, wrapped)?)
+            }
+        }
+    }
+}
+
+/// Checks that `key` names a key of a supported service
+pub(crate) fn check_key(key: &str) -> Result<()> {
+    Service::of(key).map(|_| ())
+}
+
+/// Encrypts the file key with the KMS key `key`
+pub(crate) struct Recipient {
+    key: String,
+}
+
+impl Recipient {
+    pub fn new(key: &str) -> Result<Self> {
+        check_key(key)?;
+        Ok(Self { key: key.into() })
+    }
+}
+
+impl ::age::Recipient for Recipient {
+    fn wrap_file_key(&self, file_key: &FileKey) -> Result<Vec<Stanza>, EncryptError> {
+        let body = Service::of(&self.key)
+            .and_then(|s| s.wrap(&self.key, file_key.expose_secret()))
+            .with_context(|| format!("Couldn't encrypt with KMS key '{}'", self.key))
+            .map_err(|e| EncryptError::Io(io::Error::other(format!("{:#}", e))))?;
+        Ok(vec![Stanza {
+            tag: TAG.into(),
+            args: vec![self.key.clone()],
+            body,
+        }])
+    }
+}
+
+/// Decrypts the file key of KMS stanzas with the service, if the credentials at hand allow it
+pub(crate) struct Identity;
+
+impl ::age::Identity for Identity {
+    fn unwrap_stanza(&self, stanza: &Stanza) -> Option<Result<FileKey, DecryptError>> {
+        if stanza.tag != TAG {
+            return None;
+        }
+        let key = stanza.args.first()?;
+        let file_key = Service::of(key)
+            .and_then(|s| s.unwrap(key, &stanza.body))
+            .and_then(|mut bytes| {
+                let file_key = <[u8; 16]>::try_from(&bytes[..])
+                    .map(FileKey::from)
+                    .context("The KMS returned a file key of the wrong length");
+                bytes.zeroize();
+                file_key
+            });
+        match file_key {
+            Ok(file_key) => Some(Ok(file_key)),
+            Err(err) => {
+                // Other identities may still be able to decrypt the file
+                log::warn!(
+                    "Couldn't decrypt with KMS key; key={}, error={:#}",
+                    key,
+                    err
+                );
+                None
+            }
+        }
+    }
+}
+
+fn decode(output: &[u8]) -> Result<Vec<u8>> {
+    BASE64_STANDARD
+        .decode(String::from_utf8_lossy(output).trim())
+        .context("Invalid base64 in KMS output")
+}
+
+fn run(mut command: process::Command, input: &[u8]) -> Result<Vec<u8>> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let mut child = command
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Couldn't run {}, is it installed?", program))?;
+    child.stdin.take().unwrap().write_all(input)?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        bail!(
+            "{} failed with {}: {}",
+            program,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(output.stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case("arn:aws:kms:eu-west-1:111122223333:key/1234abcd", Some(Service::Aws))]
+    #[case(
+        "arn:aws-us-gov:kms:us-gov-west-1:111122223333:alias/ci",
+        Some(Service::Aws)
+    )]
+    #[case(
+        "projects/p/locations/global/keyRings/ci/cryptoKeys/secrets",
+        Some(Service::Gcp)
+    )]
+    #[case(
+        "https://vault.example.com:8200/v1/transit/keys/ci",
+        Some(Service::Vault { address: "https://vault.example.com:8200", mount: "transit", name: "ci" })
+    )]
+    #[case(
+        "http://127.0.0.1:8200/v1/team/transit/keys/ci",
+        Some(Service::Vault { address: "http://127.0.0.1:8200", mount: "team/transit", name: "ci" })
+    )]
+    #[case("arn:aws:s3:::bucket", None)]
+    #[case("projects/p/locations/global/keyRings/ci", None)]
+    #[case("https://vault.example.com:8200/v1/transit/keys/", None)]
+    #[case("age1abc", None)]
+    fn test_service(#[case] key: &str, #[case] expected: Option<Service>) {
+        assert_eq!(Service::of(key).ok(), expected);
+    }
+}
@@ -0,0 +1,52 @@
+//! Transparent age encryption of files in git repositories.
+//!
+//! Besides the `git-agecrypt` command, the logic of the git filters can be embedded in other
+//! tools through [`Context`]:
+//!
+//! ```no_run
+//! # fn main() -> git_agecrypt::Result<()> {
+//! let ctx = git_agecrypt::Context::open(".", None)?;
+//! let ciphertext = ctx.clean("secrets/db.env", b"PASSWORD=hunter2\n".to_vec())?;
+//! let plaintext = ctx.smudge("secrets/db.env", ciphertext)?;
+//! # Ok(())
+//! # }
+//! ```
+
+mod age;
+mod agent;
+mod api;
+mod attributes;
+mod audit;
+mod cache;
+mod cli;
+mod compress;
+mod config;
+mod ctx;
+mod deterministic;
+mod error;
+mod git;
+mod hooks;
+mod kms;
+mod magic;
+mod mmap;
+mod pktline;
+mod recipients;
+mod secret;
+mod sidecars;
+mod stream;
+mod tar;
+mod threshold;
+mod values;
+
+pub use api::Context;
+pub use config::{
+    AppConfig as RuleSet, Compression, Error as ConfigError, Mode, Rule, RuleOptions,
+};
+pub use error::{Error, Result};
+pub use git::Error as GitError;
+
+/// Runs the `git-agecrypt` command with the arguments of the process, for the binary
+#[doc(hidden)]
+pub fn run() -> std::process::ExitCode {
+    cli::run(cli::parse_args())
+}
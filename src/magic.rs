@@ -0,0 +1,118 @@
+//! Recognizes common binary file formats by their leading bytes
+
+use std::{fmt, str::FromStr};
+
+use anyhow::bail;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FileType {
+    Zip,
+    Png,
+    Elf,
+    MachO,
+    Pdf,
+}
+
+const SIGNATURES: &[(FileType, &[u8])] = &[
+    (FileType::Zip, b"PK\x03\x04"),
+    (FileType::Zip, b"PK\x05\x06"),
+    (FileType::Zip, b"PK\x07\x08"),
+    (FileType::Png, b"\x89PNG\r\n\x1a\n"),
+    (FileType::Elf, b"\x7fELF"),
+    (FileType::MachO, b"\xfe\xed\xfa\xce"),
+    (FileType::MachO, b"\xfe\xed\xfa\xcf"),
+    (FileType::MachO, b"\xce\xfa\xed\xfe"),
+    (FileType::MachO, b"\xcf\xfa\xed\xfe"),
+    (FileType::MachO, b"\xca\xfe\xba\xbe"),
+    (FileType::Pdf, b"%PDF-"),
+];
+
+impl FileType {
+    const ALL: [FileType; 5] = [
+        FileType::Zip,
+        FileType::Png,
+        FileType::Elf,
+        FileType::MachO,
+        FileType::Pdf,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            FileType::Zip => "zip",
+            FileType::Png => "png",
+            FileType::Elf => "elf",
+            FileType::MachO => "mach-o",
+            FileType::Pdf => "pdf",
+        }
+    }
+}
+
+impl fmt::Display for FileType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for FileType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_lowercase();
+        match FileType::ALL.into_iter().find(|t| t.name() == s) {
+            Some(t) => Ok(t),
+            None => {
+                let names: Vec<&str> = FileType::ALL.iter().map(|t| t.name()).collect();
+                bail!(
+                    "Unknown binary file type '{}', expected one of {}",
+                    s,
+                    names.join(", ")
+                )
+            }
+        }
+    }
+}
+
+pub(crate) fn detect(contents: &[u8]) -> Option<FileType> {
+    SIGNATURES
+        .iter()
+        .find(|(_, magic)| contents.starts_with(magic))
+        .map(|(t, _)| *t)
+}
+
+/// Whether `contents` look like binary data, by git's heuristic of a NUL byte among them
+pub(crate) fn is_binary(contents: &[u8]) -> bool {
+    contents.contains(&0)
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case(b"PK\x03\x04rest", Some(FileType::Zip))]
+    #[case(b"\x89PNG\r\n\x1a\n\0\0", Some(FileType::Png))]
+    #[case(b"\x7fELF\x02\x01", Some(FileType::Elf))]
+    #[case(b"\xcf\xfa\xed\xfe\x07", Some(FileType::MachO))]
+    #[case(b"%PDF-1.7", Some(FileType::Pdf))]
+    #[case(b"password=hunter2", None)]
+    #[case(b"PK", None)]
+    fn test_detect(#[case] contents: &[u8], #[case] expected: Option<FileType>) {
+        assert_eq!(detect(contents), expected);
+    }
+
+    #[rstest]
+    #[case(b"SQLite format 3\0\x10\0", true)]
+    #[case(b"password=hunter2\n", false)]
+    #[case(b"", false)]
+    fn test_is_binary(#[case] contents: &[u8], #[case] expected: bool) {
+        assert_eq!(is_binary(contents), expected);
+    }
+
+    #[rstest]
+    fn test_parse() {
+        assert_eq!("Mach-O".parse::<FileType>().unwrap(), FileType::MachO);
+        assert!("jpeg".parse::<FileType>().is_err());
+    }
+}
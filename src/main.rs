@@ -1,17 +1,6 @@
-mod age;
-mod cli;
-mod config;
-mod ctx;
-mod git;
+use std::process::ExitCode;
 
-use anyhow::Result;
-use cli::run;
-
-fn main() -> Result<()> {
+fn main() -> ExitCode {
     env_logger::init();
-    let args = cli::parse_args();
-    let repo = git::LibGit2Repository::from_current_dir()?;
-    let ctx = ctx::new(repo);
-
-    run(args, ctx)
+    git_agecrypt::run()
 }
@@ -0,0 +1,122 @@
+//! Read-only memory maps of files, so that large files handed to `textconv` are paged in by
+//! the kernel as they are decrypted instead of being read into memory first
+
+use std::{
+    fs::File,
+    io::{self, Read},
+    ops::Deref,
+};
+
+/// The contents of a file, either mapped into memory or read into memory
+pub(crate) struct Mapped(Contents);
+
+enum Contents {
+    #[cfg(unix)]
+    Mapped {
+        ptr: *mut libc::c_void,
+        len: usize,
+    },
+    Read(Vec<u8>),
+}
+
+// The mapping is read-only and owned by this value
+unsafe impl Send for Mapped {}
+unsafe impl Sync for Mapped {}
+
+impl Mapped {
+    /// Maps `file` into memory on unix, reads it into memory elsewhere.
+    ///
+    /// # Safety
+    ///
+    /// The file must not be changed by anyone while it is mapped. A private mapping doesn't
+    /// protect against that: writes to the file may show up in the mapped contents, and
+    /// reading the part of the mapping beyond the end of a truncated file kills the process
+    /// with `SIGBUS`. This holds for the temporary files git hands to `textconv`, but not for
+    /// files in the working tree.
+    #[cfg(unix)]
+    pub unsafe fn new(file: &File) -> io::Result<Self> {
+        use std::os::unix::io::AsRawFd;
+
+        let len = usize::try_from(file.metadata()?.len()).map_err(io::Error::other)?;
+        // Empty mappings aren't allowed
+        if len == 0 {
+            return Ok(Self(Contents::Read(vec![])));
+        }
+        let ptr = libc::mmap(
+            std::ptr::null_mut(),
+            len,
+            libc::PROT_READ,
+            libc::MAP_PRIVATE,
+            file.as_raw_fd(),
+            0,
+        );
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self(Contents::Mapped { ptr, len }))
+    }
+
+    /// Reads `file` into memory, see the unix version
+    ///
+    /// # Safety
+    ///
+    /// Always safe, it is only `unsafe` like the unix version.
+    #[cfg(not(unix))]
+    pub unsafe fn new(file: &File) -> io::Result<Self> {
+        Self::read(file)
+    }
+
+    /// Reads `file` from its current position into memory, for files which may change while
+    /// they are read
+    pub fn read(mut file: &File) -> io::Result<Self> {
+        let mut contents = vec![];
+        file.read_to_end(&mut contents)?;
+        Ok(Self(Contents::Read(contents)))
+    }
+}
+
+impl Deref for Mapped {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.0 {
+            #[cfg(unix)]
+            Contents::Mapped { ptr, len } => unsafe {
+                std::slice::from_raw_parts(ptr.cast(), *len)
+            },
+            Contents::Read(contents) => contents,
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Mapped {
+    fn drop(&mut self) {
+        if let Contents::Mapped { ptr, len } = self.0 {
+            unsafe { libc::munmap(ptr, len) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use assert_fs::{prelude::*, NamedTempFile};
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case(b"")]
+    #[case(b"secret")]
+    fn test_mapped(#[case] contents: &[u8]) -> Result<()> {
+        let file = NamedTempFile::new("blob")?;
+        file.write_binary(contents)?;
+        // Nothing else knows about the file
+        let mapped = unsafe { Mapped::new(&File::open(file.path())?)? };
+        assert_eq!(&mapped[..], contents);
+        let read = Mapped::read(&File::open(file.path())?)?;
+        assert_eq!(&read[..], contents);
+        Ok(())
+    }
+}
@@ -0,0 +1,124 @@
+//! Git's pkt-line framing, used by the long-running filter process protocol.
+//!
+//! See `gitprotocol-common(5)` and the "Long Running Filter Process" section of
+//! `gitattributes(5)`.
+
+use std::io::{self, Read, Write};
+
+use anyhow::{bail, Context, Result};
+use zeroize::Zeroize;
+
+use crate::secret::SecretBuf;
+
+/// Largest payload a single packet can hold
+const MAX_DATA_LEN: usize = 65516;
+
+pub(crate) struct Reader<R: Read> {
+    inner: R,
+}
+
+impl<R: Read> Reader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner }
+    }
+
+    /// Reads a single packet, `None` means a flush packet.
+    ///
+    /// Returns an `UnexpectedEof` error if the stream ends before the packet starts.
+    pub fn read_packet(&mut self) -> Result<Option<Vec<u8>>> {
+        let mut len = [0u8; 4];
+        self.inner.read_exact(&mut len)?;
+        let len = std::str::from_utf8(&len)
+            .ok()
+            .and_then(|l| usize::from_str_radix(l, 16).ok())
+            .context("Invalid pkt-line length")?;
+        match len {
+            0 => Ok(None),
+            1..=4 => bail!("Unexpected pkt-line length {}", len),
+            _ => {
+                let mut data = vec![0u8; len - 4];
+                self.inner.read_exact(&mut data)?;
+                Ok(Some(data))
+            }
+        }
+    }
+
+    /// Reads text packets until the next flush packet
+    pub fn read_lines(&mut self) -> Result<Vec<String>> {
+        let mut rv = vec![];
+        while let Some(packet) = self.read_packet()? {
+            let line = String::from_utf8(packet).context("Invalid text packet")?;
+            rv.push(line.trim_end_matches('\n').to_string());
+        }
+        Ok(rv)
+    }
+
+    /// Reads binary packets until the next flush packet, the content may be plaintext
+    pub fn read_content(&mut self) -> Result<SecretBuf> {
+        let mut rv = SecretBuf::new();
+        while let Some(mut packet) = self.read_packet()? {
+            rv.extend_from_slice(&packet);
+            packet.zeroize();
+        }
+        Ok(rv)
+    }
+}
+
+pub(crate) struct Writer<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner }
+    }
+
+    fn write_packet(&mut self, data: &[u8]) -> io::Result<()> {
+        write!(self.inner, "{:04x}", data.len() + 4)?;
+        self.inner.write_all(data)
+    }
+
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        self.write_packet(format!("{}\n", line).as_bytes())
+    }
+
+    /// Writes binary content split into packets, without the terminating flush packet
+    pub fn write_content(&mut self, content: &[u8]) -> io::Result<()> {
+        for chunk in content.chunks(MAX_DATA_LEN) {
+            self.write_packet(chunk)?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.inner.write_all(b"0000")?;
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    fn test_roundtrip() -> Result<()> {
+        let content = vec![42u8; MAX_DATA_LEN + 10];
+        let mut buffer = vec![];
+        let mut writer = Writer::new(&mut buffer);
+        writer.write_line("command=clean")?;
+        writer.write_line("pathname=foo")?;
+        writer.flush()?;
+        writer.write_content(&content)?;
+        writer.flush()?;
+        assert!(buffer.starts_with(b"0012command=clean\n0011pathname=foo\n0000"));
+
+        let mut reader = Reader::new(&buffer[..]);
+        assert_eq!(reader.read_lines()?, ["command=clean", "pathname=foo"]);
+        assert_eq!(reader.read_content()?, content);
+        assert!(reader.read_packet().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,155 @@
+use std::{fs, path::PathBuf};
+
+use anyhow::{Context, Result};
+
+use super::{parse_public_keys, RecipientSource};
+
+/// Extensions of the recipients files loaded when a directory is given instead of a pattern
+const DIRECTORY_EXTENSIONS: &[&str] = &["pub", "age"];
+
+/// Recipients files matching a glob pattern relative to the repository root, e.g.
+/// `file:keys/*.pub`, or all `*.pub` and `*.age` files in a directory, e.g. `file:keys/`.
+/// Every match is parsed as an age recipients file.
+pub(crate) struct FileSource {
+    pub base: PathBuf,
+}
+
+impl FileSource {
+    fn patterns(&self, spec: &str) -> Vec<String> {
+        let path = self.base.join(spec);
+        if path.is_dir() {
+            let dir = glob::Pattern::escape(&path.to_string_lossy());
+            DIRECTORY_EXTENSIONS
+                .iter()
+                .map(|ext| format!("{}/*.{}", dir.trim_end_matches('/'), ext))
+                .collect()
+        } else {
+            vec![path.to_string_lossy().into()]
+        }
+    }
+}
+
+impl RecipientSource for FileSource {
+    fn scheme(&self) -> &'static str {
+        "file"
+    }
+
+    fn cached(&self) -> bool {
+        false
+    }
+
+    fn fetch(&self, spec: &str) -> Result<Vec<String>> {
+        let mut files = vec![];
+        for pattern in self.patterns(spec) {
+            for file in glob::glob(&pattern)
+                .with_context(|| format!("Invalid recipients file pattern '{}'", spec))?
+            {
+                files.push(file?);
+            }
+        }
+        files.sort();
+
+        if files.is_empty() {
+            log::warn!("No recipients file matches the pattern; pattern={:?}", spec);
+        }
+
+        let mut rv = vec![];
+        for file in files {
+            let contents = fs::read_to_string(&file)
+                .with_context(|| format!("Couldn't read recipients file '{}'", file.display()))?;
+            for pk in parse_public_keys(&contents, &format!("'{}'", file.display()))? {
+                if !rv.contains(&pk) {
+                    rv.push(pk);
+                }
+            }
+        }
+        Ok(rv)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use assert_fs::prelude::*;
+    use assert_fs::TempDir;
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case("keys/*.pub", 1)]
+    #[case("keys", 2)]
+    #[case("keys/", 2)]
+    #[case("./keys/", 2)]
+    fn test_fetch(#[case] spec: &str, #[case] expected: usize) -> Result<()> {
+        let dir = TempDir::new()?;
+        let keys: Vec<String> = (0..3)
+            .map(|_| ::age::x25519::Identity::generate().to_public().to_string())
+            .collect();
+        dir.child("keys/alice.pub").write_str(&keys[0])?;
+        dir.child("keys/bob.age").write_str(&keys[1])?;
+        dir.child("keys/README.md").write_str("not a key")?;
+        dir.child("keys/nested/carol.pub").write_str(&keys[2])?;
+
+        let source = FileSource {
+            base: dir.path().into(),
+        };
+        assert_eq!(source.fetch(spec)?, keys[..expected]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fetch_comments() -> Result<()> {
+        let dir = TempDir::new()?;
+        let keys: Vec<String> = (0..2)
+            .map(|_| ::age::x25519::Identity::generate().to_public().to_string())
+            .collect();
+        dir.child("recipients.txt").write_str(&format!(
+            "# Alice\n\n  {}  \n\n# Bob, listed twice\n{}\n{}\n",
+            keys[0], keys[1], keys[1]
+        ))?;
+
+        let source = FileSource {
+            base: dir.path().into(),
+        };
+        assert_eq!(source.fetch("recipients.txt")?, keys);
+        Ok(())
+    }
+
+    #[rstest]
+    #[case("not-a-key\n", false)]
+    #[case("# only comments\n\n", false)]
+    #[case("ecdsa-sha2-nistp256 AAAAE2VjZHNh\n{key}\n", true)]
+    fn test_fetch_invalid(#[case] contents: &str, #[case] usable: bool) -> Result<()> {
+        let dir = TempDir::new()?;
+        let key = ::age::x25519::Identity::generate().to_public().to_string();
+        dir.child("recipients.txt")
+            .write_str(&contents.replace("{key}", &key))?;
+
+        let source = FileSource {
+            base: dir.path().into(),
+        };
+        let result = source.fetch("recipients.txt");
+        if usable {
+            assert_eq!(result?, vec![key]);
+        } else {
+            assert!(result.is_err());
+        }
+        Ok(())
+    }
+
+    #[rstest]
+    #[case("missing.pub")]
+    #[case("keys/*.pub")]
+    #[case("keys/")]
+    fn test_fetch_missing(#[case] spec: &str) -> Result<()> {
+        let dir = TempDir::new()?;
+        dir.child("keys").create_dir_all()?;
+
+        let source = FileSource {
+            base: dir.path().into(),
+        };
+        assert!(source.fetch(spec)?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,51 @@
+use std::{collections::BTreeMap, fs, io, path::Path};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// Name of the lockfile in the repository root
+pub(crate) const LOCKFILE: &str = "git-agecrypt.lock";
+
+const HEADER: &str = "# Recipients resolved from online sources, maintained by git-agecrypt.\n\
+                      # Commit this file, it is used when a source can't be reached.\n";
+
+/// Recipients last resolved from sources which can change over time, committed to the
+/// repository so that the keys are reviewable and available offline
+#[derive(Default, Serialize, Deserialize)]
+pub(crate) struct Lockfile {
+    #[serde(default)]
+    recipients: BTreeMap<String, Vec<String>>,
+}
+
+impl Lockfile {
+    pub fn load(path: &Path) -> Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(contents) => toml::from_str(&contents)
+                .with_context(|| format!("Couldn't parse lockfile '{}'", path.display())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => {
+                Err(e).with_context(|| format!("Couldn't read lockfile '{}'", path.display()))
+            }
+        }
+    }
+
+    pub fn get(&self, reference: &str) -> Option<&Vec<String>> {
+        self.recipients.get(reference)
+    }
+
+    /// Records the recipients of a reference, returns whether this changed the lockfile
+    pub fn update(&mut self, reference: &str, recipients: &[String]) -> bool {
+        if self.get(reference).is_some_and(|r| r == recipients) {
+            return false;
+        }
+        self.recipients
+            .insert(reference.to_string(), recipients.to_vec());
+        true
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let contents = HEADER.to_string() + &toml::to_string_pretty(self)?;
+        fs::write(path, contents)
+            .with_context(|| format!("Couldn't write lockfile '{}'", path.display()))
+    }
+}
@@ -0,0 +1,386 @@
+mod file;
+mod lockfile;
+mod pkcs11;
+mod web;
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{age, config::Rule, kms};
+
+use self::lockfile::{Lockfile, LOCKFILE};
+
+/// Provides recipients referenced by a `<scheme>:<spec>` entry in the configuration
+pub(crate) trait RecipientSource {
+    fn scheme(&self) -> &'static str;
+
+    /// Whether results should be cached for use when the source becomes unavailable
+    fn cached(&self) -> bool {
+        true
+    }
+
+    /// How long cached results are used without fetching again, `None` to always fetch
+    fn ttl(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Whether results are recorded in the lockfile committed to the repository
+    fn locked(&self) -> bool {
+        false
+    }
+
+    /// Checks that `spec` is well-formed without contacting the source
+    fn check(&self, _spec: &str) -> Result<()> {
+        Ok(())
+    }
+
+    /// Fetch the recipients identified by `spec` (the part after `<scheme>:`)
+    fn fetch(&self, spec: &str) -> Result<Vec<String>>;
+}
+
+/// Expands recipient source references into plain recipients.
+///
+/// Results of every successful lookup are cached, so that configured sources remain usable
+/// when the backing service or device is not reachable.
+pub(crate) struct Resolver {
+    sources: Vec<Box<dyn RecipientSource>>,
+    cache_dir: PathBuf,
+    lockfile: PathBuf,
+}
+
+impl Resolver {
+    pub fn new(cache_dir: PathBuf, base: PathBuf) -> Self {
+        Self {
+            lockfile: base.join(LOCKFILE),
+            sources: vec![
+                Box::new(file::FileSource { base }),
+                Box::new(pkcs11::Pkcs11Source),
+                Box::new(web::WebSource::github()),
+                Box::new(web::WebSource::gitlab()),
+            ],
+            cache_dir,
+        }
+    }
+
+    pub fn resolve(&self, recipients: &[impl AsRef<str>]) -> Result<Vec<String>> {
+        let mut rv: Vec<String> = vec![];
+        for recipient in recipients {
+            let recipient = recipient.as_ref();
+            let resolved = match self.source_for(recipient) {
+                Some((source, spec)) => self.fetch_cached(source, recipient, spec)?,
+                None => vec![recipient.to_string()],
+            };
+            for r in resolved {
+                if !rv.contains(&r) {
+                    rv.push(r);
+                }
+            }
+        }
+        Ok(rv)
+    }
+
+    /// The recipients a file covered by `rule` is encrypted to, including its KMS key
+    pub fn resolve_rule(&self, rule: &Rule) -> Result<Vec<String>> {
+        let mut rv = self.resolve(&rule.recipients)?;
+        if let Some(key) = &rule.options.kms {
+            kms::check_key(key)?;
+            rv.push(format!("{}{}", kms::PREFIX, key));
+        }
+        Ok(rv)
+    }
+
+    fn source_for<'a>(&self, recipient: &'a str) -> Option<(&dyn RecipientSource, &'a str)> {
+        let (scheme, spec) = recipient.split_once(':')?;
+        self.sources
+            .iter()
+            .find(|s| s.scheme() == scheme)
+            .map(|s| (s.as_ref(), spec))
+    }
+
+    fn fetch_cached(
+        &self,
+        source: &dyn RecipientSource,
+        recipient: &str,
+        spec: &str,
+    ) -> Result<Vec<String>> {
+        // An invalid spec must not fall back to recipients cached before it was rejected
+        source
+            .check(spec)
+            .with_context(|| format!("Invalid recipient source '{}'", recipient))?;
+        if !source.cached() {
+            return source.fetch(spec);
+        }
+        let cache_file = self.cache_file(recipient);
+        if let Some(ttl) = source.ttl() {
+            if is_fresh(&cache_file, ttl) {
+                if let Some(recipients) = load_cache(&cache_file) {
+                    return Ok(recipients);
+                }
+            }
+        }
+        match source.fetch(spec) {
+            Ok(recipients) => {
+                if let Err(err) = store_cache(&cache_file, &recipients) {
+                    log::warn!(
+                        "Couldn't cache recipients; source={:?}, error={:?}",
+                        recipient,
+                        err
+                    );
+                }
+                if source.locked() {
+                    self.lock(recipient, &recipients)?;
+                }
+                Ok(recipients)
+            }
+            Err(err) => match load_cache(&cache_file).or_else(|| self.locked(source, recipient)) {
+                Some(recipients) => {
+                    log::warn!(
+                        "Using cached recipients, source is unavailable; source={:?}, error={:?}",
+                        recipient,
+                        err
+                    );
+                    Ok(recipients)
+                }
+                None => {
+                    Err(err.context(format!("Couldn't resolve recipients from '{}'", recipient)))
+                }
+            },
+        }
+    }
+
+    /// Records freshly fetched recipients in the lockfile
+    fn lock(&self, recipient: &str, recipients: &[String]) -> Result<()> {
+        let mut lockfile = Lockfile::load(&self.lockfile)?;
+        if lockfile.update(recipient, recipients) {
+            log::info!(
+                "Recipients of '{}' changed, updating {}",
+                recipient,
+                self.lockfile.display()
+            );
+            lockfile.save(&self.lockfile)?;
+        }
+        Ok(())
+    }
+
+    /// Recipients of `recipient` recorded in the lockfile
+    fn locked(&self, source: &dyn RecipientSource, recipient: &str) -> Option<Vec<String>> {
+        if !source.locked() {
+            return None;
+        }
+        match Lockfile::load(&self.lockfile) {
+            Ok(lockfile) => lockfile.get(recipient).cloned(),
+            Err(err) => {
+                log::warn!("{:?}", err);
+                None
+            }
+        }
+    }
+
+    fn cache_file(&self, recipient: &str) -> PathBuf {
+        let key = blake3::hash(recipient.as_bytes());
+        self.cache_dir.join(key.to_hex().as_str())
+    }
+}
+
+fn store_cache(path: &Path, recipients: &[String]) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, recipients.join("\n") + "\n")?;
+    Ok(())
+}
+
+fn is_fresh(path: &Path, ttl: Duration) -> bool {
+    fs::metadata(path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+        .is_some_and(|age| age < ttl)
+}
+
+fn load_cache(path: &Path) -> Option<Vec<String>> {
+    let contents = fs::read_to_string(path).ok()?;
+    Some(contents.lines().map(String::from).collect())
+}
+
+/// What the recipients of a rule can be, for error messages
+pub(crate) const FORMATS: &str = "age public keys (age1...), SSH public keys (ssh-ed25519 or \
+    ssh-rsa), age plugin recipients (age1<plugin>1...), KMS keys (kms:<key>), recipient sources \
+    like github:<user> and group names";
+
+/// Checks that an entry is either a valid recipient or references a known source, explaining
+/// what is wrong with it otherwise
+pub(crate) fn check(recipient: &str) -> Result<()> {
+    let resolver = Resolver::new(PathBuf::new(), PathBuf::new());
+    if let Some((source, spec)) = resolver.source_for(recipient) {
+        return source.check(spec);
+    }
+    age::check_recipient(recipient)
+}
+
+/// Checks that every entry is either a valid recipient or references a known source
+pub(crate) fn validate(recipients: &[impl AsRef<str>]) -> Result<()> {
+    let resolver = Resolver::new(PathBuf::new(), PathBuf::new());
+    let mut plain = vec![];
+    for recipient in recipients {
+        let recipient = recipient.as_ref();
+        match resolver.source_for(recipient) {
+            Some((source, spec)) => source
+                .check(spec)
+                .with_context(|| format!("Invalid recipient source '{}'", recipient))?,
+            None => {
+                age::check_recipient(recipient).with_context(|| {
+                    format!(
+                        "Invalid recipient '{}', recipients are {}",
+                        recipient, FORMATS
+                    )
+                })?;
+                plain.push(recipient);
+            }
+        }
+    }
+    // Also checks that the plugins of plugin recipients are installed
+    age::validate_public_keys(&plain)
+}
+
+/// Parses the output of tools listing public keys, keeping only keys age can encrypt to
+pub(crate) fn parse_public_keys(listing: &str, origin: &str) -> Result<Vec<String>> {
+    let mut rv = vec![];
+    for line in listing.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match age::normalize_recipient(line) {
+            Ok(pk) => rv.push(pk),
+            Err(_) => log::warn!(
+                "Ignoring key not supported by age; origin={}, key={:?}",
+                origin,
+                line.split_whitespace().next().unwrap_or_default()
+            ),
+        }
+    }
+    if rv.is_empty() {
+        bail!(
+            "No public keys usable with age (x25519, ssh-ed25519, ssh-rsa) were found in {}",
+            origin
+        );
+    }
+    Ok(rv)
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+    use assert_fs::TempDir;
+    use rstest::rstest;
+
+    use super::*;
+
+    const KEY: &str = "age1ql3z7hjy54pw3hyww5ayyfg7zqgvc7w3j2elw8zmrj2kg5sfn9aqmcac8p";
+
+    struct FakeSource {
+        online: bool,
+        ttl: Option<Duration>,
+        locked: bool,
+    }
+
+    impl FakeSource {
+        fn new(online: bool) -> Self {
+            Self {
+                online,
+                ttl: None,
+                locked: false,
+            }
+        }
+    }
+
+    impl RecipientSource for FakeSource {
+        fn scheme(&self) -> &'static str {
+            "fake"
+        }
+
+        fn ttl(&self) -> Option<Duration> {
+            self.ttl
+        }
+
+        fn locked(&self) -> bool {
+            self.locked
+        }
+
+        fn fetch(&self, spec: &str) -> Result<Vec<String>> {
+            if self.online {
+                Ok(vec![spec.into()])
+            } else {
+                Err(anyhow!("offline"))
+            }
+        }
+    }
+
+    #[rstest]
+    fn test_resolve_uses_cache_when_offline() -> Result<()> {
+        let dir = TempDir::new()?;
+        let resolver = Resolver {
+            sources: vec![Box::new(FakeSource::new(true))],
+            cache_dir: dir.path().into(),
+            lockfile: dir.path().join(LOCKFILE),
+        };
+        let spec = format!("fake:{}", KEY);
+
+        assert_eq!(resolver.resolve(&[&spec, KEY])?, [KEY]);
+
+        let resolver = Resolver {
+            sources: vec![Box::new(FakeSource::new(false))],
+            cache_dir: dir.path().into(),
+            lockfile: dir.path().join(LOCKFILE),
+        };
+        assert_eq!(resolver.resolve(&[&spec])?, [KEY]);
+        assert!(resolver.resolve(&["fake:uncached"]).is_err());
+        assert!(!dir.path().join(LOCKFILE).exists());
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_resolve_ttl_and_lockfile() -> Result<()> {
+        let dir = TempDir::new()?;
+        let resolver = |online, cache: &str| Resolver {
+            sources: vec![Box::new(FakeSource {
+                online,
+                ttl: Some(Duration::from_secs(60)),
+                locked: true,
+            })],
+            cache_dir: dir.path().join(cache),
+            lockfile: dir.path().join(LOCKFILE),
+        };
+        let spec = format!("fake:{}", KEY);
+        assert_eq!(resolver(true, "cache").resolve(&[&spec])?, [KEY]);
+        let lockfile = fs::read_to_string(dir.path().join(LOCKFILE))?;
+        assert!(lockfile.contains(KEY));
+
+        // A fresh cache entry is used without fetching
+        fs::write(resolver(true, "cache").cache_file(&spec), "cached\n")?;
+        assert_eq!(resolver(true, "cache").resolve(&[&spec])?, ["cached"]);
+
+        // The lockfile is used by clones without a cache
+        let offline = resolver(false, "other");
+        assert_eq!(offline.resolve(&[&spec])?, [KEY]);
+        assert!(offline.resolve(&["fake:unlocked"]).is_err());
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_parse_public_keys() -> Result<()> {
+        let listing = format!(
+            "ecdsa-sha2-nistp256 AAAAE2VjZHNhLXNoYTItbmlzdHAyNTYAAAAIbmlzdHAyNTY= label\n{}\n",
+            KEY
+        );
+        assert_eq!(parse_public_keys(&listing, "test")?, [KEY]);
+        assert!(parse_public_keys("", "test").is_err());
+        Ok(())
+    }
+}
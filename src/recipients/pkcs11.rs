@@ -0,0 +1,118 @@
+use std::process;
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use super::{parse_public_keys, RecipientSource};
+
+/// Public keys stored on a PKCS#11 token, e.g. an HSM slot or a smart card.
+///
+/// The token is addressed with an RFC 7512 URI whose `module-path` query attribute names the
+/// PKCS#11 library, e.g. `pkcs11:?module-path=/usr/lib/opensc-pkcs11.so`. Keys are enumerated
+/// with `ssh-keygen -D`, so every RSA and Ed25519 key found on every token of the module is
+/// used. As `ssh-keygen` can't select a token or object, URIs with path attributes like
+/// `token=` or `id=` are rejected rather than silently matching every key.
+pub(crate) struct Pkcs11Source;
+
+impl RecipientSource for Pkcs11Source {
+    fn scheme(&self) -> &'static str {
+        "pkcs11"
+    }
+
+    fn check(&self, spec: &str) -> Result<()> {
+        module_path(spec).map(|_| ())
+    }
+
+    fn fetch(&self, spec: &str) -> Result<Vec<String>> {
+        let module = module_path(spec)?;
+        let mut command = process::Command::new("ssh-keygen");
+        command.arg("-D").arg(&module);
+        let output = command
+            .output()
+            .context("Couldn't execute ssh-keygen to enumerate PKCS#11 keys")?;
+        if !output.status.success() {
+            bail!(
+                "Listing keys of PKCS#11 module '{}' failed: {}",
+                module,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        parse_public_keys(
+            &String::from_utf8_lossy(&output.stdout),
+            &format!("PKCS#11 module '{}'", module),
+        )
+    }
+}
+
+fn module_path(spec: &str) -> Result<String> {
+    let (path, query) = spec.split_once('?').unwrap_or((spec, ""));
+    if !path.is_empty() {
+        let names: Vec<&str> = path
+            .split(';')
+            .map(|attr| attr.split_once('=').map_or(attr, |(name, _)| name))
+            .collect();
+        bail!(
+            "PKCS#11 URI 'pkcs11:{spec}' selects a token or object ({}), which is not \
+             supported, all keys of the module are used; remove the path attributes",
+            names.join(", ")
+        );
+    }
+    let mut module = None;
+    for attr in query.split('&').filter(|attr| !attr.is_empty()) {
+        let (name, value) = attr.split_once('=').unwrap_or((attr, ""));
+        match name {
+            "module-path" => module = Some(percent_decode(value)?),
+            // Listing public keys doesn't need to log in
+            "pin-value" | "pin-source" => {}
+            _ => bail!("PKCS#11 URI 'pkcs11:{spec}' has unsupported attribute '{name}'"),
+        }
+    }
+    module.ok_or_else(|| anyhow!("PKCS#11 URI 'pkcs11:{spec}' has no 'module-path' attribute"))
+}
+
+/// Decodes `%XX` escapes in an attribute value as required by RFC 7512
+fn percent_decode(value: &str) -> Result<String> {
+    let mut rv = Vec::with_capacity(value.len());
+    let mut bytes = value.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'%' {
+            rv.push(b);
+            continue;
+        }
+        let hex = [bytes.next(), bytes.next()];
+        let decoded = match hex {
+            [Some(hi), Some(lo)] => std::str::from_utf8(&[hi, lo])
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+        rv.push(decoded.ok_or_else(|| anyhow!("Invalid percent-encoding in '{value}'"))?);
+    }
+    String::from_utf8(rv).map_err(|_| anyhow!("Percent-encoded '{value}' is not valid UTF-8"))
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case("?module-path=/usr/lib/opensc-pkcs11.so", "/usr/lib/opensc-pkcs11.so")]
+    #[case("?module-path=/opt/My%20HSM/lib%25.so", "/opt/My HSM/lib%.so")]
+    #[case("?pin-value=1234&module-path=/lib/p11.so", "/lib/p11.so")]
+    fn test_module_path(#[case] spec: &str, #[case] expected: &str) -> Result<()> {
+        assert_eq!(module_path(spec)?, expected);
+        Ok(())
+    }
+
+    #[rstest]
+    #[case("token=ops?module-path=/lib/p11.so")]
+    #[case("object=deploy;id=%01?module-path=/lib/p11.so")]
+    #[case("?module-name=opensc-pkcs11")]
+    #[case("?module-path=/lib/p11%2.so")]
+    #[case("?module-path=/lib/p11%ff.so")]
+    #[case("")]
+    fn test_module_path_invalid(#[case] spec: &str) {
+        assert!(module_path(spec).is_err());
+    }
+}
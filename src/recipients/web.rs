@@ -0,0 +1,113 @@
+use std::{process, time::Duration};
+
+use anyhow::{bail, Context, Result};
+
+use super::{parse_public_keys, RecipientSource};
+
+/// How long keys fetched from a code hosting service are used before fetching them again
+const TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Public SSH keys a user published on a code hosting service, e.g. `github:alice`.
+///
+/// `gitlab:` also accepts the host of a self-managed instance, e.g.
+/// `gitlab:gitlab.example.com/alice`. Keys are downloaded from `https://<host>/<user>.keys`
+/// with `curl`.
+pub(crate) struct WebSource {
+    pub scheme: &'static str,
+    pub host: &'static str,
+    pub custom_host: bool,
+}
+
+impl WebSource {
+    pub fn github() -> Self {
+        Self {
+            scheme: "github",
+            host: "github.com",
+            custom_host: false,
+        }
+    }
+
+    pub fn gitlab() -> Self {
+        Self {
+            scheme: "gitlab",
+            host: "gitlab.com",
+            custom_host: true,
+        }
+    }
+
+    fn url(&self, spec: &str) -> Result<String> {
+        let (host, user) = match spec.rsplit_once('/') {
+            Some((host, user)) if self.custom_host => (host, user),
+            _ => (self.host, spec),
+        };
+        let valid = |s: &str, extra: &str| {
+            !s.is_empty()
+                && s.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_".contains(c) || extra.contains(c))
+        };
+        if !valid(user, ".") || !valid(host, ".:") {
+            bail!("Invalid user name '{}:{}'", self.scheme, spec);
+        }
+        Ok(format!("https://{}/{}.keys", host, user))
+    }
+}
+
+impl RecipientSource for WebSource {
+    fn scheme(&self) -> &'static str {
+        self.scheme
+    }
+
+    fn ttl(&self) -> Option<Duration> {
+        Some(TTL)
+    }
+
+    fn locked(&self) -> bool {
+        true
+    }
+
+    fn fetch(&self, spec: &str) -> Result<Vec<String>> {
+        let url = self.url(spec)?;
+        let output = process::Command::new("curl")
+            .args(["--fail", "--silent", "--show-error", "--location"])
+            .args(["--max-time", "30", &url])
+            .output()
+            .context("Couldn't execute curl to fetch public keys")?;
+        if !output.status.success() {
+            bail!(
+                "Fetching public keys from '{}' failed: {}",
+                url,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        parse_public_keys(
+            &String::from_utf8_lossy(&output.stdout),
+            &format!("'{}'", url),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case(WebSource::github(), "alice", Some("https://github.com/alice.keys"))]
+    #[case(WebSource::github(), "example.com/alice", None)]
+    #[case(
+        WebSource::gitlab(),
+        "alice.b",
+        Some("https://gitlab.com/alice.b.keys")
+    )]
+    #[case(
+        WebSource::gitlab(),
+        "git.example.com:8443/alice",
+        Some("https://git.example.com:8443/alice.keys")
+    )]
+    #[case(WebSource::gitlab(), "alice?x=1", None)]
+    #[case(WebSource::gitlab(), "", None)]
+    fn test_url(#[case] source: WebSource, #[case] spec: &str, #[case] expected: Option<&str>) {
+        assert_eq!(source.url(spec).ok().as_deref(), expected);
+    }
+}
@@ -0,0 +1,158 @@
+//! Memory holding plaintext and key material.
+//!
+//! A [`SecretBuf`] is wiped when it's dropped, and also when it grows, before the old
+//! allocation is released. Where the platform allows, its pages are locked into memory so they
+//! are never written to swap. Locking is best effort: it fails e.g. beyond `RLIMIT_MEMLOCK`,
+//! and unlocking a buffer unlocks the whole pages it shares with other allocations.
+
+use std::{fmt, io, mem, ops::Deref};
+
+use zeroize::Zeroize;
+
+#[derive(Default)]
+pub(crate) struct SecretBuf(Vec<u8>);
+
+impl SecretBuf {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self::from(Vec::with_capacity(capacity))
+    }
+
+    pub fn extend_from_slice(&mut self, data: &[u8]) {
+        self.reserve(data.len());
+        self.0.extend_from_slice(data);
+    }
+
+    /// Makes room for `additional` bytes, moving the contents to a new locked allocation instead
+    /// of letting `Vec` reallocate and leave a copy behind
+    fn reserve(&mut self, additional: usize) {
+        if self.0.capacity() - self.0.len() >= additional {
+            return;
+        }
+        let capacity = (self.0.len() + additional).max(self.0.capacity() * 2);
+        let mut grown = Vec::with_capacity(capacity);
+        lock(&grown);
+        grown.extend_from_slice(&self.0);
+        wipe(mem::replace(&mut self.0, grown));
+    }
+}
+
+impl From<Vec<u8>> for SecretBuf {
+    /// Takes over the allocation of `data`, copies `data` may have left behind while it grew
+    /// aren't wiped
+    fn from(data: Vec<u8>) -> Self {
+        lock(&data);
+        Self(data)
+    }
+}
+
+impl Deref for SecretBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl AsRef<[u8]> for SecretBuf {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Clone for SecretBuf {
+    fn clone(&self) -> Self {
+        let mut rv = Self::with_capacity(self.0.len());
+        rv.extend_from_slice(&self.0);
+        rv
+    }
+}
+
+impl<T: AsRef<[u8]>> PartialEq<T> for SecretBuf {
+    fn eq(&self, other: &T) -> bool {
+        self.0 == other.as_ref()
+    }
+}
+
+impl fmt::Debug for SecretBuf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SecretBuf([REDACTED; {}])", self.0.len())
+    }
+}
+
+impl io::Write for SecretBuf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for SecretBuf {
+    fn drop(&mut self) {
+        wipe(mem::take(&mut self.0));
+    }
+}
+
+fn wipe(mut data: Vec<u8>) {
+    // Zeroes the spare capacity as well
+    data.zeroize();
+    unlock(&data);
+}
+
+#[cfg(unix)]
+fn lock(data: &Vec<u8>) {
+    if data.capacity() > 0 {
+        // SAFETY: the range is the allocation owned by `data`
+        let rv = unsafe { libc::mlock(data.as_ptr().cast(), data.capacity()) };
+        if rv != 0 {
+            log::debug!(
+                "Couldn't lock secret in memory; error={}",
+                io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+#[cfg(unix)]
+fn unlock(data: &Vec<u8>) {
+    if data.capacity() > 0 {
+        // SAFETY: the range is the allocation owned by `data`
+        unsafe { libc::munlock(data.as_ptr().cast(), data.capacity()) };
+    }
+}
+
+#[cfg(not(unix))]
+fn lock(_data: &Vec<u8>) {}
+
+#[cfg(not(unix))]
+fn unlock(_data: &Vec<u8>) {}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use anyhow::Result;
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    fn test_secret_buf() -> Result<()> {
+        let mut buf = SecretBuf::new();
+        for chunk in [&b"sec"[..], b"ret", &[7; 1000]] {
+            buf.write_all(chunk)?;
+        }
+        assert_eq!(&buf[..6], b"secret");
+        assert_eq!(buf.len(), 1006);
+        assert_eq!(buf.clone(), buf);
+        assert_eq!(format!("{:?}", buf), "SecretBuf([REDACTED; 1006])");
+        Ok(())
+    }
+}
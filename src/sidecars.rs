@@ -0,0 +1,577 @@
+//! Index of the sidecars, the state `clean` and `smudge` keep about each encrypted file, e.g.
+//! the hash of the plaintext last checked out and the matching ciphertext.
+//!
+//! All sidecars are kept in a single file, an append-only log of records, each a JSON header
+//! followed by the value and a newline:
+//!
+//! ```text
+//! git-agecrypt sidecar index v1 <generation>
+//! {"path":"dir/secret.txt","key":"hash","len":32}
+//! <32 bytes>
+//! {"path":"dir/secret.txt","key":"age","len":null}
+//! ```
+//!
+//! The last record of a sidecar wins, one without length removes it. Writers append while
+//! holding a lock file, so concurrent filter processes don't lose each other's records.
+//! Readers don't lock, they ignore a record until it is written completely. Once most records
+//! are superseded, the log is compacted into a new file with a new generation, which replaces
+//! the old one.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+const INDEX: &str = "sidecars.index";
+const LOCK: &str = "sidecars.index.lock";
+const HEADER: &str = "git-agecrypt sidecar index v1";
+
+/// Logs smaller than this are never compacted
+const COMPACT_MIN_SIZE: u64 = 1024 * 1024;
+
+/// How long to wait for another process to release the lock
+const LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Length of the blake3 hashes kept as `hash` sidecars
+pub(crate) const HASH_LEN: usize = 32;
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+    path: PathBuf,
+    key: String,
+    /// Length of the value, `None` if the sidecar is removed
+    len: Option<u64>,
+}
+
+/// Location of a value in the log
+#[derive(Clone, Copy)]
+struct Entry {
+    offset: u64,
+    len: u64,
+    /// Size of the whole record, to tell how much of the log is still in use
+    record_len: u64,
+}
+
+/// What has been read of the log so far
+#[derive(Default)]
+struct State {
+    generation: String,
+    /// End of the last complete record
+    offset: u64,
+    entries: HashMap<(PathBuf, String), Entry>,
+    /// Size of the records still in use
+    live: u64,
+}
+
+/// The value of a sidecar read as a stream
+pub(crate) type SidecarReader = io::Take<File>;
+
+pub(crate) struct SidecarIndex {
+    dir: PathBuf,
+    state: RefCell<State>,
+}
+
+impl SidecarIndex {
+    /// The index kept in `dir`, which is created on the first write
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            state: RefCell::new(State::default()),
+        }
+    }
+
+    fn index_path(&self) -> PathBuf {
+        self.dir.join(INDEX)
+    }
+
+    /// The value of the sidecar `key` of `path`, `None` if there is none
+    pub fn get(&self, path: &Path, key: &str) -> Result<Option<Vec<u8>>> {
+        match self.open(path, key)? {
+            Some(mut reader) => {
+                let mut rv = vec![];
+                reader.read_to_end(&mut rv)?;
+                Ok(Some(rv))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Opens the value of the sidecar `key` of `path` for reading it as a stream
+    pub fn open(&self, path: &Path, key: &str) -> Result<Option<SidecarReader>> {
+        let Some(mut file) = self.refresh()? else {
+            return Ok(None);
+        };
+        let entry = self
+            .state
+            .borrow()
+            .entries
+            .get(&(path.to_path_buf(), key.to_string()))
+            .copied();
+        let Some(entry) = entry else {
+            return Ok(None);
+        };
+        // Still readable after a compaction replaced the file, the handle refers to the old one
+        file.seek(SeekFrom::Start(entry.offset))?;
+        Ok(Some(file.take(entry.len)))
+    }
+
+    /// Sets the value of the sidecar `key` of `path`
+    pub fn set(&self, path: &Path, key: &str, value: &[u8]) -> Result<()> {
+        self.write(|w| w.append(path, key, Some((&mut &value[..], value.len() as u64))))
+    }
+
+    /// Sets the value of the sidecar `key` of `path` to the rest of `value`
+    pub fn set_from(&self, path: &Path, key: &str, value: &mut File) -> Result<()> {
+        let len = value.metadata()?.len() - value.stream_position()?;
+        self.write(|w| w.append(path, key, Some((value, len))))
+    }
+
+    /// Starts setting the value of the sidecar `key` of `path` as a stream, it is stored by
+    /// [`SidecarIndex::finish`]
+    pub fn create(&self, path: &Path, key: &str) -> Result<SidecarWriter> {
+        fs::create_dir_all(&self.dir)?;
+        Ok(SidecarWriter {
+            path: path.to_path_buf(),
+            key: key.to_string(),
+            spool: tempfile::tempfile_in(&self.dir)?,
+        })
+    }
+
+    pub fn finish(&self, mut writer: SidecarWriter) -> Result<()> {
+        writer.spool.rewind()?;
+        self.set_from(&writer.path, &writer.key, &mut writer.spool)
+    }
+
+    pub fn remove(&self, path: &Path, key: &str) -> Result<()> {
+        if self.open(path, key)?.is_none() {
+            return Ok(());
+        }
+        self.write(|w| w.append(path, key, None))
+    }
+
+    /// Imports the sidecars of earlier versions, stored in a directory mirroring the working
+    /// tree, e.g. the hash of `dir/secret.txt` in `dir/secret.txt.hash`, and removes the
+    /// directory
+    pub fn import(&self, legacy: &Path) -> Result<()> {
+        if !legacy.is_dir() {
+            return Ok(());
+        }
+        self.write(|w| {
+            // Another process may have imported it while we waited for the lock
+            if !legacy.is_dir() {
+                return Ok(());
+            }
+            let mut dirs = vec![legacy.to_path_buf()];
+            while let Some(dir) = dirs.pop() {
+                for entry in fs::read_dir(&dir)? {
+                    let entry = entry?;
+                    let path = entry.path();
+                    if entry.file_type()?.is_dir() {
+                        dirs.push(path);
+                        continue;
+                    }
+                    let relpath = path.strip_prefix(legacy)?;
+                    let Some(key) = relpath.extension().and_then(|e| e.to_str()) else {
+                        continue;
+                    };
+                    let mut value = File::open(&path)?;
+                    let len = value.metadata()?.len();
+                    // Concurrent checkouts could truncate the files, `clean` re-creates them
+                    if key == "hash" && len != HASH_LEN as u64 {
+                        log::debug!("Skipping truncated sidecar {}", path.display());
+                        continue;
+                    }
+                    log::debug!("Importing sidecar {}", path.display());
+                    w.append(&relpath.with_extension(""), key, Some((&mut value, len)))?;
+                }
+            }
+            Ok(fs::remove_dir_all(legacy)?)
+        })
+    }
+
+    /// Reads the records appended since the last call, or the whole log if it was replaced,
+    /// returns the open log, `None` if there is none yet
+    fn refresh(&self) -> Result<Option<File>> {
+        let path = self.index_path();
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                *self.state.borrow_mut() = State::default();
+                return Ok(None);
+            }
+            Err(e) => return Err(e).with_context(|| format!("Couldn't open {:?}", path)),
+        };
+        let size = file.metadata()?.len();
+        let mut reader = BufReader::new(&file);
+        let mut line = vec![];
+        reader.read_until(b'\n', &mut line)?;
+        let generation = std::str::from_utf8(&line)
+            .ok()
+            .and_then(|l| l.strip_suffix('\n'))
+            .and_then(|l| l.strip_prefix(HEADER))
+            .and_then(|l| l.strip_prefix(' '));
+        let Some(generation) = generation else {
+            bail!("{:?} isn't a sidecar index, remove it to start over", path);
+        };
+
+        let mut state = self.state.borrow_mut();
+        if state.generation != generation || state.offset > size {
+            *state = State {
+                generation: generation.to_string(),
+                offset: line.len() as u64,
+                ..State::default()
+            };
+        }
+        reader.seek(SeekFrom::Start(state.offset))?;
+        let mut pos = state.offset;
+        loop {
+            line.clear();
+            reader.read_until(b'\n', &mut line)?;
+            if !line.ends_with(b"\n") {
+                break;
+            }
+            let Ok(record) = serde_json::from_slice::<Record>(&line) else {
+                break;
+            };
+            let offset = pos + line.len() as u64;
+            let record_len = match record.len {
+                Some(len) if offset + len < size => line.len() as u64 + len + 1,
+                // The value isn't written completely yet
+                Some(_) => break,
+                None => line.len() as u64,
+            };
+            reader.seek_relative(record_len as i64 - line.len() as i64)?;
+            pos += record_len;
+
+            let entry = record.len.map(|len| Entry {
+                offset,
+                len,
+                record_len,
+            });
+            state.apply((record.path, record.key), entry);
+        }
+        state.offset = pos;
+        drop(reader);
+        Ok(Some(file))
+    }
+
+    /// Runs `f` with the log opened for appending while holding the lock, then compacts the
+    /// log if most of it is superseded
+    fn write(&self, f: impl FnOnce(&mut Writer) -> Result<()>) -> Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let _lock = Lock::acquire(self.dir.join(LOCK))?;
+        let path = self.index_path();
+        if self.refresh()?.is_none() {
+            // Written to a temporary file first, readers must never see a partial header
+            let tmp = self.dir.join(format!("{}.tmp", INDEX));
+            fs::write(&tmp, format!("{} {}\n", HEADER, new_generation()))?;
+            fs::rename(&tmp, &path)?;
+            self.refresh()?;
+        }
+        let file = File::options().append(true).open(&path)?;
+        // Drops what a process which crashed while appending left behind
+        file.set_len(self.state.borrow().offset)?;
+
+        let mut writer = Writer {
+            file: io::BufWriter::new(file),
+            state: &self.state,
+        };
+        f(&mut writer)?;
+        writer.file.flush()?;
+
+        let state = self.state.borrow();
+        if state.offset > COMPACT_MIN_SIZE && state.offset > state.live * 2 {
+            drop(state);
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the records in use into a new log, must hold the lock
+    fn compact(&self) -> Result<()> {
+        let path = self.index_path();
+        log::debug!("Compacting sidecar index {}", path.display());
+        let mut old = File::open(&path)?;
+        let tmp = self.dir.join(format!("{}.tmp", INDEX));
+        let generation = new_generation();
+        let mut file = File::create(&tmp)?;
+        writeln!(file, "{} {}", HEADER, generation)?;
+        let mut state = self.state.borrow_mut();
+        let mut compacted = State {
+            generation,
+            offset: file.stream_position()?,
+            ..State::default()
+        };
+        let mut file = io::BufWriter::new(file);
+        for ((path, key), entry) in &state.entries {
+            old.seek(SeekFrom::Start(entry.offset))?;
+            let header = write_header(&mut file, path, key, Some(entry.len))?;
+            io::copy(&mut (&mut old).take(entry.len), &mut file)?;
+            file.write_all(b"\n")?;
+            let new = Entry {
+                offset: compacted.offset + header,
+                ..*entry
+            };
+            compacted.offset += entry.record_len;
+            compacted.apply((path.clone(), key.clone()), Some(new));
+        }
+        file.into_inner()?.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        *state = compacted;
+        Ok(())
+    }
+}
+
+impl State {
+    fn apply(&mut self, key: (PathBuf, String), entry: Option<Entry>) {
+        let old = match entry {
+            Some(entry) => {
+                self.live += entry.record_len;
+                self.entries.insert(key, entry)
+            }
+            None => self.entries.remove(&key),
+        };
+        if let Some(old) = old {
+            self.live -= old.record_len;
+        }
+    }
+}
+
+struct Writer<'a> {
+    file: io::BufWriter<File>,
+    state: &'a RefCell<State>,
+}
+
+impl Writer<'_> {
+    /// Appends a record setting `key` of `path` to the `len` bytes of the value, or removing
+    /// it without a value
+    fn append(
+        &mut self,
+        path: &Path,
+        key: &str,
+        value: Option<(&mut dyn Read, u64)>,
+    ) -> Result<()> {
+        let offset = self.state.borrow().offset;
+        let len = value.as_ref().map(|(_, len)| *len);
+        let header = write_header(&mut self.file, path, key, len)?;
+        let entry = match value {
+            Some((value, len)) => {
+                let copied = io::copy(&mut value.take(len), &mut self.file)?;
+                if copied != len {
+                    bail!(
+                        "The value of sidecar {:?} of {:?} changed while storing it",
+                        key,
+                        path
+                    );
+                }
+                self.file.write_all(b"\n")?;
+                Some(Entry {
+                    offset: offset + header,
+                    len,
+                    record_len: header + len + 1,
+                })
+            }
+            None => None,
+        };
+        let mut state = self.state.borrow_mut();
+        state.offset += entry.map_or(header, |e| e.record_len);
+        state.apply((path.to_path_buf(), key.to_string()), entry);
+        Ok(())
+    }
+}
+
+/// The value of a sidecar being written, spooled to a temporary file until it is complete
+pub(crate) struct SidecarWriter {
+    path: PathBuf,
+    key: String,
+    spool: File,
+}
+
+impl Write for SidecarWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.spool.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.spool.flush()
+    }
+}
+
+/// Writes the header of a record, returns its length
+fn write_header(out: &mut impl Write, path: &Path, key: &str, len: Option<u64>) -> Result<u64> {
+    let record = Record {
+        path: path.to_path_buf(),
+        key: key.to_string(),
+        len,
+    };
+    let mut line = serde_json::to_vec(&record)?;
+    line.push(b'\n');
+    out.write_all(&line)?;
+    Ok(line.len() as u64)
+}
+
+fn new_generation() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
+/// Exclusive access to the index among processes, released when dropped
+struct Lock {
+    path: PathBuf,
+}
+
+impl Lock {
+    fn acquire(path: PathBuf) -> Result<Self> {
+        let start = Instant::now();
+        loop {
+            match File::options().write(true).create_new(true).open(&path) {
+                Ok(_) => return Ok(Self { path }),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    if start.elapsed() > LOCK_TIMEOUT {
+                        bail!(
+                            "Couldn't lock the sidecar index, remove {:?} if no git-agecrypt \
+                             process is running",
+                            path
+                        );
+                    }
+                    thread::sleep(Duration::from_millis(10));
+                }
+                Err(e) => return Err(e).with_context(|| format!("Couldn't create {:?}", path)),
+            }
+        }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_file(&self.path) {
+            log::warn!("Couldn't remove lock file {:?}: {}", self.path, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_fs::{prelude::*, TempDir};
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    fn test_index() -> Result<()> {
+        let dir = TempDir::new()?;
+        let index = SidecarIndex::new(dir.path().into());
+        let path = Path::new("dir/s.txt");
+        assert_eq!(index.get(path, "hash")?, None);
+
+        index.set(path, "hash", b"first")?;
+        index.set(path, "age", b"ciphertext")?;
+        index.set(path, "hash", b"second")?;
+        assert_eq!(index.get(path, "hash")?.as_deref(), Some(&b"second"[..]));
+        let mut writer = index.create(path, "age")?;
+        writer.write_all(b"cipher")?;
+        writer.write_all(b"text")?;
+        index.finish(writer)?;
+
+        // Another process sees the changes and appends its own
+        let other = SidecarIndex::new(dir.path().into());
+        assert_eq!(other.get(path, "age")?.as_deref(), Some(&b"ciphertext"[..]));
+        other.remove(path, "age")?;
+        assert_eq!(index.get(path, "age")?, None);
+        assert!(!dir.child(LOCK).exists());
+
+        // A partially written record is ignored, and dropped by the next write
+        let log = dir.child(INDEX);
+        let mut file = File::options().append(true).open(log.path())?;
+        file.write_all(b"{\"path\":\"dir/s.txt\",\"key\":\"hash\",\"len\":10}\npart")?;
+        assert_eq!(index.get(path, "hash")?.as_deref(), Some(&b"second"[..]));
+        index.set(Path::new("other"), "hash", b"other")?;
+        assert_eq!(other.get(path, "hash")?.as_deref(), Some(&b"second"[..]));
+        assert_eq!(
+            other.get(Path::new("other"), "hash")?.as_deref(),
+            Some(&b"other"[..])
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_concurrent_writes() -> Result<()> {
+        let dir = TempDir::new()?;
+        // Like smudge run by parallel checkout workers, each with its own index
+        std::thread::scope(|scope| {
+            for worker in 0..8u8 {
+                let dir = dir.path().to_path_buf();
+                scope.spawn(move || {
+                    let index = SidecarIndex::new(dir);
+                    for i in 0..20u8 {
+                        let path = PathBuf::from(format!("{}/{}", worker, i));
+                        index.set(&path, "hash", &[i; HASH_LEN]).unwrap();
+                        index.set(&path, "age", &vec![worker; 1000]).unwrap();
+                    }
+                });
+            }
+        });
+        let index = SidecarIndex::new(dir.path().into());
+        for worker in 0..8u8 {
+            for i in 0..20u8 {
+                let path = PathBuf::from(format!("{}/{}", worker, i));
+                assert_eq!(index.get(&path, "hash")?, Some(vec![i; HASH_LEN]));
+                assert_eq!(index.get(&path, "age")?, Some(vec![worker; 1000]));
+            }
+        }
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_compact() -> Result<()> {
+        let dir = TempDir::new()?;
+        let index = SidecarIndex::new(dir.path().into());
+        let reader = SidecarIndex::new(dir.path().into());
+        let value = vec![b'x'; 64 * 1024];
+        index.set(Path::new("kept"), "age", b"kept")?;
+        reader.get(Path::new("kept"), "age")?;
+        let size = |dir: &TempDir| dir.child(INDEX).path().metadata().map(|m| m.len());
+        for _ in 0..40 {
+            index.set(Path::new("s.txt"), "age", &value)?;
+        }
+        assert!(size(&dir)? <= COMPACT_MIN_SIZE + value.len() as u64);
+        assert_eq!(index.get(Path::new("s.txt"), "age")?, Some(value));
+        // Readers notice that the log was replaced
+        assert_eq!(
+            reader.get(Path::new("kept"), "age")?.as_deref(),
+            Some(&b"kept"[..])
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_import() -> Result<()> {
+        let dir = TempDir::new()?;
+        let legacy = dir.child("sidecars");
+        legacy
+            .child("dir/s.txt.hash")
+            .write_binary(&[7; HASH_LEN])?;
+        legacy.child("short.txt.hash").write_binary(&[7; 10])?;
+        legacy.child("s.yaml.age").write_str("age")?;
+        let index = SidecarIndex::new(dir.path().into());
+        index.import(legacy.path())?;
+        assert!(!legacy.exists());
+        assert_eq!(
+            index.get(Path::new("dir/s.txt"), "hash")?.as_deref(),
+            Some(&[7; HASH_LEN][..])
+        );
+        assert_eq!(index.get(Path::new("short.txt"), "hash")?, None);
+        assert_eq!(
+            index.get(Path::new("s.yaml"), "age")?.as_deref(),
+            Some(&b"age"[..])
+        );
+        Ok(())
+    }
+}
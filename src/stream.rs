@@ -0,0 +1,90 @@
+//! Adapters for processing file contents as a stream instead of buffering them in memory
+
+use std::io::{self, Read, Write};
+
+/// Copies everything read from `inner` to `copy`
+pub(crate) struct TeeReader<R, W> {
+    inner: R,
+    copy: W,
+}
+
+impl<R: Read, W: Write> TeeReader<R, W> {
+    pub fn new(inner: R, copy: W) -> Self {
+        Self { inner, copy }
+    }
+
+    /// Reads the rest of the input, so that `copy` receives all of it
+    pub fn finish(mut self) -> io::Result<W> {
+        io::copy(&mut self, &mut io::sink())?;
+        self.copy.flush()?;
+        Ok(self.copy)
+    }
+}
+
+impl<R: Read, W: Write> Read for TeeReader<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.copy.write_all(&buf[..n])?;
+        Ok(n)
+    }
+}
+
+/// Writes everything to both `first` and `second`
+pub(crate) struct TeeWriter<A, B> {
+    first: A,
+    second: B,
+}
+
+impl<A: Write, B: Write> TeeWriter<A, B> {
+    pub fn new(first: A, second: B) -> Self {
+        Self { first, second }
+    }
+
+    pub fn into_inner(self) -> (A, B) {
+        (self.first, self.second)
+    }
+}
+
+impl<A: Write, B: Write> Write for TeeWriter<A, B> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.first.write_all(buf)?;
+        self.second.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.first.flush()?;
+        self.second.flush()
+    }
+}
+
+/// Reads up to `len` bytes, fewer only at the end of the input
+pub(crate) fn read_prefix(input: &mut impl Read, len: usize) -> io::Result<Vec<u8>> {
+    let mut prefix = vec![];
+    input.take(len as u64).read_to_end(&mut prefix)?;
+    Ok(prefix)
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    fn test_tee() -> io::Result<()> {
+        let mut input = &b"some contents"[..];
+        let prefix = read_prefix(&mut input, 5)?;
+        assert_eq!(prefix, b"some ");
+
+        let mut tee = TeeReader::new(io::Cursor::new(prefix).chain(input), vec![]);
+        let mut head = [0; 4];
+        tee.read_exact(&mut head)?;
+        assert_eq!(tee.finish()?, b"some contents");
+
+        let mut writer = TeeWriter::new(vec![], vec![]);
+        writer.write_all(&head)?;
+        assert_eq!(writer.into_inner(), (b"some".to_vec(), b"some".to_vec()));
+        Ok(())
+    }
+}
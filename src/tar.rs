@@ -0,0 +1,224 @@
+//! Tar archives of regular files in the POSIX ustar format, the bundles of `export` and
+//! `import`.
+//!
+//! Only what the bundles need is supported: paths of up to 255 bytes and regular files, the
+//! contents of which are kept in [`SecretBuf`]s when reading.
+
+use std::{
+    io::Write,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::secret::SecretBuf;
+
+const BLOCK_LEN: usize = 512;
+
+/// Mode of the files written, they are secrets
+const FILE_MODE: u32 = 0o600;
+
+pub(crate) struct Writer<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner }
+    }
+
+    /// Adds a regular file, `path` has to be relative
+    pub fn append(&mut self, path: &Path, contents: &[u8]) -> Result<()> {
+        let mut header = [0u8; BLOCK_LEN];
+        let (prefix, name) = split_path(path)?;
+        header[..name.len()].copy_from_slice(name);
+        header[345..345 + prefix.len()].copy_from_slice(prefix);
+        write_octal(&mut header[100..108], FILE_MODE as u64);
+        write_octal(&mut header[108..116], 0);
+        write_octal(&mut header[116..124], 0);
+        write_octal(&mut header[124..136], contents.len() as u64);
+        write_octal(&mut header[136..148], 0);
+        header[156] = b'0';
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        let checksum = checksum(&header);
+        write_octal(&mut header[148..155], checksum);
+        header[155] = b' ';
+
+        self.inner.write_all(&header)?;
+        self.inner.write_all(contents)?;
+        self.inner
+            .write_all(&[0u8; BLOCK_LEN][..padding(contents.len())])?;
+        Ok(())
+    }
+
+    /// Writes the end of archive marker
+    pub fn finish(mut self) -> Result<W> {
+        self.inner.write_all(&[0u8; 2 * BLOCK_LEN])?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+/// The regular files in `archive`, with their paths checked to stay inside the directory the
+/// archive is extracted to. Directory entries are skipped, other kinds of entries rejected.
+pub(crate) fn read(archive: &[u8]) -> Result<Vec<(PathBuf, SecretBuf)>> {
+    let mut rv = vec![];
+    let mut rest = archive;
+    loop {
+        if rest.len() < BLOCK_LEN {
+            bail!("Truncated tar archive");
+        }
+        let (header, tail) = rest.split_at(BLOCK_LEN);
+        if header.iter().all(|b| *b == 0) {
+            return Ok(rv);
+        }
+        if read_octal(&header[148..156])? != checksum(header) {
+            bail!("Invalid tar header checksum");
+        }
+        let path = header_path(header)?;
+        let size = read_octal(&header[124..136])? as usize;
+        if tail.len() < size {
+            bail!("Truncated tar archive at {:?}", path);
+        }
+        let (contents, tail) = tail.split_at(size);
+        rest = &tail[padding(size).min(tail.len())..];
+        match header[156] {
+            b'0' | 0 => rv.push((path, SecretBuf::from(contents.to_vec()))),
+            b'5' => {}
+            kind => bail!(
+                "Unsupported tar entry {:?} of type '{}'",
+                path,
+                kind as char
+            ),
+        }
+    }
+}
+
+/// Splits a path into the prefix and name fields of the header
+fn split_path(path: &Path) -> Result<(&[u8], &[u8])> {
+    let bytes = path
+        .to_str()
+        .with_context(|| format!("Path {:?} isn't valid UTF-8", path))?
+        .as_bytes();
+    if bytes.len() <= 100 {
+        return Ok((&[], bytes));
+    }
+    // The name has to fit into 100 bytes, the prefix into 155
+    let start = bytes.len().saturating_sub(101);
+    match bytes[start..].iter().position(|b| *b == b'/') {
+        Some(i) if start + i <= 155 => Ok((&bytes[..start + i], &bytes[start + i + 1..])),
+        _ => bail!("Path {:?} is too long for a tar archive", path),
+    }
+}
+
+fn header_path(header: &[u8]) -> Result<PathBuf> {
+    let field = |bytes: &[u8]| {
+        let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+        String::from_utf8(bytes[..end].to_vec()).context("Tar entry path isn't valid UTF-8")
+    };
+    let name = field(&header[..100])?;
+    let prefix = field(&header[345..500])?;
+    let path = if prefix.is_empty() {
+        PathBuf::from(name)
+    } else {
+        Path::new(&prefix).join(name)
+    };
+    if path.as_os_str().is_empty()
+        || !path
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        bail!("Tar entry {:?} points outside of the archive", path);
+    }
+    Ok(path)
+}
+
+/// Sum of the header bytes, counting the checksum field as spaces
+fn checksum(header: &[u8]) -> u64 {
+    header
+        .iter()
+        .enumerate()
+        .map(|(i, b)| if (148..156).contains(&i) { b' ' } else { *b } as u64)
+        .sum()
+}
+
+/// Writes `value` as zero padded octal number terminated by a NUL byte
+fn write_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[digits.len()] = 0;
+}
+
+fn read_octal(field: &[u8]) -> Result<u64> {
+    let digits = std::str::from_utf8(field)
+        .ok()
+        .map(|f| f.trim_matches(|c: char| c == ' ' || c == '\0'))
+        .context("Invalid number in tar header")?;
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(digits, 8).context("Invalid number in tar header")
+}
+
+/// Bytes filling up the last block of contents of length `len`
+fn padding(len: usize) -> usize {
+    (BLOCK_LEN - len % BLOCK_LEN) % BLOCK_LEN
+}
+
+#[cfg(test)]
+mod tests {
+    use duct::cmd;
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    fn test_roundtrip() -> Result<()> {
+        let long = Path::new(&"d".repeat(120)).join("f".repeat(90));
+        let mut writer = Writer::new(vec![]);
+        writer.append(Path::new("secrets/db.env"), b"PASSWORD=hunter2\n")?;
+        writer.append(Path::new("empty"), b"")?;
+        writer.append(&long, &[7u8; 1000])?;
+        let archive = writer.finish()?;
+        assert_eq!(archive.len() % BLOCK_LEN, 0);
+
+        let files = read(&archive)?;
+        assert_eq!(files.len(), 3);
+        assert_eq!(files[0].0, Path::new("secrets/db.env"));
+        assert_eq!(files[0].1, b"PASSWORD=hunter2\n");
+        assert_eq!(files[1].1, b"");
+        assert_eq!(files[2].0, long);
+        assert_eq!(files[2].1, [7u8; 1000]);
+
+        // Readable by tar itself
+        let listing = cmd!("tar", "-tf", "-").stdin_bytes(archive.clone()).read()?;
+        assert_eq!(
+            listing.lines().collect::<Vec<_>>(),
+            ["secrets/db.env", "empty", long.to_str().unwrap()]
+        );
+
+        assert!(read(&archive[..BLOCK_LEN]).is_err());
+        assert!(Writer::new(vec![])
+            .append(Path::new(&"f".repeat(120)), b"")
+            .is_err());
+        Ok(())
+    }
+
+    #[rstest]
+    #[case("../outside")]
+    #[case("/etc/passwd")]
+    #[case("a/../../b")]
+    fn test_read_rejects_escaping_paths(#[case] path: &str) -> Result<()> {
+        let mut header = [0u8; BLOCK_LEN];
+        header[..path.len()].copy_from_slice(path.as_bytes());
+        write_octal(&mut header[124..136], 0);
+        header[156] = b'0';
+        let checksum = checksum(&header);
+        write_octal(&mut header[148..155], checksum);
+        let mut archive = header.to_vec();
+        archive.extend([0u8; 2 * BLOCK_LEN]);
+        assert!(read(&archive).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,299 @@
+//! Experimental threshold (K of N) encryption.
+//!
+//! The plaintext is encrypted with age to a freshly generated X25519 identity. That identity
+//! is split into N shares using Shamir's secret sharing over GF(256), any K of which are
+//! enough to reconstruct it. Each share is encrypted with age to exactly one recipient.
+//!
+//! The resulting file is laid out as follows:
+//!
+//! ```text
+//! git-agecrypt.org/threshold/v1
+//! <K>
+//! <share encrypted to recipient 1, base64>
+//! ...
+//! <share encrypted to recipient N, base64>
+//! ---
+//! <age file encrypted to the generated identity>
+//! ```
+//!
+//! A decrypted share consists of its x coordinate (one byte, 1 to N) followed by the
+//! y coordinates for each byte of the generated identity's Bech32 encoding
+//! (`AGE-SECRET-KEY-1...`). The identity can be recovered with Lagrange interpolation at zero
+//! using the irreducible polynomial x^8 + x^4 + x^3 + x + 1, as in AES.
+
+use std::io::{self, BufRead, Read};
+
+use ::age::secrecy::ExposeSecret;
+use anyhow::{bail, Context, Result};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use rand::{rngs::OsRng, RngCore};
+
+use crate::{age, secret::SecretBuf};
+
+const MAGIC: &str = "git-agecrypt.org/threshold/v1";
+const SEPARATOR: &str = "---";
+
+pub(crate) fn is_threshold(contents: &[u8]) -> bool {
+    contents.starts_with(MAGIC.as_bytes())
+}
+
+pub(crate) fn encrypt(
+    public_keys: &[impl AsRef<str> + std::fmt::Debug],
+    threshold: u8,
+    armor: bool,
+    cleartext: &mut impl Read,
+) -> Result<Vec<u8>> {
+    let n = public_keys.len();
+    if threshold == 0 || usize::from(threshold) > n || n > 255 {
+        bail!(
+            "Invalid threshold {} for {} recipients; it has to be between 1 and the number of recipients",
+            threshold,
+            n
+        );
+    }
+
+    log::warn!("Threshold encryption is experimental, the format may change");
+    let identity = ::age::x25519::Identity::generate();
+    let inner = age::encrypt(&[identity.to_public().to_string()], armor, cleartext)?;
+    let secret = identity.to_string();
+    let shares = split(secret.expose_secret().as_bytes(), threshold, n as u8);
+
+    let mut rv = format!("{}\n{}\n", MAGIC, threshold).into_bytes();
+    for (public_key, share) in public_keys.iter().zip(shares) {
+        let encrypted = age::encrypt(&[public_key], false, &mut &share[..])?;
+        rv.extend(BASE64_STANDARD.encode(encrypted).as_bytes());
+        rv.push(b'\n');
+    }
+    rv.extend(SEPARATOR.as_bytes());
+    rv.push(b'\n');
+    rv.extend(inner);
+    Ok(rv)
+}
+
+/// The parts of a threshold encrypted file
+pub(crate) struct Container<'a> {
+    pub threshold: usize,
+    /// Age encrypted shares, one for each recipient
+    pub shares: Vec<Vec<u8>>,
+    payload: &'a [u8],
+}
+
+pub(crate) fn parse(contents: &[u8]) -> Result<Container<'_>> {
+    if !is_threshold(contents) {
+        bail!("Not a threshold encrypted file");
+    }
+    let mut reader = contents;
+    let mut lines = vec![];
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            bail!("Truncated threshold encrypted file");
+        }
+        let line = line.trim_end().to_string();
+        if line == SEPARATOR {
+            break;
+        }
+        lines.push(line);
+    }
+    let threshold: usize = lines
+        .get(1)
+        .and_then(|t| t.parse().ok())
+        .context("Invalid threshold in threshold encrypted file")?;
+    let shares = lines[2..]
+        .iter()
+        .map(|line| {
+            BASE64_STANDARD
+                .decode(line)
+                .context("Invalid share in threshold encrypted file")
+        })
+        .collect::<Result<_>>()?;
+    Ok(Container {
+        threshold,
+        shares,
+        payload: reader,
+    })
+}
+
+/// Decrypts a threshold encrypted file, returning the plaintext and the identities used
+pub(crate) fn decrypt(
+    identities: &[impl AsRef<std::path::Path>],
+    contents: &[u8],
+) -> Result<Option<(SecretBuf, String)>> {
+    if !is_threshold(contents) {
+        return Ok(None);
+    }
+    let container = parse(contents)?;
+    let threshold = container.threshold;
+
+    let mut shares = vec![];
+    let mut used = vec![];
+    for share in &container.shares {
+        // Shares for recipients we have no identity for are expected to fail
+        match age::decrypt(identities, &mut &share[..]) {
+            Ok(Some((share, identity))) => {
+                shares.push(share);
+                if !used.contains(&identity) {
+                    used.push(identity);
+                }
+            }
+            Ok(None) => bail!("Invalid share in threshold encrypted file"),
+            Err(err) => log::debug!("Couldn't decrypt share; error={:?}", err),
+        }
+        if shares.len() == threshold {
+            break;
+        }
+    }
+    if shares.len() < threshold {
+        return Err(::age::DecryptError::NoMatchingKeys).with_context(|| {
+            format!(
+                "Only {} of the {} shares required for decryption could be decrypted",
+                shares.len(),
+                threshold
+            )
+        });
+    }
+
+    let secret = combine(&shares)?;
+    let secret = std::str::from_utf8(&secret)
+        .ok()
+        .filter(|s| s.starts_with("AGE-SECRET-KEY-1"))
+        .context("Reconstructed key is invalid")?;
+    let identity: ::age::x25519::Identity = secret
+        .parse()
+        .map_err(|e| anyhow::anyhow!("Reconstructed key is invalid: {}", e))?;
+
+    let mut decryptor = match ::age::Decryptor::new(container.payload)? {
+        ::age::Decryptor::Recipients(d) => d.decrypt(std::iter::once(&identity as _))?,
+        ::age::Decryptor::Passphrase(_) => bail!("Invalid threshold encrypted file"),
+    };
+    let mut rv = SecretBuf::new();
+    io::copy(&mut decryptor, &mut rv)?;
+    Ok(Some((rv, used.join(", "))))
+}
+
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut rv = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            rv ^= a;
+        }
+        let carry = a & 0x80 != 0;
+        a <<= 1;
+        if carry {
+            a ^= 0x1b;
+        }
+        b >>= 1;
+    }
+    rv
+}
+
+fn gf_inv(a: u8) -> u8 {
+    // a^254 == a^-1 in GF(256)
+    let mut rv = 1;
+    for _ in 0..254 {
+        rv = gf_mul(rv, a);
+    }
+    rv
+}
+
+/// Splits `secret` into `n` shares, `k` of which are required to reconstruct it
+fn split(secret: &[u8], k: u8, n: u8) -> Vec<Vec<u8>> {
+    let mut shares: Vec<Vec<u8>> = (1..=n).map(|x| vec![x]).collect();
+    let mut coefficients = vec![0u8; usize::from(k)];
+    for &byte in secret {
+        coefficients[0] = byte;
+        OsRng.fill_bytes(&mut coefficients[1..]);
+        for share in shares.iter_mut() {
+            let x = share[0];
+            // Horner's method
+            let y = coefficients
+                .iter()
+                .rev()
+                .fold(0, |acc, &c| gf_mul(acc, x) ^ c);
+            share.push(y);
+        }
+    }
+    shares
+}
+
+fn combine(shares: &[impl AsRef<[u8]>]) -> Result<SecretBuf> {
+    let shares: Vec<&[u8]> = shares.iter().map(AsRef::as_ref).collect();
+    let len = shares.first().map(|s| s.len()).unwrap_or(0);
+    if len < 2 || shares.iter().any(|s| s.len() != len) {
+        bail!("Inconsistent shares");
+    }
+    let xs: Vec<u8> = shares.iter().map(|s| s[0]).collect();
+    let mut basis = vec![];
+    for (j, &xj) in xs.iter().enumerate() {
+        let mut b = 1;
+        for (m, &xm) in xs.iter().enumerate() {
+            if m != j {
+                if xm == xj {
+                    bail!("Duplicate shares");
+                }
+                b = gf_mul(b, gf_mul(xm, gf_inv(xm ^ xj)));
+            }
+        }
+        basis.push(b);
+    }
+    let mut rv = SecretBuf::with_capacity(len - 1);
+    for i in 1..len {
+        let byte = shares
+            .iter()
+            .zip(&basis)
+            .fold(0, |acc, (s, &b)| acc ^ gf_mul(s[i], b));
+        rv.extend_from_slice(&[byte]);
+    }
+    Ok(rv)
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use assert_fs::prelude::*;
+    use assert_fs::TempDir;
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    fn test_split_combine() -> Result<()> {
+        let secret = b"AGE-SECRET-KEY-1EXAMPLE";
+        let shares = split(secret, 3, 5);
+        assert_eq!(combine(&shares[..3])?, secret);
+        assert_eq!(
+            combine(&[shares[4].clone(), shares[0].clone(), shares[2].clone()])?,
+            secret
+        );
+        assert_ne!(combine(&shares[..2])?, secret);
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_encrypt_decrypt() -> Result<()> {
+        let dir = TempDir::new()?;
+        let ids: Vec<::age::x25519::Identity> = (0..3)
+            .map(|_| ::age::x25519::Identity::generate())
+            .collect();
+        let public_keys: Vec<String> = ids.iter().map(|i| i.to_public().to_string()).collect();
+        let files: Vec<_> = ids
+            .iter()
+            .enumerate()
+            .map(|(n, i)| {
+                let f = dir.child(format!("id{}", n));
+                f.write_str(&format!("{}\n", i.to_string().expose_secret()))
+                    .unwrap();
+                f.path().to_path_buf()
+            })
+            .collect();
+
+        let encrypted = encrypt(&public_keys, 2, false, &mut &b"secret"[..])?;
+        assert!(is_threshold(&encrypted));
+
+        let (plaintext, _) = decrypt(&files[1..], &encrypted)?.unwrap();
+        assert_eq!(plaintext, b"secret");
+        assert!(decrypt(&files[..1], &encrypted).is_err());
+        assert!(encrypt(&public_keys, 4, false, &mut &b"secret"[..]).is_err());
+        Ok(())
+    }
+}